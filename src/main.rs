@@ -1,42 +1,18 @@
 mod modules;
 
-use std::error::Error;
+use std::process;
 
-use actix_web::{web, App, HttpServer, Responder};
-use tokio::{select, signal::ctrl_c};
+use clap::Parser;
 
-use crate::modules::{
-    app::start_node,
-    client::{start_client, Client},
-};
+use crate::modules::cli::Cli;
 
-async fn hello() -> impl Responder {
-    "Hello, World!"
-}
-// In your main.rs, you can now start the server as follows:
+// Parses the command line and runs the chosen command. Variables in a .env file are loaded
+// into the environment first, so they can set VERSUS_* settings.
 #[tokio::main]
 async fn main() {
-    HttpServer::new(move || App::new().route("/", web::get().to(Client::handle_index)))
-        .bind("127.0.0.1:8000")
-        .unwrap()
-        .run()
-        .await;
-    start_client().await;
-    let _ = HttpServer::new(|| App::new().route("/hello", web::get().to(hello)))
-        .bind("127.0.0.1:8080")
-        .unwrap()
-        .run()
-        .await;
-    // let client_server = tokio::spawn(start_client().await);
-    // let node_server = tokio::spawn(start_node().await);
-
-    // // Wait for both servers to complete or for a termination signal
-    // select! {
-    //     _ = client_server => {},
-    //     _ = node_server => {},
-    //     _ = ctrl_c() => {},
-    // }
-
-    // Print success message
-    println!("Both servers started successfully");
+    dotenv::dotenv().ok();
+    if let Err(e) = Cli::parse().run().await {
+        eprintln!("Error: {}", e);
+        process::exit(1);
+    }
 }
@@ -0,0 +1,277 @@
+use actix_web::{http::header, http::Method, http::StatusCode, web, HttpRequest, HttpResponse};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+use subtle::ConstantTimeEq;
+
+use super::openapi::{Audience, Endpoint};
+use super::{not_found, respond, respond_error, ApiError};
+use crate::modules::app::Application;
+use crate::modules::blockchain::chain::Blockchain;
+use crate::modules::network::scoring::Offence;
+use crate::modules::webhooks::{DeadLetter, Webhook, WebhookEvent, Webhooks};
+
+// Routes for operators of the node, under /v1/admin:
+//
+//   GET    /v1/admin/webhooks                 WebhookList
+//   POST   /v1/admin/webhooks                 NewWebhook -> Webhook registered
+//   DELETE /v1/admin/webhooks/{id}            Webhook removed
+//   GET    /v1/admin/webhooks/dead_letters    DeadLetterList of the last failed deliveries
+//   GET    /v1/admin/peers                    PeerStandings: misbehaviour scores and bans
+//
+// They are guarded by AdminAccess.
+
+// Number of dead letters listed unless the operator asks for another number.
+const DEFAULT_DEAD_LETTERS: usize = 100;
+
+// AdminAccess guards the admin routes. When an admin token is configured, requests must carry
+// it as a bearer token; otherwise only requests from the loopback interface are served.
+#[derive(Debug, Clone, Default)]
+pub struct AdminAccess {
+    token: Option<String>,
+}
+
+impl AdminAccess {
+    pub fn new(token: Option<String>) -> AdminAccess {
+        AdminAccess { token }
+    }
+
+    fn check(&self, req: &HttpRequest) -> Result<(), ApiError> {
+        match &self.token {
+            Some(token) => {
+                let bearer = req
+                    .headers()
+                    .get(header::AUTHORIZATION)
+                    .and_then(|value| value.to_str().ok())
+                    .and_then(|value| value.strip_prefix("Bearer "));
+                match bearer {
+                    // Compared in constant time so that response times do not leak the token
+                    Some(bearer) if bool::from(bearer.as_bytes().ct_eq(token.as_bytes())) => Ok(()),
+                    _ => Err(ApiError::new(
+                        "unauthorized",
+                        "Missing or wrong admin token",
+                    )),
+                }
+            }
+            None => match req.peer_addr() {
+                Some(addr) if addr.ip().is_loopback() => Ok(()),
+                _ => Err(ApiError::new(
+                    "forbidden",
+                    "Admin routes are only served on the loopback interface without an admin token",
+                )),
+            },
+        }
+    }
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookList {
+    pub length: usize,
+    pub webhooks: Vec<Webhook>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct NewWebhook {
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct DeadLetterList {
+    pub length: usize,
+    pub dead_letters: Vec<DeadLetter>,
+}
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct DeadLetterQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+}
+
+// PeerStanding is the misbehaviour score of a registered peer, under the key it is scored by.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PeerStanding {
+    pub node_address: String,
+    pub key: String,
+    pub score: u32,
+}
+
+// BannedPeer is an active ban, until a unix time in seconds.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct BannedPeer {
+    pub key: String,
+    pub until: i64,
+    pub reason: Offence,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct PeerStandings {
+    pub peers: Vec<PeerStanding>,
+    pub bans: Vec<BannedPeer>,
+}
+
+impl PeerStandings {
+    pub fn of(blockchain: &Blockchain) -> PeerStandings {
+        let scores = &blockchain.peer_scores;
+        let peers = blockchain
+            .peers
+            .iter()
+            .map(|peer| {
+                let key = blockchain.peer_key_of(&peer.node_address);
+                PeerStanding {
+                    node_address: peer.node_address.clone(),
+                    score: scores.score(&key),
+                    key,
+                }
+            })
+            .collect();
+        let mut bans: Vec<BannedPeer> = scores
+            .bans()
+            .iter()
+            .filter(|(key, _)| scores.is_banned(key))
+            .map(|(key, ban)| BannedPeer {
+                key: key.clone(),
+                until: ban.until,
+                reason: ban.reason,
+            })
+            .collect();
+        bans.sort_by(|a, b| a.key.cmp(&b.key));
+        PeerStandings { peers, bans }
+    }
+}
+
+impl Application {
+    // Endpoint GET /v1/admin/webhooks
+    async fn handle_admin_webhooks(
+        webhooks: web::Data<Webhooks>,
+        admin: web::Data<AdminAccess>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(error) = admin.check(&req) {
+            return respond_error(error);
+        }
+        let webhooks = webhooks.list();
+        respond(
+            StatusCode::OK,
+            WebhookList {
+                length: webhooks.len(),
+                webhooks,
+            },
+        )
+    }
+
+    // Endpoint POST /v1/admin/webhooks
+    async fn handle_admin_new_webhook(
+        webhooks: web::Data<Webhooks>,
+        admin: web::Data<AdminAccess>,
+        webhook: web::Json<NewWebhook>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(error) = admin.check(&req) {
+            return respond_error(error);
+        }
+        let NewWebhook { url, events } = webhook.into_inner();
+        match webhooks.add(&url, events) {
+            Ok(webhook) => respond(StatusCode::CREATED, webhook),
+            Err(message) => respond_error(ApiError::new("invalid_request", message)),
+        }
+    }
+
+    // Endpoint DELETE /v1/admin/webhooks/{id}
+    async fn handle_admin_remove_webhook(
+        webhooks: web::Data<Webhooks>,
+        admin: web::Data<AdminAccess>,
+        id: web::Path<String>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(error) = admin.check(&req) {
+            return respond_error(error);
+        }
+        match webhooks.remove(&id) {
+            Ok(Some(webhook)) => respond(StatusCode::OK, webhook),
+            Ok(None) => not_found(format!("Webhook {}", id)),
+            Err(e) => respond_error(ApiError::new("internal", e.to_string())),
+        }
+    }
+
+    // Endpoint GET /v1/admin/webhooks/dead_letters
+    async fn handle_admin_dead_letters(
+        webhooks: web::Data<Webhooks>,
+        admin: web::Data<AdminAccess>,
+        query: web::Query<DeadLetterQuery>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(error) = admin.check(&req) {
+            return respond_error(error);
+        }
+        match webhooks.dead_letters(query.limit.unwrap_or(DEFAULT_DEAD_LETTERS)) {
+            Ok(dead_letters) => respond(
+                StatusCode::OK,
+                DeadLetterList {
+                    length: dead_letters.len(),
+                    dead_letters,
+                },
+            ),
+            Err(e) => respond_error(ApiError::new("internal", e.to_string())),
+        }
+    }
+
+    // Endpoint GET /v1/admin/peers
+    async fn handle_admin_peers(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        admin: web::Data<AdminAccess>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(error) = admin.check(&req) {
+            return respond_error(error);
+        }
+        let standings = PeerStandings::of(&blockchain.lock().unwrap());
+        respond(StatusCode::OK, standings)
+    }
+
+    pub(crate) fn admin_endpoints() -> Vec<Endpoint> {
+        use Audience::Admin;
+        vec![
+            Endpoint::new(
+                Admin,
+                Method::GET,
+                "/v1/admin/webhooks",
+                "Webhooks of the node",
+                Self::handle_admin_webhooks,
+            )
+            .data::<WebhookList>(StatusCode::OK, "Webhooks"),
+            Endpoint::new(
+                Admin,
+                Method::POST,
+                "/v1/admin/webhooks",
+                "Register a webhook for some kinds of events",
+                Self::handle_admin_new_webhook,
+            )
+            .data::<Webhook>(StatusCode::CREATED, "Webhook registered"),
+            Endpoint::new(
+                Admin,
+                Method::GET,
+                "/v1/admin/webhooks/dead_letters",
+                "Last webhook deliveries that failed every attempt",
+                Self::handle_admin_dead_letters,
+            )
+            .data::<DeadLetterList>(StatusCode::OK, "Failed deliveries, oldest first"),
+            Endpoint::new(
+                Admin,
+                Method::DELETE,
+                "/v1/admin/webhooks/{id}",
+                "Remove a webhook",
+                Self::handle_admin_remove_webhook,
+            )
+            .data::<Webhook>(StatusCode::OK, "Webhook removed"),
+            Endpoint::new(
+                Admin,
+                Method::GET,
+                "/v1/admin/peers",
+                "Misbehaviour scores of the peers and active bans",
+                Self::handle_admin_peers,
+            )
+            .data::<PeerStandings>(StatusCode::OK, "Scores and bans"),
+        ]
+    }
+}
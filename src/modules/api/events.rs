@@ -0,0 +1,223 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, Codec, Frame, Message};
+use actix_web::{
+    http::header, http::header::HeaderValue, http::Method, http::StatusCode, web, HttpRequest,
+    HttpResponse,
+};
+use futures_util::{stream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::convert::Infallible;
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+
+use super::openapi::{Audience, Endpoint};
+use super::{respond_error, ApiError};
+use crate::modules::app::Application;
+use crate::modules::blockchain::events::{EventBus, EventTopic, NodeEvent};
+
+// Events of the node, streamed to clients as they happen:
+//
+//   GET /v1/events       Server-Sent Events, each named after its topic and holding its data
+//   GET /v1/events/ws    WebSocket, a text message per event: {"type": <topic>, "data": ...}
+//
+// Both take ?topics=block,transaction to receive only some of the topics: transaction, block,
+// reorg and peer. Subscribers that fall more than EVENT_BUFFER events behind are sent a lagged
+// event with the number of events they missed.
+
+// Interval of the comments sent on idle Server-Sent Events streams, which keep proxies from
+// closing them and tell us when subscribers have left.
+const KEEPALIVE: Duration = Duration::from_secs(15);
+
+#[derive(Debug, Default, Serialize, Deserialize, JsonSchema)]
+pub struct EventsQuery {
+    // Comma separated topics, all of them unless set.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub topics: Option<String>,
+}
+
+impl EventsQuery {
+    fn topics(&self) -> Result<Vec<EventTopic>, ApiError> {
+        match &self.topics {
+            None => Ok(EventTopic::ALL.to_vec()),
+            Some(topics) => topics
+                .split(',')
+                .map(|topic| topic.trim().parse())
+                .collect::<Result<_, String>>()
+                .map_err(|e| ApiError::new("invalid_request", e)),
+        }
+    }
+}
+
+// Subscription is the events of some topics, as a subscriber receives them.
+struct Subscription {
+    receiver: broadcast::Receiver<NodeEvent>,
+    topics: Vec<EventTopic>,
+}
+
+impl Subscription {
+    // Next message to send: {"type": <topic>, "data": ...}, or None once the node shuts down.
+    async fn next(&mut self) -> Option<Value> {
+        loop {
+            match self.receiver.recv().await {
+                Ok(event) if self.topics.contains(&event.topic()) => return Some(json!(event)),
+                Ok(_) => continue,
+                Err(RecvError::Lagged(missed)) => {
+                    return Some(json!({ "type": "lagged", "data": { "missed": missed } }))
+                }
+                Err(RecvError::Closed) => return None,
+            }
+        }
+    }
+}
+
+// Socket is a WebSocket connection to a subscriber. Events are sent as text messages and pings
+// answered, until either side closes the connection.
+struct Socket {
+    payload: web::Payload,
+    subscription: Subscription,
+    codec: Codec,
+    received: web::BytesMut,
+    closed: bool,
+}
+
+impl Socket {
+    // Frames to send next, or None once the connection is over.
+    async fn next(&mut self) -> Option<web::Bytes> {
+        if self.closed {
+            return None;
+        }
+        let message = loop {
+            match self.codec.decode(&mut self.received) {
+                Ok(Some(Frame::Ping(ping))) => break Message::Pong(ping),
+                Ok(Some(Frame::Close(reason))) => {
+                    self.closed = true;
+                    break Message::Close(reason);
+                }
+                // Subscribers have nothing to tell us beyond pings and closes
+                Ok(Some(_)) => continue,
+                Err(_) => {
+                    self.closed = true;
+                    break Message::Close(Some(CloseCode::Protocol.into()));
+                }
+                Ok(None) => {}
+            }
+            tokio::select! {
+                chunk = self.payload.next() => match chunk {
+                    Some(Ok(chunk)) => self.received.extend_from_slice(&chunk),
+                    // The subscriber left
+                    _ => return None,
+                },
+                message = self.subscription.next() => match message {
+                    Some(message) => break Message::Text(message.to_string().into()),
+                    None => {
+                        self.closed = true;
+                        break Message::Close(Some(CloseCode::Away.into()));
+                    }
+                },
+            }
+        };
+        let mut frames = web::BytesMut::new();
+        self.codec.encode(message, &mut frames).ok()?;
+        Some(frames.freeze())
+    }
+}
+
+impl Application {
+    // Endpoint GET /v1/events
+    async fn handle_v1_events(
+        events: web::Data<EventBus>,
+        query: web::Query<EventsQuery>,
+    ) -> HttpResponse {
+        let topics = match query.topics() {
+            Ok(topics) => topics,
+            Err(error) => return respond_error(error),
+        };
+        let subscription = Subscription {
+            receiver: events.subscribe(),
+            topics,
+        };
+        let stream = stream::unfold(subscription, |mut subscription| async move {
+            let chunk = match tokio::time::timeout(KEEPALIVE, subscription.next()).await {
+                Ok(Some(message)) => format!(
+                    "event: {}\ndata: {}\n\n",
+                    message["type"].as_str().unwrap_or_default(),
+                    message["data"]
+                ),
+                Ok(None) => return None,
+                Err(_) => ": keepalive\n\n".to_string(),
+            };
+            Some((Ok::<_, Infallible>(web::Bytes::from(chunk)), subscription))
+        });
+        HttpResponse::Ok()
+            .content_type("text/event-stream")
+            .insert_header((header::CACHE_CONTROL, "no-cache"))
+            .streaming(stream)
+    }
+
+    // Endpoint GET /v1/events/ws
+    async fn handle_v1_events_ws(
+        events: web::Data<EventBus>,
+        query: web::Query<EventsQuery>,
+        payload: web::Payload,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        let topics = match query.topics() {
+            Ok(topics) => topics,
+            Err(error) => return respond_error(error),
+        };
+        if let Err(e) = ws::verify_handshake(req.head()) {
+            return respond_error(ApiError::new("invalid_request", e.to_string()));
+        }
+        let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+            Some(key) => ws::hash_key(key.as_bytes()),
+            None => return respond_error(ApiError::new("invalid_request", "Missing key")),
+        };
+        let socket = Socket {
+            payload,
+            subscription: Subscription {
+                receiver: events.subscribe(),
+                topics,
+            },
+            codec: Codec::new(),
+            received: web::BytesMut::new(),
+            closed: false,
+        };
+        let stream = stream::unfold(socket, |mut socket| async move {
+            let frames = socket.next().await?;
+            Some((Ok::<_, Infallible>(frames), socket))
+        });
+        HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS)
+            .upgrade("websocket")
+            .insert_header((
+                header::SEC_WEBSOCKET_ACCEPT,
+                HeaderValue::from_bytes(&key).expect("Accept keys are ASCII"),
+            ))
+            .streaming(stream)
+    }
+
+    pub(crate) fn event_endpoints() -> Vec<Endpoint> {
+        vec![
+            Endpoint::new(
+                Audience::Client,
+                Method::GET,
+                "/v1/events",
+                "Stream of the events of the node, as Server-Sent Events",
+                Self::handle_v1_events,
+            )
+            .events::<NodeEvent>(StatusCode::OK, "Events of the topics asked for"),
+            Endpoint::new(
+                Audience::Client,
+                Method::GET,
+                "/v1/events/ws",
+                "Stream of the events of the node, over a WebSocket",
+                Self::handle_v1_events_ws,
+            )
+            .empty(
+                StatusCode::SWITCHING_PROTOCOLS,
+                "WebSocket sending a NodeEvent per text message",
+            ),
+        ]
+    }
+}
@@ -0,0 +1,869 @@
+use actix_codec::{Decoder, Encoder};
+use actix_http::ws::{self, CloseCode, CloseReason, Codec, Frame, Message};
+use actix_web::{
+    http::header, http::header::HeaderValue, http::Method, http::StatusCode, web, HttpRequest,
+    HttpResponse,
+};
+use async_graphql::http::{playground_source, GraphQLPlaygroundConfig, WebSocket, WsMessage};
+use async_graphql::{
+    BatchRequest, Context, Data, EmptyMutation, Enum, ErrorExtensions, InputObject, Object, Schema,
+    SimpleObject, Subscription,
+};
+use futures_util::{stream, Stream, StreamExt};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::collections::HashMap;
+use std::convert::{Infallible, TryFrom};
+use std::pin::Pin;
+use std::sync::{Arc, Mutex, MutexGuard};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio::sync::mpsc;
+
+use super::listing::{
+    list_blocks, list_mempool, list_transactions, list_transactions_at, ListQuery, Order,
+    DEFAULT_PAGE_SIZE,
+};
+use super::openapi::{Audience, Endpoint};
+use super::{ApiError, ChainSummary, PeerInfo, TransactionRecord, TransactionStatus};
+use crate::modules::app::Application;
+use crate::modules::blockchain::block::Block;
+use crate::modules::blockchain::chain::Blockchain;
+use crate::modules::blockchain::events::{EventBus, NodeEvent};
+
+// GraphQL interface of the node, for explorers that follow the chain from block to
+// transactions to authors and their other posts in a single request:
+//
+//   POST /graphql      queries, alone or in batches, answered with {"data": ..., "errors": [...]}
+//   GET  /graphql      GraphQL Playground, to try queries from a browser
+//   GET  /graphql/ws   subscriptions over a WebSocket, speaking graphql-transport-ws or the older
+//                      graphql-ws protocol
+//
+// The types are Chain, Block, Transaction, Author and Peer. Lists are pages of the listings of
+// the /v1 API, taking first (the page size, at most MAX_CONNECTION_SIZE), after (the nextCursor
+// of the previous page), order and a ListFilter, and answering with the total, the nodes of the
+// page and a nextCursor. The only subscription is newBlocks, the blocks added to the chain from
+// then on.
+//
+// Queries are refused before they run if they nest deeper than MAX_QUERY_DEPTH or cost more than
+// MAX_QUERY_COMPLEXITY, where each field costs one for every node of the lists it is in.
+//
+// Errors carry the code of the matching ApiError in their extensions.
+
+pub const GRAPHQL_PATH: &str = "/graphql";
+pub const GRAPHQL_WS_PATH: &str = "/graphql/ws";
+
+// Deepest nesting of fields a query may ask for, so that a query following blocks to authors
+// to posts to blocks without end cannot keep the node busy.
+const MAX_QUERY_DEPTH: usize = 12;
+// Largest cost of a query. Asking for the posts of the authors of a full page of blocks is
+// refused, while pages of blocks with their transactions and authors are served.
+const MAX_QUERY_COMPLEXITY: usize = 10_000;
+// Largest page a list may be asked for. It is smaller than the pages of the /v1 listings, as
+// one query may ask for many lists.
+const MAX_CONNECTION_SIZE: usize = DEFAULT_PAGE_SIZE;
+
+pub type ChainSchema = Schema<QueryRoot, EmptyMutation, SubscriptionRoot>;
+
+// GraphqlRequest and GraphqlResponse describe the bodies of POST /graphql in the OpenAPI
+// document. Batches are lists of them.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "camelCase")]
+pub struct GraphqlRequest {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub operation_name: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub variables: Option<Value>,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct GraphqlResponse {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub data: Option<Value>,
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub errors: Vec<Value>,
+}
+
+fn graphql_error(error: ApiError) -> async_graphql::Error {
+    let ApiError { code, message } = error;
+    async_graphql::Error::new(message).extend_with(|_, e| e.set("code", code))
+}
+
+fn blockchain<'a>(ctx: &Context<'a>) -> MutexGuard<'a, Blockchain> {
+    ctx.data_unchecked::<web::Data<Mutex<Blockchain>>>()
+        .lock()
+        .expect("Unable to lock blockchain for read")
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(name = "Order", remote = "Order")]
+pub enum ListOrder {
+    Asc,
+    Desc,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Enum)]
+#[graphql(name = "TransactionStatus", remote = "TransactionStatus")]
+pub enum Status {
+    Pending,
+    Confirmed,
+}
+
+// ListFilter narrows down a list, as the filters of ListQuery do.
+#[derive(Debug, Default, InputObject)]
+pub struct ListFilter {
+    pub author: Option<String>,
+    pub since: Option<i64>,
+    pub until: Option<i64>,
+    pub from_height: Option<i32>,
+    pub to_height: Option<i32>,
+}
+
+// Cost of a list: the cost of a node for each node the page may hold.
+fn connection_complexity(first: Option<usize>, child_complexity: usize) -> usize {
+    first
+        .unwrap_or(DEFAULT_PAGE_SIZE)
+        .min(MAX_CONNECTION_SIZE)
+        .saturating_mul(child_complexity)
+}
+
+fn list_query(
+    first: Option<usize>,
+    after: Option<String>,
+    order: Option<ListOrder>,
+    filter: Option<ListFilter>,
+) -> async_graphql::Result<ListQuery> {
+    if first.is_some_and(|first| first > MAX_CONNECTION_SIZE) {
+        return Err(graphql_error(ApiError::new(
+            "invalid_request",
+            format!("first must be at most {}", MAX_CONNECTION_SIZE),
+        )));
+    }
+    let filter = filter.unwrap_or_default();
+    Ok(ListQuery {
+        limit: first,
+        cursor: after,
+        offset: None,
+        order: order.map(Order::from).unwrap_or_default(),
+        author: filter.author,
+        since: filter.since,
+        until: filter.until,
+        from_height: filter.from_height,
+        to_height: filter.to_height,
+    })
+}
+
+#[derive(SimpleObject)]
+pub struct BlockConnection {
+    pub total: usize,
+    pub nodes: Vec<BlockNode>,
+    pub next_cursor: Option<String>,
+}
+
+#[derive(SimpleObject)]
+pub struct TransactionConnection {
+    pub total: usize,
+    pub nodes: Vec<TransactionNode>,
+    pub next_cursor: Option<String>,
+}
+
+fn transaction_connection(
+    list: Result<super::TransactionList, ApiError>,
+) -> async_graphql::Result<TransactionConnection> {
+    let list = list.map_err(graphql_error)?;
+    Ok(TransactionConnection {
+        total: list.total,
+        nodes: list.transactions.into_iter().map(TransactionNode).collect(),
+        next_cursor: list.next_cursor,
+    })
+}
+
+pub struct ChainNode(ChainSummary);
+
+#[Object(name = "Chain")]
+impl ChainNode {
+    async fn chain_id(&self) -> &str {
+        &self.0.chain_id
+    }
+
+    async fn genesis_hash(&self) -> &str {
+        &self.0.genesis_hash
+    }
+
+    async fn difficulty(&self) -> i32 {
+        self.0.difficulty
+    }
+
+    // Height of the tip; the genesis block is at height 0.
+    async fn height(&self) -> i32 {
+        self.0.height
+    }
+
+    async fn tip_hash(&self) -> &str {
+        &self.0.tip_hash
+    }
+}
+
+pub struct BlockNode(Block);
+
+#[Object(name = "Block")]
+impl BlockNode {
+    async fn height(&self) -> i32 {
+        self.0.index
+    }
+
+    async fn hash(&self) -> &str {
+        &self.0.hash
+    }
+
+    async fn previous_hash(&self) -> &str {
+        &self.0.previous_hash
+    }
+
+    async fn timestamp(&self) -> i64 {
+        self.0.timestamp
+    }
+
+    async fn nonce(&self) -> i32 {
+        self.0.nonce
+    }
+
+    async fn transaction_count(&self) -> usize {
+        self.0.transactions.len()
+    }
+
+    async fn transactions(&self) -> Vec<TransactionNode> {
+        self.0
+            .transactions
+            .iter()
+            .map(|tx| TransactionNode(TransactionRecord::confirmed(tx, &self.0)))
+            .collect()
+    }
+}
+
+pub struct TransactionNode(TransactionRecord);
+
+#[Object(name = "Transaction")]
+impl TransactionNode {
+    async fn hash(&self) -> &str {
+        &self.0.hash
+    }
+
+    async fn author(&self) -> Author {
+        Author {
+            name: self.0.transaction.author.clone(),
+        }
+    }
+
+    async fn content(&self) -> &str {
+        &self.0.transaction.content
+    }
+
+    async fn timestamp(&self) -> i64 {
+        self.0.transaction.timestamp
+    }
+
+    async fn status(&self) -> Status {
+        self.0.status.into()
+    }
+
+    async fn block_height(&self) -> Option<i32> {
+        self.0.block_height
+    }
+
+    // Block holding the transaction, once confirmed and while that block is in the chain.
+    async fn block(&self, ctx: &Context<'_>) -> Option<BlockNode> {
+        let height = usize::try_from(self.0.block_height?).ok()?;
+        let blockchain = blockchain(ctx);
+        let block = blockchain.chain.get(height)?;
+        match Some(&block.hash) == self.0.block_hash.as_ref() {
+            true => Some(BlockNode(block.clone())),
+            false => None,
+        }
+    }
+}
+
+// AuthorIndex maps authors to the heights and positions of their confirmed transactions, so
+// that the posts of the authors of a page of transactions are found without going through the
+// chain for each of them. Each request or WebSocket connection has its own, built on first use
+// and again whenever the tip it was built at changes.
+#[derive(Clone, Default)]
+pub struct AuthorIndex(Arc<Mutex<Option<AuthorPositions>>>);
+
+struct AuthorPositions {
+    // Hash of the tip the positions were taken at.
+    built_at: String,
+    authors: HashMap<String, Vec<(usize, usize)>>,
+}
+
+impl AuthorIndex {
+    // Heights and positions of the transactions of an author in the chain.
+    fn positions(&self, blockchain: &Blockchain, author: &str) -> Vec<(usize, usize)> {
+        let tip = &blockchain.get_last_block().hash;
+        let mut index = self.0.lock().unwrap();
+        if index.as_ref().is_none_or(|built| built.built_at != *tip) {
+            let mut authors: HashMap<String, Vec<(usize, usize)>> = HashMap::new();
+            for (height, block) in blockchain.chain.iter().enumerate() {
+                for (position, tx) in block.transactions.iter().enumerate() {
+                    authors
+                        .entry(tx.author.clone())
+                        .or_default()
+                        .push((height, position));
+                }
+            }
+            *index = Some(AuthorPositions {
+                built_at: tip.clone(),
+                authors,
+            });
+        }
+        let built = index.as_ref().expect("Index was just built");
+        built.authors.get(author).cloned().unwrap_or_default()
+    }
+}
+
+// Author is whoever signs transactions with a name.
+pub struct Author {
+    name: String,
+}
+
+#[Object]
+impl Author {
+    async fn name(&self) -> &str {
+        &self.name
+    }
+
+    // Confirmed transactions of the author. The author of the filter, if any, is ignored.
+    #[graphql(complexity = "connection_complexity(first, child_complexity)")]
+    async fn posts(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+        order: Option<ListOrder>,
+        filter: Option<ListFilter>,
+    ) -> async_graphql::Result<TransactionConnection> {
+        let mut query = list_query(first, after, order, filter)?;
+        query.author = Some(self.name.clone());
+        let blockchain = blockchain(ctx);
+        let positions = match ctx.data_opt::<AuthorIndex>() {
+            Some(index) => index.positions(&blockchain, &self.name),
+            None => AuthorIndex::default().positions(&blockchain, &self.name),
+        };
+        transaction_connection(list_transactions_at(&blockchain.chain, &positions, &query))
+    }
+
+    // Transactions of the author still in the mempool.
+    #[graphql(complexity = "connection_complexity(first, child_complexity)")]
+    async fn pending_posts(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+        order: Option<ListOrder>,
+    ) -> async_graphql::Result<TransactionConnection> {
+        let mut query = list_query(first, after, order, None)?;
+        query.author = Some(self.name.clone());
+        let blockchain = blockchain(ctx);
+        transaction_connection(list_mempool(&blockchain.unconfirmed_transactions, &query))
+    }
+}
+
+pub struct PeerNode(PeerInfo);
+
+#[Object(name = "Peer")]
+impl PeerNode {
+    async fn node_address(&self) -> &str {
+        &self.0.node_address
+    }
+
+    // Whether the node registered with the peer, rather than the peer with the node.
+    async fn outbound(&self) -> bool {
+        self.0.outbound
+    }
+
+    async fn chain_id(&self) -> Option<&str> {
+        self.0.chain_id.as_deref()
+    }
+
+    async fn tip_height(&self) -> Option<i32> {
+        self.0.tip_height
+    }
+
+    async fn software_version(&self) -> Option<&str> {
+        self.0.software_version.as_deref()
+    }
+
+    async fn p2p_address(&self) -> Option<&str> {
+        self.0.p2p_address.as_deref()
+    }
+
+    async fn node_key(&self) -> Option<&str> {
+        self.0.node_key.as_deref()
+    }
+}
+
+pub struct QueryRoot;
+
+#[Object(name = "Query")]
+impl QueryRoot {
+    async fn chain(&self, ctx: &Context<'_>) -> ChainNode {
+        ChainNode(ChainSummary::of(&blockchain(ctx)))
+    }
+
+    // Block at a height or with a hash.
+    async fn block(
+        &self,
+        ctx: &Context<'_>,
+        height: Option<usize>,
+        hash: Option<String>,
+    ) -> async_graphql::Result<Option<BlockNode>> {
+        let blockchain = blockchain(ctx);
+        let block = match (height, hash) {
+            (Some(height), None) => blockchain.chain.get(height),
+            (None, Some(hash)) => blockchain.chain.iter().find(|block| block.hash == hash),
+            _ => {
+                return Err(graphql_error(ApiError::new(
+                    "invalid_request",
+                    "Give either the height or the hash of the block",
+                )))
+            }
+        };
+        Ok(block.cloned().map(BlockNode))
+    }
+
+    #[graphql(complexity = "connection_complexity(first, child_complexity)")]
+    async fn blocks(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+        order: Option<ListOrder>,
+        filter: Option<ListFilter>,
+    ) -> async_graphql::Result<BlockConnection> {
+        let query = list_query(first, after, order, filter)?;
+        let list = list_blocks(&blockchain(ctx).chain, &query).map_err(graphql_error)?;
+        Ok(BlockConnection {
+            total: list.total,
+            nodes: list.blocks.into_iter().map(BlockNode).collect(),
+            next_cursor: list.next_cursor,
+        })
+    }
+
+    // Transaction with a hash, confirmed or pending.
+    async fn transaction(&self, ctx: &Context<'_>, hash: String) -> Option<TransactionNode> {
+        TransactionRecord::find(&blockchain(ctx), &hash).map(TransactionNode)
+    }
+
+    // Confirmed transactions.
+    #[graphql(complexity = "connection_complexity(first, child_complexity)")]
+    async fn transactions(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+        order: Option<ListOrder>,
+        filter: Option<ListFilter>,
+    ) -> async_graphql::Result<TransactionConnection> {
+        let query = list_query(first, after, order, filter)?;
+        transaction_connection(list_transactions(&blockchain(ctx).chain, &query))
+    }
+
+    // Pending transactions. They have no height, so filters on heights are refused.
+    #[graphql(complexity = "connection_complexity(first, child_complexity)")]
+    async fn mempool(
+        &self,
+        ctx: &Context<'_>,
+        first: Option<usize>,
+        after: Option<String>,
+        order: Option<ListOrder>,
+        filter: Option<ListFilter>,
+    ) -> async_graphql::Result<TransactionConnection> {
+        let query = list_query(first, after, order, filter)?;
+        let blockchain = blockchain(ctx);
+        transaction_connection(list_mempool(&blockchain.unconfirmed_transactions, &query))
+    }
+
+    async fn author(&self, name: String) -> Author {
+        Author { name }
+    }
+
+    async fn peers(&self, ctx: &Context<'_>) -> Vec<PeerNode> {
+        blockchain(ctx)
+            .peers
+            .iter()
+            .map(|peer| PeerNode(PeerInfo::from(peer)))
+            .collect()
+    }
+}
+
+pub struct SubscriptionRoot;
+
+#[Subscription(name = "Subscription")]
+impl SubscriptionRoot {
+    // Blocks added to the chain from now on, mined here, sent by peers or adopted by consensus.
+    // Subscribers that fall behind miss blocks rather than hold the node back.
+    async fn new_blocks(&self, ctx: &Context<'_>) -> impl Stream<Item = BlockNode> {
+        let receiver = ctx.data_unchecked::<EventBus>().subscribe();
+        stream::unfold(receiver, |mut receiver| async move {
+            loop {
+                match receiver.recv().await {
+                    Ok(NodeEvent::Block(block)) => return Some((BlockNode(block), receiver)),
+                    Ok(_) | Err(RecvError::Lagged(_)) => continue,
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        })
+    }
+}
+
+// Socket is a WebSocket connection to a GraphQL client. Its messages are handed to the
+// protocol, whose answers are sent back, until either side closes the connection.
+struct Socket {
+    payload: web::Payload,
+    codec: Codec,
+    received: web::BytesMut,
+    incoming: mpsc::UnboundedSender<web::Bytes>,
+    outgoing: Pin<Box<dyn Stream<Item = WsMessage>>>,
+    // Tells when the node shuts down, as clients may keep the connection open without any
+    // subscription running.
+    shutdown: broadcast::Receiver<NodeEvent>,
+    closed: bool,
+}
+
+impl Socket {
+    // Frames to send next, or None once the connection is over.
+    async fn next(&mut self) -> Option<web::Bytes> {
+        if self.closed {
+            return None;
+        }
+        let message = loop {
+            match self.codec.decode(&mut self.received) {
+                Ok(Some(Frame::Text(text))) | Ok(Some(Frame::Binary(text))) => {
+                    // The protocol only stops reading once it closed the connection
+                    let _ = self.incoming.send(text);
+                    continue;
+                }
+                Ok(Some(Frame::Ping(ping))) => break Message::Pong(ping),
+                Ok(Some(Frame::Close(reason))) => {
+                    self.closed = true;
+                    break Message::Close(reason);
+                }
+                Ok(Some(_)) => continue,
+                Err(_) => {
+                    self.closed = true;
+                    break Message::Close(Some(CloseCode::Protocol.into()));
+                }
+                Ok(None) => {}
+            }
+            tokio::select! {
+                chunk = self.payload.next() => match chunk {
+                    Some(Ok(chunk)) => self.received.extend_from_slice(&chunk),
+                    // The client left
+                    _ => return None,
+                },
+                message = self.outgoing.next() => match message {
+                    Some(WsMessage::Text(text)) => break Message::Text(text.into()),
+                    Some(WsMessage::Close(code, description)) => {
+                        self.closed = true;
+                        break Message::Close(Some(CloseReason {
+                            code: code.into(),
+                            description: Some(description),
+                        }));
+                    }
+                    None => {
+                        self.closed = true;
+                        break Message::Close(Some(CloseCode::Normal.into()));
+                    }
+                },
+                event = self.shutdown.recv() => {
+                    if let Err(RecvError::Closed) = event {
+                        self.closed = true;
+                        break Message::Close(Some(CloseCode::Away.into()));
+                    }
+                }
+            }
+        };
+        let mut frames = web::BytesMut::new();
+        self.codec.encode(message, &mut frames).ok()?;
+        Some(frames.freeze())
+    }
+}
+
+impl Application {
+    // Schema answering GraphQL requests from a blockchain and its events.
+    pub(crate) fn graphql_schema(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        events: &EventBus,
+    ) -> ChainSchema {
+        Schema::build(QueryRoot, EmptyMutation, SubscriptionRoot)
+            .data(blockchain.clone())
+            .data(events.clone())
+            .limit_depth(MAX_QUERY_DEPTH)
+            .limit_complexity(MAX_QUERY_COMPLEXITY)
+            .finish()
+    }
+
+    // Endpoint POST /graphql
+    async fn handle_graphql(schema: web::Data<ChainSchema>, body: web::Bytes) -> HttpResponse {
+        match serde_json::from_slice::<BatchRequest>(&body) {
+            Ok(request) => {
+                let request = request.data(AuthorIndex::default());
+                HttpResponse::Ok().json(schema.execute_batch(request).await)
+            }
+            Err(e) => HttpResponse::BadRequest().json(GraphqlResponse {
+                data: None,
+                errors: vec![serde_json::json!({ "message": e.to_string() })],
+            }),
+        }
+    }
+
+    // Endpoint GET /graphql
+    async fn handle_graphql_playground() -> HttpResponse {
+        HttpResponse::Ok()
+            .content_type("text/html; charset=utf-8")
+            .body(playground_source(
+                GraphQLPlaygroundConfig::new(GRAPHQL_PATH).subscription_endpoint(GRAPHQL_WS_PATH),
+            ))
+    }
+
+    // Endpoint GET /graphql/ws
+    async fn handle_graphql_ws(
+        schema: web::Data<ChainSchema>,
+        events: web::Data<EventBus>,
+        payload: web::Payload,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        if let Err(e) = ws::verify_handshake(req.head()) {
+            return HttpResponse::BadRequest().body(e.to_string());
+        }
+        let key = match req.headers().get(header::SEC_WEBSOCKET_KEY) {
+            Some(key) => ws::hash_key(key.as_bytes()),
+            None => return HttpResponse::BadRequest().body("Missing key"),
+        };
+        // Clients list the protocols they speak, and get the first one we speak too
+        let protocol = req
+            .headers()
+            .get(header::SEC_WEBSOCKET_PROTOCOL)
+            .and_then(|value| value.to_str().ok())
+            .unwrap_or("graphql-transport-ws")
+            .split(',')
+            .find_map(|protocol| protocol.trim().parse().ok());
+        let protocol = match protocol {
+            Some(protocol) => protocol,
+            None => return HttpResponse::BadRequest().body("Unsupported WebSocket protocol"),
+        };
+        let mut connection_data = Data::default();
+        connection_data.insert(AuthorIndex::default());
+        let (incoming, receiver) = mpsc::unbounded_channel();
+        let messages = stream::unfold(receiver, |mut receiver| async move {
+            receiver.recv().await.map(|message| (message, receiver))
+        });
+        let socket = Socket {
+            payload,
+            codec: Codec::new(),
+            received: web::BytesMut::new(),
+            incoming,
+            outgoing: Box::pin(
+                WebSocket::new(schema.as_ref().clone(), messages, protocol)
+                    .connection_data(connection_data),
+            ),
+            shutdown: events.subscribe(),
+            closed: false,
+        };
+        let stream = stream::unfold(socket, |mut socket| async move {
+            let frames = socket.next().await?;
+            Some((Ok::<_, Infallible>(frames), socket))
+        });
+        HttpResponse::build(StatusCode::SWITCHING_PROTOCOLS)
+            .upgrade("websocket")
+            .insert_header((
+                header::SEC_WEBSOCKET_ACCEPT,
+                HeaderValue::from_bytes(&key).expect("Accept keys are ASCII"),
+            ))
+            .insert_header((
+                header::SEC_WEBSOCKET_PROTOCOL,
+                protocol.sec_websocket_protocol(),
+            ))
+            .streaming(stream)
+    }
+
+    pub(crate) fn graphql_endpoints() -> Vec<Endpoint> {
+        use Audience::Graphql;
+        vec![
+            Endpoint::new(
+                Graphql,
+                Method::POST,
+                GRAPHQL_PATH,
+                "Run a GraphQL query, or a batch of them",
+                Self::handle_graphql,
+            )
+            .request::<GraphqlRequest>()
+            .json::<GraphqlResponse>(StatusCode::OK, "Result of the query, with its errors")
+            .json::<GraphqlResponse>(StatusCode::BAD_REQUEST, "Body is not a GraphQL request"),
+            Endpoint::new(
+                Graphql,
+                Method::GET,
+                GRAPHQL_PATH,
+                "GraphQL Playground",
+                Self::handle_graphql_playground,
+            )
+            .html(StatusCode::OK, "Playground page"),
+            Endpoint::new(
+                Graphql,
+                Method::GET,
+                GRAPHQL_WS_PATH,
+                "GraphQL subscriptions over a WebSocket",
+                Self::handle_graphql_ws,
+            )
+            .empty(
+                StatusCode::SWITCHING_PROTOCOLS,
+                "WebSocket speaking graphql-transport-ws or graphql-ws",
+            )
+            .text(StatusCode::BAD_REQUEST, "Not a WebSocket handshake"),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::Transaction;
+    use crate::modules::blockchain::genesis::Network;
+    use futures_util::FutureExt;
+    use serde_json::json;
+
+    fn post(blockchain: &mut Blockchain, author: &str, content: &str) {
+        blockchain.add_new_transaction(Transaction {
+            author: author.to_string(),
+            content: content.to_string(),
+            timestamp: 1_700_000_000,
+        });
+    }
+
+    fn schema() -> (web::Data<Mutex<Blockchain>>, ChainSchema) {
+        let mut blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        post(&mut blockchain, "alice", "first");
+        blockchain.mine_block().unwrap();
+        post(&mut blockchain, "bob", "reply");
+        post(&mut blockchain, "alice", "second");
+        blockchain.mine_block().unwrap();
+        post(&mut blockchain, "alice", "draft");
+        let events = blockchain.events.clone();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let schema = Application::graphql_schema(&blockchain, &events);
+        (blockchain, schema)
+    }
+
+    #[actix_web::test]
+    async fn nested_queries_follow_blocks_to_authors() {
+        let (_, schema) = schema();
+        let response = schema
+            .execute(
+                r#"{
+                    chain { height }
+                    blocks(first: 1, order: DESC) {
+                        total
+                        nextCursor
+                        nodes {
+                            height
+                            transactions {
+                                author {
+                                    name
+                                    posts(first: 1) { total nodes { content block { height } } }
+                                    pendingPosts { total }
+                                }
+                            }
+                        }
+                    }
+                }"#,
+            )
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+        let alice = json!({
+            "name": "alice",
+            "posts": {
+                "total": 2,
+                "nodes": [{ "content": "first", "block": { "height": 1 } }],
+            },
+            "pendingPosts": { "total": 1 },
+        });
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({
+                "chain": { "height": 2 },
+                "blocks": {
+                    "total": 3,
+                    "nextCursor": "0000000002",
+                    "nodes": [{
+                        "height": 2,
+                        "transactions": [
+                            { "author": {
+                                "name": "bob",
+                                "posts": {
+                                    "total": 1,
+                                    "nodes": [{ "content": "reply", "block": { "height": 2 } }],
+                                },
+                                "pendingPosts": { "total": 0 },
+                            } },
+                            { "author": alice },
+                        ],
+                    }],
+                },
+            })
+        );
+
+        // Errors of the listings keep their code
+        let response = schema.execute("{ blocks(first: 0) { total } }").await;
+        assert_eq!(
+            response.errors[0].extensions.as_ref().unwrap().get("code"),
+            Some(&async_graphql::Value::from("invalid_request"))
+        );
+    }
+
+    #[actix_web::test]
+    async fn costly_queries_are_refused() {
+        let (blockchain, schema) = schema();
+        let response = schema.execute("{ blocks(first: 101) { total } }").await;
+        assert_eq!(
+            response.errors[0].extensions.as_ref().unwrap().get("code"),
+            Some(&async_graphql::Value::from("invalid_request"))
+        );
+        // The posts of every author of a page of blocks cost too much even on a short chain
+        let response = schema
+            .execute(
+                "{ blocks(first: 100) { nodes { transactions { author { \
+                 posts(first: 100) { nodes { content } } } } } } }",
+            )
+            .await;
+        assert!(response.errors[0].message.contains("too complex"));
+        let response = schema
+            .execute("{ blocks(first: 100) { nodes { transactions { author { name } } } } }")
+            .await;
+        assert!(response.errors.is_empty(), "{:?}", response.errors);
+
+        // Authors are looked up in an index kept for the request, rebuilt as the chain grows
+        let index = AuthorIndex::default();
+        let alice = |blockchain: &Blockchain| index.positions(blockchain, "alice");
+        assert_eq!(alice(&blockchain.lock().unwrap()), vec![(1, 0), (2, 1)]);
+        blockchain.lock().unwrap().mine_block().unwrap();
+        assert_eq!(
+            alice(&blockchain.lock().unwrap()),
+            vec![(1, 0), (2, 1), (3, 0)]
+        );
+    }
+
+    #[actix_web::test]
+    async fn new_blocks_are_pushed_to_subscribers() {
+        let (blockchain, schema) = schema();
+        let mut blocks = schema.execute_stream("subscription { newBlocks { height } }");
+        // Start the subscription before the block is mined
+        assert!(blocks.next().now_or_never().is_none());
+        blockchain.lock().unwrap().mine_block().unwrap();
+        let response = blocks.next().await.unwrap();
+        assert_eq!(
+            response.data.into_json().unwrap(),
+            json!({ "newBlocks": { "height": 3 } })
+        );
+    }
+}
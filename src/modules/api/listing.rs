@@ -0,0 +1,581 @@
+use std::convert::TryFrom;
+
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+
+use super::{ApiError, BlockList, TransactionList, TransactionRecord};
+use crate::modules::blockchain::block::Block;
+use crate::modules::blockchain::chain::Transaction;
+
+// Number of items in a page when the client does not ask for a size, and the largest size it
+// may ask for.
+pub const DEFAULT_PAGE_SIZE: usize = 100;
+pub const MAX_PAGE_SIZE: usize = 1000;
+
+// Order of the items of a listing: blocks and confirmed transactions by height, pending
+// transactions by timestamp.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Order {
+    #[default]
+    Asc,
+    Desc,
+}
+
+// ListQuery is the query string of the listings: GET /v1/blocks, /v1/transactions and
+// /v1/mempool.
+//
+//   limit        number of items in the page, DEFAULT_PAGE_SIZE unless set, at most MAX_PAGE_SIZE
+//   cursor       next_cursor of the previous page, to continue after it
+//   offset       number of items to skip, after the cursor if there is one
+//   order        asc or desc
+//   author       only transactions by this author, or blocks holding one
+//   since/until  only items with a timestamp in this range, in unix seconds, both inclusive
+//   from_height/to_height
+//                only blocks, or transactions of blocks, in this range of heights, both
+//                inclusive; pending transactions have no height, so the mempool refuses them
+//
+// Cursors stay valid while the chain grows, unlike offsets.
+#[derive(Debug, Clone, Default, Serialize, Deserialize, JsonSchema)]
+pub struct ListQuery {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub limit: Option<usize>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub cursor: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub offset: Option<usize>,
+    #[serde(default)]
+    pub order: Order,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub author: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub since: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub until: Option<i64>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub to_height: Option<i32>,
+}
+
+// Page is a page of a listing, with the number of items matching the filters in all pages.
+struct Page<T> {
+    total: usize,
+    items: Vec<T>,
+    next_cursor: Option<String>,
+}
+
+fn invalid(message: &str) -> ApiError {
+    ApiError::new("invalid_request", message)
+}
+
+impl ListQuery {
+    fn check(&self) -> Result<(), ApiError> {
+        match self.limit {
+            Some(0) => return Err(invalid("limit must be at least 1")),
+            Some(limit) if limit > MAX_PAGE_SIZE => {
+                return Err(invalid(&format!("limit must be at most {}", MAX_PAGE_SIZE)))
+            }
+            _ => {}
+        }
+        if let (Some(since), Some(until)) = (self.since, self.until) {
+            if since > until {
+                return Err(invalid("since must not be after until"));
+            }
+        }
+        if let (Some(from), Some(to)) = (self.from_height, self.to_height) {
+            if from > to {
+                return Err(invalid("from_height must not be above to_height"));
+            }
+        }
+        Ok(())
+    }
+
+    fn in_time_range(&self, timestamp: i64) -> bool {
+        self.since.is_none_or(|since| timestamp >= since)
+            && self.until.is_none_or(|until| timestamp <= until)
+    }
+
+    fn by_author(&self, transaction: &Transaction) -> bool {
+        self.author
+            .as_ref()
+            .is_none_or(|author| transaction.author == *author)
+    }
+
+    fn matches(&self, transaction: &Transaction) -> bool {
+        self.by_author(transaction) && self.in_time_range(transaction.timestamp)
+    }
+
+    fn after_cursor(&self, key: &str) -> bool {
+        match &self.cursor {
+            Some(cursor) => match self.order {
+                Order::Asc => key > cursor.as_str(),
+                Order::Desc => key < cursor.as_str(),
+            },
+            None => true,
+        }
+    }
+
+    // Whether some of the items keyed between the bounds come after the cursor.
+    fn some_after_cursor(&self, (first, last): &(String, String)) -> bool {
+        match self.order {
+            Order::Asc => self.after_cursor(last),
+            Order::Desc => self.after_cursor(first),
+        }
+    }
+
+    // Whether all of the items keyed between the bounds come after the cursor.
+    fn all_after_cursor(&self, (first, last): &(String, String)) -> bool {
+        match self.order {
+            Order::Asc => self.after_cursor(first),
+            Order::Desc => self.after_cursor(last),
+        }
+    }
+
+    // Items of a slice sorted by height that are in the height range of the query.
+    fn in_heights<'a, T>(&self, items: &'a [T], height: impl Fn(&T) -> i32) -> &'a [T] {
+        let start = self
+            .from_height
+            .map_or(0, |from| items.partition_point(|item| height(item) < from));
+        let end = self.to_height.map_or(items.len(), |to| {
+            items.partition_point(|item| height(item) <= to)
+        });
+        &items[start..end.max(start)]
+    }
+
+    // Items of a slice sorted by key that may come after the cursor, in the order of the
+    // listing. Each item gives the bounds of the keys it stands for, and the cursor is found by
+    // binary search, so the items before it are never looked at.
+    fn following<'a, T>(
+        &self,
+        items: &'a [T],
+        bounds: impl Fn(&T) -> (String, String),
+    ) -> Box<dyn Iterator<Item = &'a T> + 'a> {
+        match self.order {
+            Order::Asc => {
+                let start = items.partition_point(|item| !self.some_after_cursor(&bounds(item)));
+                Box::new(items[start..].iter())
+            }
+            Order::Desc => {
+                let end = items.partition_point(|item| self.some_after_cursor(&bounds(item)));
+                Box::new(items[..end].iter().rev())
+            }
+        }
+    }
+
+    // Number of items the page asks for, and one more to tell whether there is a next page.
+    fn wanted(&self) -> usize {
+        self.offset
+            .unwrap_or(0)
+            .saturating_add(self.limit.unwrap_or(DEFAULT_PAGE_SIZE))
+            .saturating_add(1)
+    }
+
+    // Cut the page the query asks for out of the matching items that follow the cursor, in the
+    // order of the listing. The cursor of a page is the key of its last item.
+    fn page<T>(
+        &self,
+        total: usize,
+        following: impl Iterator<Item = T>,
+        key: impl Fn(&T) -> String,
+    ) -> Page<T> {
+        let limit = self.limit.unwrap_or(DEFAULT_PAGE_SIZE);
+        let mut items: Vec<T> = following
+            .skip(self.offset.unwrap_or(0))
+            .take(limit.saturating_add(1))
+            .collect();
+        let next_cursor = match items.len() > limit {
+            true => {
+                items.truncate(limit);
+                items.last().map(key)
+            }
+            false => None,
+        };
+        Page {
+            total,
+            items,
+            next_cursor,
+        }
+    }
+}
+
+// Keys are padded so that they sort as the items they stand for.
+fn block_key(height: i32) -> String {
+    format!("{:010}", height.max(0))
+}
+
+fn confirmed_key(height: i32, position: usize) -> String {
+    format!("{}.{:06}", block_key(height), position)
+}
+
+fn pending_key(record: &TransactionRecord) -> String {
+    format!(
+        "{}.{}",
+        timestamp_key(record.transaction.timestamp),
+        record.hash
+    )
+}
+
+fn timestamp_key(timestamp: i64) -> String {
+    format!("{:020}", timestamp.max(0))
+}
+
+// Bounds of the keys starting with a prefix: keys are made of digits, hex and dots, which all
+// sort before the tilde.
+fn bounds_of(prefix: String) -> (String, String) {
+    let last = format!("{}~", prefix);
+    (prefix, last)
+}
+
+fn exactly(key: String) -> (String, String) {
+    (key.clone(), key)
+}
+
+// List the blocks of a chain the query asks for.
+pub fn list_blocks(chain: &[Block], query: &ListQuery) -> Result<BlockList, ApiError> {
+    query.check()?;
+    let chain = query.in_heights(chain, |block| block.index);
+    let matches = |block: &&Block| {
+        query.in_time_range(block.timestamp)
+            && (query.author.is_none() || block.transactions.iter().any(|tx| query.by_author(tx)))
+    };
+    let total = chain.iter().filter(matches).count();
+    let following = query
+        .following(chain, |block| exactly(block_key(block.index)))
+        .filter(matches);
+    let page = query.page(total, following, |block| block_key(block.index));
+    Ok(BlockList {
+        total: page.total,
+        blocks: page.items.into_iter().cloned().collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+// List the confirmed transactions of a chain the query asks for.
+pub fn list_transactions(chain: &[Block], query: &ListQuery) -> Result<TransactionList, ApiError> {
+    query.check()?;
+    let chain = query.in_heights(chain, |block| block.index);
+    let total = chain
+        .iter()
+        .flat_map(|block| &block.transactions)
+        .filter(|tx| query.matches(tx))
+        .count();
+    let following = query
+        .following(chain, |block| bounds_of(block_key(block.index)))
+        .flat_map(|block| {
+            let positions = 0..block.transactions.len();
+            let positions: Box<dyn Iterator<Item = usize>> = match query.order {
+                Order::Asc => Box::new(positions),
+                Order::Desc => Box::new(positions.rev()),
+            };
+            positions.map(move |position| (block, position))
+        })
+        .skip_while(|&(block, position)| {
+            !query.after_cursor(&confirmed_key(block.index, position))
+        });
+    Ok(list_confirmed(total, following, query))
+}
+
+// List the transactions the query asks for among those at the given heights and positions in
+// their block, in chain order, such as the ones of an author looked up in an index. Positions
+// that are not in the chain any more are skipped.
+pub fn list_transactions_at(
+    chain: &[Block],
+    positions: &[(usize, usize)],
+    query: &ListQuery,
+) -> Result<TransactionList, ApiError> {
+    query.check()?;
+    let height = |&(height, _): &(usize, usize)| i32::try_from(height).unwrap_or(i32::MAX);
+    let positions = query.in_heights(positions, height);
+    let locate = |&(height, position): &(usize, usize)| Some((chain.get(height)?, position));
+    let total = positions
+        .iter()
+        .filter_map(locate)
+        .filter(|&(block, position)| matching_at(query, block, position).is_some())
+        .count();
+    let following = query
+        .following(positions, |at| exactly(confirmed_key(height(at), at.1)))
+        .filter_map(locate);
+    Ok(list_confirmed(total, following, query))
+}
+
+// Transaction at a position of a block, if it is there and matches the query.
+fn matching_at<'a>(
+    query: &ListQuery,
+    block: &'a Block,
+    position: usize,
+) -> Option<&'a Transaction> {
+    block
+        .transactions
+        .get(position)
+        .filter(|tx| query.matches(tx))
+}
+
+fn list_confirmed<'a>(
+    total: usize,
+    following: impl Iterator<Item = (&'a Block, usize)>,
+    query: &ListQuery,
+) -> TransactionList {
+    let following = following.filter_map(|(block, position)| {
+        Some((block, position, matching_at(query, block, position)?))
+    });
+    let page = query.page(total, following, |&(block, position, _)| {
+        confirmed_key(block.index, position)
+    });
+    TransactionList {
+        total: page.total,
+        transactions: page
+            .items
+            .into_iter()
+            .map(|(block, _, tx)| TransactionRecord::confirmed(tx, block))
+            .collect(),
+        next_cursor: page.next_cursor,
+    }
+}
+
+// List the pending transactions the query asks for.
+pub fn list_mempool(
+    transactions: &[Transaction],
+    query: &ListQuery,
+) -> Result<TransactionList, ApiError> {
+    query.check()?;
+    if query.from_height.is_some() || query.to_height.is_some() {
+        return Err(invalid("Pending transactions have no height"));
+    }
+    let mut matching: Vec<&Transaction> =
+        transactions.iter().filter(|tx| query.matches(tx)).collect();
+    let total = matching.len();
+    matching.sort_by_key(|tx| tx.timestamp);
+
+    // Hashes break ties between timestamps but are costly, so only the transactions that can
+    // make the page are hashed: those after the cursor, up to and including every one sharing
+    // the timestamp of the last transaction the page can reach.
+    let wanted = query.wanted();
+    let mut candidates: Vec<&Transaction> = Vec::new();
+    let mut surely_after = 0;
+    for tx in query.following(&matching, |tx| bounds_of(timestamp_key(tx.timestamp))) {
+        let tied = candidates
+            .last()
+            .is_some_and(|last| last.timestamp == tx.timestamp);
+        if surely_after >= wanted && !tied {
+            break;
+        }
+        if query.all_after_cursor(&bounds_of(timestamp_key(tx.timestamp))) {
+            surely_after += 1;
+        }
+        candidates.push(tx);
+    }
+    let mut records: Vec<(String, TransactionRecord)> = candidates
+        .into_iter()
+        .map(|tx| {
+            let record = TransactionRecord::pending(tx);
+            (pending_key(&record), record)
+        })
+        .filter(|(key, _)| query.after_cursor(key))
+        .collect();
+    records.sort_by(|a, b| a.0.cmp(&b.0));
+    if query.order == Order::Desc {
+        records.reverse();
+    }
+    let page = query.page(total, records.into_iter(), |(key, _)| key.clone());
+    Ok(TransactionList {
+        total: page.total,
+        transactions: page.items.into_iter().map(|(_, record)| record).collect(),
+        next_cursor: page.next_cursor,
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn chain() -> Vec<Block> {
+        (0..25)
+            .map(|index| Block {
+                index,
+                transactions: vec![
+                    Transaction {
+                        author: format!("author {}", index % 2),
+                        content: format!("post {}", index),
+                        timestamp: 1_000 + index as i64,
+                    },
+                    Transaction {
+                        author: "author 2".to_string(),
+                        content: format!("reply {}", index),
+                        timestamp: 1_000 + index as i64,
+                    },
+                ],
+                timestamp: 1_000 + index as i64,
+                previous_hash: String::new(),
+                nonce: 0,
+                hash: format!("{:064}", index),
+            })
+            .collect()
+    }
+
+    fn heights(list: &BlockList) -> Vec<i32> {
+        list.blocks.iter().map(|block| block.index).collect()
+    }
+
+    #[test]
+    fn cursors_walk_every_block_once_in_both_orders() {
+        let chain = chain();
+        for order in [Order::Asc, Order::Desc] {
+            let mut query = ListQuery {
+                limit: Some(10),
+                order,
+                ..ListQuery::default()
+            };
+            let mut seen = Vec::new();
+            loop {
+                let page = list_blocks(&chain, &query).unwrap();
+                assert_eq!(page.total, 25);
+                seen.extend(heights(&page));
+                match page.next_cursor {
+                    Some(cursor) => query.cursor = Some(cursor),
+                    None => break,
+                }
+            }
+            let mut expected: Vec<i32> = (0..25).collect();
+            if order == Order::Desc {
+                expected.reverse();
+            }
+            assert_eq!(seen, expected);
+        }
+    }
+
+    // Walk a listing with pages of three and check it yields what a single page holds.
+    fn walk(list: impl Fn(&ListQuery) -> TransactionList, query: ListQuery) -> Vec<String> {
+        let whole = list(&ListQuery {
+            limit: Some(MAX_PAGE_SIZE),
+            ..query.clone()
+        });
+        let mut query = ListQuery {
+            limit: Some(3),
+            ..query
+        };
+        let mut seen = Vec::new();
+        loop {
+            let page = list(&query);
+            assert_eq!(page.total, whole.total);
+            seen.extend(page.transactions.into_iter().map(|record| record.hash));
+            match page.next_cursor {
+                Some(cursor) => query.cursor = Some(cursor),
+                None => break,
+            }
+        }
+        let expected: Vec<String> = whole
+            .transactions
+            .into_iter()
+            .map(|record| record.hash)
+            .collect();
+        assert_eq!(seen, expected);
+        seen
+    }
+
+    #[test]
+    fn cursors_walk_every_transaction_once_in_both_orders() {
+        let chain = chain();
+        let mempool: Vec<Transaction> = chain
+            .iter()
+            .rev()
+            .flat_map(|block| block.transactions.clone())
+            .collect();
+        let positions: Vec<(usize, usize)> = (0..chain.len())
+            .flat_map(|height| [(height, 0), (height, 1)])
+            .collect();
+        for order in [Order::Asc, Order::Desc] {
+            let query = ListQuery {
+                order,
+                ..ListQuery::default()
+            };
+            let confirmed = walk(
+                |query| list_transactions(&chain, query).unwrap(),
+                query.clone(),
+            );
+            assert_eq!(confirmed.len(), 50);
+            let at = walk(
+                |query| list_transactions_at(&chain, &positions, query).unwrap(),
+                query.clone(),
+            );
+            assert_eq!(at, confirmed);
+            // Pending transactions share timestamps in pairs, so ties are broken by hash
+            let pending = walk(
+                |query| list_mempool(&mempool, query).unwrap(),
+                query.clone(),
+            );
+            assert_eq!(pending.len(), 50);
+            let mut sorted = pending.clone();
+            sorted.sort_by_key(|hash| {
+                let tx = mempool
+                    .iter()
+                    .find(|tx| tx.compute_hash().unwrap() == *hash)
+                    .unwrap();
+                (tx.timestamp, hash.clone())
+            });
+            if order == Order::Desc {
+                sorted.reverse();
+            }
+            assert_eq!(pending, sorted);
+
+            let filtered = ListQuery {
+                author: Some("author 2".to_string()),
+                from_height: Some(4),
+                ..query
+            };
+            assert_eq!(
+                walk(
+                    |query| list_transactions(&chain, query).unwrap(),
+                    filtered.clone()
+                )
+                .len(),
+                21
+            );
+            let pending = ListQuery {
+                from_height: None,
+                since: Some(1_004),
+                ..filtered
+            };
+            assert_eq!(
+                walk(|query| list_mempool(&mempool, query).unwrap(), pending).len(),
+                21
+            );
+        }
+    }
+
+    #[test]
+    fn filters_combine() {
+        let chain = chain();
+        let query = ListQuery {
+            author: Some("author 1".to_string()),
+            since: Some(1_005),
+            to_height: Some(12),
+            offset: Some(1),
+            ..ListQuery::default()
+        };
+        assert_eq!(
+            heights(&list_blocks(&chain, &query).unwrap()),
+            vec![7, 9, 11]
+        );
+
+        let transactions = list_transactions(&chain, &query).unwrap();
+        assert_eq!(transactions.total, 4);
+        let contents: Vec<&str> = transactions
+            .transactions
+            .iter()
+            .map(|record| record.transaction.content.as_str())
+            .collect();
+        assert_eq!(contents, vec!["post 7", "post 9", "post 11"]);
+
+        let pending = &chain[3].transactions;
+        assert!(list_mempool(pending, &query).is_err());
+        assert!(list_blocks(
+            &chain,
+            &ListQuery {
+                limit: Some(0),
+                ..query
+            }
+        )
+        .is_err());
+    }
+}
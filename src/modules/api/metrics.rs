@@ -0,0 +1,39 @@
+use actix_web::{http::Method, http::StatusCode, web, HttpResponse};
+use std::sync::Mutex;
+
+use super::openapi::{Audience, Endpoint};
+use crate::modules::app::Application;
+use crate::modules::blockchain::chain::Blockchain;
+use crate::modules::metrics::NodeMetrics;
+use crate::modules::network::transport::Transport;
+
+pub const METRICS_PATH: &str = "/metrics";
+
+// Content type of the Prometheus text format.
+const METRICS_CONTENT_TYPE: &str = "text/plain; version=0.0.4; charset=utf-8";
+
+impl Application {
+    // Endpoint GET /metrics
+    async fn handle_metrics(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        metrics: web::Data<NodeMetrics>,
+    ) -> HttpResponse {
+        metrics.observe_chain(&blockchain.lock().unwrap());
+        metrics.observe_transport_peers(transport.connected_peers().len());
+        HttpResponse::Ok()
+            .content_type(METRICS_CONTENT_TYPE)
+            .body(metrics.render())
+    }
+
+    pub(crate) fn metrics_endpoint() -> Endpoint {
+        Endpoint::new(
+            Audience::Monitoring,
+            Method::GET,
+            METRICS_PATH,
+            "Metrics of the node, in the Prometheus text format",
+            Self::handle_metrics,
+        )
+        .text(StatusCode::OK, "Metrics")
+    }
+}
@@ -0,0 +1,661 @@
+use actix_web::{http::Method, http::StatusCode, web, HttpRequest, HttpResponse, Resource};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::sync::Mutex;
+
+use super::app::{Application, NodeActivity};
+use super::blockchain::block::Block;
+use super::blockchain::chain::{Blockchain, MiningCancelled, NodePeer, Transaction};
+use super::network::auth::NodeAuth;
+use super::network::transport::Transport;
+
+pub mod admin;
+pub mod events;
+pub mod graphql;
+pub mod listing;
+pub mod metrics;
+pub mod openapi;
+pub mod rpc;
+pub mod status;
+
+use listing::{list_blocks, list_mempool, list_transactions, ListQuery};
+use openapi::{Audience, Endpoint};
+use status::NodeStatus;
+
+// Version 1 of the node API. Every route lives under /v1 and answers with an envelope:
+//
+//   {"data": <payload>}                                on success
+//   {"error": {"code": <code>, "message": <text>}}     on failure
+//
+// The payload types below are the schema of the API. Nodes use them to serve requests and to
+// read the answers of their peers during consensus, and the command line uses them as a client.
+//
+//   GET  /v1/chain                  ChainSummary
+//   GET  /v1/blocks                 BlockList, paged and filtered by a ListQuery
+//   GET  /v1/blocks/latest          Block at the tip
+//   GET  /v1/blocks/{height}        Block at a height
+//   GET  /v1/blocks/hash/{hash}     Block with a hash
+//   GET  /v1/transactions           TransactionList of confirmed transactions, as /v1/blocks
+//   POST /v1/transactions           NewTransaction -> TransactionRecord of the pending transaction
+//   GET  /v1/transactions/{hash}    TransactionRecord, confirmed or pending
+//   GET  /v1/mempool                TransactionList of pending transactions, as /v1/blocks
+//   GET  /v1/peers                  PeerList
+//   POST /v1/peers                  NewPeer -> PeerInfo of the node registered with
+//   POST /v1/mine                   MineResult
+//   GET  /v1/status                 NodeStatus, also served at GET /status
+//   GET  /v1/openapi.json           OpenAPI document of every route of the node
+//   GET  /v1/events                 NodeEvents as Server-Sent Events, see events.rs
+//   GET  /v1/events/ws              NodeEvents over a WebSocket
+//        /v1/admin/...              routes for operators, see admin.rs
+//
+// Metrics are served to Prometheus at GET /metrics, see metrics.rs.
+// The same operations are offered to JSON-RPC tools at POST /rpc, see rpc.rs, and the chain
+// can be queried with GraphQL at POST /graphql, see graphql.rs.
+//
+// The routes are registered from the endpoints of v1_endpoints, which the OpenAPI document is
+// generated from too. A copy of the document is kept in openapi.json, which tests compare with
+// the generated one, so changes to the API show up in review.
+
+// Prefix of the routes of this version of the API.
+pub const API_PREFIX: &str = "/v1";
+
+// Envelope wraps every response of the API.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum Envelope<T> {
+    Data(T),
+    Error(ApiError),
+}
+
+impl<T> Envelope<T> {
+    // Turn the envelope into the payload, or the error the node answered with.
+    pub fn into_result(self) -> Result<T, ApiError> {
+        match self {
+            Envelope::Data(data) => Ok(data),
+            Envelope::Error(error) => Err(error),
+        }
+    }
+}
+
+// ApiError is a failure reported by the API. The code is stable and meant for programs; the
+// message is meant for people.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ApiError {
+    pub code: String,
+    pub message: String,
+}
+
+impl ApiError {
+    pub fn new(code: &str, message: impl Into<String>) -> ApiError {
+        ApiError {
+            code: code.to_string(),
+            message: message.into(),
+        }
+    }
+
+    // Status the error is served with.
+    pub(crate) fn status(&self) -> StatusCode {
+        match self.code.as_str() {
+            "invalid_request" => StatusCode::BAD_REQUEST,
+            "unauthorized" => StatusCode::UNAUTHORIZED,
+            "forbidden" => StatusCode::FORBIDDEN,
+            "not_found" => StatusCode::NOT_FOUND,
+            "conflict" => StatusCode::CONFLICT,
+            "peer_unreachable" => StatusCode::BAD_GATEWAY,
+            "shutting_down" => StatusCode::SERVICE_UNAVAILABLE,
+            _ => StatusCode::INTERNAL_SERVER_ERROR,
+        }
+    }
+}
+
+impl fmt::Display for ApiError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl Error for ApiError {}
+
+// ChainSummary describes the chain of a node.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChainSummary {
+    pub chain_id: String,
+    pub genesis_hash: String,
+    pub difficulty: i32,
+    // Height of the tip; the genesis block is at height 0.
+    pub height: i32,
+    // Number of blocks, including the genesis block.
+    pub length: usize,
+    pub tip_hash: String,
+}
+
+// BlockList is a page of blocks. total counts the blocks matching the filters in all pages, and
+// next_cursor continues with the next page, if there is one.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct BlockList {
+    pub total: usize,
+    pub blocks: Vec<Block>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+// TransactionStatus tells whether a transaction is in a block yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum TransactionStatus {
+    Pending,
+    Confirmed,
+}
+
+// TransactionRecord is a transaction with its hash and where it is recorded.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TransactionRecord {
+    pub hash: String,
+    #[serde(flatten)]
+    pub transaction: Transaction,
+    pub status: TransactionStatus,
+    // Block holding the transaction, once confirmed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub block_hash: Option<String>,
+}
+
+// TransactionList is a page of transactions, paged as BlockList is.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct TransactionList {
+    pub total: usize,
+    pub transactions: Vec<TransactionRecord>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub next_cursor: Option<String>,
+}
+
+// NewTransaction is a transaction submitted by a client. The node sets its timestamp.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct NewTransaction {
+    pub author: String,
+    pub content: String,
+}
+
+// PeerInfo describes a peer of the node, with what its handshake told about it.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PeerInfo {
+    pub node_address: String,
+    // Whether the node registered with the peer, rather than the peer with the node.
+    pub outbound: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub chain_id: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tip_height: Option<i32>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub software_version: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub p2p_address: Option<String>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub node_key: Option<String>,
+}
+
+// PeerList is a list of peers.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct PeerList {
+    pub length: usize,
+    pub peers: Vec<PeerInfo>,
+}
+
+// NewPeer is a node to register with.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct NewPeer {
+    pub node_address: String,
+}
+
+// MineResult is the outcome of a mining request: the mined block, if there was anything to
+// mine, and the length of the chain afterwards.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct MineResult {
+    pub block: Option<Block>,
+    pub length: usize,
+}
+
+impl ChainSummary {
+    pub fn of(blockchain: &Blockchain) -> ChainSummary {
+        let tip = blockchain.get_last_block();
+        ChainSummary {
+            chain_id: blockchain.genesis.chain_id.clone(),
+            genesis_hash: blockchain.genesis_hash().to_string(),
+            difficulty: blockchain.difficulty,
+            height: tip.index,
+            length: blockchain.chain.len(),
+            tip_hash: tip.hash.clone(),
+        }
+    }
+}
+
+impl TransactionRecord {
+    pub(crate) fn pending(transaction: &Transaction) -> TransactionRecord {
+        TransactionRecord {
+            hash: transaction.compute_hash().unwrap_or_default(),
+            transaction: transaction.clone(),
+            status: TransactionStatus::Pending,
+            block_height: None,
+            block_hash: None,
+        }
+    }
+
+    pub(crate) fn confirmed(transaction: &Transaction, block: &Block) -> TransactionRecord {
+        TransactionRecord {
+            status: TransactionStatus::Confirmed,
+            block_height: Some(block.index),
+            block_hash: Some(block.hash.clone()),
+            ..TransactionRecord::pending(transaction)
+        }
+    }
+
+    // Find a transaction by hash, in the chain or else in the mempool.
+    pub(crate) fn find(blockchain: &Blockchain, hash: &str) -> Option<TransactionRecord> {
+        let is_wanted = |tx: &Transaction| tx.compute_hash().ok().as_deref() == Some(hash);
+        let confirmed = blockchain.chain.iter().find_map(|block| {
+            block
+                .transactions
+                .iter()
+                .find(|tx| is_wanted(tx))
+                .map(|tx| TransactionRecord::confirmed(tx, block))
+        });
+        confirmed.or_else(|| {
+            blockchain
+                .unconfirmed_transactions
+                .iter()
+                .find(|tx| is_wanted(tx))
+                .map(TransactionRecord::pending)
+        })
+    }
+}
+
+impl From<&NodePeer> for PeerInfo {
+    fn from(peer: &NodePeer) -> PeerInfo {
+        let handshake = peer.handshake.as_ref();
+        PeerInfo {
+            node_address: peer.node_address.clone(),
+            outbound: peer.outbound,
+            chain_id: handshake.map(|h| h.chain_id.clone()),
+            tip_height: handshake.map(|h| h.tip_height),
+            software_version: handshake.map(|h| h.software_version.clone()),
+            p2p_address: handshake.and_then(|h| h.p2p_address.clone()),
+            node_key: handshake.and_then(|h| h.node_key.clone()),
+        }
+    }
+}
+
+// Answer with a payload in an envelope.
+pub fn respond<T: Serialize>(status: StatusCode, data: T) -> HttpResponse {
+    HttpResponse::build(status).json(Envelope::Data(data))
+}
+
+// Answer with an error in an envelope.
+pub fn respond_error(error: ApiError) -> HttpResponse {
+    HttpResponse::build(error.status()).json(Envelope::<()>::Error(error))
+}
+
+fn not_found(what: String) -> HttpResponse {
+    respond_error(ApiError::new("not_found", format!("{} not found", what)))
+}
+
+impl Application {
+    // Endpoint GET /v1/chain
+    async fn handle_v1_chain(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        respond(StatusCode::OK, ChainSummary::of(&blockchain))
+    }
+
+    // Endpoint GET /v1/blocks
+    async fn handle_v1_blocks(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        query: web::Query<ListQuery>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match list_blocks(&blockchain.chain, &query) {
+            Ok(list) => respond(StatusCode::OK, list),
+            Err(error) => respond_error(error),
+        }
+    }
+
+    // Endpoint GET /v1/blocks/latest
+    async fn handle_v1_latest_block(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        respond(StatusCode::OK, blockchain.get_last_block())
+    }
+
+    // Endpoint GET /v1/blocks/{height}
+    async fn handle_v1_block_by_height(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        height: web::Path<usize>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match blockchain.chain.get(*height) {
+            Some(block) => respond(StatusCode::OK, block),
+            None => not_found(format!("Block at height {}", height)),
+        }
+    }
+
+    // Endpoint GET /v1/blocks/hash/{hash}
+    async fn handle_v1_block_by_hash(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        hash: web::Path<String>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match blockchain.chain.iter().find(|block| block.hash == *hash) {
+            Some(block) => respond(StatusCode::OK, block),
+            None => not_found(format!("Block {}", hash)),
+        }
+    }
+
+    // Endpoint GET /v1/transactions
+    async fn handle_v1_transactions(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        query: web::Query<ListQuery>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match list_transactions(&blockchain.chain, &query) {
+            Ok(list) => respond(StatusCode::OK, list),
+            Err(error) => respond_error(error),
+        }
+    }
+
+    // Endpoint GET /v1/transactions/{hash}
+    async fn handle_v1_transaction(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        hash: web::Path<String>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match TransactionRecord::find(&blockchain, &hash) {
+            Some(record) => respond(StatusCode::OK, record),
+            None => not_found(format!("Transaction {}", hash)),
+        }
+    }
+
+    // Endpoint POST /v1/transactions
+    async fn handle_v1_new_transaction(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        transaction: web::Json<NewTransaction>,
+    ) -> HttpResponse {
+        let NewTransaction { author, content } = transaction.into_inner();
+        match Self::accept_transaction(&blockchain, &transport, author, content) {
+            Ok(transaction) => respond(
+                StatusCode::CREATED,
+                TransactionRecord::pending(&transaction),
+            ),
+            Err(message) => respond_error(ApiError::new("invalid_request", message)),
+        }
+    }
+
+    // Endpoint GET /v1/mempool
+    async fn handle_v1_mempool(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        query: web::Query<ListQuery>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        match list_mempool(&blockchain.unconfirmed_transactions, &query) {
+            Ok(list) => respond(StatusCode::OK, list),
+            Err(error) => respond_error(error),
+        }
+    }
+
+    // Endpoint GET /v1/peers
+    async fn handle_v1_peers(blockchain: web::Data<Mutex<Blockchain>>) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        let peers: Vec<PeerInfo> = blockchain.peers.iter().map(PeerInfo::from).collect();
+        respond(
+            StatusCode::OK,
+            PeerList {
+                length: peers.len(),
+                peers,
+            },
+        )
+    }
+
+    // Endpoint POST /v1/peers
+    async fn handle_v1_new_peer(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        peer: web::Json<NewPeer>,
+        req: HttpRequest,
+    ) -> HttpResponse {
+        let node_address = peer.into_inner().node_address;
+        match Self::register_with_node(&blockchain, &transport, &auth, &req, &node_address).await {
+            Ok(peer) => respond(StatusCode::CREATED, PeerInfo::from(&peer)),
+            Err(error) => respond_error(error),
+        }
+    }
+
+    // Endpoint POST /v1/mine
+    async fn handle_v1_mine(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        activity: web::Data<NodeActivity>,
+    ) -> HttpResponse {
+        let block = match Self::mine_and_announce(&blockchain, &transport, &auth, &activity).await {
+            Ok(block) => block,
+            Err(e) if e.is::<MiningCancelled>() => {
+                return respond_error(ApiError::new("shutting_down", "Node is shutting down"))
+            }
+            Err(e) => {
+                return respond_error(ApiError::new(
+                    "internal",
+                    format!("Failed to mine block: {}", e),
+                ))
+            }
+        };
+        let length = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read")
+            .chain
+            .len();
+        respond(StatusCode::OK, MineResult { block, length })
+    }
+
+    // Answer requests the API cannot parse with an error envelope.
+    fn handle_v1_json_error(
+        err: actix_web::error::JsonPayloadError,
+        _req: &HttpRequest,
+    ) -> actix_web::Error {
+        let response = respond_error(ApiError::new("invalid_request", err.to_string()));
+        actix_web::error::InternalError::from_response(err, response).into()
+    }
+
+    fn handle_v1_query_error(
+        err: actix_web::error::QueryPayloadError,
+        _req: &HttpRequest,
+    ) -> actix_web::Error {
+        let response = respond_error(ApiError::new("invalid_request", err.to_string()));
+        actix_web::error::InternalError::from_response(err, response).into()
+    }
+
+    fn handle_v1_path_error(
+        err: actix_web::error::PathError,
+        _req: &HttpRequest,
+    ) -> actix_web::Error {
+        let response = respond_error(ApiError::new("invalid_request", err.to_string()));
+        actix_web::error::InternalError::from_response(err, response).into()
+    }
+
+    // Endpoint GET /v1/openapi.json
+    async fn handle_v1_openapi() -> HttpResponse {
+        HttpResponse::Ok().json(Self::openapi())
+    }
+
+    // Answer the requests of a /v1 resource that cannot be parsed with error envelopes.
+    pub(crate) fn v1_resource(path: &str) -> Resource {
+        web::resource(path)
+            .app_data(web::JsonConfig::default().error_handler(Self::handle_v1_json_error))
+            .app_data(web::QueryConfig::default().error_handler(Self::handle_v1_query_error))
+            .app_data(web::PathConfig::default().error_handler(Self::handle_v1_path_error))
+    }
+
+    // Endpoints of the /v1 API. Routes that overlap are matched in this order, so
+    // /v1/blocks/latest comes before /v1/blocks/{height}.
+    pub(crate) fn v1_endpoints() -> Vec<Endpoint> {
+        use Audience::Client;
+        vec![
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/chain",
+                "Summary of the chain",
+                Self::handle_v1_chain,
+            )
+            .data::<ChainSummary>(StatusCode::OK, "Chain summary"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/blocks",
+                "Page of the blocks of the chain",
+                Self::handle_v1_blocks,
+            )
+            .data::<BlockList>(StatusCode::OK, "Blocks matching the query"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/blocks/latest",
+                "Block at the tip of the chain",
+                Self::handle_v1_latest_block,
+            )
+            .data::<Block>(StatusCode::OK, "Tip of the chain"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/blocks/hash/{hash}",
+                "Block with a hash",
+                Self::handle_v1_block_by_hash,
+            )
+            .data::<Block>(StatusCode::OK, "Block with the hash"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/blocks/{height}",
+                "Block at a height",
+                Self::handle_v1_block_by_height,
+            )
+            .data::<Block>(StatusCode::OK, "Block at the height"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/transactions",
+                "Page of the confirmed transactions",
+                Self::handle_v1_transactions,
+            )
+            .data::<TransactionList>(StatusCode::OK, "Transactions matching the query"),
+            Endpoint::new(
+                Client,
+                Method::POST,
+                "/v1/transactions",
+                "Submit a transaction to the mempool",
+                Self::handle_v1_new_transaction,
+            )
+            .data::<TransactionRecord>(StatusCode::CREATED, "Pending transaction"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/transactions/{hash}",
+                "Confirmed or pending transaction with a hash",
+                Self::handle_v1_transaction,
+            )
+            .data::<TransactionRecord>(StatusCode::OK, "Transaction with the hash"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/mempool",
+                "Page of the pending transactions",
+                Self::handle_v1_mempool,
+            )
+            .data::<TransactionList>(StatusCode::OK, "Transactions matching the query"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/peers",
+                "Peers of the node",
+                Self::handle_v1_peers,
+            )
+            .data::<PeerList>(StatusCode::OK, "Peers"),
+            Endpoint::new(
+                Client,
+                Method::POST,
+                "/v1/peers",
+                "Register with a node and sync with its chain",
+                Self::handle_v1_new_peer,
+            )
+            .data::<PeerInfo>(StatusCode::CREATED, "Node registered with"),
+            Endpoint::new(
+                Client,
+                Method::POST,
+                "/v1/mine",
+                "Mine the pending transactions",
+                Self::handle_v1_mine,
+            )
+            .data::<MineResult>(StatusCode::OK, "Mined block, if there was anything to mine"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/status",
+                "Chain, peers, mempool and mining state of the node",
+                Self::handle_v1_status,
+            )
+            .data::<NodeStatus>(StatusCode::OK, "Node status"),
+            Endpoint::new(
+                Client,
+                Method::GET,
+                "/v1/openapi.json",
+                "This document",
+                Self::handle_v1_openapi,
+            )
+            .json::<serde_json::Value>(StatusCode::OK, "OpenAPI document of the node API"),
+        ]
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use serde_json::json;
+
+    #[test]
+    fn envelopes_hold_data_or_an_error() {
+        let data = serde_json::to_value(Envelope::Data(NewPeer {
+            node_address: "http://127.0.0.1:8080".to_string(),
+        }))
+        .unwrap();
+        assert_eq!(
+            data,
+            json!({ "data": { "node_address": "http://127.0.0.1:8080" } })
+        );
+
+        let error: Envelope<BlockList> = serde_json::from_value(json!({
+            "error": { "code": "not_found", "message": "Block at height 9 not found" }
+        }))
+        .unwrap();
+        let error = error.into_result().unwrap_err();
+        assert_eq!(error.code, "not_found");
+        assert_eq!(error.status(), StatusCode::NOT_FOUND);
+    }
+}
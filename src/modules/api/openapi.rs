@@ -0,0 +1,434 @@
+use actix_web::{
+    http::Method, http::StatusCode, web, FromRequest, Handler, HttpRequest, Responder, Route,
+};
+use schemars::gen::{SchemaGenerator, SchemaSettings};
+use schemars::schema::{Schema, SchemaObject, SingleOrVec};
+use schemars::JsonSchema;
+use serde_json::{json, Map, Value};
+
+use super::{ApiError, Envelope};
+use crate::modules::network::auth::{
+    NODE_KEY_HEADER, NODE_SIGNATURE_HEADER, NODE_TIMESTAMP_HEADER,
+};
+use crate::modules::network::handshake::SOFTWARE_VERSION;
+
+// Version of the OpenAPI specification the document follows.
+const OPENAPI_VERSION: &str = "3.0.3";
+
+// Audience is who a route is meant for, which decides how its errors are answered.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Audience {
+    // Clients of the /v1 API, answered with error envelopes.
+    Client,
+    // Other nodes, whose requests are signed and whose malformed payloads are an offence.
+    Peer,
+    // Clients of the routes that predate /v1.
+    Legacy,
+    // Tools speaking JSON-RPC 2.0, answered with JSON-RPC errors.
+    Rpc,
+    // Operators of the node, answered as clients of the /v1 API.
+    Admin,
+    // GraphQL clients, answered with GraphQL errors.
+    Graphql,
+    // Monitoring systems scraping the node.
+    Monitoring,
+}
+
+impl Audience {
+    fn tag(&self) -> &'static str {
+        match self {
+            Audience::Client => "v1",
+            Audience::Peer => "peer",
+            Audience::Legacy => "legacy",
+            Audience::Rpc => "rpc",
+            Audience::Admin => "admin",
+            Audience::Graphql => "graphql",
+            Audience::Monitoring => "monitoring",
+        }
+    }
+}
+
+// Operation is the description of a route in the OpenAPI document.
+#[derive(Debug, Default)]
+pub struct Operation {
+    parameters: Vec<Value>,
+    request_body: Option<Value>,
+}
+
+// DescribeRequest describes what a handler reads from requests, from the types of its
+// extractors: JSON bodies, query strings and path segments. Other extractors add nothing.
+pub trait DescribeRequest {
+    fn describe(path: &str, operation: &mut Operation, gen: &mut SchemaGenerator);
+}
+
+impl<T: ?Sized + 'static> DescribeRequest for web::Data<T> {
+    fn describe(_: &str, _: &mut Operation, _: &mut SchemaGenerator) {}
+}
+
+impl DescribeRequest for HttpRequest {
+    fn describe(_: &str, _: &mut Operation, _: &mut SchemaGenerator) {}
+}
+
+// Handlers reading the raw body parse it themselves, and document it with Endpoint::request.
+impl DescribeRequest for web::Bytes {
+    fn describe(_: &str, _: &mut Operation, _: &mut SchemaGenerator) {}
+}
+
+impl DescribeRequest for web::Payload {
+    fn describe(_: &str, _: &mut Operation, _: &mut SchemaGenerator) {}
+}
+
+impl<T: JsonSchema> DescribeRequest for web::Json<T> {
+    fn describe(_: &str, operation: &mut Operation, gen: &mut SchemaGenerator) {
+        operation.request_body = Some(json!({
+            "required": true,
+            "content": { "application/json": { "schema": gen.subschema_for::<T>() } },
+        }));
+    }
+}
+
+impl<T: JsonSchema> DescribeRequest for web::Query<T> {
+    fn describe(_: &str, operation: &mut Operation, gen: &mut SchemaGenerator) {
+        let schema = gen.subschema_for::<T>();
+        let schema = resolve(gen, schema);
+        let Some(object) = schema.object else {
+            return;
+        };
+        for (name, property) in &object.properties {
+            operation.parameters.push(json!({
+                "name": name,
+                "in": "query",
+                "required": object.required.contains(name),
+                "schema": property,
+            }));
+        }
+    }
+}
+
+impl<T: JsonSchema> DescribeRequest for web::Path<T> {
+    fn describe(path: &str, operation: &mut Operation, gen: &mut SchemaGenerator) {
+        let names = path_parameters(path);
+        let schema = gen.subschema_for::<T>();
+        // Several segments are extracted as a tuple, which has a schema per item
+        let items = match resolve(gen, schema.clone()).array.map(|array| *array) {
+            Some(array) if names.len() > 1 => match array.items {
+                Some(SingleOrVec::Vec(items)) => items,
+                _ => vec![],
+            },
+            _ => vec![schema],
+        };
+        for (name, schema) in names.iter().zip(items) {
+            operation.parameters.push(json!({
+                "name": name,
+                "in": "path",
+                "required": true,
+                "schema": schema,
+            }));
+        }
+    }
+}
+
+macro_rules! describe_tuple {
+    ($($arg:ident),*) => {
+        impl<$($arg: DescribeRequest),*> DescribeRequest for ($($arg,)*) {
+            #[allow(unused_variables)]
+            fn describe(path: &str, operation: &mut Operation, gen: &mut SchemaGenerator) {
+                $($arg::describe(path, operation, gen);)*
+            }
+        }
+    };
+}
+
+describe_tuple!();
+describe_tuple!(A);
+describe_tuple!(A, B);
+describe_tuple!(A, B, C);
+describe_tuple!(A, B, C, D);
+describe_tuple!(A, B, C, D, E);
+describe_tuple!(A, B, C, D, E, F);
+
+// Follow a reference to the definition of a type.
+fn resolve(gen: &SchemaGenerator, schema: Schema) -> SchemaObject {
+    gen.dereference(&schema)
+        .cloned()
+        .unwrap_or(schema)
+        .into_object()
+}
+
+// Names of the {segments} of a path.
+fn path_parameters(path: &str) -> Vec<&str> {
+    path.split('/')
+        .filter_map(|segment| segment.strip_prefix('{')?.strip_suffix('}'))
+        .collect()
+}
+
+// Response is a documented answer of a route.
+struct Response {
+    status: StatusCode,
+    description: &'static str,
+    content: Content,
+}
+
+enum Content {
+    Json(fn(&mut SchemaGenerator) -> Schema),
+    Events(fn(&mut SchemaGenerator) -> Schema),
+    Text,
+    Html,
+    Empty,
+}
+
+fn schema_of<T: JsonSchema>(gen: &mut SchemaGenerator) -> Schema {
+    gen.subschema_for::<T>()
+}
+
+// ErrorEnvelope is how the /v1 API answers with an error.
+#[derive(JsonSchema)]
+#[allow(dead_code)]
+struct ErrorEnvelope {
+    error: ApiError,
+}
+
+// Endpoint is a route of the node API together with its documentation. Nodes register the
+// routes of their endpoints, and the OpenAPI document is generated from the same endpoints, so
+// that the two cannot drift apart.
+pub struct Endpoint {
+    pub method: Method,
+    pub path: &'static str,
+    pub audience: Audience,
+    pub route: Route,
+    summary: &'static str,
+    describe_request: fn(&str, &mut Operation, &mut SchemaGenerator),
+    request_body: Option<fn(&mut SchemaGenerator) -> Schema>,
+    responses: Vec<Response>,
+}
+
+impl Endpoint {
+    pub fn new<F, Args>(
+        audience: Audience,
+        method: Method,
+        path: &'static str,
+        summary: &'static str,
+        handler: F,
+    ) -> Endpoint
+    where
+        F: Handler<Args>,
+        Args: FromRequest + DescribeRequest + 'static,
+        F::Output: Responder + 'static,
+    {
+        Endpoint {
+            route: web::method(method.clone()).to(handler),
+            method,
+            path,
+            audience,
+            summary,
+            describe_request: Args::describe,
+            request_body: None,
+            responses: Vec::new(),
+        }
+    }
+
+    // Document a JSON body holding T, for handlers reading the raw body.
+    pub fn request<T: JsonSchema>(mut self) -> Endpoint {
+        self.request_body = Some(schema_of::<T>);
+        self
+    }
+
+    // Document an answer holding T in an envelope.
+    pub fn data<T: JsonSchema>(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Json(schema_of::<Envelope<T>>))
+    }
+
+    // Document an answer holding T as it is.
+    pub fn json<T: JsonSchema>(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Json(schema_of::<T>))
+    }
+
+    // Document a stream of Server-Sent Events, each holding the data of a T.
+    pub fn events<T: JsonSchema>(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Events(schema_of::<T>))
+    }
+
+    // Document an answer holding a message in plain text.
+    pub fn text(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Text)
+    }
+
+    // Document an answer holding a page for browsers.
+    pub fn html(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Html)
+    }
+
+    // Document an answer without a body.
+    pub fn empty(self, status: StatusCode, description: &'static str) -> Endpoint {
+        self.response(status, description, Content::Empty)
+    }
+
+    fn response(mut self, status: StatusCode, description: &'static str, content: Content) -> Self {
+        self.responses.push(Response {
+            status,
+            description,
+            content,
+        });
+        self
+    }
+
+    fn operation(&self, gen: &mut SchemaGenerator) -> Value {
+        let mut operation = Operation::default();
+        (self.describe_request)(self.path, &mut operation, gen);
+        if let Some(schema) = self.request_body {
+            operation.request_body = Some(json!({
+                "required": true,
+                "content": { "application/json": { "schema": schema(gen) } },
+            }));
+        }
+        if self.audience == Audience::Peer {
+            for header in [
+                NODE_KEY_HEADER,
+                NODE_TIMESTAMP_HEADER,
+                NODE_SIGNATURE_HEADER,
+            ] {
+                operation.parameters.push(json!({
+                    "name": header,
+                    "in": "header",
+                    "required": true,
+                    "schema": { "type": "string" },
+                }));
+            }
+        }
+        if self.audience == Audience::Admin {
+            operation.parameters.push(json!({
+                "name": "Authorization",
+                "in": "header",
+                "description": "Bearer admin token, when the node has one",
+                "required": false,
+                "schema": { "type": "string" },
+            }));
+        }
+
+        let mut responses = Map::new();
+        for response in &self.responses {
+            let mut answer = json!({ "description": response.description });
+            match response.content {
+                Content::Json(schema) => {
+                    answer["content"] = json!({ "application/json": { "schema": schema(gen) } })
+                }
+                Content::Events(schema) => {
+                    answer["content"] = json!({ "text/event-stream": { "schema": schema(gen) } })
+                }
+                Content::Text => {
+                    answer["content"] = json!({ "text/plain": { "schema": { "type": "string" } } })
+                }
+                Content::Html => {
+                    answer["content"] = json!({ "text/html": { "schema": { "type": "string" } } })
+                }
+                Content::Empty => {}
+            }
+            responses.insert(response.status.as_u16().to_string(), answer);
+        }
+        if matches!(self.audience, Audience::Client | Audience::Admin) {
+            responses.insert(
+                "default".to_string(),
+                json!({
+                    "description": "Error",
+                    "content": {
+                        "application/json": { "schema": gen.subschema_for::<ErrorEnvelope>() }
+                    },
+                }),
+            );
+        }
+
+        let mut description = json!({
+            "summary": self.summary,
+            "tags": [self.audience.tag()],
+            "responses": responses,
+        });
+        if !operation.parameters.is_empty() {
+            description["parameters"] = Value::Array(operation.parameters);
+        }
+        if let Some(body) = operation.request_body {
+            description["requestBody"] = body;
+        }
+        description
+    }
+}
+
+// Generate the OpenAPI document describing the given endpoints.
+pub fn openapi_document(endpoints: &[Endpoint]) -> Value {
+    let mut gen = SchemaSettings::openapi3().into_generator();
+    let mut paths = Map::new();
+    for endpoint in endpoints {
+        let operation = endpoint.operation(&mut gen);
+        let item = paths
+            .entry(endpoint.path.to_string())
+            .or_insert_with(|| json!({}));
+        item[endpoint.method.as_str().to_lowercase()] = operation;
+    }
+    json!({
+        "openapi": OPENAPI_VERSION,
+        "info": {
+            "title": "Versus node API",
+            "version": SOFTWARE_VERSION,
+        },
+        "paths": paths,
+        "components": { "schemas": gen.take_definitions() },
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use actix_web::{http::StatusCode, test as web_test, web, App, HttpResponse};
+
+    use crate::modules::app::{Application, NodeOptions};
+    use crate::modules::network::auth::{NodeAuth, NodeIdentity};
+
+    #[test]
+    fn generated_spec_matches_the_committed_one() {
+        let committed: serde_json::Value =
+            serde_json::from_str(include_str!("openapi.json")).unwrap();
+        assert!(
+            *Application::openapi() == committed,
+            "The node API changed, regenerate its spec with `versus openapi > src/modules/api/openapi.json`"
+        );
+    }
+
+    #[actix_web::test]
+    async fn every_documented_route_is_served() {
+        let data_dir = std::env::temp_dir().join(format!("versus-openapi-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let options = NodeOptions {
+            data_dir: data_dir.clone(),
+            ..NodeOptions::default()
+        };
+        let app =
+            Application::new(NodeAuth::new(NodeIdentity::generate(), None), &options).unwrap();
+        // Requests no route matches are answered with a status no route answers with
+        let service =
+            web_test::init_service(App::new().configure(|cfg| app.config(cfg)).default_service(
+                web::to(|| async { HttpResponse::new(StatusCode::IM_A_TEAPOT) }),
+            ))
+            .await;
+
+        let paths = Application::openapi()["paths"].as_object().unwrap();
+        for (path, item) in paths {
+            let uri = path.replace("{height}", "0").replace("{hash}", "unknown");
+            for method in item.as_object().unwrap().keys() {
+                // Mining would hold the test up until a block is found
+                if uri.ends_with("mine") {
+                    continue;
+                }
+                let request = web_test::TestRequest::default()
+                    .method(method.to_uppercase().parse().unwrap())
+                    .uri(&uri)
+                    .to_request();
+                let status = web_test::call_service(&service, request).await.status();
+                assert!(
+                    status != StatusCode::IM_A_TEAPOT && status != StatusCode::METHOD_NOT_ALLOWED,
+                    "{} {} is documented but not served",
+                    method,
+                    path
+                );
+            }
+        }
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
@@ -0,0 +1,404 @@
+use actix_web::{http::Method, http::StatusCode, web, HttpResponse};
+use schemars::JsonSchema;
+use serde::de::DeserializeOwned;
+use serde::{Deserialize, Serialize};
+use serde_json::{Map, Value};
+use std::fmt;
+use std::sync::Mutex;
+
+use super::listing::{list_mempool, ListQuery};
+use super::openapi::{Audience, Endpoint};
+use super::{ApiError, NewTransaction, PeerInfo, TransactionRecord};
+use crate::modules::app::Application;
+use crate::modules::blockchain::chain::Blockchain;
+use crate::modules::network::transport::Transport;
+
+// JSON-RPC 2.0 interface of the node, for tools that speak it rather than the /v1 API. Calls
+// are posted to /rpc, alone or in batches, and run the same operations as the /v1 routes:
+//
+//   getblockcount                     height of the tip
+//   getblock [height or hash]         Block, by height when given a number and by hash otherwise
+//   sendtransaction [author, content] TransactionRecord of the pending transaction
+//   getmempool {ListQuery}            TransactionList of pending transactions, as /v1/mempool
+//   getpeerinfo                       list of PeerInfo
+//
+// Parameters are given by position or by name: getblock takes "block", sendtransaction
+// "author" and "content", and getmempool only takes names, those of the listing query.
+
+pub const RPC_PATH: &str = "/rpc";
+pub const JSONRPC_VERSION: &str = "2.0";
+
+// Error codes defined by the specification.
+pub const PARSE_ERROR: i64 = -32700;
+pub const INVALID_REQUEST: i64 = -32600;
+pub const METHOD_NOT_FOUND: i64 = -32601;
+pub const INVALID_PARAMS: i64 = -32602;
+pub const INTERNAL_ERROR: i64 = -32603;
+// Error codes of the node, in the range the specification leaves to servers.
+pub const NOT_FOUND: i64 = -32001;
+
+// RpcRequest is a call. Calls without an id are notifications, which are run but not answered.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RpcRequest {
+    pub jsonrpc: String,
+    pub method: String,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub params: Option<Value>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Value>,
+}
+
+// RpcResponse answers a call with its result or an error.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+pub struct RpcResponse {
+    pub jsonrpc: String,
+    #[serde(flatten)]
+    pub outcome: RpcOutcome,
+    pub id: Value,
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum RpcOutcome {
+    Result(Value),
+    Error(RpcError),
+}
+
+#[derive(Debug, Clone, PartialEq, Serialize, Deserialize, JsonSchema)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+}
+
+// RpcPayload is the body of a POST /rpc, and RpcAnswer what it is answered with: a response,
+// or the responses to the calls of a batch that are not notifications.
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RpcPayload {
+    Call(RpcRequest),
+    Batch(Vec<RpcRequest>),
+}
+
+#[derive(Debug, Serialize, Deserialize, JsonSchema)]
+#[serde(untagged)]
+pub enum RpcAnswer {
+    Single(RpcResponse),
+    Batch(Vec<RpcResponse>),
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: impl Into<String>) -> RpcError {
+        RpcError {
+            code,
+            message: message.into(),
+        }
+    }
+}
+
+impl From<ApiError> for RpcError {
+    fn from(error: ApiError) -> RpcError {
+        let code = match error.code.as_str() {
+            "not_found" => NOT_FOUND,
+            "invalid_request" => INVALID_PARAMS,
+            _ => INTERNAL_ERROR,
+        };
+        RpcError::new(code, error.message)
+    }
+}
+
+impl fmt::Display for RpcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{} ({})", self.message, self.code)
+    }
+}
+
+impl std::error::Error for RpcError {}
+
+impl RpcResponse {
+    fn new(id: Value, outcome: Result<Value, RpcError>) -> RpcResponse {
+        RpcResponse {
+            jsonrpc: JSONRPC_VERSION.to_string(),
+            outcome: match outcome {
+                Ok(result) => RpcOutcome::Result(result),
+                Err(error) => RpcOutcome::Error(error),
+            },
+            id,
+        }
+    }
+}
+
+// Identify a block by height or by hash.
+#[derive(Debug, Deserialize)]
+#[serde(untagged)]
+enum BlockId {
+    Height(usize),
+    Hash(String),
+}
+
+#[derive(Debug, Deserialize)]
+struct GetBlockParams {
+    block: BlockId,
+}
+
+// Read the parameters of a call, given by position in the order of names or by name.
+fn read_params<T: DeserializeOwned>(params: Option<Value>, names: &[&str]) -> Result<T, RpcError> {
+    let named = match params {
+        None => Value::Object(Map::new()),
+        Some(Value::Array(values)) => {
+            if values.len() > names.len() {
+                return Err(RpcError::new(
+                    INVALID_PARAMS,
+                    format!("Expected at most {} parameters", names.len()),
+                ));
+            }
+            Value::Object(
+                names
+                    .iter()
+                    .map(|name| name.to_string())
+                    .zip(values)
+                    .collect(),
+            )
+        }
+        Some(params) => params,
+    };
+    serde_json::from_value(named).map_err(|e| RpcError::new(INVALID_PARAMS, e.to_string()))
+}
+
+fn result<T: Serialize>(value: T) -> Result<Value, RpcError> {
+    serde_json::to_value(value).map_err(|e| RpcError::new(INTERNAL_ERROR, e.to_string()))
+}
+
+impl Application {
+    // Endpoint POST /rpc
+    async fn handle_rpc(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        body: web::Bytes,
+    ) -> HttpResponse {
+        match Self::rpc_answer(&blockchain, &transport, &body) {
+            Some(answer) => HttpResponse::Ok().json(answer),
+            // A batch of notifications is not answered
+            None => HttpResponse::NoContent().finish(),
+        }
+    }
+
+    // Run the calls of a request body, and answer those that are not notifications.
+    pub(crate) fn rpc_answer(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        body: &[u8],
+    ) -> Option<RpcAnswer> {
+        let payload = match serde_json::from_slice::<Value>(body) {
+            Ok(payload) => payload,
+            Err(e) => {
+                let error = RpcError::new(PARSE_ERROR, e.to_string());
+                return Some(RpcAnswer::Single(RpcResponse::new(Value::Null, Err(error))));
+            }
+        };
+        match payload {
+            Value::Array(calls) if calls.is_empty() => {
+                let error = RpcError::new(INVALID_REQUEST, "Empty batch");
+                Some(RpcAnswer::Single(RpcResponse::new(Value::Null, Err(error))))
+            }
+            Value::Array(calls) => {
+                let responses: Vec<RpcResponse> = calls
+                    .into_iter()
+                    .filter_map(|call| Self::rpc_call(blockchain, transport, call))
+                    .collect();
+                match responses.is_empty() {
+                    true => None,
+                    false => Some(RpcAnswer::Batch(responses)),
+                }
+            }
+            call => Self::rpc_call(blockchain, transport, call).map(RpcAnswer::Single),
+        }
+    }
+
+    fn rpc_call(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        call: Value,
+    ) -> Option<RpcResponse> {
+        // A null id still asks for an answer, unlike a missing one
+        let id = call.get("id").cloned();
+        let valid = |request: &RpcRequest| {
+            request.jsonrpc == JSONRPC_VERSION
+                && request
+                    .params
+                    .as_ref()
+                    .is_none_or(|params| params.is_array() || params.is_object())
+                && id
+                    .as_ref()
+                    .is_none_or(|id| id.is_string() || id.is_number() || id.is_null())
+        };
+        let request = match serde_json::from_value::<RpcRequest>(call) {
+            Ok(request) if valid(&request) => request,
+            // The id of an invalid request cannot be trusted, so it is answered with a null one
+            _ => {
+                let error = RpcError::new(INVALID_REQUEST, "Invalid JSON-RPC 2.0 request");
+                return Some(RpcResponse::new(Value::Null, Err(error)));
+            }
+        };
+        let outcome = Self::rpc_dispatch(blockchain, transport, &request.method, request.params);
+        id.map(|id| RpcResponse::new(id, outcome))
+    }
+
+    fn rpc_dispatch(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        method: &str,
+        params: Option<Value>,
+    ) -> Result<Value, RpcError> {
+        let lock = || {
+            blockchain
+                .lock()
+                .expect("Unable to lock blockchain for read")
+        };
+        match method {
+            "getblockcount" => result(lock().get_last_block().index),
+            "getblock" => {
+                let GetBlockParams { block } = read_params(params, &["block"])?;
+                let blockchain = lock();
+                let found = match &block {
+                    BlockId::Height(height) => blockchain.chain.get(*height),
+                    BlockId::Hash(hash) => blockchain.chain.iter().find(|b| b.hash == *hash),
+                };
+                match found {
+                    Some(block) => result(block),
+                    None => Err(RpcError::new(NOT_FOUND, "Block not found")),
+                }
+            }
+            "sendtransaction" => {
+                let NewTransaction { author, content } =
+                    read_params(params, &["author", "content"])?;
+                let transaction = Self::accept_transaction(blockchain, transport, author, content)
+                    .map_err(|message| RpcError::new(INVALID_PARAMS, message))?;
+                result(TransactionRecord::pending(&transaction))
+            }
+            "getmempool" => {
+                let query: ListQuery = read_params(params, &[])?;
+                result(list_mempool(&lock().unconfirmed_transactions, &query)?)
+            }
+            "getpeerinfo" => {
+                let peers: Vec<PeerInfo> = lock().peers.iter().map(PeerInfo::from).collect();
+                result(peers)
+            }
+            _ => Err(RpcError::new(
+                METHOD_NOT_FOUND,
+                format!("Method {} not found", method),
+            )),
+        }
+    }
+
+    pub(crate) fn rpc_endpoint() -> Endpoint {
+        Endpoint::new(
+            Audience::Rpc,
+            Method::POST,
+            RPC_PATH,
+            "JSON-RPC 2.0 calls, alone or in a batch",
+            Self::handle_rpc,
+        )
+        .request::<RpcPayload>()
+        .json::<RpcAnswer>(StatusCode::OK, "Responses to the calls")
+        .empty(StatusCode::NO_CONTENT, "Only notifications were sent")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::network::auth::{NodeAuth, NodeIdentity};
+    use serde_json::json;
+    use std::sync::Arc;
+
+    fn node() -> (web::Data<Mutex<Blockchain>>, Transport) {
+        let blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let auth = Arc::new(NodeAuth::new(NodeIdentity::generate(), None));
+        let transport = Transport::new(blockchain.clone(), auth, "127.0.0.1:0", "http://node");
+        (blockchain, transport)
+    }
+
+    fn answer(node: &(web::Data<Mutex<Blockchain>>, Transport), body: Value) -> Value {
+        let answer = Application::rpc_answer(&node.0, &node.1, body.to_string().as_bytes());
+        serde_json::to_value(answer).unwrap()
+    }
+
+    #[test]
+    fn batches_answer_every_call_but_notifications() {
+        let node = node();
+        let answer = answer(
+            &node,
+            json!([
+                {"jsonrpc": "2.0", "method": "sendtransaction", "params": ["alice", "hello"], "id": 1},
+                {"jsonrpc": "2.0", "method": "sendtransaction", "params": {"author": "bob", "content": "hi"}},
+                {"jsonrpc": "2.0", "method": "getmempool", "params": {"author": "bob"}, "id": "mempool"},
+                {"jsonrpc": "2.0", "method": "getblock", "params": [0], "id": null},
+                {"jsonrpc": "2.0", "method": "getblockcount", "id": 4},
+            ]),
+        );
+        let answer = answer.as_array().unwrap();
+        assert_eq!(answer.len(), 4);
+        assert_eq!(answer[0]["id"], 1);
+        assert_eq!(answer[0]["result"]["author"], "alice");
+        assert_eq!(answer[0]["result"]["status"], "pending");
+        // The notification was run, though not answered
+        assert_eq!(answer[1]["id"], "mempool");
+        assert_eq!(answer[1]["result"]["total"], 1);
+        assert_eq!(answer[1]["result"]["transactions"][0]["content"], "hi");
+        assert_eq!(answer[2]["id"], Value::Null);
+        assert_eq!(answer[2]["result"]["index"], 0);
+        assert_eq!(answer[3], json!({"jsonrpc": "2.0", "result": 0, "id": 4}));
+
+        let notifications = json!([{"jsonrpc": "2.0", "method": "getblockcount"}]);
+        assert!(
+            Application::rpc_answer(&node.0, &node.1, notifications.to_string().as_bytes())
+                .is_none()
+        );
+    }
+
+    #[test]
+    fn errors_use_the_standard_codes() {
+        let node = node();
+        let code = |body: Value| answer(&node, body)["error"]["code"].as_i64().unwrap();
+        let call = |method: &str, params: Value| json!({"jsonrpc": "2.0", "method": method, "params": params, "id": 1});
+
+        let parse_error =
+            Application::rpc_answer(&node.0, &node.1, b"{\"jsonrpc\": \"2.0\", \"method\"");
+        assert_eq!(
+            serde_json::to_value(parse_error).unwrap()["error"]["code"],
+            PARSE_ERROR
+        );
+        assert_eq!(code(json!([])), INVALID_REQUEST);
+        assert_eq!(
+            code(json!({"jsonrpc": "1.0", "method": "getblockcount", "id": 1})),
+            INVALID_REQUEST
+        );
+        assert_eq!(
+            code(json!({"jsonrpc": "2.0", "method": "getblockcount", "params": 3, "id": 1})),
+            INVALID_REQUEST
+        );
+        assert_eq!(code(call("getbalance", json!([]))), METHOD_NOT_FOUND);
+        assert_eq!(code(call("getblock", json!([]))), INVALID_PARAMS);
+        assert_eq!(code(call("getblock", json!([0, 1]))), INVALID_PARAMS);
+        assert_eq!(
+            code(call("sendtransaction", json!(["alice", ""]))),
+            INVALID_PARAMS
+        );
+        assert_eq!(
+            code(call("getmempool", json!({"limit": 0}))),
+            INVALID_PARAMS
+        );
+        assert_eq!(code(call("getblock", json!([7]))), NOT_FOUND);
+        assert_eq!(
+            code(call("getblock", json!({"block": "unknown"}))),
+            NOT_FOUND
+        );
+
+        // Each call of a batch is answered on its own
+        let batch = answer(&node, json!([1, call("getblockcount", json!([]))]));
+        assert_eq!(batch[0]["error"]["code"], INVALID_REQUEST);
+        assert_eq!(batch[1]["result"], 0);
+    }
+}
@@ -0,0 +1,150 @@
+use actix_web::{http::StatusCode, web, HttpResponse};
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::sync::Mutex;
+
+use super::respond;
+use crate::modules::app::{Application, NodeActivity};
+use crate::modules::blockchain::chain::Blockchain;
+use crate::modules::network::handshake::{PROTOCOL_VERSION, SOFTWARE_VERSION};
+
+// NodeStatus is what GET /v1/status reports: the chain the node holds and what it is doing.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct NodeStatus {
+    pub version: String,
+    pub protocol_version: u32,
+    #[serde(flatten)]
+    pub chain: ChainStatus,
+    // Whether the node is mining a block right now, and whether it mines in the background.
+    pub mining: bool,
+    pub auto_mine: bool,
+    pub started_at: i64,
+    pub uptime_secs: i64,
+}
+
+// ChainStatus is the part of the status read from the blockchain.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct ChainStatus {
+    pub chain_id: String,
+    pub difficulty: i32,
+    pub height: i32,
+    pub tip_hash: String,
+    pub tip_timestamp: i64,
+    pub peers: usize,
+    pub mempool_size: usize,
+    pub sync: SyncStatus,
+}
+
+// SyncStatus compares our height with the highest one we have seen peers at, from their
+// handshakes, consensus rounds and the blocks they announce.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct SyncStatus {
+    pub best_peer_height: i32,
+    // Share of the best peer height we have, from 0 to 1.
+    pub progress: f64,
+    pub synced: bool,
+}
+
+impl ChainStatus {
+    pub fn of(blockchain: &Blockchain) -> ChainStatus {
+        let tip = blockchain.get_last_block();
+        let handshake_height = blockchain
+            .peers
+            .iter()
+            .filter_map(|peer| peer.handshake.as_ref())
+            .map(|handshake| handshake.tip_height)
+            .max()
+            .unwrap_or(0);
+        let best_peer_height = handshake_height.max(blockchain.best_peer_height);
+        let progress = match best_peer_height {
+            best if best <= tip.index => 1.0,
+            best => tip.index.max(0) as f64 / best as f64,
+        };
+        ChainStatus {
+            chain_id: blockchain.genesis.chain_id.clone(),
+            difficulty: blockchain.difficulty,
+            height: tip.index,
+            tip_hash: tip.hash.clone(),
+            tip_timestamp: tip.timestamp,
+            peers: blockchain.peers.len(),
+            mempool_size: blockchain.unconfirmed_transactions.len(),
+            sync: SyncStatus {
+                best_peer_height,
+                progress,
+                synced: best_peer_height <= tip.index,
+            },
+        }
+    }
+}
+
+impl NodeStatus {
+    pub fn new(chain: ChainStatus, activity: &NodeActivity) -> NodeStatus {
+        NodeStatus {
+            version: SOFTWARE_VERSION.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            chain,
+            mining: activity.is_mining(),
+            auto_mine: activity.is_auto_mining(),
+            started_at: activity.started_at,
+            uptime_secs: activity.uptime(),
+        }
+    }
+}
+
+impl Application {
+    // Endpoint GET /v1/status
+    pub(crate) async fn handle_v1_status(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        activity: web::Data<NodeActivity>,
+    ) -> HttpResponse {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        let status = NodeStatus::new(ChainStatus::of(&blockchain), &activity);
+        respond(StatusCode::OK, status)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::{Received, Transaction};
+    use crate::modules::blockchain::genesis::Network;
+
+    #[test]
+    fn sync_progress_follows_blocks_announced_by_peers() {
+        let genesis = Network::Devnet.genesis();
+        let mut ahead = Blockchain::from_genesis(&genesis).unwrap();
+        for i in 0..4 {
+            ahead.add_new_transaction(Transaction {
+                author: "author".to_string(),
+                content: format!("post {}", i),
+                timestamp: 1_700_000_000 + i,
+            });
+            ahead.mine_block().unwrap();
+        }
+        let mut behind = Blockchain::from_genesis(&genesis).unwrap();
+        behind
+            .receive_block("peer", ahead.chain[1].clone())
+            .unwrap();
+        assert!(ChainStatus::of(&behind).sync.synced);
+
+        // A block we cannot link yet still tells us how far ahead the peer is
+        assert_eq!(
+            behind
+                .receive_block("peer", ahead.chain[4].clone())
+                .unwrap(),
+            Received::Orphan(ahead.chain[3].hash.clone())
+        );
+        let sync = ChainStatus::of(&behind).sync;
+        assert_eq!(sync.best_peer_height, 4);
+        assert_eq!(sync.progress, 0.25);
+        assert!(!sync.synced);
+
+        // Blocks without the work are not believed
+        let mut forged = ahead.chain[4].clone();
+        forged.index = 100;
+        let _ = behind.receive_block("peer", forged);
+        assert_eq!(ChainStatus::of(&behind).sync.best_peer_height, 4);
+    }
+}
@@ -1,255 +1,1091 @@
+use super::api::{
+    admin::AdminAccess,
+    openapi::{openapi_document, Audience, Endpoint},
+    status::NodeStatus,
+    ApiError,
+};
 use super::blockchain::{
     block::Block,
-    chain::{Blockchain, NodePeer, Transaction},
+    chain::{
+        Blockchain, MiningCancelled, NodePeer, Received, Transaction, CHAIN_FILE, MEMPOOL_FILE,
+        PEERS_FILE,
+    },
+    events::EventBus,
+    genesis::GenesisSpec,
+};
+use super::clock::{SharedClock, SharedRng};
+use super::logging;
+use super::metrics::{NodeMetrics, CONSENSUS_FAILED};
+use super::network::auth::{AuthError, NodeAuth, NodeSignature};
+use super::network::discovery::{run_discovery, DiscoveryConfig};
+use super::network::handshake::{register_with, Handshake, HandshakeError, Registration};
+use super::network::scoring::{
+    resolve_peer_key, socket_key, BanPolicy, Offence, PeerScores, BAN_FILE,
+};
+use super::network::transport::{Transport, DEFAULT_P2P_ADDRESS};
+use super::webhooks::Webhooks;
+use actix_web::{
+    dev::Server, dev::Service, http::Method, http::StatusCode, web, App, HttpRequest, HttpResponse,
+    HttpServer, Resource, Responder,
 };
-use actix_web::{dev::Server, web, App, HttpRequest, HttpResponse, HttpServer, Responder};
-use serde_json::{from_value, json, to_value, Map, Value};
-use std::{error::Error, io, sync::Mutex};
+use futures_util::StreamExt;
+use serde::de::DeserializeOwned;
+use serde_json::{json, to_value, Value};
+use std::{
+    error::Error,
+    io, net,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex, OnceLock,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{error, info, info_span, warn, Instrument, Span};
+
+// Default address the node API listens on.
+pub const DEFAULT_NODE_ADDRESS: &str = "127.0.0.1:8080";
+// Default directory the node keeps its chain, key and ban list in.
+pub const DEFAULT_DATA_DIR: &str = "data";
+// Maximum accepted body size on peer endpoints; larger payloads count as an offence.
+const MAX_PEER_PAYLOAD_BYTES: usize = 4 * 1024 * 1024;
+
+// NodeOptions are the settings a node is started with.
+#[derive(Debug, Clone)]
+pub struct NodeOptions {
+    // Directory holding the chain, node key and ban list.
+    pub data_dir: PathBuf,
+    // Address the node API listens on.
+    pub node_address: String,
+    // Address the peer-to-peer transport listens on.
+    pub p2p_address: String,
+    // Genesis of the network the node runs; an existing chain must have been created from it.
+    pub genesis: GenesisSpec,
+    // Clock the node timestamps blocks, transactions and signatures with.
+    pub clock: SharedClock,
+    // Source of the IDs and nonces the node draws.
+    pub rng: SharedRng,
+    // Token operators present to use the admin routes; unset serves them on loopback only.
+    pub admin_token: Option<String>,
+    // When misbehaving peers get banned, and for how long.
+    pub ban_policy: BanPolicy,
+}
+
+impl Default for NodeOptions {
+    fn default() -> Self {
+        NodeOptions {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            node_address: DEFAULT_NODE_ADDRESS.to_string(),
+            p2p_address: DEFAULT_P2P_ADDRESS.to_string(),
+            genesis: GenesisSpec::default(),
+            clock: SharedClock::default(),
+            rng: SharedRng::default(),
+            admin_token: None,
+            ban_policy: BanPolicy::default(),
+        }
+    }
+}
 
+// NodeActivity is what the node is doing, kept apart from the blockchain so that reading it does
+// not wait for the lock on the blockchain.
+#[derive(Debug)]
+pub struct NodeActivity {
+    clock: SharedClock,
+    pub started_at: i64,
+    auto_mine: AtomicBool,
+    // Whether a proof of work is being searched for.
+    mining: AtomicBool,
+}
+
+impl NodeActivity {
+    pub fn new(clock: SharedClock) -> NodeActivity {
+        NodeActivity {
+            started_at: clock.now(),
+            clock,
+            auto_mine: AtomicBool::new(false),
+            mining: AtomicBool::new(false),
+        }
+    }
+
+    // Number of seconds since the node started.
+    pub fn uptime(&self) -> i64 {
+        self.clock.now() - self.started_at
+    }
+
+    pub fn is_auto_mining(&self) -> bool {
+        self.auto_mine.load(Ordering::SeqCst)
+    }
+
+    pub fn is_mining(&self) -> bool {
+        self.mining.load(Ordering::SeqCst)
+    }
+
+    fn set_mining(&self, mining: bool) {
+        self.mining.store(mining, Ordering::SeqCst);
+    }
+}
+
+#[derive(Clone)]
 pub struct Application {
-    pub blockchain: Mutex<Blockchain>,
+    pub blockchain: web::Data<Mutex<Blockchain>>,
+    pub transport: Arc<Transport>,
+    pub auth: Arc<NodeAuth>,
+    pub data_dir: PathBuf,
+    pub activity: web::Data<NodeActivity>,
+    // Shared with the blockchain so events can be subscribed to without locking it.
+    pub events: EventBus,
+    pub webhooks: Arc<Webhooks>,
+    pub admin: web::Data<AdminAccess>,
+    // Shared with the blockchain so metrics can be scraped without locking it.
+    pub metrics: NodeMetrics,
+    // Shared with the blockchain so mining can be cancelled without locking it.
+    cancel_mining: Arc<AtomicBool>,
+    // Source of the IDs of requests that come without one.
+    rng: SharedRng,
 }
 
 impl Application {
-    // Create a new blockchain application.
-    pub fn new() -> Result<Application, Box<dyn Error>> {
-        let blockchain = Blockchain::new_blockchain()?;
+    // Create a new blockchain application whose peer messages are signed and checked by auth.
+    // The node API and peer-to-peer transport addresses are advertised to peers.
+    pub fn new(auth: NodeAuth, options: &NodeOptions) -> Result<Application, Box<dyn Error>> {
+        // Resume from the chain written on the last shutdown, if any
+        let mut blockchain = load_chain(&options.data_dir, Some(&options.genesis))?;
+        blockchain.peer_scores =
+            PeerScores::load(&options.data_dir.join(BAN_FILE), options.ban_policy.clone())?;
+        blockchain.set_clock(options.clock.clone());
+        // Pick up the pending transactions and peers we had when we last stopped
+        blockchain.load_mempool(&options.data_dir.join(MEMPOOL_FILE))?;
+        blockchain.load_peers(&options.data_dir.join(PEERS_FILE))?;
+        let cancel_mining = blockchain.cancel_mining.clone();
+        let events = blockchain.events.clone();
+        let metrics = blockchain.metrics.clone();
+        let webhooks = Webhooks::load(
+            &options.data_dir,
+            options.clock.clone(),
+            options.rng.clone(),
+        )?;
+        let blockchain = web::Data::new(Mutex::new(blockchain));
+        let auth = Arc::new(auth.with_clock(options.clock.clone()));
+        let transport = Arc::new(
+            Transport::new(
+                blockchain.clone(),
+                auth.clone(),
+                &options.p2p_address,
+                &format!("http://{}", options.node_address),
+            )
+            .with_rng(options.rng.clone()),
+        );
         Ok(Application {
-            blockchain: Mutex::new(blockchain),
+            blockchain,
+            transport,
+            auth,
+            data_dir: options.data_dir.clone(),
+            activity: web::Data::new(NodeActivity::new(options.clock.clone())),
+            events,
+            webhooks: Arc::new(webhooks),
+            admin: web::Data::new(AdminAccess::new(options.admin_token.clone())),
+            metrics,
+            cancel_mining,
+            rng: options.rng.clone(),
         })
     }
+    // Span the work of this node is logged in, telling apart the nodes of a devnet.
+    pub fn span(&self) -> Span {
+        info_span!("node", address = self.transport.http_address())
+    }
+    // Stop mining in progress and keep the miner from starting new blocks. Pending
+    // transactions stay in the mempool.
+    pub fn cancel_mining(&self) {
+        self.cancel_mining.store(true, Ordering::SeqCst);
+    }
+    // Flush node state to disk, before the node shuts down.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        self.webhooks.flush()?;
+        // A handler that panicked must not stop us from saving what we have
+        let blockchain = self
+            .blockchain
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner());
+        blockchain.save_to_file(&self.data_dir.join(CHAIN_FILE))?;
+        blockchain.save_mempool(&self.data_dir.join(MEMPOOL_FILE))?;
+        blockchain.save_peers(&self.data_dir.join(PEERS_FILE))?;
+        blockchain.peer_scores.save()
+    }
+    // Mine the pending transactions, run consensus and announce the resulting tip to peers.
+    // Returns the mined block, or None if there was nothing to mine. The lock on the blockchain
+    // is released while the proof of work is searched for and while peers are contacted.
+    pub async fn mine_and_announce(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        auth: &NodeAuth,
+        activity: &web::Data<NodeActivity>,
+    ) -> Result<Option<Block>, Box<dyn Error>> {
+        // Mine block on a snapshot of the tip, without holding the lock on the blockchain. If a
+        // block arrives from a peer meanwhile, the pending transactions are mined again on top.
+        let (mined_block, peers, chain_length, genesis) = loop {
+            let job = match blockchain.lock().unwrap().mining_job()? {
+                Some(job) => job,
+                None => return Ok(None),
+            };
+            // Proof of work keeps a thread busy, so it runs away from the threads that serve
+            // requests. Mining logs belong to the request or miner that asked for the block.
+            let span = Span::current();
+            let activity = activity.clone();
+            let solver =
+                tokio::task::spawn_blocking(move || -> Result<_, Box<dyn Error + Send + Sync>> {
+                    let _entered = span.enter();
+                    activity.set_mining(true);
+                    let solved = job.solve();
+                    activity.set_mining(false);
+                    match solved {
+                        Ok(block) => Ok(block),
+                        Err(e) if e.is::<MiningCancelled>() => Err(Box::new(MiningCancelled)),
+                        Err(e) => Err(e.to_string().into()),
+                    }
+                });
+            let block = solver.await?.map_err(|e| -> Box<dyn Error> { e })?;
+
+            let mut blockchain = blockchain.lock().unwrap();
+            if blockchain.submit_mined(block.clone())? {
+                break (
+                    block,
+                    blockchain.consensus_peers(),
+                    blockchain.chain.len(),
+                    blockchain.genesis.clone(),
+                );
+            }
+        };
+
+        // Persist chain with max length, without holding the lock while peers answer. The error
+        // is kept as a string so the miner task stays Send.
+        let round = Blockchain::fetch_longest_chain(transport, peers, chain_length, &genesis)
+            .await
+            .map_err(|e| e.to_string());
+        let (last_block, peers, metrics) = {
+            let mut blockchain = blockchain.lock().unwrap();
+            match round {
+                Ok(round) => {
+                    blockchain.apply_consensus(round);
+                }
+                Err(e) => {
+                    blockchain.metrics.consensus_run(CONSENSUS_FAILED);
+                    warn!(error = %e, "Failed to run consensus");
+                }
+            }
+            (
+                blockchain.get_last_block().clone(),
+                blockchain.consensus_peers(),
+                blockchain.metrics.clone(),
+            )
+        };
+        // Announce the new block over the transport, or over HTTP to peers not connected to it
+        if let Err(e) =
+            Blockchain::announce_new_block(transport, peers, &last_block, auth, &metrics).await
+        {
+            warn!(error = %e, "Failed to announce new block");
+        }
+        Ok(Some(mined_block))
+    }
     // Implementation of HandleMine
-    async fn handle_mine(blockchain: web::Data<Mutex<Blockchain>>) -> impl Responder {
-        let mut blockchain = blockchain.lock().unwrap();
-        // Mine block
-        let success = blockchain.mine_block().expect("Failed to mine block");
+    async fn handle_mine(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        activity: web::Data<NodeActivity>,
+    ) -> impl Responder {
+        let mined_block =
+            match Self::mine_and_announce(&blockchain, &transport, &auth, &activity).await {
+                Ok(mined_block) => mined_block,
+                Err(e) if e.is::<MiningCancelled>() => {
+                    return HttpResponse::ServiceUnavailable().body("Node is shutting down")
+                }
+                Err(e) => {
+                    return HttpResponse::InternalServerError()
+                        .body(format!("Failed to mine block: {}", e))
+                }
+            };
 
         // Define response default details
+        let chain_length = blockchain.lock().unwrap().chain.len();
         let mut mine_data = json!({
             "message": "",
-            "chain_length": blockchain.chain.len(),
+            "chain_length": chain_length,
             "transactions": Value::Null
         });
 
-        // If mine is successful, add length of transactions in block and do consensus and broadcast
-        if success {
-            // app.blockchain.Consensus(); // Persist chain with max length
-            blockchain
-                .consensus()
-                .await
-                .expect("Failed to run consensus!");
-            // app.blockchain.AnnounceNewBlock(); // Broadcast new block
-
-            // Add message and transactions in mined block to response data
-            mine_data["message"] = "New block mined".into();
+        // Add message and transactions in mined block to response data
+        match mined_block {
+            Some(block) => {
+                mine_data["message"] = "New block mined".into();
 
-            // Convert transactions to serde_json::Value
-            mine_data["transactions"] = to_value(
-                blockchain
-                    .chain
-                    .last()
-                    .expect("Failed to get last block of the chain")
-                    .transactions
-                    .clone(),
-            )
-            .expect("Failed to convert transactions to value");
-        } else {
-            mine_data["message"] = "No transaction to mine".into();
+                // Convert transactions to serde_json::Value
+                mine_data["transactions"] =
+                    to_value(block.transactions).expect("Failed to convert transactions to value");
+            }
+            None => mine_data["message"] = "No transaction to mine".into(),
         }
 
         // Forward response data as JSON
         HttpResponse::Ok().json(mine_data)
     }
+    // Key under which the peer that sent a request is scored: the node key it was signed with,
+    // else its socket address
+    fn request_peer(req: &HttpRequest, signer: Option<&str>) -> String {
+        match signer {
+            Some(signer) => signer.to_string(),
+            None => req.peer_addr().map(socket_key).unwrap_or_default(),
+        }
+    }
+    // Penalise the peer that sent a request, signed by signer if it was signed
+    fn penalise_request_peer(req: &HttpRequest, signer: Option<&str>, offence: Offence) {
+        if let Some(blockchain) = req.app_data::<web::Data<Mutex<Blockchain>>>() {
+            let mut blockchain = blockchain
+                .lock()
+                .expect("Unable to lock blockchain for update");
+            blockchain
+                .peer_scores
+                .penalise(&Self::request_peer(req, signer), offence);
+        }
+    }
+    // Read the body of a peer message, penalising peers that send oversized ones
+    async fn read_peer_body(
+        mut payload: web::Payload,
+        req: &HttpRequest,
+    ) -> Result<web::Bytes, HttpResponse> {
+        let mut body = web::BytesMut::new();
+        while let Some(chunk) = payload.next().await {
+            let chunk = chunk.map_err(|e| HttpResponse::BadRequest().body(e.to_string()))?;
+            if body.len() + chunk.len() > MAX_PEER_PAYLOAD_BYTES {
+                Self::penalise_request_peer(req, None, Offence::OversizedPayload);
+                return Err(HttpResponse::PayloadTooLarge().body("Payload too large"));
+            }
+            body.extend_from_slice(&chunk);
+        }
+        Ok(body.freeze())
+    }
+    // Read a peer message: verify the node signature over the body exactly as it was sent, then
+    // parse it, penalising peers that send unparsable JSON. Returns the message and the key of
+    // the signing node, if any.
+    async fn read_peer_message<T: DeserializeOwned>(
+        auth: &NodeAuth,
+        req: &HttpRequest,
+        payload: web::Payload,
+    ) -> Result<(T, Option<String>), HttpResponse> {
+        let body = Self::read_peer_body(payload, req).await?;
+        let signature = NodeSignature::from_headers(|name| req.headers().get(name)?.to_str().ok());
+        let signer = auth
+            .verify(req.path(), &body, signature)
+            .map_err(|e| match e {
+                AuthError::NotAllowed(_) => HttpResponse::Forbidden().body(e.to_string()),
+                _ => HttpResponse::Unauthorized().body(e.to_string()),
+            })?;
+        let message = serde_json::from_slice(&body).map_err(|e| {
+            Self::penalise_request_peer(req, signer.as_deref(), Offence::MalformedJson);
+            HttpResponse::BadRequest().body(e.to_string())
+        })?;
+        Ok((message, signer))
+    }
     // Implementation of HandleVerifyAndAddBlock
     async fn handle_verify_and_add_block(
         blockchain: web::Data<Mutex<Blockchain>>,
-        block: web::Json<Block>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        payload: web::Payload,
+        req: HttpRequest,
     ) -> HttpResponse {
-        // Extract the inner Block data from the web::Json wrapper
-        let block_data: Block = block.into_inner();
+        // Only accept blocks from authenticated (and, if configured, allowlisted) nodes
+        let (block_data, signer): (Block, _) =
+            match Self::read_peer_message(&auth, &req, payload).await {
+                Ok(message) => message,
+                Err(response) => return response,
+            };
 
         let mut blockchain = blockchain
             .lock()
             .expect("Unable to lock blockchain for update");
-        let result = blockchain.add_block(block_data);
-        if !result.is_ok() {
-            return HttpResponse::InternalServerError().body("Block not added");
+        let peer = Self::request_peer(&req, signer.as_deref());
+        if blockchain.peer_scores.is_banned(&peer) {
+            return HttpResponse::Forbidden().body("Peer is banned");
+        }
+        match blockchain.receive_block(&peer, block_data) {
+            Ok(Received::Added) => {}
+            Ok(Received::Known) => return HttpResponse::Ok().body("Block already known"),
+            // Blocks on another branch are kept until it connects to our chain and is longer
+            Ok(Received::Fork) | Ok(Received::Orphan(_)) => {
+                return HttpResponse::Accepted().body("Block kept on another branch")
+            }
+            Err(_) => return HttpResponse::InternalServerError().body("Block not added"),
         }
+        // Relay our new tip to peers connected over the transport
+        transport.announce_block(blockchain.get_last_block(), None);
         // Return an HTTP response
         HttpResponse::Created().body("Success")
     }
+    // Handshake describing this node, including its peer-to-peer transport and node key
+    fn local_handshake(
+        blockchain: &Blockchain,
+        transport: &Transport,
+        auth: &NodeAuth,
+        req: &HttpRequest,
+    ) -> Handshake {
+        Handshake::local(blockchain, &Self::own_address(req))
+            .with_p2p_address(Some(transport.listen_address.clone()))
+            .with_node_key(auth.public_key())
+    }
+    // Public address of this node as seen by the caller of a request
+    fn own_address(req: &HttpRequest) -> String {
+        let connection_info = req.connection_info();
+        format!("{}://{}", connection_info.scheme(), connection_info.host())
+    }
+    // Register with a remote node and sync with its chain if it is longer. Returns the peer
+    // that was added, or the error to answer with.
+    pub(crate) async fn register_with_node(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        auth: &NodeAuth,
+        req: &HttpRequest,
+        node_address: &str,
+    ) -> Result<NodePeer, ApiError> {
+        // handle empty node address
+        if node_address.is_empty() {
+            return Err(ApiError::new("invalid_request", "Invalid node data"));
+        }
+
+        // Prepare the handshake describing this node
+        let (local, genesis) = {
+            let blockchain = blockchain
+                .lock()
+                .expect("Unable to lock blockchain for read");
+            (
+                Self::local_handshake(&blockchain, transport, auth, req),
+                blockchain.genesis.clone(),
+            )
+        };
+
+        // Register with the remote node, refusing it if it is incompatible
+        let registration = register_with(&reqwest::Client::new(), node_address, &local, auth)
+            .await
+            .map_err(|e| {
+                let code = if e.is::<HandshakeError>() {
+                    "conflict"
+                } else if e.is::<AuthError>() {
+                    "forbidden"
+                } else {
+                    "peer_unreachable"
+                };
+                ApiError::new(code, e.to_string())
+            })?;
+
+        let synced_blockchain =
+            Blockchain::create_chain_from_dump(registration.chain, vec![], &genesis)
+                .map_err(|_| ApiError::new("internal", "Failed to create blockchain from dump"))?;
+        let key = match &registration.signer {
+            Some(signer) => signer.clone(),
+            None => resolve_peer_key(node_address).await,
+        };
+
+        let mut blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for update");
+        blockchain.adopt_chain(synced_blockchain.chain);
+        let peer = NodePeer {
+            node_address: node_address.to_string(),
+            handshake: Some(registration.handshake),
+            outbound: true,
+            key: Some(key),
+        };
+        blockchain.add_node_peer(peer.clone());
+        // Keep the remote node's peers around for discovery
+        blockchain.remember_addresses(registration.peers.into_iter().map(|peer| peer.node_address));
+        Ok(peer)
+    }
     // Endpoint /register_with handler function - registers node to list via synced node and syncs the calling node
     pub async fn handle_register_node_with(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
         node: web::Json<NodePeer>,
         req: HttpRequest,
     ) -> impl Responder {
         let node = node.into_inner();
-
-        // handle empty node address
-        if node.node_address.is_empty() {
-            return HttpResponse::BadRequest().body("Invalid node data");
-        }
-
-        // Prepare the request payload
-        let data = json!({
-            "node_address": req.connection_info().host(),
-        });
-        let payload = data.to_string();
-
-        // Make a request to register with the remote node
-        let client = reqwest::Client::new();
-        let response = match client
-            .post(format!("{}/register_node", node.node_address))
-            .header("Content-Type", "application/json")
-            .body(payload)
-            .send()
+        match Self::register_with_node(&blockchain, &transport, &auth, &req, &node.node_address)
             .await
         {
-            Ok(response) => response,
-            Err(_) => return HttpResponse::InternalServerError().body("Internal Server Error"),
-        };
-
-        let status = response.status();
-        if status == reqwest::StatusCode::OK {
-            let body = response.text().await.unwrap();
-            let parsed_response: serde_json::Map<String, Value> =
-                serde_json::from_str(&body).unwrap();
-
-            let chain = parsed_response
-                .get("chain")
-                .and_then(|c| c.as_array())
-                .map(|array| {
-                    array
-                        .iter()
-                        .filter_map(|value| from_value::<Map<String, Value>>(value.clone()).ok())
-                        .collect::<Vec<Map<String, Value>>>()
-                })
-                .expect("Unable to unbox received chain data");
-            let peers = parsed_response
-                .get("peers")
-                .and_then(|p| p.as_array())
-                .map(|array| {
-                    array
-                        .iter()
-                        .filter_map(|value| from_value::<String>(value.clone()).ok())
-                        .collect::<Vec<String>>()
-                })
-                .expect("Unable to unbox received peers");
-
-            match Blockchain::create_chain_from_dump(chain, peers) {
-                Ok(_) => return HttpResponse::Ok().body("Registration successful"),
-                Err(_) => {
-                    return HttpResponse::InternalServerError()
-                        .body("Failed to create blockchain from dump")
-                }
-            };
-        } else {
-            let body = response.text().await.unwrap();
-            HttpResponse::build(status).body(body)
+            Ok(_) => HttpResponse::Ok().body("Registration successful"),
+            Err(e) => HttpResponse::build(e.status()).body(e.message),
         }
     }
 
-    // Endpoint /register_node handler - adds node peer to list
+    // Endpoint /register_node handler - checks the peer handshake and adds node peer to list
     pub async fn handle_register_node(
         blockchain: web::Data<Mutex<Blockchain>>,
-        req: web::Json<NodePeer>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        payload: web::Payload,
+        req: HttpRequest,
     ) -> impl Responder {
-        let node = req.into_inner();
+        // Only accept registrations from authenticated (and, if configured, allowlisted) nodes,
+        // and make sure the handshake advertises the key it was signed with
+        let (handshake, signer) =
+            match Self::read_peer_message::<Handshake>(&auth, &req, payload).await {
+                Ok((handshake, Some(signer))) if handshake.node_key.as_ref() != Some(&signer) => {
+                    return HttpResponse::Unauthorized()
+                        .body("Handshake node key does not match signer")
+                }
+                Ok(message) => message,
+                Err(response) => return response,
+            };
+        let peer = Self::request_peer(&req, signer.as_deref());
 
         // Check and prevent empty node_address
-        if node.node_address.is_empty() {
+        if handshake.node_address.is_empty() {
             return HttpResponse::BadRequest().body("Invalid node data");
         }
         let mut blockchain = blockchain
             .lock()
             .expect("Unable to lock blockchain data for update");
+        // Refuse registration from banned peers
+        if blockchain.peer_scores.is_banned(&peer) {
+            return HttpResponse::Forbidden().body("Peer is banned");
+        }
+        // Refuse peers that run an incompatible protocol or network
+        let local = Self::local_handshake(&blockchain, &transport, &auth, &req);
+        if let Err(e) = handshake.check_compatible(&local) {
+            return HttpResponse::Conflict().body(e.to_string());
+        }
         // Add peer to list
-        blockchain.add_node_peer(node);
+        blockchain.add_node_peer(NodePeer {
+            node_address: handshake.node_address.clone(),
+            handshake: Some(handshake),
+            outbound: false,
+            key: Some(peer),
+        });
 
         let response_data = json!({
+            "handshake": local,
             "chain": blockchain.chain,
             "peers": blockchain.peers,
         });
 
+        // Sign the reply so the registering node can authenticate us
         let response_str = serde_json::to_string(&response_data).unwrap();
-        HttpResponse::Created().body(response_str)
+        let mut response = HttpResponse::Created();
+        for header in auth.sign(req.path(), response_str.as_bytes()).headers() {
+            response.insert_header(header);
+        }
+        response.body(response_str)
+    }
+    // Endpoint /handshake handler - describes this node so peers can check compatibility
+    pub async fn handle_get_handshake(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
+        auth: web::Data<NodeAuth>,
+        req: HttpRequest,
+    ) -> impl Responder {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        HttpResponse::Ok().json(Self::local_handshake(&blockchain, &transport, &auth, &req))
+    }
+    // Endpoint /peers handler - lists the addresses of known peers for discovery
+    pub async fn handle_get_peers(blockchain: web::Data<Mutex<Blockchain>>) -> impl Responder {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        let addresses: Vec<&str> = blockchain
+            .peers
+            .iter()
+            .map(|peer| peer.node_address.as_str())
+            .collect();
+        HttpResponse::Ok().json(addresses)
     }
     pub async fn handle_get_pending_transactions(
         blockchain: web::Data<Mutex<Blockchain>>,
     ) -> impl Responder {
         let blockchain = blockchain
+            .lock()
+            .expect("Unable to block blockchain for read");
+        HttpResponse::Ok().json(&blockchain.unconfirmed_transactions)
+    }
+    // Timestamp a transaction submitted by a client, add it to the pending transactions and
+    // push it to peers. Returns the transaction, or why it was refused.
+    pub(crate) fn accept_transaction(
+        blockchain: &web::Data<Mutex<Blockchain>>,
+        transport: &Transport,
+        author: String,
+        content: String,
+    ) -> Result<Transaction, String> {
+        let mut blockchain = blockchain
             .lock()
             .expect("Unable to block blockchain for update");
+        let transaction = Transaction {
+            author,
+            content,
+            timestamp: blockchain.clock.now(),
+        };
 
-        let response_json =
-            serde_json::to_string(&blockchain.unconfirmed_transactions).map_err(|e| {
-                eprintln!("Error marshaling pending transaction data: {}", e);
-                HttpResponse::InternalServerError()
-            });
-
-        match response_json {
-            Ok(json) => HttpResponse::Ok().json(json),
-            Err(response) => response.into(),
-        }
+        // Validate the transaction, add it to pending tx (unconfirmed transactions) and push it
+        // to peers
+        blockchain
+            .accept_transaction(transaction.clone())
+            .map_err(|e| e.to_string())?;
+        transport.announce_transaction(&transaction, None);
+        Ok(transaction)
     }
     pub async fn handle_new_transaction(
         transaction: web::Json<Transaction>,
         blockchain: web::Data<Mutex<Blockchain>>,
+        transport: web::Data<Transport>,
     ) -> impl Responder {
-        // Lock the Mutex to gain access to the Arc
-        let mut arc_blockchain = blockchain
-            .lock()
-            .expect("Unable to block blockchain for update");
-        let mut transaction_data = transaction.into_inner();
-
-        // Validate transaction details
-        if transaction_data.author.is_empty() || transaction_data.content.is_empty() {
-            return HttpResponse::BadRequest().body("Invalid transaction data");
+        let Transaction {
+            author, content, ..
+        } = transaction.into_inner();
+        match Self::accept_transaction(&blockchain, &transport, author, content) {
+            Ok(_) => HttpResponse::Created().body("Success"),
+            Err(message) => HttpResponse::BadRequest().body(message),
         }
-        let current_timestamp = chrono::Utc::now().timestamp();
-        transaction_data.timestamp = current_timestamp;
-
-        // Add new tx to pending tx (unconfirmed transactions)
-        arc_blockchain.add_new_transaction(transaction_data);
-
-        HttpResponse::Created().body("Success")
     }
-    pub async fn get_chain(blchain: web::Data<Blockchain>) -> impl Responder {
-        HttpResponse::Ok().json(blchain.chain.clone())
+    pub async fn get_chain(blockchain: web::Data<Mutex<Blockchain>>) -> impl Responder {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        HttpResponse::Ok().json(&blockchain.chain)
     }
 
-    fn config(cfg: &mut web::ServiceConfig) {
-        let app = Application::new().expect("Failed to initialize application");
-
-        cfg.app_data(app.blockchain)
-            .service(web::resource("/mine").route(web::get().to(Self::handle_mine)))
-            .service(
-                web::resource("/add_block")
-                    .route(web::post().to(Self::handle_verify_and_add_block)),
+    // Endpoints of the routes that predate /v1 and of the routes other nodes call.
+    fn legacy_endpoints() -> Vec<Endpoint> {
+        use Audience::{Legacy, Peer};
+        vec![
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/mine",
+                "Mine the pending transactions",
+                Self::handle_mine,
+            )
+            .json::<Value>(
+                StatusCode::OK,
+                "Message, chain_length and the transactions of the mined block",
+            )
+            .text(StatusCode::SERVICE_UNAVAILABLE, "Node is shutting down"),
+            Endpoint::new(
+                Peer,
+                Method::POST,
+                "/add_block",
+                "Add a block mined by a peer",
+                Self::handle_verify_and_add_block,
             )
-            .service(web::resource("/chains").route(web::get().to(Self::get_chain)))
-            .service(
-                web::resource("/new_transaction")
-                    .route(web::post().to(Self::handle_new_transaction)),
+            .request::<Block>()
+            .text(StatusCode::CREATED, "Block added")
+            .text(StatusCode::OK, "Block already known")
+            .text(StatusCode::ACCEPTED, "Block kept on another branch")
+            .text(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid node signature",
             )
-            .service(
-                web::resource("/mempool")
-                    .route(web::get().to(Self::handle_get_pending_transactions)),
+            .text(StatusCode::FORBIDDEN, "Peer is banned or not allowed")
+            .text(StatusCode::BAD_REQUEST, "Block is not valid JSON")
+            .text(StatusCode::PAYLOAD_TOO_LARGE, "Block is too large")
+            .text(StatusCode::INTERNAL_SERVER_ERROR, "Block not added"),
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/chains",
+                "Every block of the chain",
+                Self::get_chain,
             )
-            .service(
-                web::resource("/register_with")
-                    .route(web::post().to(Self::handle_register_node_with)),
+            .json::<Vec<Block>>(StatusCode::OK, "Blocks of the chain"),
+            Endpoint::new(
+                Legacy,
+                Method::POST,
+                "/new_transaction",
+                "Submit a transaction to the mempool; its timestamp is set by the node",
+                Self::handle_new_transaction,
+            )
+            .text(StatusCode::CREATED, "Transaction added")
+            .text(StatusCode::BAD_REQUEST, "Invalid transaction data"),
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/mempool",
+                "Pending transactions",
+                Self::handle_get_pending_transactions,
+            )
+            .json::<Vec<Transaction>>(StatusCode::OK, "Pending transactions"),
+            Endpoint::new(
+                Legacy,
+                Method::POST,
+                "/register_with",
+                "Register with a node and sync with its chain",
+                Self::handle_register_node_with,
+            )
+            .text(StatusCode::OK, "Registration successful")
+            .text(StatusCode::CONFLICT, "Node is incompatible")
+            .text(StatusCode::BAD_GATEWAY, "Node could not be reached"),
+            Endpoint::new(
+                Peer,
+                Method::POST,
+                "/register_node",
+                "Register a peer presenting its handshake",
+                Self::handle_register_node,
+            )
+            .request::<Handshake>()
+            .json::<Registration>(
+                StatusCode::CREATED,
+                "Our handshake, chain and peers, signed with our node key",
+            )
+            .text(
+                StatusCode::UNAUTHORIZED,
+                "Missing or invalid node signature",
+            )
+            .text(StatusCode::FORBIDDEN, "Peer is banned or not allowed")
+            .text(StatusCode::CONFLICT, "Peer is incompatible")
+            .text(
+                StatusCode::BAD_REQUEST,
+                "Handshake is not valid JSON or has no node address",
+            )
+            .text(StatusCode::PAYLOAD_TOO_LARGE, "Handshake is too large"),
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/handshake",
+                "Handshake describing this node",
+                Self::handle_get_handshake,
+            )
+            .json::<Handshake>(StatusCode::OK, "Handshake"),
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/peers",
+                "Addresses of the peers, for discovery",
+                Self::handle_get_peers,
+            )
+            .json::<Vec<String>>(StatusCode::OK, "Peer addresses"),
+            Endpoint::new(
+                Legacy,
+                Method::GET,
+                "/status",
+                "Same as /v1/status",
+                Self::handle_v1_status,
+            )
+            .data::<NodeStatus>(StatusCode::OK, "Node status"),
+        ]
+    }
+
+    // Every endpoint of the node, in the order routes are matched.
+    pub fn endpoints() -> Vec<Endpoint> {
+        let mut endpoints = Self::legacy_endpoints();
+        endpoints.extend(Self::v1_endpoints());
+        endpoints.extend(Self::event_endpoints());
+        endpoints.extend(Self::admin_endpoints());
+        endpoints.push(Self::rpc_endpoint());
+        endpoints.extend(Self::graphql_endpoints());
+        endpoints.push(Self::metrics_endpoint());
+        endpoints
+    }
+
+    // OpenAPI document of every route of the node, generated from its endpoints.
+    pub fn openapi() -> &'static Value {
+        static OPENAPI: OnceLock<Value> = OnceLock::new();
+        OPENAPI.get_or_init(|| openapi_document(&Self::endpoints()))
+    }
+
+    pub(crate) fn config(&self, cfg: &mut web::ServiceConfig) {
+        cfg.app_data(self.blockchain.clone())
+            .app_data(web::Data::from(self.transport.clone()))
+            .app_data(web::Data::from(self.auth.clone()))
+            .app_data(self.activity.clone())
+            .app_data(web::Data::new(self.events.clone()))
+            .app_data(web::Data::from(self.webhooks.clone()))
+            .app_data(self.admin.clone())
+            .app_data(web::Data::new(self.metrics.clone()))
+            .app_data(web::Data::new(Self::graphql_schema(
+                &self.blockchain,
+                &self.events,
+            )));
+
+        // Routes of the same path share a resource, configured for the audience of the first
+        let mut resources: Vec<(&str, Resource)> = Vec::new();
+        for endpoint in Self::endpoints() {
+            let position = match resources
+                .iter()
+                .position(|(path, _)| *path == endpoint.path)
+            {
+                Some(position) => position,
+                None => {
+                    let resource = match endpoint.audience {
+                        Audience::Client | Audience::Admin => Self::v1_resource(endpoint.path),
+                        Audience::Peer
+                        | Audience::Legacy
+                        | Audience::Rpc
+                        | Audience::Graphql
+                        | Audience::Monitoring => web::resource(endpoint.path),
+                    };
+                    resources.push((endpoint.path, resource));
+                    resources.len() - 1
+                }
+            };
+            let (path, resource) = resources.remove(position);
+            resources.insert(position, (path, resource.route(endpoint.route)));
+        }
+        // Requests are timed by the route they matched, and handled in a span carrying their ID,
+        // which is sent back in the response
+        let node_span = self.span();
+        for (path, resource) in resources {
+            let metrics = self.metrics.clone();
+            let node_span = node_span.clone();
+            let rng = self.rng.clone();
+            cfg.service(resource.wrap_fn(move |req, service| {
+                let started = Instant::now();
+                let method = req.method().clone();
+                let request_id = logging::request_id(&req, &rng);
+                let span = info_span!(
+                    parent: &node_span,
+                    "http_request",
+                    request_id = request_id.to_str().unwrap_or_default(),
+                    method = %method,
+                    route = path,
+                );
+                let answer = span.in_scope(|| service.call(req));
+                let metrics = metrics.clone();
+                async move {
+                    let mut res = match answer.await {
+                        Ok(res) => res,
+                        Err(e) => {
+                            warn!(error = %e, "Request failed");
+                            return Err(e);
+                        }
+                    };
+                    let status = res.status().as_u16();
+                    let elapsed = started.elapsed();
+                    metrics.http_request(method.as_str(), path, status, elapsed);
+                    info!(
+                        status,
+                        elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+                        "Request answered"
+                    );
+                    res.headers_mut()
+                        .insert(logging::REQUEST_ID_HEADER, request_id);
+                    Ok(res)
+                }
+                .instrument(span)
+            }));
+        }
+    }
+}
+
+// Load the chain kept in a data directory, or create one from the given genesis if there is
+// none yet. An existing chain must have been created from the given genesis, if any.
+pub fn load_chain(
+    data_dir: &Path,
+    genesis: Option<&GenesisSpec>,
+) -> Result<Blockchain, Box<dyn Error>> {
+    let dump = data_dir.join(CHAIN_FILE);
+    if !dump.exists() {
+        return Blockchain::from_genesis(&genesis.cloned().unwrap_or_default());
+    }
+    let blockchain = Blockchain::create_chain_from_file(&dump)?;
+    match genesis {
+        Some(genesis) if *genesis == blockchain.genesis => Ok(blockchain),
+        // The genesis block does not cover the consensus parameters, so a chain created with
+        // other ones has the same genesis hash and would otherwise be reported as on a network
+        // identical to ours
+        Some(genesis)
+            if genesis.chain_id == blockchain.genesis.chain_id
+                && genesis.hash()? == blockchain.genesis_hash() =>
+        {
+            Err(format!(
+                "Chain in {} was created with difficulty {} and cannot be run with difficulty {}; \
+                 remove the difficulty override or use another data directory",
+                dump.display(),
+                blockchain.genesis.consensus.difficulty,
+                genesis.consensus.difficulty
             )
-            .service(
-                web::resource("/register_node").route(web::post().to(Self::handle_register_node)),
-            );
+            .into())
+        }
+        Some(genesis) => Err(format!(
+            "Chain in {} belongs to network {} (genesis {}), not {} (genesis {})",
+            dump.display(),
+            blockchain.genesis.chain_id,
+            blockchain.genesis_hash(),
+            genesis.chain_id,
+            genesis.hash()?
+        )
+        .into()),
+        None => Ok(blockchain),
     }
 }
 
+// NodeListeners are the sockets of a node. They are bound before the node is created, so that
+// ports picked by the OS for port 0 are known when the node advertises its addresses.
+pub struct NodeListeners {
+    pub http: net::TcpListener,
+    pub p2p: net::TcpListener,
+}
+
+impl NodeListeners {
+    // Bind the node API and peer-to-peer sockets, and update the options with the ports picked
+    // by the OS for addresses with port 0.
+    pub fn bind(options: &mut NodeOptions) -> io::Result<NodeListeners> {
+        let http = net::TcpListener::bind(&options.node_address)?;
+        let p2p = net::TcpListener::bind(&options.p2p_address)?;
+        options.node_address = bound_address(&options.node_address, &http)?;
+        options.p2p_address = bound_address(&options.p2p_address, &p2p)?;
+        Ok(NodeListeners { http, p2p })
+    }
+}
+
+// Address to advertise for a socket bound to the configured address: the configured address,
+// with the port picked by the OS if port 0 was asked for.
+fn bound_address(configured: &str, listener: &net::TcpListener) -> io::Result<String> {
+    let port = listener.local_addr()?.port();
+    Ok(match configured.rsplit_once(':') {
+        Some((host, "0")) => format!("{}:{}", host, port),
+        _ => configured.to_string(),
+    })
+}
+
+// Mine the pending transactions at a fixed interval in the background, as /mine would.
+pub fn spawn_miner(app: &Application, interval: Duration) {
+    let app = app.clone();
+    app.activity.auto_mine.store(true, Ordering::SeqCst);
+    tokio::spawn(async move {
+        let mut interval = tokio::time::interval(interval);
+        loop {
+            interval.tick().await;
+            if app.cancel_mining.load(Ordering::SeqCst) {
+                break;
+            }
+            if let Err(e) = Application::mine_and_announce(
+                &app.blockchain,
+                &app.transport,
+                &app.auth,
+                &app.activity,
+            )
+            .instrument(info_span!(parent: &app.span(), "miner"))
+            .await
+            {
+                error!(error = %e, "Failed to mine block");
+            }
+        }
+    });
+}
+
 // Define a function to start the Actix-web server
-pub async fn start_node() -> Server {
-    HttpServer::new(move || App::new().configure(Application::config))
-        .bind("127.0.0.1:8080")
-        .unwrap()
-        .run()
+// Start the node on its listeners: the peer-to-peer transport and peer discovery run in the
+// background and the HTTP API is served. The returned server must be awaited to serve requests.
+pub async fn start_node(
+    app: &Application,
+    listeners: NodeListeners,
+    discovery: DiscoveryConfig,
+) -> io::Result<Server> {
+    // Serve the peer-to-peer transport alongside the HTTP API
+    listeners.p2p.set_nonblocking(true)?;
+    let listener = tokio::net::TcpListener::from_std(listeners.p2p)?;
+    tokio::spawn(app.transport.clone().serve(listener).instrument(app.span()));
+
+    // Keep looking for peers in the background, advertising the addresses we listen on
+    let discovery = DiscoveryConfig {
+        own_address: app.transport.http_address().to_string(),
+        p2p_address: Some(app.transport.listen_address.clone()),
+        ..discovery
+    };
+    tokio::spawn(
+        run_discovery(app.blockchain.clone(), app.auth.clone(), discovery).instrument(app.span()),
+    );
+
+    // Deliver chain events to the webhooks operators registered
+    tokio::spawn(
+        app.webhooks
+            .clone()
+            .run(app.events.subscribe(), app.auth.clone())
+            .instrument(app.span()),
+    );
+
+    // Signals are handled by the caller, so that it can flush state on shutdown
+    let app = app.clone();
+    Ok(HttpServer::new(move || {
+        let app = app.clone();
+        App::new().configure(move |cfg| app.config(cfg))
+    })
+    .disable_signals()
+    .listen(listeners.http)?
+    .run())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::test as web_test;
+
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::network::auth::NodeIdentity;
+
+    #[test]
+    fn chains_are_only_loaded_with_the_rules_they_were_created_with() {
+        let data_dir = std::env::temp_dir().join(format!("versus-load-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let devnet = Network::Devnet.genesis();
+        Blockchain::from_genesis(&devnet)
+            .unwrap()
+            .save_to_file(&data_dir.join(CHAIN_FILE))
+            .unwrap();
+
+        assert!(load_chain(&data_dir, Some(&devnet)).is_ok());
+        assert!(load_chain(&data_dir, None).is_ok());
+        let mut harder = devnet.clone();
+        harder.consensus.difficulty += 1;
+        let error = load_chain(&data_dir, Some(&harder))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("created with difficulty 1"), "{}", error);
+        assert!(error.contains("difficulty 2"), "{}", error);
+        let error = load_chain(&data_dir, Some(&Network::Testnet.genesis()))
+            .unwrap_err()
+            .to_string();
+        assert!(error.contains("not ultra-testnet"), "{}", error);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn peer_messages_are_checked_against_the_bytes_sent() {
+        let data_dir = std::env::temp_dir().join(format!("versus-app-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let options = NodeOptions {
+            data_dir: data_dir.clone(),
+            genesis: Network::Devnet.genesis(),
+            ..NodeOptions::default()
+        };
+        let app =
+            Application::new(NodeAuth::new(NodeIdentity::generate(), None), &options).unwrap();
+        let service = web_test::init_service(App::new().configure(|cfg| app.config(cfg))).await;
+
+        let mut miner = Blockchain::from_genesis(&options.genesis).unwrap();
+        miner.add_new_transaction(Transaction {
+            author: "alice".to_string(),
+            content: "post".to_string(),
+            timestamp: 1_700_000_000,
+        });
+        miner.mine_block().unwrap();
+        let block = miner.get_last_block().clone();
+        let peer = NodeAuth::new(NodeIdentity::generate(), None);
+        let post = |body: String, signed: &[u8]| {
+            let mut request = web_test::TestRequest::post()
+                .uri("/add_block")
+                .insert_header(("Content-Type", "application/json"));
+            for header in peer.sign("/add_block", signed).headers() {
+                request = request.insert_header(header);
+            }
+            web_test::call_service(&service, request.set_payload(body).to_request())
+        };
+
+        // A body laid out differently from how we would write it is checked as it was sent
+        let body = serde_json::to_string_pretty(&block).unwrap();
+        let tampered = body.replace("alice", "mallory");
+        let response = post(tampered, body.as_bytes()).await;
+        assert_eq!(response.status(), StatusCode::UNAUTHORIZED);
+        let response = post(body.clone(), body.as_bytes()).await;
+        assert_eq!(response.status(), StatusCode::CREATED);
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
 }
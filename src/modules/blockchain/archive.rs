@@ -0,0 +1,220 @@
+use std::error::Error;
+use std::fmt;
+use std::io::{self, BufRead, Write};
+use std::path::Path;
+use std::str::FromStr;
+
+use super::block::Block;
+use crate::modules::network::wire::MAX_FRAME_BYTES;
+
+// Magic bytes a binary archive starts with, which also tells it apart from JSON Lines.
+const BINARY_MAGIC: &[u8; 8] = b"VERSUSB1";
+
+// ArchiveFormat is how exported blocks are laid out: JSON Lines holds one block per line, and
+// the binary format holds the magic bytes then one frame per block, as on the wire: a 4-byte
+// big-endian length followed by the bincode encoding of the block.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Jsonl,
+    Binary,
+}
+
+impl fmt::Display for ArchiveFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ArchiveFormat::Jsonl => write!(f, "jsonl"),
+            ArchiveFormat::Binary => write!(f, "binary"),
+        }
+    }
+}
+
+impl FromStr for ArchiveFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "jsonl" => Ok(ArchiveFormat::Jsonl),
+            "binary" => Ok(ArchiveFormat::Binary),
+            _ => Err(format!("Unknown archive format {}", name)),
+        }
+    }
+}
+
+impl ArchiveFormat {
+    // Format matching the extension of a file: .bin files are binary, anything else JSON Lines.
+    pub fn from_path(path: &Path) -> ArchiveFormat {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some("bin") => ArchiveFormat::Binary,
+            _ => ArchiveFormat::Jsonl,
+        }
+    }
+}
+
+// BlockWriter writes blocks to an archive one at a time.
+pub struct BlockWriter<W: Write> {
+    writer: W,
+    format: ArchiveFormat,
+    written: usize,
+}
+
+impl<W: Write> BlockWriter<W> {
+    pub fn new(mut writer: W, format: ArchiveFormat) -> io::Result<BlockWriter<W>> {
+        if format == ArchiveFormat::Binary {
+            writer.write_all(BINARY_MAGIC)?;
+        }
+        Ok(BlockWriter {
+            writer,
+            format,
+            written: 0,
+        })
+    }
+
+    pub fn write(&mut self, block: &Block) -> Result<(), Box<dyn Error>> {
+        match self.format {
+            ArchiveFormat::Jsonl => {
+                serde_json::to_writer(&mut self.writer, block)?;
+                self.writer.write_all(b"\n")?;
+            }
+            ArchiveFormat::Binary => {
+                let bytes = bincode::serialize(block)?;
+                self.writer.write_all(&(bytes.len() as u32).to_be_bytes())?;
+                self.writer.write_all(&bytes)?;
+            }
+        }
+        self.written += 1;
+        Ok(())
+    }
+
+    // Flush the archive and return the number of blocks written.
+    pub fn finish(mut self) -> io::Result<usize> {
+        self.writer.flush()?;
+        Ok(self.written)
+    }
+}
+
+// BlockReader reads the blocks of an archive one at a time, in either format.
+pub struct BlockReader<R: BufRead> {
+    reader: R,
+    format: ArchiveFormat,
+    // Number of blocks read so far, to point at the broken one on errors.
+    read: usize,
+}
+
+impl<R: BufRead> BlockReader<R> {
+    // Open an archive, telling its format from the first bytes.
+    pub fn open(mut reader: R) -> io::Result<BlockReader<R>> {
+        let format = if reader.fill_buf()?.starts_with(BINARY_MAGIC) {
+            reader.consume(BINARY_MAGIC.len());
+            ArchiveFormat::Binary
+        } else {
+            ArchiveFormat::Jsonl
+        };
+        Ok(BlockReader {
+            reader,
+            format,
+            read: 0,
+        })
+    }
+
+    pub fn format(&self) -> ArchiveFormat {
+        self.format
+    }
+
+    fn read_jsonl(&mut self) -> Result<Option<Block>, Box<dyn Error>> {
+        let mut line = String::new();
+        loop {
+            line.clear();
+            if self.reader.read_line(&mut line)? == 0 {
+                return Ok(None);
+            }
+            if !line.trim().is_empty() {
+                return Ok(Some(serde_json::from_str(&line)?));
+            }
+        }
+    }
+
+    fn read_binary(&mut self) -> Result<Option<Block>, Box<dyn Error>> {
+        if self.reader.fill_buf()?.is_empty() {
+            return Ok(None);
+        }
+        let mut len = [0; 4];
+        self.reader.read_exact(&mut len)?;
+        let len = u32::from_be_bytes(len) as usize;
+        if len > MAX_FRAME_BYTES {
+            return Err(format!("Frame of {} bytes is too large", len).into());
+        }
+        let mut bytes = vec![0; len];
+        self.reader.read_exact(&mut bytes)?;
+        Ok(Some(bincode::deserialize(&bytes)?))
+    }
+}
+
+impl<R: BufRead> Iterator for BlockReader<R> {
+    type Item = Result<Block, Box<dyn Error>>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let block = match self.format {
+            ArchiveFormat::Jsonl => self.read_jsonl(),
+            ArchiveFormat::Binary => self.read_binary(),
+        };
+        self.read += 1;
+        block
+            .map_err(|e| format!("Invalid block #{} in archive: {}", self.read, e).into())
+            .transpose()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::Transaction;
+
+    fn blocks() -> Vec<Block> {
+        (0..3)
+            .map(|index| Block {
+                index,
+                transactions: vec![Transaction {
+                    author: "author".to_string(),
+                    content: format!("post {}", index),
+                    timestamp: 1_688_169_600,
+                }],
+                timestamp: 1_688_169_600 + index as i64,
+                previous_hash: format!("{:064}", index),
+                nonce: index * 7,
+                hash: format!("{:064}", index + 1),
+            })
+            .collect()
+    }
+
+    fn round_trip(format: ArchiveFormat) -> (ArchiveFormat, Vec<Block>) {
+        let mut writer = BlockWriter::new(Vec::new(), format).unwrap();
+        for block in blocks() {
+            writer.write(&block).unwrap();
+        }
+        let bytes = writer.writer;
+        let reader = BlockReader::open(bytes.as_slice()).unwrap();
+        let format = reader.format();
+        (format, reader.collect::<Result<_, _>>().unwrap())
+    }
+
+    #[test]
+    fn blocks_round_trip_in_both_formats() {
+        for format in [ArchiveFormat::Jsonl, ArchiveFormat::Binary] {
+            let (detected, read) = round_trip(format);
+            assert_eq!(detected, format);
+            assert_eq!(
+                serde_json::to_string(&read).unwrap(),
+                serde_json::to_string(&blocks()).unwrap()
+            );
+        }
+    }
+
+    #[test]
+    fn truncated_binary_archive_is_an_error() {
+        let mut writer = BlockWriter::new(Vec::new(), ArchiveFormat::Binary).unwrap();
+        writer.write(&blocks()[0]).unwrap();
+        let bytes = &writer.writer[..writer.writer.len() - 1];
+        let mut reader = BlockReader::open(bytes).unwrap();
+        assert!(reader.next().unwrap().is_err());
+    }
+}
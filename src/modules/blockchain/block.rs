@@ -1,3 +1,4 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
 use sha2::{Digest, Sha256};
 use std::error::Error;
@@ -5,7 +6,7 @@ use std::error::Error;
 use super::chain::Transaction;
 
 // Block represents a block in the blockchain.
-#[derive(Debug, Serialize, Deserialize, Clone)] // Add Clone trait to the Block struct
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)] // Add Clone trait to the Block struct
 pub struct Block {
     pub index: i32,
     pub transactions: Vec<Transaction>,
@@ -15,6 +16,16 @@ pub struct Block {
     pub hash: String,
 }
 
+// BlockHeader is a block without its transactions, used to sync chains cheaply.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BlockHeader {
+    pub index: i32,
+    pub timestamp: i64,
+    pub previous_hash: String,
+    pub nonce: i32,
+    pub hash: String,
+}
+
 impl Block {
     // A function that returns the hash of the block contents.
     pub fn compute_hash(&self) -> Result<String, Box<dyn Error>> {
@@ -26,6 +37,12 @@ impl Block {
         Ok(format!("{:x}", hash))
     }
 
+    // Hash a proof of work is searched for and checked against: the hash of the block with an
+    // empty hash field, so that the hash of a block never covers itself.
+    pub fn proof_hash(&self) -> Result<String, Box<dyn Error>> {
+        self.with_modified_hash("").compute_hash()
+    }
+
     // Create a new block with a modified hash.
     pub fn with_modified_hash(&self, modified_hash: &str) -> Block {
         Block {
@@ -37,4 +54,15 @@ impl Block {
             hash: modified_hash.to_string(),
         }
     }
+
+    // Get the header of the block.
+    pub fn header(&self) -> BlockHeader {
+        BlockHeader {
+            index: self.index,
+            timestamp: self.timestamp,
+            previous_hash: self.previous_hash.clone(),
+            nonce: self.nonce,
+            hash: self.hash.clone(),
+        }
+    }
 }
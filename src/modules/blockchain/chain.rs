@@ -1,76 +1,368 @@
+use schemars::JsonSchema;
 use serde::{Deserialize, Serialize};
+use sha2::{Digest, Sha256};
+use std::collections::{HashMap, HashSet};
 use std::error::Error;
-use std::fs::File;
+use std::fmt;
+use std::fs::{self, File};
 use std::io::Read;
-use std::time::SystemTime;
+use std::path::Path;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Instant;
+use tracing::{debug, info, instrument, warn};
 
 use super::block::Block;
+use super::events::{EventBus, NodeEvent, Reorg};
+use super::genesis::GenesisSpec;
+use super::verify::{verify_chain, VerifyReport};
+use crate::modules::api::{BlockList, Envelope, TransactionRecord};
+use crate::modules::clock::SharedClock;
+use crate::modules::metrics::{NodeMetrics, CONSENSUS_ADOPTED, CONSENSUS_KEPT};
+use crate::modules::network::auth::NodeAuth;
+use crate::modules::network::discovery::AddressBook;
+use crate::modules::network::handshake::Handshake;
+use crate::modules::network::peer_client::PeerClient;
+use crate::modules::network::scoring::{peer_key, Offence, PeerScores};
+use crate::modules::network::wire::{Inventory, Message};
 
 // Transaction represents a transaction in the blockchain.
-#[derive(Debug, Serialize, Deserialize, Clone)]
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
 pub struct Transaction {
     pub author: String,
     pub content: String,
+    // Set by the node that accepts the transaction, so clients may leave it out.
+    #[serde(default)]
     pub timestamp: i64,
 }
 
+impl Transaction {
+    // A function that returns the hash of the transaction, used to identify it between nodes.
+    pub fn compute_hash(&self) -> Result<String, Box<dyn Error>> {
+        let json_str = serde_json::to_string(self)?;
+        let mut hasher = Sha256::new();
+        hasher.update(json_str.as_bytes());
+        Ok(format!("{:x}", hasher.finalize()))
+    }
+}
+
 // NodePeer represents a peer node in the blockchain network.
-#[derive(Debug, Serialize, Deserialize)]
+#[derive(Debug, Serialize, Deserialize, Clone, JsonSchema)]
 pub struct NodePeer {
     pub node_address: String,
+    // Handshake the peer presented when it was registered.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub handshake: Option<Handshake>,
+    // Whether we registered with the peer (outbound) rather than it with us (inbound).
+    #[serde(default)]
+    pub outbound: bool,
+    // Key the peer is scored under, fixed when it was registered: the node key it proved it
+    // holds, else the IP address and port it was resolved to.
+    #[serde(default, alias = "ip", skip_serializing_if = "Option::is_none")]
+    pub key: Option<String>,
+}
+
+// Name of the blockchain dump in the data directory, loaded on startup and written on shutdown.
+pub const CHAIN_FILE: &str = "chain.json";
+// Names of the pending transactions and peer table in the data directory, written on shutdown
+// and reloaded on startup.
+pub const MEMPOOL_FILE: &str = "mempool.json";
+pub const PEERS_FILE: &str = "peers.json";
+// Number of leading zeros a block hash needs on the default network.
+pub const DEFAULT_DIFFICULTY: i32 = 2;
+// Largest number of blocks kept while waiting for their parent to arrive.
+pub const MAX_ORPHANS: usize = 1024;
+// Largest number of pending transactions; new ones are refused until blocks make room.
+pub const MAX_PENDING_TRANSACTIONS: usize = 10_000;
+
+// BlockError represents the reasons a block can be rejected by add_block.
+#[derive(Debug, PartialEq, Eq)]
+pub enum BlockError {
+    PreviousHashMismatch,
+    InvalidProof,
+}
+
+impl BlockError {
+    // Stable name of the reason, for metrics.
+    pub fn code(&self) -> &'static str {
+        match self {
+            BlockError::PreviousHashMismatch => "previous_hash_mismatch",
+            BlockError::InvalidProof => "invalid_proof",
+        }
+    }
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            BlockError::PreviousHashMismatch => write!(f, "Previous hash incorrect"),
+            BlockError::InvalidProof => write!(f, "Block proof invalid"),
+        }
+    }
+}
+
+impl Error for BlockError {}
+
+// TransactionError represents the reasons a transaction can be refused by accept_transaction.
+#[derive(Debug, PartialEq, Eq)]
+pub enum TransactionError {
+    InvalidData,
+    MempoolFull,
+}
+
+impl fmt::Display for TransactionError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TransactionError::InvalidData => write!(f, "Invalid transaction data"),
+            TransactionError::MempoolFull => write!(f, "Too many pending transactions"),
+        }
+    }
+}
+
+impl Error for TransactionError {}
+
+// MiningCancelled is returned by mine_block when mining was cancelled, e.g. on shutdown. The
+// pending transactions are kept.
+#[derive(Debug)]
+pub struct MiningCancelled;
+
+impl fmt::Display for MiningCancelled {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Mining cancelled")
+    }
+}
+
+impl Error for MiningCancelled {}
+
+// MiningJob is a block of pending transactions on top of the tip it was taken at, whose proof of
+// work is yet to be found.
+#[derive(Debug)]
+pub struct MiningJob {
+    pub block: Block,
+    difficulty: i32,
+    cancel: Arc<AtomicBool>,
+    metrics: NodeMetrics,
+}
+
+impl MiningJob {
+    // Perform the Proof of Work algorithm to find a hash that satisfies the difficulty criteria.
+    pub fn solve(self) -> Result<Block, Box<dyn Error>> {
+        let MiningJob {
+            mut block,
+            difficulty,
+            cancel,
+            metrics,
+        } = self;
+        let prefix = "0".repeat(difficulty as usize);
+        let started = Instant::now();
+        while !block.hash.starts_with(&prefix) {
+            if cancel.load(Ordering::SeqCst) {
+                return Err(MiningCancelled.into());
+            }
+            block.nonce += 1;
+            block.hash = block.proof_hash()?;
+        }
+        let elapsed = started.elapsed();
+        // Every nonce tried was hashed once
+        metrics.block_mined(block.nonce as u64, elapsed);
+        info!(
+            index = block.index,
+            hash = %block.hash,
+            nonce = block.nonce,
+            elapsed_ms = elapsed.as_secs_f64() * 1000.0,
+            "Mined block"
+        );
+        Ok(block)
+    }
+}
+
+// Received is what became of a block a peer sent us.
+#[derive(Debug, PartialEq, Eq)]
+pub enum Received {
+    // We already had the block.
+    Known,
+    // The block, and any kept blocks building on it, extended our chain or replaced part of it
+    // with a longer branch.
+    Added,
+    // The block is on a branch that is not longer than ours. It is kept in case the branch grows.
+    Fork,
+    // The block does not connect to our chain yet, because we are behind or on another branch.
+    // It is kept until its ancestors arrive; this is the hash of the one to ask the peer for.
+    Orphan(String),
+}
+
+// ConsensusRound is what a round of consensus learned from peers: the longest valid chain
+// they serve, if it beats ours, and the offences peers committed along the way, by node address.
+#[derive(Debug, Default)]
+pub struct ConsensusRound {
+    pub longest_chain: Option<Vec<Block>>,
+    pub offences: Vec<(String, Offence)>,
 }
 
 // Blockchain represents the blockchain and related operations.
 #[derive(Debug, Serialize, Deserialize)]
 pub struct Blockchain {
     pub difficulty: i32,
+    // Spec the chain was created from; chains written before specs existed are on mainnet.
+    #[serde(default)]
+    pub genesis: GenesisSpec,
+    // Pending transactions and peers are kept in their own files, but older dumps hold them.
+    #[serde(default, skip_serializing)]
     pub unconfirmed_transactions: Vec<Transaction>,
     pub chain: Vec<Block>,
+    // Hashes of the transactions on the chain, to tell gossiped transactions we already
+    // confirmed from new ones.
+    #[serde(skip)]
+    confirmed_transactions: HashSet<String>,
+    #[serde(default, skip_serializing)]
     pub peers: Vec<NodePeer>,
+    #[serde(skip)]
+    pub peer_scores: PeerScores,
+    // Addresses learned from other nodes that we have not connected to yet.
+    #[serde(skip)]
+    pub address_book: AddressBook,
+    // Clock blocks are timestamped with.
+    #[serde(skip)]
+    pub clock: SharedClock,
+    // Set to stop mining in progress. It is shared with mining jobs, which are solved without the
+    // blockchain.
+    #[serde(skip)]
+    pub cancel_mining: Arc<AtomicBool>,
+    // Highest height of a block with a valid proof of work that peers announced to us.
+    #[serde(skip)]
+    pub best_peer_height: i32,
+    // Blocks with a valid proof of work that are not on our chain, by hash: blocks waiting for
+    // their parent and branches that are not longer than ours.
+    #[serde(skip)]
+    pub orphans: HashMap<String, Block>,
+    // Subscribers to the transactions, blocks, reorgs and peers of the chain.
+    #[serde(skip)]
+    pub events: EventBus,
+    #[serde(skip)]
+    pub metrics: NodeMetrics,
 }
 
 impl Blockchain {
-    // Create a new blockchain with a genesis block.
-    pub fn new_blockchain() -> Result<Blockchain, Box<dyn Error>> {
+    // Create a new blockchain holding the genesis block described by a spec.
+    pub fn from_genesis(genesis: &GenesisSpec) -> Result<Blockchain, Box<dyn Error>> {
         let mut bc = Blockchain {
-            difficulty: 2,
+            difficulty: genesis.consensus.difficulty,
+            genesis: genesis.clone(),
             unconfirmed_transactions: Vec::new(),
             chain: Vec::new(),
+            confirmed_transactions: HashSet::new(),
             peers: Vec::new(),
+            peer_scores: PeerScores::default(),
+            address_book: AddressBook::default(),
+            clock: SharedClock::default(),
+            cancel_mining: Arc::new(AtomicBool::new(false)),
+            best_peer_height: 0,
+            orphans: HashMap::new(),
+            events: EventBus::default(),
+            metrics: NodeMetrics::default(),
         };
         bc.create_genesis_block()?;
         Ok(bc)
     }
 
+    // Create a new blockchain from the blocks of another node, starting with our genesis block.
+    // Every other block is checked as it is added.
+    pub fn from_blocks(
+        blocks: Vec<Block>,
+        genesis: &GenesisSpec,
+    ) -> Result<Blockchain, Box<dyn Error>> {
+        let mut blockchain = Blockchain::from_genesis(genesis)?;
+        let mut blocks = blocks.into_iter();
+        match blocks.next() {
+            Some(first) if first.hash == blockchain.genesis_hash() => {}
+            _ => return Err("Genesis block does not match".into()),
+        }
+        for block in blocks {
+            blockchain.add_block(block)?;
+        }
+        Ok(blockchain)
+    }
+
     // Create a new blockchain by loading the blockchain data from a file.
-    pub fn create_chain_from_file(dump: &str) -> Result<Blockchain, Box<dyn Error>> {
+    pub fn create_chain_from_file(dump: &Path) -> Result<Blockchain, Box<dyn Error>> {
         let mut file = File::open(dump)?;
         let mut content = String::new();
         file.read_to_string(&mut content)?;
-        let blockchain: Blockchain = serde_json::from_str(&content)?;
+        let mut blockchain: Blockchain = serde_json::from_str(&content)?;
+        blockchain.index_transactions();
         Ok(blockchain)
     }
 
-    // Create a new blockchain by loading the blockchain data from a dump.
+    // Write the blockchain data to a file that create_chain_from_file can load.
+    pub fn save_to_file(&self, dump: &Path) -> Result<(), Box<dyn Error>> {
+        write_atomically(dump, &serde_json::to_string(self)?)
+    }
+
+    // Write the pending transactions to a file that load_mempool can read.
+    pub fn save_mempool(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        write_atomically(
+            path,
+            &serde_json::to_string(&self.unconfirmed_transactions)?,
+        )
+    }
+
+    // Replace the pending transactions with the ones saved in a file, if it exists.
+    pub fn load_mempool(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if path.exists() {
+            self.unconfirmed_transactions = serde_json::from_str(&fs::read_to_string(path)?)?;
+        }
+        Ok(())
+    }
+
+    // Write the peer table to a file that load_peers can read.
+    pub fn save_peers(&self, path: &Path) -> Result<(), Box<dyn Error>> {
+        write_atomically(path, &serde_json::to_string(&self.peers)?)
+    }
+
+    // Replace the peer table with the one saved in a file, if it exists.
+    pub fn load_peers(&mut self, path: &Path) -> Result<(), Box<dyn Error>> {
+        if path.exists() {
+            self.peers = serde_json::from_str(&fs::read_to_string(path)?)?;
+        }
+        Ok(())
+    }
+
+    // Create a new blockchain by loading the blockchain data from a dump, checking every block
+    // against the rules of the given genesis spec.
     pub fn create_chain_from_dump(
         chain_dump: Vec<serde_json::Map<String, serde_json::Value>>,
         node_addresses: Vec<String>,
+        genesis: &GenesisSpec,
     ) -> Result<Blockchain, Box<dyn Error>> {
-        let mut generated_blockchain = Blockchain::new_blockchain()?;
+        let mut generated_blockchain = Blockchain::from_genesis(genesis)?;
         for (idx, block_data) in chain_dump.into_iter().enumerate() {
             if idx == 0 {
-                continue; // Skip genesis block
+                // Skip genesis block, which must be ours
+                if block_data["hash"].as_str() != Some(generated_blockchain.genesis_hash()) {
+                    return Err("Genesis block does not match".into());
+                }
+                continue;
             }
 
             let block = Block {
-                index: block_data["index"].as_i64().unwrap() as i32,
+                index: block_data["index"].as_i64().ok_or("Invalid block index")? as i32,
                 transactions: Blockchain::parse_transactions(
-                    block_data["transactions"].as_array().unwrap(),
+                    block_data["transactions"]
+                        .as_array()
+                        .ok_or("Invalid block transactions")?,
                 )?,
-                timestamp: block_data["timestamp"].as_i64().unwrap(),
-                previous_hash: block_data["previous_hash"].as_str().unwrap().to_string(),
-                nonce: block_data["nonce"].as_i64().unwrap() as i32,
-                hash: block_data["hash"].as_str().unwrap().to_string(),
+                timestamp: block_data["timestamp"]
+                    .as_i64()
+                    .ok_or("Invalid block timestamp")?,
+                previous_hash: block_data["previous_hash"]
+                    .as_str()
+                    .ok_or("Invalid block previous hash")?
+                    .to_string(),
+                nonce: block_data["nonce"].as_i64().ok_or("Invalid block nonce")? as i32,
+                hash: block_data["hash"]
+                    .as_str()
+                    .ok_or("Invalid block hash")?
+                    .to_string(),
             };
 
             generated_blockchain.add_block(block)?;
@@ -79,6 +371,9 @@ impl Blockchain {
             .into_iter()
             .map(|node_address| NodePeer {
                 node_address: node_address.to_string(),
+                handshake: None,
+                outbound: false,
+                key: None,
             })
             .collect();
         generated_blockchain.peers = node_peers;
@@ -92,189 +387,766 @@ impl Blockchain {
         let mut transactions = Vec::new();
         for transaction_data in transactions_data {
             let transaction = Transaction {
-                author: transaction_data["author"].as_str().unwrap().to_string(),
-                content: transaction_data["content"].as_str().unwrap().to_string(),
-                timestamp: transaction_data["timestamp"].as_i64().unwrap(),
+                author: transaction_data["author"]
+                    .as_str()
+                    .ok_or("Invalid transaction author")?
+                    .to_string(),
+                content: transaction_data["content"]
+                    .as_str()
+                    .ok_or("Invalid transaction content")?
+                    .to_string(),
+                timestamp: transaction_data["timestamp"]
+                    .as_i64()
+                    .ok_or("Invalid transaction timestamp")?,
             };
             transactions.push(transaction);
         }
         Ok(transactions)
     }
 
-    // Create the genesis block of the blockchain.
+    // Create the genesis block of the blockchain from its genesis spec.
     pub fn create_genesis_block(&mut self) -> Result<(), Box<dyn Error>> {
-        let genesis_block = Block {
-            index: 0,
-            transactions: Vec::new(),
-            timestamp: 0,
-            previous_hash: "0".to_string(),
-            nonce: 0,
-            hash: "".to_string(),
-        };
-        let computed_hash = genesis_block.compute_hash()?;
-        self.chain.push(Block {
-            hash: computed_hash,
-            ..genesis_block
-        });
+        let genesis_block = self.genesis.block()?;
+        self.chain.push(genesis_block);
+        self.index_transactions();
         Ok(())
     }
 
+    // Get the hash of the genesis block, which identifies the network the chain belongs to.
+    pub fn genesis_hash(&self) -> &str {
+        &self.chain[0].hash
+    }
+
     // Get the last block in the chain.
     pub fn get_last_block(&self) -> &Block {
         self.chain.last().unwrap()
     }
 
-    // Add the block to the chain after verification.
+    // Add the block to the chain after verification, dropping the transactions it confirms from
+    // the pending ones.
+    #[instrument(level = "debug", skip_all, fields(index = block.index, hash = %block.hash))]
     pub fn add_block(&mut self, block: Block) -> Result<(), Box<dyn Error>> {
         // Compare the previous hash.
         if self.get_last_block().hash != block.previous_hash {
-            return Err("Previous hash incorrect".into());
+            return Err(self.reject_block(BlockError::PreviousHashMismatch));
         }
 
         if !self.is_valid_proof(&block, &block.hash) {
-            return Err("Block proof invalid".into());
+            return Err(self.reject_block(BlockError::InvalidProof));
         }
 
+        self.events.publish(|| NodeEvent::Block(block.clone()));
+        for transaction in &block.transactions {
+            self.confirmed_transactions
+                .insert(transaction.compute_hash()?);
+        }
+        self.unconfirmed_transactions
+            .retain(|transaction| !block.transactions.contains(transaction));
         self.chain.push(block);
         Ok(())
     }
 
+    fn reject_block(&self, error: BlockError) -> Box<dyn Error> {
+        debug!(reason = error.code(), "Rejected block");
+        self.metrics.block_rejected(error.code());
+        error.into()
+    }
+
     // Add the pending transactions to the blockchain by adding them to a block and figuring out Proof of Work.
+    #[instrument(skip_all, fields(transactions = self.unconfirmed_transactions.len()))]
     pub fn mine_block(&mut self) -> Result<bool, Box<dyn Error>> {
+        let job = match self.mining_job()? {
+            Some(job) => job,
+            None => return Ok(false),
+        };
+        let block = job.solve()?;
+        self.submit_mined(block)
+    }
+
+    // Start mining the pending transactions on top of the current tip, or None if there are
+    // none. The job is solved without the blockchain, so a miner need not hold its lock.
+    pub fn mining_job(&self) -> Result<Option<MiningJob>, Box<dyn Error>> {
         if self.unconfirmed_transactions.is_empty() {
-            return Ok(false);
+            return Ok(None);
+        }
+        if self.cancel_mining.load(Ordering::SeqCst) {
+            return Err(MiningCancelled.into());
         }
 
         let last_block = self.get_last_block();
-        let index = last_block.index + 1;
-        let timestamp = SystemTime::now()
-            .duration_since(SystemTime::UNIX_EPOCH)?
-            .as_secs() as i64;
-        let previous_hash = last_block.hash.clone();
-
-        let mut new_block = Block {
-            index,
-            transactions: self.unconfirmed_transactions.clone(),
-            timestamp,
-            previous_hash,
-            nonce: 0,
-            hash: "".to_string(),
-        };
-
-        self.proof_of_work(&mut new_block)?;
-        self.add_block(new_block)?;
+        Ok(Some(MiningJob {
+            block: Block {
+                index: last_block.index + 1,
+                transactions: self.unconfirmed_transactions.clone(),
+                timestamp: self.clock.now(),
+                previous_hash: last_block.hash.clone(),
+                nonce: 0,
+                hash: "".to_string(),
+            },
+            difficulty: self.difficulty,
+            cancel: self.cancel_mining.clone(),
+            metrics: self.metrics.clone(),
+        }))
+    }
 
-        self.unconfirmed_transactions.clear();
+    // Append a block solved from a mining job. Returns false, keeping the transactions it holds
+    // pending, if the tip changed while it was mined.
+    pub fn submit_mined(&mut self, block: Block) -> Result<bool, Box<dyn Error>> {
+        if block.previous_hash != self.get_last_block().hash {
+            debug!(index = block.index, "Tip changed while mining");
+            return Ok(false);
+        }
+        self.add_block(block)?;
         Ok(true)
     }
 
-    // Add a new node peer to the blockchain.
+    // Use the given clock for blocks and peer bans instead of the system clock.
+    pub fn set_clock(&mut self, clock: SharedClock) {
+        self.peer_scores.clock = clock.clone();
+        self.clock = clock;
+    }
+
+    // Add a new node peer to the blockchain, replacing an existing entry with the same address.
     pub fn add_node_peer(&mut self, node: NodePeer) {
+        let known = self
+            .peers
+            .iter()
+            .any(|peer| peer.node_address == node.node_address);
+        if !known {
+            self.events.publish(|| NodeEvent::Peer(node.clone()));
+        }
+        self.peers
+            .retain(|peer| peer.node_address != node.node_address);
         self.peers.push(node);
     }
 
+    // Remember peer addresses learned from other nodes so discovery can try them later.
+    pub fn remember_addresses(&mut self, addresses: impl IntoIterator<Item = String>) {
+        let known: HashSet<&str> = self
+            .peers
+            .iter()
+            .map(|peer| peer.node_address.as_str())
+            .collect();
+        for address in addresses {
+            if !known.contains(address.as_str()) {
+                self.address_book.insert(address);
+            }
+        }
+    }
+
+    // Check a transaction from a client or peer and add it to the pending ones. Transactions
+    // need an author and content, and are refused while the mempool is full.
+    pub fn accept_transaction(&mut self, transaction: Transaction) -> Result<(), TransactionError> {
+        if transaction.author.is_empty() || transaction.content.is_empty() {
+            return Err(TransactionError::InvalidData);
+        }
+        if self.unconfirmed_transactions.len() >= MAX_PENDING_TRANSACTIONS {
+            return Err(TransactionError::MempoolFull);
+        }
+        self.add_new_transaction(transaction);
+        Ok(())
+    }
+
     // Add a new transaction to the list of unconfirmed transactions.
     pub fn add_new_transaction(&mut self, transaction: Transaction) {
+        self.events
+            .publish(|| NodeEvent::Transaction(TransactionRecord::pending(&transaction)));
         self.unconfirmed_transactions.push(transaction);
     }
 
-    // Perform the Proof of Work algorithm to find a hash that satisfies the difficulty criteria.
-    pub fn proof_of_work(&self, block: &mut Block) -> Result<(), Box<dyn Error>> {
-        let prefix = "0".repeat(self.difficulty as usize);
-        while !block.hash.starts_with(&prefix) {
-            block.nonce += 1;
-            let hash = block.compute_hash()?;
-            block.hash = hash;
+    // Whether a transaction, by hash, is pending or on the chain.
+    pub fn has_transaction(&self, hash: &str) -> bool {
+        self.confirmed_transactions.contains(hash)
+            || self
+                .unconfirmed_transactions
+                .iter()
+                .any(|transaction| transaction.compute_hash().ok().as_deref() == Some(hash))
+    }
+
+    // Announce a new block to the given peers: peers connected over the transport are sent its
+    // hash and ask for it if they miss it, others are sent the block over HTTP, signed with the
+    // node key. This takes a snapshot of the peers so it can run without holding a lock on the
+    // blockchain.
+    #[instrument(skip_all, fields(index = block.index, hash = %block.hash))]
+    pub async fn announce_new_block(
+        client: &impl PeerClient,
+        node_addresses: Vec<String>,
+        block: &Block,
+        auth: &NodeAuth,
+        metrics: &NodeMetrics,
+    ) -> Result<(), Box<dyn Error>> {
+        for node_address in node_addresses {
+            let inv = Message::Inv(vec![Inventory::Block(block.hash.clone())]);
+            let gossiped = client.send_message(&node_address, inv).await.is_ok();
+            let sent = if gossiped {
+                Ok(())
+            } else {
+                client.send_block(&node_address, block, auth).await
+            };
+            metrics.block_announced(sent.is_ok());
+            match sent {
+                Ok(()) => debug!(peer = %node_address, "Announced block"),
+                Err(e) => warn!(peer = %node_address, error = %e, "Failed to announce block"),
+            }
         }
         Ok(())
     }
 
-    // Announce a new block to the network.
-    pub async fn announce_new_block(&self) -> Result<(), Box<dyn Error>> {
-        let client = reqwest::Client::new();
-        for peer in &self.peers {
-            let url = format!("{}/add_block", peer.node_address);
+    // Accept a block announced by a peer, e.g. over HTTP or the transport, whichever brings it
+    // first. Senders of blocks without a valid proof of work are penalised. A block that does not
+    // build on our tip is not an offence: one of us may be behind, or two miners found a block
+    // at the same height. It is kept, and once its branch connects to our chain and is longer,
+    // we switch to it.
+    #[instrument(skip(self, block), fields(index = block.index, hash = %block.hash))]
+    pub fn receive_block(&mut self, peer: &str, block: Block) -> Result<Received, Box<dyn Error>> {
+        if self.has_block(&block.hash) {
+            return Ok(Received::Known);
+        }
+        self.note_peer_block(&block);
+        if !self.is_valid_proof(&block, &block.hash) {
+            let e = self.reject_block(BlockError::InvalidProof);
+            self.peer_scores
+                .penalise(peer, Offence::from(&BlockError::InvalidProof));
+            warn!(error = %e, "Refused block from peer");
+            return Err(e);
+        }
+        if block.previous_hash == self.get_last_block().hash {
+            let hash = block.hash.clone();
+            self.add_block(block)?;
+            self.connect_orphans(&hash);
+            info!("Accepted block from peer");
+            return Ok(Received::Added);
+        }
+
+        // Find where the branch of the block starts, among the blocks kept so far
+        let hash = block.hash.clone();
+        self.keep_orphan(block);
+        let mut branch = vec![hash];
+        loop {
+            let parent = &self.orphans[branch.last().unwrap()].previous_hash;
+            if !self.orphans.contains_key(parent) {
+                break;
+            }
+            branch.push(parent.clone());
+        }
+        let root = &self.orphans[branch.last().unwrap()];
+        let fork_point = match self
+            .chain
+            .iter()
+            .position(|known| known.hash == root.previous_hash)
+        {
+            Some(position) => position,
+            None => {
+                debug!(missing = %root.previous_hash, "Kept block until its ancestors arrive");
+                return Ok(Received::Orphan(root.previous_hash.clone()));
+            }
+        };
+        branch.reverse();
+        self.extend_branch(&mut branch);
+        if fork_point + 1 + branch.len() <= self.chain.len() {
+            debug!(fork_height = fork_point, "Kept block on a shorter branch");
+            return Ok(Received::Fork);
+        }
+        self.switch_to_branch(fork_point, branch)?;
+        Ok(Received::Added)
+    }
+
+    // Whether a block is on our chain or kept aside.
+    pub fn has_block(&self, hash: &str) -> bool {
+        self.orphans.contains_key(hash) || self.chain.iter().any(|block| block.hash == hash)
+    }
+
+    // Keep a block that is not on our chain, making room by dropping the highest kept block,
+    // which is the furthest from connecting, if there are too many.
+    fn keep_orphan(&mut self, block: Block) {
+        if self.orphans.len() >= MAX_ORPHANS {
+            let highest = self
+                .orphans
+                .values()
+                .max_by_key(|orphan| orphan.index)
+                .map(|orphan| orphan.hash.clone());
+            if let Some(highest) = highest {
+                self.orphans.remove(&highest);
+            }
+        }
+        self.orphans.insert(block.hash.clone(), block);
+    }
 
-            let json_str = serde_json::to_string(&self.get_last_block())?;
-            let response = client.post(&url).body(json_str).send().await?;
+    // Extend a branch, given by block hashes, with the longest run of kept blocks building on it.
+    fn extend_branch(&self, branch: &mut Vec<String>) {
+        let mut children: HashMap<&str, Vec<&str>> = HashMap::new();
+        for orphan in self.orphans.values() {
+            children
+                .entry(orphan.previous_hash.as_str())
+                .or_default()
+                .push(orphan.hash.as_str());
+        }
+        let children_of = |hash: &str| children.get(hash).into_iter().flatten().copied();
 
-            if response.status().is_success() {
-                println!("Block added to node {}", peer.node_address);
+        // Length of the longest run starting at each block building on the branch, leaves first
+        let start = branch.last().unwrap().clone();
+        let mut lengths: HashMap<&str, usize> = HashMap::new();
+        let mut stack: Vec<&str> = children_of(&start).collect();
+        while let Some(&hash) = stack.last() {
+            let pending: Vec<&str> = children_of(hash)
+                .filter(|child| !lengths.contains_key(child))
+                .collect();
+            if pending.is_empty() {
+                let length = children_of(hash).map(|child| lengths[child]).max();
+                lengths.insert(hash, length.unwrap_or(0) + 1);
+                stack.pop();
             } else {
-                println!(
-                    "Failed to add block to node {}: {}",
-                    peer.node_address,
-                    response.status()
-                );
+                stack.extend(pending);
+            }
+        }
+
+        let mut last = start.as_str();
+        while let Some(child) = children_of(last).max_by_key(|child| (lengths[child], *child)) {
+            branch.push(child.to_string());
+            last = child;
+        }
+    }
+
+    // Append the kept blocks that build on the block just added to our tip.
+    fn connect_orphans(&mut self, tip: &str) {
+        let mut branch = vec![tip.to_string()];
+        self.extend_branch(&mut branch);
+        for hash in branch.into_iter().skip(1) {
+            let block = self.orphans.remove(&hash).unwrap();
+            if self.add_block(block).is_err() {
+                break;
+            }
+        }
+    }
+
+    // Replace the blocks after fork_point with a longer branch of kept blocks, checking the
+    // resulting chain as one served during consensus.
+    fn switch_to_branch(
+        &mut self,
+        fork_point: usize,
+        branch: Vec<String>,
+    ) -> Result<(), Box<dyn Error>> {
+        let mut blocks = self.chain[..=fork_point].to_vec();
+        blocks.extend(branch.iter().map(|hash| self.orphans[hash].clone()));
+        let candidate = Blockchain::from_blocks(blocks, &self.genesis);
+        for hash in &branch {
+            self.orphans.remove(hash);
+        }
+        let candidate = candidate.map_err(|e| {
+            warn!(error = %e, "Refused branch from peer");
+            e
+        })?;
+        // Keep the blocks we switch away from, in case their branch grows longer again
+        let replaced = self.chain[fork_point + 1..].to_vec();
+        if self.adopt_chain(candidate.chain) {
+            let replaced_count = replaced.len();
+            for block in replaced {
+                self.keep_orphan(block);
             }
+            let tip = self.get_last_block();
+            info!(
+                fork_height = fork_point,
+                replaced = replaced_count,
+                height = tip.index,
+                hash = %tip.hash,
+                "Switched to a longer branch"
+            );
         }
         Ok(())
     }
 
-    // Perform consensus - If a longer valid chain is found, our chain is replaced with it.
-    pub async fn consensus(&mut self) -> Result<bool, Box<dyn Error>> {
-        let mut current_len = self.chain.len() as i64;
-        let mut longest_chain = Vec::new();
-        for peer in &self.peers {
-            let res_body = reqwest::get(&format!("{}/chain", peer.node_address))
-                .await?
-                .bytes()
-                .await?;
-
-            let json_data: serde_json::Value = serde_json::from_slice(&res_body)?;
-            let length = json_data["length"].as_i64().unwrap();
-            let chain_data: Vec<serde_json::Map<String, serde_json::Value>> = json_data["chain"]
-                .as_array()
-                .ok_or("Invalid JSON format")?
-                .iter()
-                .map(|item| {
-                    if let Some(obj) = item.as_object() {
-                        obj.clone()
-                    } else {
-                        serde_json::Map::new()
-                    }
-                })
-                .collect();
-            let new_blockchain =
-                Blockchain::create_chain_from_dump(chain_data.to_vec(), vec![]).unwrap();
+    // Remember the height of a block a peer sent, if it is ahead of our tip and work went into
+    // it, to tell how far behind we are.
+    pub fn note_peer_block(&mut self, block: &Block) {
+        if block.index > self.best_peer_height
+            && block.index > self.get_last_block().index
+            && self.is_valid_proof(block, &block.hash)
+        {
+            self.best_peer_height = block.index;
+        }
+    }
+
+    // Consensus - If a longer valid chain is found, our chain is replaced with it. It runs in
+    // three steps so the network round trips happen without holding a lock on the blockchain:
+    // consensus_peers, fetch_longest_chain and apply_consensus. Banned peers are not asked, and
+    // peers serving malformed or invalid chains are penalised.
+
+    // Addresses of the peers asked during consensus, skipping banned ones.
+    pub fn consensus_peers(&self) -> Vec<String> {
+        self.peers
+            .iter()
+            .map(|peer| peer.node_address.clone())
+            .filter(|node_address| !self.peer_scores.is_banned(&self.peer_key_of(node_address)))
+            .collect()
+    }
+
+    // Key a peer is scored under: the one fixed when it was registered, else the IP address and
+    // port its node address names.
+    pub fn peer_key_of(&self, node_address: &str) -> String {
+        self.peers
+            .iter()
+            .find(|peer| peer.node_address == node_address)
+            .and_then(|peer| peer.key.clone())
+            .unwrap_or_else(|| peer_key(node_address))
+    }
 
-            if length > current_len && new_blockchain.check_chain_validity() {
-                current_len = length;
-                longest_chain = chain_data.to_vec();
+    // Fetch the chain of a peer a page at a time, or None if it is not longer than current_len.
+    // The inner error is the offence of a peer that answered with something other than pages of
+    // its chain, as GET /v1/blocks does.
+    async fn fetch_peer_chain(
+        client: &impl PeerClient,
+        node_address: &str,
+        current_len: i64,
+    ) -> Result<Result<Option<Vec<Block>>, Offence>, Box<dyn Error>> {
+        let mut blocks = Vec::new();
+        let mut cursor: Option<String> = None;
+        loop {
+            let body = client.fetch_blocks(node_address, cursor.as_deref()).await?;
+            let page = match serde_json::from_slice::<Envelope<BlockList>>(&body)
+                .map(Envelope::into_result)
+            {
+                Ok(Ok(page)) => page,
+                _ => return Ok(Err(Offence::MalformedJson)),
+            };
+            if page.total as i64 <= current_len {
+                return Ok(Ok(None));
+            }
+            let progressed = !page.blocks.is_empty();
+            blocks.extend(page.blocks);
+            match page.next_cursor {
+                None => return Ok(Ok(Some(blocks))),
+                // Every page must move the peer's chain forward
+                Some(next) if progressed && cursor.as_ref() != Some(&next) => cursor = Some(next),
+                Some(_) => return Ok(Err(Offence::MalformedJson)),
             }
         }
+    }
 
-        if !longest_chain.is_empty() {
-            self.chain = Blockchain::create_chain_from_dump(longest_chain, vec![])
-                .unwrap()
-                .chain;
-            Ok(true)
-        } else {
-            Ok(false)
+    // Ask peers for their chains and find the longest valid one that is longer than current_len.
+    // This does not touch the local blockchain, so it can run without holding a lock on it.
+    #[instrument(name = "consensus", skip_all, fields(peers = node_addresses.len(), current_len = current_len))]
+    pub async fn fetch_longest_chain(
+        client: &impl PeerClient,
+        node_addresses: Vec<String>,
+        current_len: usize,
+        genesis: &GenesisSpec,
+    ) -> Result<ConsensusRound, Box<dyn Error>> {
+        let mut current_len = current_len as i64;
+        let mut round = ConsensusRound::default();
+        for node_address in node_addresses {
+            let peer = node_address.clone();
+            // Peers that cannot be reached are left out of this round
+            let blocks = match Self::fetch_peer_chain(client, &node_address, current_len).await {
+                Ok(Ok(Some(blocks))) => blocks,
+                Ok(Ok(None)) => continue,
+                Ok(Err(offence)) => {
+                    warn!(peer = %node_address, ?offence, "Peer served a malformed chain");
+                    round.offences.push((peer, offence));
+                    continue;
+                }
+                Err(e) => {
+                    warn!(peer = %node_address, error = %e, "Failed to fetch chain");
+                    continue;
+                }
+            };
+            let length = blocks.len() as i64;
+            if length <= current_len {
+                continue;
+            }
+
+            let new_blockchain = match Blockchain::from_blocks(blocks, genesis) {
+                Ok(new_blockchain) => new_blockchain,
+                Err(e) => {
+                    warn!(peer = %node_address, error = %e, "Peer served an invalid chain");
+                    let offence = e
+                        .downcast_ref::<BlockError>()
+                        .map_or(Offence::MalformedJson, Offence::from);
+                    round.offences.push((peer, offence));
+                    continue;
+                }
+            };
+            if !new_blockchain.check_chain_validity() {
+                warn!(peer = %node_address, "Peer served an invalid chain");
+                round.offences.push((peer, Offence::InvalidChain));
+                continue;
+            }
+            debug!(peer = %node_address, length, "Found a longer chain");
+            current_len = length;
+            round.longest_chain = Some(new_blockchain.chain);
+        }
+        Ok(round)
+    }
+
+    // Apply the outcome of a consensus round: penalise the peers that misbehaved and adopt the
+    // longest chain if it is still longer than ours. Returns true if our chain was replaced.
+    #[instrument(skip_all, fields(offences = round.offences.len()))]
+    pub fn apply_consensus(&mut self, round: ConsensusRound) -> bool {
+        for (node_address, offence) in round.offences {
+            let peer = self.peer_key_of(&node_address);
+            self.peer_scores.penalise(&peer, offence);
+        }
+        let adopted = match round.longest_chain {
+            Some(longest_chain) => self.adopt_chain(longest_chain),
+            None => false,
+        };
+        self.metrics.consensus_run(match adopted {
+            true => CONSENSUS_ADOPTED,
+            false => CONSENSUS_KEPT,
+        });
+        if adopted {
+            let tip = self.get_last_block();
+            info!(height = tip.index, hash = %tip.hash, "Adopted the chain of a peer");
+        }
+        adopted
+    }
+
+    // Replace our chain with a valid one if it is longer. Returns true if it was replaced.
+    pub fn adopt_chain(&mut self, chain: Vec<Block>) -> bool {
+        if chain.len() <= self.chain.len() {
+            return false;
+        }
+        let old_chain = std::mem::replace(&mut self.chain, chain);
+        let shared = old_chain
+            .iter()
+            .zip(&self.chain)
+            .take_while(|(old, new)| old.hash == new.hash)
+            .count();
+        self.index_transactions();
+        self.requeue_replaced(&old_chain[shared..]);
+        self.publish_adopted(&old_chain, shared);
+        true
+    }
+
+    // Index the transactions on the chain by hash.
+    fn index_transactions(&mut self) {
+        self.confirmed_transactions = self
+            .chain
+            .iter()
+            .flat_map(|block| &block.transactions)
+            .filter_map(|transaction| transaction.compute_hash().ok())
+            .collect();
+    }
+
+    // Put the transactions of blocks our chain no longer holds back among the pending ones, ahead
+    // of the others, and drop the pending transactions the chain now confirms.
+    fn requeue_replaced(&mut self, replaced: &[Block]) {
+        let pending = std::mem::take(&mut self.unconfirmed_transactions);
+        let mut seen = HashSet::new();
+        self.unconfirmed_transactions = replaced
+            .iter()
+            .flat_map(|block| block.transactions.iter().cloned())
+            .chain(pending)
+            .filter(|transaction| match transaction.compute_hash() {
+                Ok(hash) => !self.confirmed_transactions.contains(&hash) && seen.insert(hash),
+                Err(_) => false,
+            })
+            .collect();
+    }
+
+    // Publish the blocks adopted in place of old_chain, which shares its first blocks with our
+    // chain, after a reorg event if some of its blocks were replaced rather than built upon.
+    fn publish_adopted(&self, old_chain: &[Block], shared: usize) {
+        if shared < old_chain.len() {
+            let (old_tip, new_tip) = (old_chain.last().unwrap(), self.get_last_block());
+            self.events.publish(|| {
+                NodeEvent::Reorg(Reorg {
+                    fork_height: shared as i32 - 1,
+                    old_tip_height: old_tip.index,
+                    old_tip_hash: old_tip.hash.clone(),
+                    new_tip_height: new_tip.index,
+                    new_tip_hash: new_tip.hash.clone(),
+                })
+            });
+        }
+        for block in &self.chain[shared..] {
+            self.events.publish(|| NodeEvent::Block(block.clone()));
         }
     }
 
     // Check if the given block hash is a valid proof of work and satisfies the difficulty criteria.
     pub fn is_valid_proof(&self, block: &Block, block_hash: &str) -> bool {
-        let copy_block = block.with_modified_hash(""); // Create a new block with an empty hash
-        let computed_hash = copy_block.compute_hash().unwrap();
+        let computed_hash = block.proof_hash().unwrap();
         block_hash.starts_with(&"0".repeat(self.difficulty as usize)) && block_hash == computed_hash
     }
 
     // Check the validity of the blockchain by verifying each block and its hash.
     pub fn check_chain_validity(&self) -> bool {
-        let mut previous_hash = "0".to_string();
+        self.verify().is_consensus_valid()
+    }
 
-        for block in &self.chain {
-            if block.index != 0
-                && (!self.is_valid_proof(block, &block.hash)
-                    || previous_hash != block.previous_hash)
-            {
-                return false;
-            }
-            previous_hash = block.hash.clone();
+    // Verify the chain against every rule, reporting each violation and chain statistics.
+    pub fn verify(&self) -> VerifyReport {
+        verify_chain(
+            &self.chain,
+            &self.genesis,
+            self.difficulty,
+            self.clock.now(),
+        )
+    }
+}
+
+// Write a file through a temporary one, so a crash never leaves a truncated file behind.
+pub(crate) fn write_atomically(path: &Path, contents: &str) -> Result<(), Box<dyn Error>> {
+    if let Some(dir) = path.parent() {
+        fs::create_dir_all(dir)?;
+    }
+    let tmp = path.with_extension("tmp");
+    fs::write(&tmp, contents)?;
+    fs::rename(tmp, path)?;
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::network::scoring::BanPolicy;
+
+    fn mine(blockchain: &mut Blockchain, author: &str, blocks: usize) {
+        for i in 0..blocks {
+            blockchain.add_new_transaction(Transaction {
+                author: author.to_string(),
+                content: format!("post {}", i),
+                timestamp: 1_700_000_000 + i as i64,
+            });
+            blockchain.mine_block().unwrap();
         }
+    }
 
-        true
+    #[test]
+    fn mined_blocks_carry_a_valid_proof() {
+        let mut blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        mine(&mut blockchain, "alice", 2);
+        let block = blockchain.get_last_block().clone();
+
+        assert_eq!(block.hash, block.proof_hash().unwrap());
+        assert!(blockchain.is_valid_proof(&block, &block.hash));
+        // The proof does not depend on the hash the block carries, only on its contents
+        let relabelled = block.with_modified_hash("something else");
+        assert_eq!(relabelled.proof_hash().unwrap(), block.hash);
+        let mut tampered = block.clone();
+        tampered.nonce += 1;
+        assert!(!blockchain.is_valid_proof(&tampered, &block.hash));
+        assert!(blockchain.check_chain_validity());
+    }
+
+    #[test]
+    fn blocks_mined_on_a_stale_tip_are_not_appended() {
+        let genesis = Network::Devnet.genesis();
+        let mut ours = Blockchain::from_genesis(&genesis).unwrap();
+        let mut theirs = Blockchain::from_genesis(&genesis).unwrap();
+        mine(&mut theirs, "bob", 1);
+        ours.add_new_transaction(Transaction {
+            author: "alice".to_string(),
+            content: "first".to_string(),
+            timestamp: 1_700_000_000,
+        });
+
+        let job = ours.mining_job().unwrap().unwrap();
+        // A peer extends our chain while the job is solved
+        ours.receive_block("peer", theirs.chain[1].clone()).unwrap();
+        let stale = job.solve().unwrap();
+        assert!(!ours.submit_mined(stale).unwrap());
+        assert_eq!(ours.chain.len(), 2);
+        assert_eq!(ours.unconfirmed_transactions.len(), 1);
+
+        let job = ours.mining_job().unwrap().unwrap();
+        ours.add_new_transaction(Transaction {
+            author: "alice".to_string(),
+            content: "second".to_string(),
+            timestamp: 1_700_000_001,
+        });
+        assert!(ours.submit_mined(job.solve().unwrap()).unwrap());
+        assert_eq!(ours.chain.len(), 3);
+        // Transactions that arrived while mining wait for the next block
+        assert_eq!(ours.unconfirmed_transactions.len(), 1);
+        assert_eq!(ours.unconfirmed_transactions[0].content, "second");
+    }
+
+    #[test]
+    fn peers_on_the_same_host_are_scored_apart() {
+        let genesis = Network::Devnet.genesis();
+        let mut blockchain = Blockchain::from_genesis(&genesis).unwrap();
+        blockchain.peer_scores = PeerScores::new(BanPolicy {
+            threshold: 1,
+            ..BanPolicy::default()
+        });
+        let handshake = Handshake::local(&blockchain, "http://127.0.0.1:8001")
+            .with_node_key("signing-node".to_string());
+        blockchain.add_node_peer(NodePeer {
+            node_address: "http://127.0.0.1:8001".to_string(),
+            handshake: Some(handshake),
+            outbound: true,
+            key: Some("signing-node".to_string()),
+        });
+        for port in [8002, 8003] {
+            blockchain.add_node_peer(NodePeer {
+                node_address: format!("http://127.0.0.1:{}", port),
+                handshake: None,
+                outbound: true,
+                key: None,
+            });
+        }
+
+        blockchain.apply_consensus(ConsensusRound {
+            longest_chain: None,
+            offences: vec![
+                ("http://127.0.0.1:8001".to_string(), Offence::InvalidChain),
+                ("http://127.0.0.1:8002".to_string(), Offence::InvalidChain),
+            ],
+        });
+
+        // Each offender is banned alone, under its node key or its address and port
+        assert!(blockchain.peer_scores.is_banned("signing-node"));
+        assert!(blockchain.peer_scores.is_banned("127.0.0.1:8002"));
+        assert!(!blockchain.peer_scores.is_banned("127.0.0.1"));
+        assert_eq!(
+            blockchain.consensus_peers(),
+            vec!["http://127.0.0.1:8003".to_string()]
+        );
+    }
+
+    #[test]
+    fn longer_branches_from_peers_replace_ours() {
+        let genesis = Network::Devnet.genesis();
+        let mut ours = Blockchain::from_genesis(&genesis).unwrap();
+        ours.peer_scores = PeerScores::new(BanPolicy {
+            threshold: 1,
+            ..BanPolicy::default()
+        });
+        let mut theirs = Blockchain::from_genesis(&genesis).unwrap();
+        mine(&mut ours, "alice", 1);
+        mine(&mut theirs, "bob", 3);
+        let replaced = ours.chain[1].clone();
+
+        // Their second block builds on a block we do not have: we ask for it
+        assert_eq!(
+            ours.receive_block("peer", theirs.chain[2].clone()).unwrap(),
+            Received::Orphan(theirs.chain[1].hash.clone())
+        );
+        // Their first block competes with ours, and with the second the branch is longer
+        assert_eq!(
+            ours.receive_block("peer", theirs.chain[1].clone()).unwrap(),
+            Received::Added
+        );
+        assert_eq!(ours.get_last_block().hash, theirs.chain[2].hash);
+        assert_eq!(
+            ours.receive_block("peer", theirs.chain[3].clone()).unwrap(),
+            Received::Added
+        );
+        assert_eq!(ours.get_last_block().hash, theirs.chain[3].hash);
+        // The post of the block we switched away from is pending again
+        assert_eq!(ours.unconfirmed_transactions, replaced.transactions);
+        // None of that was an offence
+        assert!(!ours.peer_scores.is_banned("peer"));
+
+        // The block we switched away from is kept, and is now on a shorter branch
+        assert_eq!(
+            ours.receive_block("peer", replaced).unwrap(),
+            Received::Known
+        );
+        let forged = theirs.chain[3].with_modified_hash("forged");
+        assert!(ours.receive_block("peer", forged).is_err());
+        assert!(ours.peer_scores.is_banned("peer"));
     }
 }
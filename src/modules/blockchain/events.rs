@@ -0,0 +1,234 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::fmt;
+use std::str::FromStr;
+use std::sync::{Arc, Mutex};
+use tokio::sync::broadcast;
+
+use super::block::Block;
+use super::chain::NodePeer;
+use crate::modules::api::TransactionRecord;
+
+// Number of events kept for subscribers that fall behind, before they miss some.
+pub const EVENT_BUFFER: usize = 1024;
+
+// NodeEvent is something that happened to the chain of a node, published as it happens.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+#[serde(tag = "type", content = "data", rename_all = "snake_case")]
+pub enum NodeEvent {
+    // A transaction entered the mempool.
+    Transaction(TransactionRecord),
+    // A block was added to the chain, mined here, sent by a peer or adopted by consensus.
+    Block(Block),
+    // Consensus replaced blocks of our chain with those of a longer one.
+    Reorg(Reorg),
+    // A node was added to our peers.
+    Peer(NodePeer),
+}
+
+// Reorg describes the blocks consensus replaced. The blocks of the new chain above the fork
+// follow as block events.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Reorg {
+    // Height of the last block both chains share.
+    pub fork_height: i32,
+    pub old_tip_height: i32,
+    pub old_tip_hash: String,
+    pub new_tip_height: i32,
+    pub new_tip_hash: String,
+}
+
+// EventTopic is the kind of an event, which subscribers filter on.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum EventTopic {
+    Transaction,
+    Block,
+    Reorg,
+    Peer,
+}
+
+impl EventTopic {
+    pub const ALL: [EventTopic; 4] = [
+        EventTopic::Transaction,
+        EventTopic::Block,
+        EventTopic::Reorg,
+        EventTopic::Peer,
+    ];
+
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            EventTopic::Transaction => "transaction",
+            EventTopic::Block => "block",
+            EventTopic::Reorg => "reorg",
+            EventTopic::Peer => "peer",
+        }
+    }
+}
+
+impl fmt::Display for EventTopic {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.write_str(self.as_str())
+    }
+}
+
+impl FromStr for EventTopic {
+    type Err = String;
+
+    fn from_str(topic: &str) -> Result<EventTopic, String> {
+        EventTopic::ALL
+            .iter()
+            .copied()
+            .find(|known| known.as_str() == topic)
+            .ok_or_else(|| format!("Unknown event topic {}", topic))
+    }
+}
+
+impl NodeEvent {
+    pub fn topic(&self) -> EventTopic {
+        match self {
+            NodeEvent::Transaction(_) => EventTopic::Transaction,
+            NodeEvent::Block(_) => EventTopic::Block,
+            NodeEvent::Reorg(_) => EventTopic::Reorg,
+            NodeEvent::Peer(_) => EventTopic::Peer,
+        }
+    }
+}
+
+// EventBus hands the events of a blockchain to its subscribers. The channel is only created
+// once someone subscribes, so that the many blockchains built to check chains of peers cost
+// nothing, and events are only built while someone listens.
+#[derive(Debug, Clone, Default)]
+pub struct EventBus {
+    state: Arc<Mutex<BusState>>,
+}
+
+#[derive(Debug, Default)]
+enum BusState {
+    #[default]
+    Idle,
+    Open(broadcast::Sender<NodeEvent>),
+    Closed,
+}
+
+impl EventBus {
+    pub fn subscribe(&self) -> broadcast::Receiver<NodeEvent> {
+        let mut state = self.state.lock().unwrap();
+        match &*state {
+            BusState::Open(sender) => sender.subscribe(),
+            BusState::Idle => {
+                let (sender, receiver) = broadcast::channel(EVENT_BUFFER);
+                *state = BusState::Open(sender);
+                receiver
+            }
+            // Subscribers of a closed bus are told so right away
+            BusState::Closed => broadcast::channel(1).1,
+        }
+    }
+
+    pub fn publish(&self, event: impl FnOnce() -> NodeEvent) {
+        if let BusState::Open(sender) = &*self.state.lock().unwrap() {
+            if sender.receiver_count() > 0 {
+                // Subscribers may leave between the check and the send, which is fine
+                let _ = sender.send(event());
+            }
+        }
+    }
+
+    // End the subscriptions, once the node shuts down.
+    pub fn close(&self) {
+        *self.state.lock().unwrap() = BusState::Closed;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::{Blockchain, ConsensusRound, Transaction};
+    use crate::modules::blockchain::genesis::Network;
+
+    fn mine(blockchain: &mut Blockchain, author: &str, blocks: i64) {
+        for i in 0..blocks {
+            blockchain.add_new_transaction(Transaction {
+                author: author.to_string(),
+                content: format!("post {}", i),
+                timestamp: 1_700_000_000 + i,
+            });
+            blockchain.mine_block().unwrap();
+        }
+    }
+
+    fn received(receiver: &mut broadcast::Receiver<NodeEvent>) -> Vec<(EventTopic, i32)> {
+        std::iter::from_fn(|| receiver.try_recv().ok())
+            .map(|event| {
+                let height = match &event {
+                    NodeEvent::Block(block) => block.index,
+                    NodeEvent::Reorg(reorg) => reorg.fork_height,
+                    _ => -1,
+                };
+                (event.topic(), height)
+            })
+            .collect()
+    }
+
+    #[test]
+    fn chain_changes_are_published_to_subscribers() {
+        let genesis = Network::Devnet.genesis();
+        let mut ours = Blockchain::from_genesis(&genesis).unwrap();
+        let mut theirs = Blockchain::from_genesis(&genesis).unwrap();
+        mine(&mut ours, "alice", 2);
+        mine(&mut theirs, "bob", 3);
+
+        let mut receiver = ours.events.subscribe();
+        mine(&mut ours, "alice", 1);
+        ours.add_node_peer(NodePeer {
+            node_address: "http://peer".to_string(),
+            handshake: None,
+            outbound: true,
+            key: None,
+        });
+        // Only new peers are announced
+        ours.add_node_peer(NodePeer {
+            node_address: "http://peer".to_string(),
+            handshake: None,
+            outbound: false,
+            key: None,
+        });
+        assert_eq!(
+            received(&mut receiver),
+            vec![
+                (EventTopic::Transaction, -1),
+                (EventTopic::Block, 3),
+                (EventTopic::Peer, -1)
+            ]
+        );
+
+        // A longer chain forking after genesis replaces our blocks
+        mine(&mut theirs, "bob", 1);
+        assert!(ours.apply_consensus(ConsensusRound {
+            longest_chain: Some(theirs.chain.clone()),
+            offences: vec![],
+        }));
+        assert_eq!(
+            received(&mut receiver),
+            vec![
+                (EventTopic::Reorg, 0),
+                (EventTopic::Block, 1),
+                (EventTopic::Block, 2),
+                (EventTopic::Block, 3),
+                (EventTopic::Block, 4)
+            ]
+        );
+
+        // Building on our chain is no reorg
+        mine(&mut theirs, "bob", 1);
+        assert!(ours.adopt_chain(theirs.chain.clone()));
+        assert_eq!(received(&mut receiver), vec![(EventTopic::Block, 5)]);
+
+        ours.events.close();
+        assert!(matches!(
+            receiver.try_recv(),
+            Err(broadcast::error::TryRecvError::Closed)
+        ));
+    }
+}
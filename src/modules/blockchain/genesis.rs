@@ -0,0 +1,153 @@
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+use std::str::FromStr;
+
+use super::block::Block;
+use super::chain::{Transaction, DEFAULT_DIFFICULTY};
+
+// Largest difficulty a sha256 hex digest can satisfy.
+pub const MAX_DIFFICULTY: i32 = 64;
+
+// GenesisSpec describes the first block of a chain and the consensus rules it starts with.
+// The hash of the resulting genesis block identifies the network.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct GenesisSpec {
+    // Name of the network, exchanged in handshakes.
+    pub chain_id: String,
+    // Timestamp of the genesis block.
+    pub timestamp: i64,
+    // Transactions recorded in the genesis block, such as initial posts or allocations.
+    #[serde(default)]
+    pub transactions: Vec<Transaction>,
+    pub consensus: ConsensusParams,
+}
+
+// ConsensusParams are the rules blocks are checked against.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct ConsensusParams {
+    // Number of leading zeros a block hash needs.
+    pub difficulty: i32,
+}
+
+// Network is one of the networks built into the node.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Network {
+    #[default]
+    Mainnet,
+    Testnet,
+    Devnet,
+}
+
+impl fmt::Display for Network {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Network::Mainnet => write!(f, "mainnet"),
+            Network::Testnet => write!(f, "testnet"),
+            Network::Devnet => write!(f, "devnet"),
+        }
+    }
+}
+
+impl FromStr for Network {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "mainnet" => Ok(Network::Mainnet),
+            "testnet" => Ok(Network::Testnet),
+            "devnet" => Ok(Network::Devnet),
+            _ => Err(format!("Unknown network {}", name)),
+        }
+    }
+}
+
+impl Network {
+    // Genesis of the network. Mainnet keeps the genesis block every chain used before networks
+    // existed, so those chains stay valid.
+    pub fn genesis(&self) -> GenesisSpec {
+        let (chain_id, timestamp, difficulty) = match self {
+            Network::Mainnet => ("ultra", 0, DEFAULT_DIFFICULTY),
+            Network::Testnet => ("ultra-testnet", 1_688_169_600, DEFAULT_DIFFICULTY),
+            Network::Devnet => ("ultra-devnet", 1_688_169_600, 1),
+        };
+        let transactions = match self {
+            Network::Mainnet => Vec::new(),
+            _ => vec![Transaction {
+                author: "genesis".to_string(),
+                content: chain_id.to_string(),
+                timestamp,
+            }],
+        };
+        GenesisSpec {
+            chain_id: chain_id.to_string(),
+            timestamp,
+            transactions,
+            consensus: ConsensusParams { difficulty },
+        }
+    }
+}
+
+impl Default for GenesisSpec {
+    fn default() -> Self {
+        Network::default().genesis()
+    }
+}
+
+impl GenesisSpec {
+    // Read a genesis spec from a JSON file and check it.
+    pub fn from_file(path: &Path) -> Result<GenesisSpec, Box<dyn Error>> {
+        let spec: GenesisSpec = serde_json::from_str(&fs::read_to_string(path)?)
+            .map_err(|e| format!("Invalid genesis spec {}: {}", path.display(), e))?;
+        spec.validate()?;
+        Ok(spec)
+    }
+
+    // Check the spec describes a usable chain.
+    pub fn validate(&self) -> Result<(), Box<dyn Error>> {
+        if self.chain_id.is_empty() {
+            return Err("Genesis chain ID must not be empty".into());
+        }
+        if !(0..=MAX_DIFFICULTY).contains(&self.consensus.difficulty) {
+            return Err(format!(
+                "Genesis difficulty must be between 0 and {}",
+                MAX_DIFFICULTY
+            )
+            .into());
+        }
+        if self
+            .transactions
+            .iter()
+            .any(|tx| tx.author.is_empty() || tx.content.is_empty())
+        {
+            return Err("Genesis transactions need an author and content".into());
+        }
+        Ok(())
+    }
+
+    // Build the genesis block described by the spec.
+    pub fn block(&self) -> Result<Block, Box<dyn Error>> {
+        let block = Block {
+            index: 0,
+            transactions: self.transactions.clone(),
+            timestamp: self.timestamp,
+            previous_hash: "0".to_string(),
+            nonce: 0,
+            hash: "".to_string(),
+        };
+        let hash = block.compute_hash()?;
+        Ok(Block { hash, ..block })
+    }
+
+    // Hash of the genesis block, which identifies the network. It does not cover the consensus
+    // parameters: handshakes compare them on their own, and load_chain refuses to run a chain
+    // with parameters other than the ones it was created with.
+    pub fn hash(&self) -> Result<String, Box<dyn Error>> {
+        Ok(self.block()?.hash)
+    }
+}
@@ -1,2 +1,6 @@
+pub mod archive;
 pub mod block;
 pub mod chain;
+pub mod events;
+pub mod genesis;
+pub mod verify;
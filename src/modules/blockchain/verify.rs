@@ -0,0 +1,370 @@
+use serde::Serialize;
+use std::collections::{BTreeMap, HashSet};
+use std::fmt;
+
+use super::block::Block;
+use super::genesis::GenesisSpec;
+
+// Largest number of seconds a block timestamp may be ahead of our clock.
+pub const MAX_FUTURE_DRIFT_SECS: i64 = 2 * 60 * 60;
+
+// Rule is a check a chain is verified against. Blocks commit to their transactions through
+// their hash only; they carry no merkle root or signatures, so there are no rules for those.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Serialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Rule {
+    // The first block is the one described by the genesis spec.
+    Genesis,
+    // Heights count up from 0 without gaps.
+    Height,
+    // Every block links to the hash of the block before it.
+    Link,
+    // The hash of a block matches its contents.
+    Hash,
+    // The hash of a block has the number of leading zeros the difficulty asks for.
+    ProofOfWork,
+    // Timestamps do not go back in time or too far ahead of our clock.
+    Timestamp,
+    // Transactions have an author and content, and are not recorded twice.
+    Transaction,
+}
+
+impl Rule {
+    // Whether breaking the rule makes nodes reject the chain. Other rules are reported by the
+    // verifier but not enforced by consensus.
+    pub fn is_consensus(&self) -> bool {
+        matches!(
+            self,
+            Rule::Genesis | Rule::Link | Rule::Hash | Rule::ProofOfWork
+        )
+    }
+}
+
+impl fmt::Display for Rule {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Rule::Genesis => write!(f, "genesis"),
+            Rule::Height => write!(f, "height"),
+            Rule::Link => write!(f, "link"),
+            Rule::Hash => write!(f, "hash"),
+            Rule::ProofOfWork => write!(f, "proof-of-work"),
+            Rule::Timestamp => write!(f, "timestamp"),
+            Rule::Transaction => write!(f, "transaction"),
+        }
+    }
+}
+
+// Violation is a rule broken by a block.
+#[derive(Debug, Clone, Serialize)]
+pub struct Violation {
+    // Position of the block in the chain, which is its height on a valid chain.
+    pub position: usize,
+    pub index: i32,
+    pub hash: String,
+    pub rule: Rule,
+    pub detail: String,
+}
+
+impl fmt::Display for Violation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "block {} (index {}, hash {}): {}: {}",
+            self.position, self.index, self.hash, self.rule, self.detail
+        )
+    }
+}
+
+// VerifyReport is the outcome of verifying a chain: every violation found and statistics.
+#[derive(Debug, Clone, Serialize)]
+pub struct VerifyReport {
+    pub chain_id: String,
+    pub difficulty: i32,
+    pub blocks: usize,
+    pub transactions: usize,
+    pub tip: Option<String>,
+    // Timestamps of the first block after genesis and of the tip.
+    pub first_timestamp: Option<i64>,
+    pub last_timestamp: Option<i64>,
+    // Mean number of seconds between blocks after genesis, when there are at least two.
+    pub mean_block_interval: Option<f64>,
+    pub violations: Vec<Violation>,
+}
+
+impl VerifyReport {
+    // Whether the chain breaks no rule at all.
+    pub fn is_valid(&self) -> bool {
+        self.violations.is_empty()
+    }
+
+    // Whether the chain is accepted by consensus, which does not enforce every rule.
+    pub fn is_consensus_valid(&self) -> bool {
+        !self.violations.iter().any(|v| v.rule.is_consensus())
+    }
+
+    // Number of violations of each rule that was broken.
+    pub fn violations_by_rule(&self) -> BTreeMap<Rule, usize> {
+        let mut counts = BTreeMap::new();
+        for violation in &self.violations {
+            *counts.entry(violation.rule).or_insert(0) += 1;
+        }
+        counts
+    }
+}
+
+// Verify a chain against its genesis spec and difficulty, checking every block against every
+// rule instead of stopping at the first violation. now is the time on our clock.
+pub fn verify_chain(
+    chain: &[Block],
+    genesis: &GenesisSpec,
+    difficulty: i32,
+    now: i64,
+) -> VerifyReport {
+    let mut violations = Vec::new();
+    let mut violation = |position: usize, block: &Block, rule: Rule, detail: String| {
+        violations.push(Violation {
+            position,
+            index: block.index,
+            hash: block.hash.clone(),
+            rule,
+            detail,
+        })
+    };
+
+    match (chain.first(), genesis.hash()) {
+        (None, _) => {}
+        (Some(first), Ok(genesis_hash)) if first.hash == genesis_hash => {}
+        (Some(first), Ok(genesis_hash)) => violation(
+            0,
+            first,
+            Rule::Genesis,
+            format!(
+                "expected the genesis block of {} with hash {}",
+                genesis.chain_id, genesis_hash
+            ),
+        ),
+        (Some(first), Err(e)) => violation(
+            0,
+            first,
+            Rule::Genesis,
+            format!("genesis spec cannot be hashed: {}", e),
+        ),
+    }
+
+    let prefix = "0".repeat(difficulty.max(0) as usize);
+    let mut seen_transactions = HashSet::new();
+    for (position, block) in chain.iter().enumerate() {
+        if block.index as i64 != position as i64 {
+            violation(
+                position,
+                block,
+                Rule::Height,
+                format!("index {} at height {}", block.index, position),
+            );
+        }
+        for transaction in &block.transactions {
+            if transaction.author.is_empty() || transaction.content.is_empty() {
+                violation(
+                    position,
+                    block,
+                    Rule::Transaction,
+                    "transaction without author or content".to_string(),
+                );
+            }
+            if let Ok(hash) = transaction.compute_hash() {
+                if !seen_transactions.insert(hash.clone()) {
+                    violation(
+                        position,
+                        block,
+                        Rule::Transaction,
+                        format!("transaction {} recorded twice", hash),
+                    );
+                }
+            }
+        }
+        if block.timestamp > now + MAX_FUTURE_DRIFT_SECS {
+            violation(
+                position,
+                block,
+                Rule::Timestamp,
+                format!(
+                    "timestamp {} is more than {}s ahead of our clock",
+                    block.timestamp, MAX_FUTURE_DRIFT_SECS
+                ),
+            );
+        }
+        // The genesis block is checked against its spec, not the rules mined blocks follow
+        let previous = match position.checked_sub(1) {
+            Some(previous) => &chain[previous],
+            None => continue,
+        };
+
+        if block.previous_hash != previous.hash {
+            violation(
+                position,
+                block,
+                Rule::Link,
+                format!(
+                    "previous hash {} does not match block {} hash {}",
+                    block.previous_hash,
+                    position - 1,
+                    previous.hash
+                ),
+            );
+        }
+        match block.proof_hash() {
+            Ok(computed) if computed == block.hash => {}
+            Ok(computed) => violation(
+                position,
+                block,
+                Rule::Hash,
+                format!("contents hash to {}", computed),
+            ),
+            Err(e) => violation(
+                position,
+                block,
+                Rule::Hash,
+                format!("contents cannot be hashed: {}", e),
+            ),
+        }
+        if !block.hash.starts_with(&prefix) {
+            violation(
+                position,
+                block,
+                Rule::ProofOfWork,
+                format!("hash has fewer than {} leading zeros", difficulty),
+            );
+        }
+        if block.timestamp < previous.timestamp {
+            violation(
+                position,
+                block,
+                Rule::Timestamp,
+                format!(
+                    "timestamp {} is before the one of block {}, {}",
+                    block.timestamp,
+                    position - 1,
+                    previous.timestamp
+                ),
+            );
+        }
+    }
+
+    let mined = chain.get(1..).unwrap_or_default();
+    let first_timestamp = mined.first().map(|block| block.timestamp);
+    let last_timestamp = mined.last().map(|block| block.timestamp);
+    let mean_block_interval = match (first_timestamp, last_timestamp) {
+        (Some(first), Some(last)) if mined.len() > 1 => {
+            Some((last - first) as f64 / (mined.len() - 1) as f64)
+        }
+        _ => None,
+    };
+    VerifyReport {
+        chain_id: genesis.chain_id.clone(),
+        difficulty,
+        blocks: chain.len(),
+        transactions: chain.iter().map(|block| block.transactions.len()).sum(),
+        tip: chain.last().map(|block| block.hash.clone()),
+        first_timestamp,
+        last_timestamp,
+        mean_block_interval,
+        violations,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::{Blockchain, Transaction};
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::clock::{Clock, ManualClock, SharedClock};
+
+    fn mined_chain(blocks: usize) -> Blockchain {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        blockchain.set_clock(SharedClock::new(clock.clone()));
+        for i in 0..blocks {
+            clock.advance(60);
+            blockchain.add_new_transaction(Transaction {
+                author: "author".to_string(),
+                content: format!("post {}", i),
+                timestamp: clock.now(),
+            });
+            blockchain.mine_block().unwrap();
+        }
+        blockchain
+    }
+
+    fn rules(report: &VerifyReport) -> Vec<(usize, Rule)> {
+        report
+            .violations
+            .iter()
+            .map(|v| (v.position, v.rule))
+            .collect()
+    }
+
+    #[test]
+    fn mined_chain_is_valid() {
+        let blockchain = mined_chain(3);
+        let report = blockchain.verify();
+        assert!(report.is_valid(), "{:?}", report.violations);
+        assert_eq!(report.blocks, 4);
+        assert_eq!(report.transactions, 4);
+        assert_eq!(report.mean_block_interval, Some(60.0));
+        assert!(blockchain.check_chain_validity());
+    }
+
+    #[test]
+    fn every_violation_is_reported_with_its_block() {
+        let mut blockchain = mined_chain(4);
+        // Tampering with a transaction breaks the hash of block 1 only
+        blockchain.chain[1].transactions[0].content = "edited".to_string();
+        // A broken link on block 3
+        blockchain.chain[3].previous_hash = "0".repeat(64);
+        // Going back in time on block 4, without touching its hash
+        blockchain.chain[4].timestamp = 0;
+
+        let report = blockchain.verify();
+        let found = rules(&report);
+        assert!(found.contains(&(1, Rule::Hash)));
+        assert!(found.contains(&(3, Rule::Link)));
+        assert!(found.contains(&(3, Rule::Hash)));
+        assert!(found.contains(&(4, Rule::Timestamp)));
+        assert!(!found.iter().any(|(position, _)| *position == 2));
+        assert!(!blockchain.check_chain_validity());
+    }
+
+    #[test]
+    fn repeated_block_breaks_height_link_and_transaction_rules() {
+        let mut blockchain = mined_chain(1);
+        blockchain.chain.push(blockchain.chain[1].clone());
+        assert_eq!(
+            rules(&blockchain.verify()),
+            vec![(2, Rule::Height), (2, Rule::Transaction), (2, Rule::Link)]
+        );
+    }
+
+    #[test]
+    fn insufficient_work_is_reported() {
+        let mut blockchain = mined_chain(1);
+        blockchain.difficulty = 64;
+        assert_eq!(rules(&blockchain.verify()), vec![(1, Rule::ProofOfWork)]);
+    }
+
+    #[test]
+    fn future_timestamps_do_not_break_consensus() {
+        let blockchain = mined_chain(2);
+        let report = verify_chain(
+            &blockchain.chain,
+            &blockchain.genesis,
+            blockchain.difficulty,
+            1_690_000_000,
+        );
+        assert_eq!(
+            rules(&report),
+            vec![(1, Rule::Timestamp), (2, Rule::Timestamp)]
+        );
+        assert!(!report.is_valid());
+        assert!(report.is_consensus_valid());
+    }
+}
@@ -0,0 +1,959 @@
+use actix_web::dev::{Server, ServerHandle};
+use actix_web::web;
+use clap::{Args, Parser, Subcommand};
+use serde::de::DeserializeOwned;
+use std::error::Error;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::{Path, PathBuf};
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+use tokio::{select, signal::ctrl_c, sync::mpsc, time::sleep};
+use tracing::{info, warn};
+
+use super::api::{
+    status::NodeStatus, Envelope, MineResult, NewPeer, NewTransaction, PeerInfo, PeerList,
+    TransactionRecord, API_PREFIX,
+};
+use super::app::{load_chain, spawn_miner, start_node, Application, NodeListeners, NodeOptions};
+use super::blockchain::{
+    archive::{ArchiveFormat, BlockReader, BlockWriter},
+    block::Block,
+    chain::{Blockchain, CHAIN_FILE},
+    genesis::{GenesisSpec, Network},
+    verify::{verify_chain, VerifyReport},
+};
+use super::client::start_client;
+use super::clock::SharedClock;
+use super::config::Config;
+use super::logging::{self, LogFormat};
+use super::network::auth::{NodeAuth, NodeIdentity, KEY_FILE};
+use super::network::discovery::DiscoveryConfig;
+
+// Time between discovery rounds of devnet nodes, short so they find each other quickly.
+const DEVNET_DISCOVERY_INTERVAL: Duration = Duration::from_secs(5);
+// Offset between the API port and the peer-to-peer port of a devnet node with fixed ports.
+const DEVNET_P2P_PORT_OFFSET: u16 = 1000;
+// Number of blocks between progress reports of export and import.
+const PROGRESS_INTERVAL: usize = 1000;
+// How long the devnet waits for its nodes to peer with each other before reporting them.
+const DEVNET_WIRING_TIMEOUT: Duration = Duration::from_secs(10);
+
+// Cli is the command line of the versus binary. Flags override the settings loaded by Config.
+#[derive(Debug, Parser)]
+#[command(
+    name = "versus",
+    version,
+    about = "Run and operate ultra blockchain nodes"
+)]
+pub struct Cli {
+    #[arg(
+        long,
+        global = true,
+        env = "VERSUS_CONFIG",
+        help = "TOML file to read settings from [default: versus.toml, if present]"
+    )]
+    pub config: Option<PathBuf>,
+    #[arg(
+        long,
+        global = true,
+        help = "Format of log events: text, pretty or json"
+    )]
+    pub log_format: Option<LogFormat>,
+    #[arg(
+        long,
+        global = true,
+        help = "Log level, optionally per module, e.g. info,versus::modules::network=debug"
+    )]
+    pub log_level: Option<String>,
+    #[command(subcommand)]
+    pub command: Command,
+}
+
+// Command is a subcommand of the versus binary.
+#[derive(Debug, Subcommand)]
+pub enum Command {
+    #[command(about = "Run a node, optionally with the web client next to it")]
+    Node(NodeArgs),
+    #[command(about = "Run the web client against a node")]
+    Client(ClientArgs),
+    #[command(about = "Run several nodes in this process, peered with each other")]
+    Devnet(DevnetArgs),
+    #[command(about = "Ask a running node to mine its pending transactions")]
+    Mine(RemoteArgs),
+    #[command(about = "Show the chain, peers, mempool and mining state of a running node")]
+    Status(StatusArgs),
+    #[command(about = "Check every block of a chain, reporting each rule it breaks")]
+    VerifyChain(VerifyArgs),
+    #[command(
+        about = "Write blocks of the chain kept in a data directory as JSON Lines or binary"
+    )]
+    Export(ExportArgs),
+    #[command(about = "Append exported blocks to the chain kept in a data directory")]
+    Import(ImportArgs),
+    #[command(subcommand, about = "Manage the peers of a running node")]
+    Peers(PeersCommand),
+    #[command(subcommand, about = "Manage the transactions of a running node")]
+    Tx(TxCommand),
+    #[command(about = "Print the genesis spec of a network, to start a custom network from")]
+    Genesis(ChainArgs),
+    #[command(about = "Print the OpenAPI document of the node API")]
+    Openapi,
+}
+
+// DataArgs select the data directory a command works on.
+#[derive(Debug, Args)]
+pub struct DataArgs {
+    #[arg(long, help = "Directory holding the chain, node key and ban list")]
+    pub data_dir: Option<PathBuf>,
+}
+
+// ChainArgs select the network a command works on.
+#[derive(Debug, Args)]
+pub struct ChainArgs {
+    #[arg(long, help = "Built-in network to run: mainnet, testnet or devnet")]
+    pub network: Option<Network>,
+    #[arg(long, help = "JSON genesis spec of a custom network")]
+    pub genesis: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Difficulty overriding the one of the genesis spec, for new chains only"
+    )]
+    pub difficulty: Option<i32>,
+}
+
+// RemoteArgs select the running node a command talks to.
+#[derive(Debug, Args)]
+pub struct RemoteArgs {
+    #[arg(long, help = "URL of the node API")]
+    pub node: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct StatusArgs {
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+    #[arg(long, help = "Print the status as JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct NodeArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    #[arg(long, help = "Address the node API listens on")]
+    pub bind: Option<String>,
+    #[arg(long, help = "Address the peer-to-peer transport listens on")]
+    pub p2p_bind: Option<String>,
+    #[arg(
+        long,
+        value_delimiter = ',',
+        help = "Node API URLs to look for peers at first"
+    )]
+    pub peers: Option<Vec<String>>,
+    #[command(flatten)]
+    pub chain: ChainArgs,
+    #[arg(
+        long = "allow",
+        value_delimiter = ',',
+        help = "Public keys of the only nodes allowed to talk to this node"
+    )]
+    pub allowlist: Option<Vec<String>>,
+    #[arg(long, help = "Misbehaviour score at which a peer gets banned")]
+    pub ban_threshold: Option<u32>,
+    #[arg(long, help = "Seconds a peer ban lasts")]
+    pub ban_secs: Option<u64>,
+    #[arg(long, help = "Mine pending transactions in the background")]
+    pub auto_mine: bool,
+    #[arg(long, help = "Also serve the web client")]
+    pub with_client: bool,
+    #[arg(long, help = "Address the web client listens on")]
+    pub client_bind: Option<String>,
+}
+
+#[derive(Debug, Args)]
+pub struct ClientArgs {
+    #[arg(long, help = "Address the web client listens on")]
+    pub bind: Option<String>,
+    #[command(flatten)]
+    pub remote: RemoteArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct DevnetArgs {
+    #[arg(long, default_value_t = 3, help = "Number of nodes to run")]
+    pub nodes: u16,
+    #[arg(long, default_value = "127.0.0.1", help = "Host the nodes listen on")]
+    pub host: String,
+    #[arg(
+        long,
+        help = "API port of the first node; the others follow it [default: ephemeral ports]"
+    )]
+    pub base_port: Option<u16>,
+    #[arg(
+        long,
+        help = "Directory holding one data directory per node [default: <data_dir>/devnet]"
+    )]
+    pub data_dir: Option<PathBuf>,
+    #[command(flatten)]
+    pub chain: ChainArgs,
+    #[arg(
+        long,
+        help = "Mine pending transactions in the background on every node"
+    )]
+    pub auto_mine: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct VerifyArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    #[arg(
+        long,
+        help = "Chain to check instead of the one in the data directory: a .json chain dump, \
+                or an export checked against the network given by the chain flags"
+    )]
+    pub file: Option<PathBuf>,
+    #[command(flatten)]
+    pub chain: ChainArgs,
+    #[arg(long, help = "Print the report as JSON")]
+    pub json: bool,
+}
+
+#[derive(Debug, Args)]
+pub struct ExportArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    #[arg(
+        long,
+        short,
+        help = "File to write the blocks to, instead of standard output"
+    )]
+    pub output: Option<PathBuf>,
+    #[arg(
+        long,
+        help = "Format of the blocks: jsonl or binary [default: binary for .bin files, else jsonl]"
+    )]
+    pub format: Option<ArchiveFormat>,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+#[derive(Debug, Args)]
+pub struct ImportArgs {
+    #[command(flatten)]
+    pub data: DataArgs,
+    #[arg(help = "File holding the exported blocks, in either format, or - for standard input")]
+    pub input: PathBuf,
+    #[command(flatten)]
+    pub chain: ChainArgs,
+    #[command(flatten)]
+    pub range: RangeArgs,
+}
+
+// RangeArgs select the heights of the blocks a command works on.
+#[derive(Debug, Args)]
+pub struct RangeArgs {
+    #[arg(long, help = "Height of the first block, inclusive [default: 0]")]
+    pub from: Option<i32>,
+    #[arg(
+        long,
+        help = "Height of the last block, inclusive [default: the last one]"
+    )]
+    pub to: Option<i32>,
+}
+
+impl RangeArgs {
+    fn check(&self) -> Result<(), Box<dyn Error>> {
+        match (self.from, self.to) {
+            (Some(from), Some(to)) if from > to => {
+                Err(format!("Height range {}..={} is empty", from, to).into())
+            }
+            _ => Ok(()),
+        }
+    }
+
+    fn contains(&self, height: i32) -> bool {
+        self.from.is_none_or(|from| height >= from) && self.to.is_none_or(|to| height <= to)
+    }
+}
+
+#[derive(Debug, Subcommand)]
+pub enum PeersCommand {
+    #[command(about = "Register the node with another node")]
+    Add {
+        #[arg(help = "URL of the node API to register with")]
+        address: String,
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+    #[command(about = "List the peers of the node")]
+    List(RemoteArgs),
+}
+
+#[derive(Debug, Subcommand)]
+pub enum TxCommand {
+    #[command(about = "Submit a transaction to the node's mempool")]
+    Submit {
+        #[arg(long)]
+        author: String,
+        #[arg(long)]
+        content: String,
+        #[command(flatten)]
+        remote: RemoteArgs,
+    },
+}
+
+impl DataArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(data_dir) = &self.data_dir {
+            config.data_dir = data_dir.clone();
+        }
+    }
+}
+
+impl ChainArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(network) = self.network {
+            config.chain.network = network;
+        }
+        if self.genesis.is_some() {
+            config.chain.genesis_file = self.genesis.clone();
+        }
+        if self.difficulty.is_some() {
+            config.chain.difficulty = self.difficulty;
+        }
+    }
+}
+
+impl RemoteArgs {
+    fn apply(&self, config: &mut Config) {
+        if let Some(node) = &self.node {
+            config.client.node_url = node.clone();
+        }
+    }
+}
+
+impl NodeArgs {
+    fn apply(&self, config: &mut Config) {
+        self.data.apply(config);
+        if let Some(bind) = &self.bind {
+            config.node.address = bind.clone();
+        }
+        if let Some(p2p_bind) = &self.p2p_bind {
+            config.node.p2p_address = p2p_bind.clone();
+        }
+        if let Some(peers) = &self.peers {
+            config.peers.seeds = peers.clone();
+        }
+        self.chain.apply(config);
+        if self.allowlist.is_some() {
+            config.node.allowlist = self.allowlist.clone();
+        }
+        if let Some(ban_threshold) = self.ban_threshold {
+            config.peers.ban_threshold = ban_threshold;
+        }
+        if let Some(ban_secs) = self.ban_secs {
+            config.peers.ban_secs = ban_secs;
+        }
+        if self.auto_mine {
+            config.mining.auto_mine = true;
+        }
+        if let Some(client_bind) = &self.client_bind {
+            config.client.address = client_bind.clone();
+        }
+    }
+}
+
+impl Command {
+    // Override the loaded settings with the flags given to the command.
+    fn apply(&self, config: &mut Config) {
+        match self {
+            Command::Node(args) => args.apply(config),
+            Command::Client(args) => {
+                args.remote.apply(config);
+                if let Some(bind) = &args.bind {
+                    config.client.address = bind.clone();
+                }
+            }
+            Command::Devnet(args) => {
+                // A devnet runs the devnet network unless told otherwise
+                config.chain.network = Network::Devnet;
+                config.chain.genesis_file = None;
+                args.chain.apply(config);
+                if args.auto_mine {
+                    config.mining.auto_mine = true;
+                }
+            }
+            Command::VerifyChain(args) => {
+                args.data.apply(config);
+                args.chain.apply(config);
+            }
+            Command::Export(args) => args.data.apply(config),
+            Command::Import(args) => {
+                args.data.apply(config);
+                args.chain.apply(config);
+            }
+            Command::Mine(remote)
+            | Command::Status(StatusArgs { remote, .. })
+            | Command::Peers(PeersCommand::Add { remote, .. })
+            | Command::Peers(PeersCommand::List(remote))
+            | Command::Tx(TxCommand::Submit { remote, .. }) => remote.apply(config),
+            Command::Genesis(chain) => chain.apply(config),
+            Command::Openapi => {}
+        }
+    }
+}
+
+impl Cli {
+    // Load and check the settings, then run the chosen command.
+    pub async fn run(self) -> Result<(), Box<dyn Error>> {
+        let mut config = Config::load(self.config.as_deref())?;
+        self.command.apply(&mut config);
+        if let Some(format) = self.log_format {
+            config.log.format = format;
+        }
+        if let Some(level) = &self.log_level {
+            config.log.level = level.clone();
+        }
+        config.validate()?;
+        logging::init(config.log.format, &config.log.level)?;
+
+        match self.command {
+            Command::Node(args) => run_node(&config, args.with_client).await,
+            Command::Client(_) => run_client(&config).await,
+            Command::Devnet(args) => run_devnet(&config, args).await,
+            Command::Mine(_) => {
+                let result: MineResult =
+                    request(&config, reqwest::Method::POST, "/mine", None).await?;
+                match result.block {
+                    Some(block) => println!(
+                        "Mined block {} ({}) with {} transactions",
+                        block.index,
+                        block.hash,
+                        block.transactions.len()
+                    ),
+                    None => println!("No transaction to mine"),
+                }
+                println!("Chain length: {}", result.length);
+                Ok(())
+            }
+            Command::Status(args) => {
+                let status: NodeStatus =
+                    request(&config, reqwest::Method::GET, "/status", None).await?;
+                match args.json {
+                    true => println!("{}", serde_json::to_string_pretty(&status)?),
+                    false => print_status(&status),
+                }
+                Ok(())
+            }
+            Command::VerifyChain(args) => verify(&config, args),
+            Command::Genesis(_) => print_genesis(&config.genesis()?),
+            Command::Openapi => {
+                println!("{}", serde_json::to_string_pretty(Application::openapi())?);
+                Ok(())
+            }
+            Command::Export(args) => export(&config, args),
+            Command::Import(args) => import(&config, args),
+            Command::Peers(PeersCommand::Add { address, .. }) => {
+                let body = serde_json::to_value(NewPeer {
+                    node_address: address,
+                })?;
+                let peer: PeerInfo =
+                    request(&config, reqwest::Method::POST, "/peers", Some(body)).await?;
+                println!("Registered with {}", peer.node_address);
+                Ok(())
+            }
+            Command::Peers(PeersCommand::List(_)) => {
+                let list: PeerList = request(&config, reqwest::Method::GET, "/peers", None).await?;
+                for peer in list.peers {
+                    println!("{}", peer.node_address);
+                }
+                Ok(())
+            }
+            Command::Tx(TxCommand::Submit {
+                author, content, ..
+            }) => {
+                let body = serde_json::to_value(NewTransaction { author, content })?;
+                let record: TransactionRecord =
+                    request(&config, reqwest::Method::POST, "/transactions", Some(body)).await?;
+                println!("Submitted transaction {}", record.hash);
+                Ok(())
+            }
+        }
+    }
+}
+
+// Start a node whose identity and chain live in the given data directory.
+fn open_node(
+    options: &NodeOptions,
+    allowlist: Option<Vec<String>>,
+) -> Result<Application, Box<dyn Error>> {
+    let identity = NodeIdentity::load_or_generate(&options.data_dir.join(KEY_FILE))?;
+    Application::new(NodeAuth::new(identity, allowlist), options)
+}
+
+// Run a node, and the web client if asked to, until a termination signal arrives.
+async fn run_node(config: &Config, with_client: bool) -> Result<(), Box<dyn Error>> {
+    let mut options = config.node_options()?;
+    let listeners = NodeListeners::bind(&mut options)?;
+    let app = open_node(&options, config.node.allowlist.clone())?;
+
+    let mut servers = vec![start_node(&app, listeners, config.discovery()).await?];
+    if let Some(interval) = config.auto_mine_interval() {
+        spawn_miner(&app, interval);
+    }
+    info!(
+        chain_id = %options.genesis.chain_id,
+        p2p_address = %options.p2p_address,
+        "Node listening on http://{}",
+        options.node_address
+    );
+    if with_client {
+        let node_url = format!("http://{}", options.node_address);
+        servers.push(start_client(&config.client.address, &node_url)?);
+        info!("Client listening on http://{}", config.client.address);
+    }
+
+    // Cancel mining and end event streams first so they do not hold up the shutdown
+    let result = serve_until_shutdown(servers, || {
+        app.cancel_mining();
+        app.events.close();
+    })
+    .await;
+    app.flush()?;
+    Ok(result?)
+}
+
+// Run the web client until a termination signal arrives.
+async fn run_client(config: &Config) -> Result<(), Box<dyn Error>> {
+    let server = start_client(&config.client.address, &config.client.node_url)?;
+    info!(
+        node_url = %config.client.node_url,
+        "Client listening on http://{}",
+        config.client.address
+    );
+    Ok(serve_until_shutdown(vec![server], || {}).await?)
+}
+
+// Run several nodes in this process until a termination signal arrives. Nodes listen on
+// ephemeral ports unless a base port is given, and each one is seeded with the nodes started
+// before it so they all peer with each other.
+async fn run_devnet(config: &Config, args: DevnetArgs) -> Result<(), Box<dyn Error>> {
+    if args.nodes == 0 {
+        return Err("A devnet needs at least one node".into());
+    }
+    let data_dir = args
+        .data_dir
+        .unwrap_or_else(|| config.data_dir.join("devnet"));
+    let ports = devnet_ports(args.base_port, args.nodes)?;
+    let genesis = config.genesis()?;
+    let mut apps: Vec<(Application, NodeOptions)> = Vec::new();
+    let mut servers = Vec::new();
+    for (i, (port, p2p_port)) in ports.into_iter().enumerate() {
+        let mut options = NodeOptions {
+            data_dir: data_dir.join(format!("node-{}", i)),
+            node_address: format!("{}:{}", args.host, port),
+            p2p_address: format!("{}:{}", args.host, p2p_port),
+            genesis: genesis.clone(),
+            ban_policy: config.ban_policy(),
+            ..NodeOptions::default()
+        };
+        let listeners = NodeListeners::bind(&mut options)?;
+        let app = open_node(&options, None)?;
+        // Peers remembered from an earlier run listened on other ports
+        app.blockchain
+            .lock()
+            .map_err(|_| "Unable to lock blockchain")?
+            .peers
+            .clear();
+        let discovery = DiscoveryConfig {
+            seeds: apps
+                .iter()
+                .map(|(_, options)| format!("http://{}", options.node_address))
+                .collect(),
+            interval: DEVNET_DISCOVERY_INTERVAL,
+            ..config.discovery()
+        };
+        servers.push(start_node(&app, listeners, discovery).await?);
+        if let Some(interval) = config.auto_mine_interval() {
+            spawn_miner(&app, interval);
+        }
+        apps.push((app, options));
+    }
+
+    // Report the nodes once they found each other, which needs the servers to be running
+    let nodes: Vec<(web::Data<Mutex<Blockchain>>, NodeOptions)> = apps
+        .iter()
+        .map(|(app, options)| (app.blockchain.clone(), options.clone()))
+        .collect();
+    tokio::spawn(report_devnet(genesis.chain_id.clone(), nodes));
+
+    let result = serve_until_shutdown(servers, || {
+        for (app, _) in &apps {
+            app.cancel_mining();
+            app.events.close();
+        }
+    })
+    .await;
+    for (app, _) in apps {
+        app.flush()?;
+    }
+    Ok(result?)
+}
+
+// API and peer-to-peer ports of each devnet node: ephemeral without a base port, else following
+// the base port, as long as every port fits in a u16.
+fn devnet_ports(base_port: Option<u16>, nodes: u16) -> Result<Vec<(u16, u16)>, Box<dyn Error>> {
+    (0..nodes)
+        .map(|i| match base_port {
+            Some(base_port) => base_port
+                .checked_add(i)
+                .and_then(|port| Some((port, port.checked_add(DEVNET_P2P_PORT_OFFSET)?)))
+                .ok_or_else(|| {
+                    format!(
+                        "Base port {} leaves no room for the ports of {} nodes",
+                        base_port, nodes
+                    )
+                    .into()
+                }),
+            None => Ok((0, 0)),
+        })
+        .collect()
+}
+
+// Wait until every devnet node knows all the others, or the wiring timeout elapses, then print
+// where the nodes listen.
+async fn report_devnet(chain_id: String, nodes: Vec<(web::Data<Mutex<Blockchain>>, NodeOptions)>) {
+    let peer_count = |blockchain: &web::Data<Mutex<Blockchain>>| {
+        blockchain.lock().map(|b| b.peers.len()).unwrap_or(0)
+    };
+    let deadline = Instant::now() + DEVNET_WIRING_TIMEOUT;
+    let wired = loop {
+        if nodes
+            .iter()
+            .all(|(blockchain, _)| peer_count(blockchain) + 1 >= nodes.len())
+        {
+            break true;
+        }
+        if Instant::now() >= deadline {
+            break false;
+        }
+        sleep(Duration::from_millis(100)).await;
+    };
+
+    println!("Devnet {} with {} nodes", chain_id, nodes.len());
+    for (i, (blockchain, options)) in nodes.iter().enumerate() {
+        println!(
+            "  node {}: http://{}  peers on {}  data in {}  ({} peers)",
+            i,
+            options.node_address,
+            options.p2p_address,
+            options.data_dir.display(),
+            peer_count(blockchain)
+        );
+    }
+    if !wired {
+        warn!("Some nodes have not found all their peers yet; discovery keeps trying");
+    }
+}
+
+// Serve until a termination signal arrives or any server stops, then run on_shutdown and stop
+// all servers, letting in-flight requests finish.
+async fn serve_until_shutdown(servers: Vec<Server>, on_shutdown: impl FnOnce()) -> io::Result<()> {
+    let handles: Vec<ServerHandle> = servers.iter().map(Server::handle).collect();
+    let (stopped_tx, mut stopped_rx) = mpsc::unbounded_channel();
+    for server in servers {
+        let stopped_tx = stopped_tx.clone();
+        tokio::spawn(async move {
+            let _ = stopped_tx.send(server.await);
+        });
+    }
+
+    let result = select! {
+        Some(result) = stopped_rx.recv() => result,
+        _ = ctrl_c() => Ok(()),
+    };
+    info!("Shutting down");
+    on_shutdown();
+    for handle in handles {
+        handle.stop(true).await;
+    }
+    result
+}
+
+// Check every block of a chain file or of the chain kept in the data directory, printing each
+// violation and statistics, and failing if any rule is broken.
+fn verify(config: &Config, args: VerifyArgs) -> Result<(), Box<dyn Error>> {
+    let source = args
+        .file
+        .clone()
+        .unwrap_or_else(|| config.data_dir.join(CHAIN_FILE));
+    let report = match &args.file {
+        Some(file) if file.extension().and_then(|ext| ext.to_str()) != Some("json") => {
+            let blocks = BlockReader::open(BufReader::new(File::open(file)?))?
+                .collect::<Result<Vec<Block>, _>>()?;
+            let genesis = config.genesis()?;
+            let now = SharedClock::default().now();
+            verify_chain(&blocks, &genesis, genesis.consensus.difficulty, now)
+        }
+        Some(file) => Blockchain::create_chain_from_file(file)?.verify(),
+        None => {
+            if !source.exists() {
+                return Err(format!("No chain found in {}", source.display()).into());
+            }
+            load_chain(&config.data_dir, None)?.verify()
+        }
+    };
+
+    if args.json {
+        println!("{}", serde_json::to_string_pretty(&report)?);
+    } else {
+        print_report(&source, &report);
+    }
+    if report.is_valid() {
+        return Ok(());
+    }
+    let consensus = if report.is_consensus_valid() {
+        "nodes still accept it"
+    } else {
+        "nodes reject it"
+    };
+    Err(format!(
+        "Chain in {} breaks {} rules; {}",
+        source.display(),
+        report.violations.len(),
+        consensus
+    )
+    .into())
+}
+
+// Print a verification report for people to read.
+fn print_report(source: &Path, report: &VerifyReport) {
+    println!(
+        "Chain in {}: network {}, {} blocks, {} transactions, difficulty {}",
+        source.display(),
+        report.chain_id,
+        report.blocks,
+        report.transactions,
+        report.difficulty
+    );
+    if let Some(tip) = &report.tip {
+        println!("  tip: {}", tip);
+    }
+    if let (Some(first), Some(last), Some(interval)) = (
+        report.first_timestamp,
+        report.last_timestamp,
+        report.mean_block_interval,
+    ) {
+        println!(
+            "  mined between {} and {}, one block every {:.1}s on average",
+            first, last, interval
+        );
+    }
+    if report.is_valid() {
+        println!("  no violations");
+        return;
+    }
+    for (rule, count) in report.violations_by_rule() {
+        println!("  {} {} violations", count, rule);
+    }
+    for violation in &report.violations {
+        println!("  {}", violation);
+    }
+}
+
+fn print_status(status: &NodeStatus) {
+    let chain = &status.chain;
+    println!(
+        "Node {} (protocol {}), up {}s",
+        status.version, status.protocol_version, status.uptime_secs
+    );
+    println!(
+        "  chain {}: height {}, tip {}, difficulty {}",
+        chain.chain_id, chain.height, chain.tip_hash, chain.difficulty
+    );
+    println!(
+        "  {} peers, {} pending transactions",
+        chain.peers, chain.mempool_size
+    );
+    println!(
+        "  mining: {}{}",
+        if status.mining { "in progress" } else { "idle" },
+        if status.auto_mine {
+            ", in the background"
+        } else {
+            ""
+        }
+    );
+    match chain.sync.synced {
+        true => println!("  synced"),
+        false => println!(
+            "  syncing: {:.1}% of height {}",
+            chain.sync.progress * 100.0,
+            chain.sync.best_peer_height
+        ),
+    }
+}
+
+// Stream blocks of the chain kept in the data directory to a file or standard output.
+fn export(config: &Config, args: ExportArgs) -> Result<(), Box<dyn Error>> {
+    args.range.check()?;
+    let blockchain = load_chain(&config.data_dir, None)?;
+    let format = args.format.unwrap_or_else(|| {
+        args.output
+            .as_deref()
+            .map_or(ArchiveFormat::Jsonl, ArchiveFormat::from_path)
+    });
+    let output: Box<dyn Write> = match &args.output {
+        Some(output) => Box::new(BufWriter::new(File::create(output)?)),
+        None => Box::new(BufWriter::new(io::stdout().lock())),
+    };
+
+    let blocks: Vec<&Block> = blockchain
+        .chain
+        .iter()
+        .filter(|block| args.range.contains(block.index))
+        .collect();
+    let mut writer = BlockWriter::new(output, format)?;
+    for (done, block) in blocks.iter().enumerate() {
+        writer.write(block)?;
+        if (done + 1) % PROGRESS_INTERVAL == 0 {
+            eprintln!("Exported {}/{} blocks", done + 1, blocks.len());
+        }
+    }
+    let exported = writer.finish()?;
+    let destination = args.output.map_or("standard output".to_string(), |output| {
+        output.display().to_string()
+    });
+    eprintln!(
+        "Exported {} blocks as {} to {}",
+        exported, format, destination
+    );
+    Ok(())
+}
+
+// Stream exported blocks into the chain kept in the data directory. Blocks the chain already
+// has must match it, and every new block is checked by add_block. Nothing is written unless
+// every block is accepted.
+fn import(config: &Config, args: ImportArgs) -> Result<(), Box<dyn Error>> {
+    args.range.check()?;
+    let mut blockchain = load_chain(&config.data_dir, Some(&config.genesis()?))?;
+    let input: Box<dyn BufRead> = if args.input.as_os_str() == "-" {
+        Box::new(BufReader::new(io::stdin().lock()))
+    } else {
+        Box::new(BufReader::new(File::open(&args.input)?))
+    };
+
+    let reader = BlockReader::open(input)?;
+    let format = reader.format();
+    let (mut read, mut imported) = (0, 0);
+    for block in reader {
+        let block = block?;
+        read += 1;
+        if read % PROGRESS_INTERVAL == 0 {
+            eprintln!(
+                "Read {} blocks, imported {}, at height {}",
+                read, imported, block.index
+            );
+        }
+        if !args.range.contains(block.index) {
+            continue;
+        }
+        match blockchain.chain.get(block.index as usize) {
+            Some(known) if known.hash == block.hash => continue,
+            Some(_) => {
+                return Err(format!("Block {} conflicts with the local chain", block.index).into())
+            }
+            None => {
+                let index = block.index;
+                blockchain
+                    .add_block(block)
+                    .map_err(|e| format!("Block {} rejected: {}", index, e))?;
+                imported += 1;
+            }
+        }
+    }
+    blockchain.save_to_file(&config.data_dir.join(CHAIN_FILE))?;
+    println!(
+        "Imported {} of {} {} blocks, chain has {} blocks",
+        imported,
+        read,
+        format,
+        blockchain.chain.len()
+    );
+    Ok(())
+}
+
+// Print a genesis spec as JSON, and the hash identifying its network.
+fn print_genesis(genesis: &GenesisSpec) -> Result<(), Box<dyn Error>> {
+    println!("{}", serde_json::to_string_pretty(genesis)?);
+    eprintln!("Genesis hash of {}: {}", genesis.chain_id, genesis.hash()?);
+    Ok(())
+}
+
+// Send a request to the /v1 API of the configured node and return the payload of its answer,
+// failing with the error the node answered with. The admin token only goes with requests to
+// the admin routes.
+async fn request<T: DeserializeOwned>(
+    config: &Config,
+    method: reqwest::Method,
+    path: &str,
+    body: Option<serde_json::Value>,
+) -> Result<T, Box<dyn Error>> {
+    let url = format!(
+        "{}{}{}",
+        config.client.node_url.trim_end_matches('/'),
+        API_PREFIX,
+        path
+    );
+    let mut request = reqwest::Client::new().request(method, url);
+    let admin_token = config.node.admin_token.as_ref();
+    if let Some(token) = admin_token.filter(|_| is_admin_path(path)) {
+        request = request.bearer_auth(token);
+    }
+    if let Some(body) = body {
+        request = request
+            .header("Content-Type", "application/json")
+            .body(serde_json::to_string(&body)?);
+    }
+    let response = request.send().await?;
+    let status = response.status();
+    let text = response.text().await?;
+    match serde_json::from_str::<Envelope<T>>(&text) {
+        Ok(envelope) => Ok(envelope.into_result()?),
+        Err(_) => Err(format!("Node answered {}: {}", status, text).into()),
+    }
+}
+
+// Whether a path of the /v1 API is one of the admin routes.
+fn is_admin_path(path: &str) -> bool {
+    path == "/admin" || path.starts_with("/admin/")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_admin_routes_get_the_admin_token() {
+        assert!(is_admin_path("/admin/webhooks"));
+        assert!(is_admin_path("/admin/peers"));
+        assert!(!is_admin_path("/peers"));
+        assert!(!is_admin_path("/transactions"));
+        assert!(!is_admin_path("/administrators"));
+    }
+
+    #[test]
+    fn devnet_ports_must_fit_in_a_u16() {
+        assert_eq!(
+            devnet_ports(Some(8000), 2).unwrap(),
+            vec![(8000, 9000), (8001, 9001)]
+        );
+        assert_eq!(devnet_ports(None, 2).unwrap(), vec![(0, 0), (0, 0)]);
+        assert!(devnet_ports(Some(64_534), 2).is_ok());
+        let error = devnet_ports(Some(64_535), 2).unwrap_err();
+        assert!(error.to_string().contains("64535"), "{}", error);
+        assert!(devnet_ports(Some(u16::MAX), 1).is_err());
+    }
+}
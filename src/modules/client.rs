@@ -1,9 +1,12 @@
-use std::{error::Error, io, sync::Mutex, vec};
+use std::{error::Error, sync::Mutex};
 
 use actix_web::{dev::Server, web, App, HttpResponse, HttpServer, Responder};
 use askama::Template;
 use serde::{Deserialize, Serialize};
-use serde_json::{json, Value};
+use serde_json::json;
+use tracing::error;
+
+use super::api::{Envelope, TransactionList, TransactionRecord, API_PREFIX};
 
 #[derive(Debug, Deserialize, Serialize)]
 pub struct Article {
@@ -11,13 +14,6 @@ pub struct Article {
     pub timestamp: i64,
     pub content: String,
 }
-// Define the base template
-#[derive(Template)]
-#[template(path = "base.html")]
-struct BaseTemplate<'a> {
-    title: &'a str,
-}
-
 // Define the index template
 #[derive(Template)]
 #[template(path = "index.html")]
@@ -32,14 +28,21 @@ pub struct PostObject {
     author: String,
     content: String,
 }
+// Default address the web client listens on.
+pub const DEFAULT_CLIENT_ADDRESS: &str = "127.0.0.1:8000";
+// Default URL of the node the web client talks to.
+pub const DEFAULT_NODE_URL: &str = "http://localhost:8080";
+// Number of pending and of confirmed posts shown on the index.
+const POSTS_PER_LISTING: usize = 50;
+
 pub struct Client {
     node: String,
 }
 impl Client {
-    // Create a new blockchain application.
-    pub fn new() -> Result<Client, Box<dyn Error>> {
+    // Create a new web client talking to the node at the given URL.
+    pub fn new(node: &str) -> Result<Client, Box<dyn Error>> {
         Ok(Client {
-            node: "http://localhost:8080".to_owned(),
+            node: node.trim_end_matches('/').to_owned(),
         })
     }
     fn get_node(&self) -> &str {
@@ -54,7 +57,11 @@ impl Client {
         let content = &form.content;
 
         // Define node public address and path (method)
-        let new_tx_address = client.lock().unwrap().get_node().to_string() + "/new_transaction"; // Replace with the actual node address
+        let new_tx_address = format!(
+            "{}{}/transactions",
+            client.lock().unwrap().get_node(),
+            API_PREFIX
+        );
 
         // Serialize the form data to JSON
         let payload = serde_json::to_string(&json!({"author": author, "content": content}))
@@ -75,56 +82,53 @@ impl Client {
                     .finish()
             }
             Err(err) => {
-                eprintln!("Error posting new transaction: {}", err);
+                error!(error = %err, "Failed to post new transaction");
                 HttpResponse::InternalServerError().finish()
             }
         }
     }
+    // Fetch the newest transactions from a listing of the node, such as the mempool.
+    async fn fetch_posts(
+        node: &str,
+        listing: &str,
+    ) -> Result<Vec<TransactionRecord>, Box<dyn Error>> {
+        let url = format!(
+            "{}{}/{}?order=desc&limit={}",
+            node, API_PREFIX, listing, POSTS_PER_LISTING
+        );
+        let envelope: Envelope<TransactionList> =
+            serde_json::from_str(&reqwest::Client::new().get(url).send().await?.text().await?)?;
+        Ok(envelope.into_result()?.transactions)
+    }
+    // Fetch the pending posts followed by the confirmed ones, newest first.
+    async fn fetch_articles(node: &str) -> Result<Vec<Article>, Box<dyn Error>> {
+        let mut records = Client::fetch_posts(node, "mempool").await?;
+        records.extend(Client::fetch_posts(node, "transactions").await?);
+        Ok(records
+            .into_iter()
+            .map(|record| Article {
+                author: record.transaction.author,
+                timestamp: record.transaction.timestamp,
+                content: record.transaction.content,
+            })
+            .collect())
+    }
     // Handler function to render the index template
-    pub async fn handle_index() -> impl Responder {
-        // let client: web::Data<Mutex<Client>>
-        if true {
-            return HttpResponse::Ok().body("hello");
-        }
-        // let client = client.lock().unwrap();
-        let node_address: &str = ""; //client.get_node();
-                                     // let get_chain_url = node_address.to_string() + "/chains";
-        let title = "My Blog";
-        /* let response = reqwest::Client::new()
-            .get(get_chain_url)
-            .header("Content-Type", "application/json")
-            .send()
-            .await
-            .unwrap();
-        if !response.status().is_success() {
-            return HttpResponse::InternalServerError().body("Failed to get chain data");
-        }
-        let body = json!(response.text().await.unwrap());
-        let articles = match &body["chain"]["unconfirmed_transactions"] {
-            Value::Array(array) => array
-                .iter()
-                .filter_map(|value| {
-                    serde_json::from_value::<Article>(value.clone())
-                        .map(|mut article| {
-                            article.timestamp = chrono::Utc::now().timestamp();
-                            article
-                        })
-                        .ok()
-                })
-                .collect(),
-            _ => Vec::new(),
-        }; */
-        // Create an instance of the IndexTemplate with the data
-        let articles = vec![Article {
-            author: "Agu".to_string(),
-            content: "Body".to_string(),
-            timestamp: chrono::Utc::now().timestamp(),
-        }];
+    pub async fn handle_index(client: web::Data<Mutex<Client>>) -> impl Responder {
+        // Do not hold the lock while waiting on the node
+        let node = client.lock().unwrap().get_node().to_owned();
+        let articles = match Client::fetch_articles(&node).await {
+            Ok(articles) => articles,
+            Err(err) => {
+                error!(error = %err, "Failed to get posts from the node");
+                return HttpResponse::InternalServerError().body("Failed to get chain data");
+            }
+        };
 
         let index_template = IndexTemplate {
-            node_address,
+            node_address: &node,
             articles,
-            title,
+            title: "My Blog",
         };
 
         // Render the template
@@ -135,16 +139,20 @@ impl Client {
             .body(html)
     }
     pub fn config(cfg: &mut web::ServiceConfig) {
-        let client = Client::new();
-        cfg.app_data(client)
-            .route("/", web::get().to(Client::handle_index))
+        cfg.route("/", web::get().to(Client::handle_index))
             .route("/submit", web::post().to(Client::handle_submit));
     }
 }
-pub async fn start_client() -> io::Result<()> {
-    HttpServer::new(move || App::new().configure(Client::config))
-        .bind("127.0.0.1:8000")
-        .unwrap()
-        .run()
-        .await
+// Start the web client on the given address, talking to the node at node_url. The returned
+// server must be awaited to serve requests.
+pub fn start_client(address: &str, node_url: &str) -> Result<Server, Box<dyn Error>> {
+    let client = web::Data::new(Mutex::new(Client::new(node_url)?));
+    Ok(HttpServer::new(move || {
+        App::new()
+            .app_data(client.clone())
+            .configure(Client::config)
+    })
+    .disable_signals()
+    .bind(address)?
+    .run())
 }
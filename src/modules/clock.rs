@@ -0,0 +1,114 @@
+use rand::rngs::StdRng;
+use rand::{RngCore, SeedableRng};
+use std::fmt;
+#[cfg(test)]
+use std::sync::atomic::{AtomicI64, Ordering};
+use std::sync::{Arc, Mutex};
+
+// Clock tells the time in unix seconds. Nodes read the system clock; tests and simulations set
+// the time by hand so that timestamps, and the block hashes covering them, are reproducible.
+pub trait Clock: Send + Sync + fmt::Debug {
+    fn now(&self) -> i64;
+}
+
+// SystemClock reads the time of the machine.
+#[derive(Debug, Default, Clone, Copy)]
+pub struct SystemClock;
+
+impl Clock for SystemClock {
+    fn now(&self) -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+// ManualClock only moves when told to. Clones share the same time.
+#[cfg(test)]
+#[derive(Debug, Default, Clone)]
+pub struct ManualClock {
+    now: Arc<AtomicI64>,
+}
+
+#[cfg(test)]
+impl ManualClock {
+    // Create a clock stopped at the given time.
+    pub fn new(now: i64) -> ManualClock {
+        ManualClock {
+            now: Arc::new(AtomicI64::new(now)),
+        }
+    }
+
+    pub fn set(&self, now: i64) {
+        self.now.store(now, Ordering::SeqCst);
+    }
+
+    pub fn advance(&self, secs: i64) {
+        self.now.fetch_add(secs, Ordering::SeqCst);
+    }
+}
+
+#[cfg(test)]
+impl Clock for ManualClock {
+    fn now(&self) -> i64 {
+        self.now.load(Ordering::SeqCst)
+    }
+}
+
+// SharedClock is the clock a component was given, the system clock unless set otherwise.
+#[derive(Debug, Clone)]
+pub struct SharedClock(Arc<dyn Clock>);
+
+impl SharedClock {
+    pub fn new(clock: impl Clock + 'static) -> SharedClock {
+        SharedClock(Arc::new(clock))
+    }
+
+    pub fn now(&self) -> i64 {
+        self.0.now()
+    }
+}
+
+impl Default for SharedClock {
+    fn default() -> Self {
+        SharedClock::new(SystemClock)
+    }
+}
+
+// SharedRng is the source of randomness a component was given, like its clock: nodes seed it
+// from the operating system; tests and simulations seed it so that the IDs and nonces it draws
+// are reproducible. Clones draw from the same generator.
+#[derive(Clone)]
+pub struct SharedRng(Arc<Mutex<StdRng>>);
+
+impl SharedRng {
+    // RNG drawing the same values on every run.
+    #[cfg(test)]
+    pub fn seeded(seed: u64) -> SharedRng {
+        SharedRng(Arc::new(Mutex::new(StdRng::seed_from_u64(seed))))
+    }
+
+    // Draw the given number of random bytes.
+    pub fn bytes(&self, len: usize) -> Vec<u8> {
+        let mut bytes = vec![0; len];
+        self.0
+            .lock()
+            .expect("Unable to lock RNG")
+            .fill_bytes(&mut bytes);
+        bytes
+    }
+
+    pub fn next_u64(&self) -> u64 {
+        self.0.lock().expect("Unable to lock RNG").next_u64()
+    }
+}
+
+impl Default for SharedRng {
+    fn default() -> Self {
+        SharedRng(Arc::new(Mutex::new(StdRng::from_entropy())))
+    }
+}
+
+impl fmt::Debug for SharedRng {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str("SharedRng")
+    }
+}
@@ -0,0 +1,524 @@
+use serde::{Deserialize, Serialize};
+use std::env;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::Duration;
+
+use super::app::{NodeOptions, DEFAULT_DATA_DIR, DEFAULT_NODE_ADDRESS};
+use super::blockchain::genesis::{GenesisSpec, Network, MAX_DIFFICULTY};
+use super::client::{DEFAULT_CLIENT_ADDRESS, DEFAULT_NODE_URL};
+use super::logging::{self, LogFormat, DEFAULT_LOG_LEVEL};
+use super::network::discovery::DiscoveryConfig;
+use super::network::scoring::{BanPolicy, MAX_BAN_SECS};
+use super::network::transport::DEFAULT_P2P_ADDRESS;
+
+// Configuration file read when none is given explicitly, if it exists.
+pub const DEFAULT_CONFIG_FILE: &str = "versus.toml";
+
+// Config holds every setting of the versus binary. It is built from defaults, then a TOML file,
+// then VERSUS_* environment variables, then command-line flags, and checked by validate.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    // Directory holding the chain, node key and ban list.
+    pub data_dir: PathBuf,
+    pub node: NodeConfig,
+    pub client: ClientConfig,
+    pub chain: ChainConfig,
+    pub peers: PeersConfig,
+    pub mining: MiningConfig,
+    pub log: LogConfig,
+}
+
+// NodeConfig holds the listen addresses and access rules of the node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NodeConfig {
+    // Address the node API listens on.
+    pub address: String,
+    // Address the peer-to-peer transport listens on.
+    pub p2p_address: String,
+    // Public keys of the only nodes allowed to talk to this node; unset allows any node.
+    pub allowlist: Option<Vec<String>>,
+    // Bearer token required by the admin routes; unset serves them on loopback only.
+    pub admin_token: Option<String>,
+}
+
+// ClientConfig holds the settings of the web client and of commands talking to a node.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ClientConfig {
+    // Address the web client listens on.
+    pub address: String,
+    // URL of the node API the web client and commands talk to.
+    pub node_url: String,
+}
+
+// ChainConfig selects the network the node runs and its consensus parameters.
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct ChainConfig {
+    // Built-in network whose genesis is used, unless genesis_file is set.
+    pub network: Network,
+    // JSON genesis spec of a custom network.
+    pub genesis_file: Option<PathBuf>,
+    // Difficulty overriding the one of the genesis spec. A chain keeps the difficulty it was
+    // created with, so this only applies to new chains.
+    pub difficulty: Option<i32>,
+}
+
+// PeersConfig holds the settings of peer discovery.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct PeersConfig {
+    // Node API URLs to look for peers at first.
+    pub seeds: Vec<String>,
+    // Number of outbound peers the node tries to maintain.
+    pub target_outbound: usize,
+    // Seconds between discovery rounds.
+    pub discovery_interval_secs: u64,
+    // Misbehaviour score at which a peer gets banned.
+    pub ban_threshold: u32,
+    // Seconds a ban lasts, at most MAX_BAN_SECS.
+    pub ban_secs: u64,
+}
+
+// MiningConfig holds the settings of the background miner.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct MiningConfig {
+    // Whether the node mines pending transactions on its own, rather than only on /mine.
+    pub auto_mine: bool,
+    // Seconds between background mining attempts.
+    pub interval_secs: u64,
+}
+
+// LogConfig selects how and what the binary logs.
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct LogConfig {
+    // Format of log events: text, pretty or json.
+    pub format: LogFormat,
+    // Default level and levels per module, e.g. "info,versus::modules::network=debug".
+    pub level: String,
+}
+
+impl Default for Config {
+    fn default() -> Self {
+        Config {
+            data_dir: PathBuf::from(DEFAULT_DATA_DIR),
+            node: NodeConfig::default(),
+            client: ClientConfig::default(),
+            chain: ChainConfig::default(),
+            peers: PeersConfig::default(),
+            mining: MiningConfig::default(),
+            log: LogConfig::default(),
+        }
+    }
+}
+
+impl Default for NodeConfig {
+    fn default() -> Self {
+        NodeConfig {
+            address: DEFAULT_NODE_ADDRESS.to_string(),
+            p2p_address: DEFAULT_P2P_ADDRESS.to_string(),
+            allowlist: None,
+            admin_token: None,
+        }
+    }
+}
+
+impl Default for ClientConfig {
+    fn default() -> Self {
+        ClientConfig {
+            address: DEFAULT_CLIENT_ADDRESS.to_string(),
+            node_url: DEFAULT_NODE_URL.to_string(),
+        }
+    }
+}
+
+impl Default for PeersConfig {
+    fn default() -> Self {
+        let discovery = DiscoveryConfig::default();
+        let ban_policy = BanPolicy::default();
+        PeersConfig {
+            seeds: discovery.seeds,
+            target_outbound: discovery.target_outbound,
+            discovery_interval_secs: discovery.interval.as_secs(),
+            ban_threshold: ban_policy.threshold,
+            ban_secs: ban_policy.ban_duration.as_secs(),
+        }
+    }
+}
+
+impl Default for MiningConfig {
+    fn default() -> Self {
+        MiningConfig {
+            auto_mine: false,
+            interval_secs: 10,
+        }
+    }
+}
+
+impl Default for LogConfig {
+    fn default() -> Self {
+        LogConfig {
+            format: LogFormat::default(),
+            level: DEFAULT_LOG_LEVEL.to_string(),
+        }
+    }
+}
+
+// ConfigError represents the reasons a configuration is refused at startup.
+#[derive(Debug, PartialEq, Eq)]
+pub enum ConfigError {
+    File(PathBuf, String),
+    Env(&'static str, String),
+    Invalid(&'static str, String),
+}
+
+impl fmt::Display for ConfigError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ConfigError::File(path, reason) => {
+                write!(f, "Cannot load config file {}: {}", path.display(), reason)
+            }
+            ConfigError::Env(name, value) => {
+                write!(
+                    f,
+                    "Invalid value {:?} for environment variable {}",
+                    value, name
+                )
+            }
+            ConfigError::Invalid(setting, reason) => {
+                write!(f, "Invalid setting {}: {}", setting, reason)
+            }
+        }
+    }
+}
+
+impl Error for ConfigError {}
+
+impl Config {
+    // Load the configuration from the given file, or from DEFAULT_CONFIG_FILE if it exists,
+    // with environment variables applied on top. Flags are applied by the caller before validate.
+    pub fn load(path: Option<&Path>) -> Result<Config, ConfigError> {
+        let mut config = match path {
+            Some(path) => Config::from_file(path)?,
+            None if Path::new(DEFAULT_CONFIG_FILE).exists() => {
+                Config::from_file(Path::new(DEFAULT_CONFIG_FILE))?
+            }
+            None => Config::default(),
+        };
+        config.apply_env()?;
+        Ok(config)
+    }
+
+    // Read a configuration from a TOML file; missing settings keep their defaults.
+    pub fn from_file(path: &Path) -> Result<Config, ConfigError> {
+        let content = fs::read_to_string(path)
+            .map_err(|e| ConfigError::File(path.to_path_buf(), e.to_string()))?;
+        toml::from_str(&content).map_err(|e| ConfigError::File(path.to_path_buf(), e.to_string()))
+    }
+
+    // Override settings with the VERSUS_* environment variables that are set.
+    pub fn apply_env(&mut self) -> Result<(), ConfigError> {
+        env_override("VERSUS_DATA_DIR", &mut self.data_dir)?;
+        env_override("VERSUS_NODE_ADDRESS", &mut self.node.address)?;
+        env_override("VERSUS_P2P_ADDRESS", &mut self.node.p2p_address)?;
+        if let Ok(keys) = env::var("VERSUS_NODE_ALLOWLIST") {
+            self.node.allowlist = Some(split_list(&keys));
+        }
+        if let Ok(token) = env::var("VERSUS_ADMIN_TOKEN") {
+            self.node.admin_token = Some(token);
+        }
+        env_override("VERSUS_CLIENT_ADDRESS", &mut self.client.address)?;
+        env_override("VERSUS_NODE_URL", &mut self.client.node_url)?;
+        env_override("VERSUS_NETWORK", &mut self.chain.network)?;
+        if let Ok(genesis_file) = env::var("VERSUS_GENESIS_FILE") {
+            self.chain.genesis_file = Some(PathBuf::from(genesis_file));
+        }
+        if let Ok(difficulty) = env::var("VERSUS_DIFFICULTY") {
+            self.chain.difficulty = Some(
+                difficulty
+                    .parse()
+                    .map_err(|_| ConfigError::Env("VERSUS_DIFFICULTY", difficulty))?,
+            );
+        }
+        if let Ok(seeds) = env::var("VERSUS_PEERS") {
+            self.peers.seeds = split_list(&seeds);
+        }
+        env_override("VERSUS_TARGET_OUTBOUND", &mut self.peers.target_outbound)?;
+        env_override(
+            "VERSUS_DISCOVERY_INTERVAL_SECS",
+            &mut self.peers.discovery_interval_secs,
+        )?;
+        env_override("VERSUS_BAN_THRESHOLD", &mut self.peers.ban_threshold)?;
+        env_override("VERSUS_BAN_SECS", &mut self.peers.ban_secs)?;
+        env_override("VERSUS_AUTO_MINE", &mut self.mining.auto_mine)?;
+        env_override("VERSUS_MINE_INTERVAL_SECS", &mut self.mining.interval_secs)?;
+        env_override("VERSUS_LOG_FORMAT", &mut self.log.format)?;
+        env_override("VERSUS_LOG", &mut self.log.level)?;
+        Ok(())
+    }
+
+    // Check the settings are usable, so a bad configuration fails at startup.
+    pub fn validate(&self) -> Result<(), ConfigError> {
+        if self.data_dir.as_os_str().is_empty() {
+            return Err(ConfigError::Invalid("data_dir", "must not be empty".into()));
+        }
+        check_address("node.address", &self.node.address)?;
+        check_address("node.p2p_address", &self.node.p2p_address)?;
+        for key in self.node.allowlist.iter().flatten() {
+            if key.len() != 64 || hex::decode(key).is_err() {
+                return Err(ConfigError::Invalid(
+                    "node.allowlist",
+                    format!("{:?} is not a hex-encoded public key", key),
+                ));
+            }
+        }
+        if self.node.admin_token.as_deref() == Some("") {
+            return Err(ConfigError::Invalid(
+                "node.admin_token",
+                "must not be empty".into(),
+            ));
+        }
+        check_address("client.address", &self.client.address)?;
+        check_url("client.node_url", &self.client.node_url)?;
+        if let Some(difficulty) = self.chain.difficulty {
+            if !(0..=MAX_DIFFICULTY).contains(&difficulty) {
+                return Err(ConfigError::Invalid(
+                    "chain.difficulty",
+                    format!("must be between 0 and {}", MAX_DIFFICULTY),
+                ));
+            }
+        }
+        self.genesis()
+            .map_err(|e| ConfigError::Invalid("chain.genesis_file", e.to_string()))?;
+        for seed in &self.peers.seeds {
+            check_url("peers.seeds", seed)?;
+        }
+        if self.peers.discovery_interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "peers.discovery_interval_secs",
+                "must be positive".into(),
+            ));
+        }
+        if self.peers.ban_threshold == 0 {
+            return Err(ConfigError::Invalid(
+                "peers.ban_threshold",
+                "must be positive".into(),
+            ));
+        }
+        if !(1..=MAX_BAN_SECS).contains(&self.peers.ban_secs) {
+            return Err(ConfigError::Invalid(
+                "peers.ban_secs",
+                format!("must be between 1 and {}", MAX_BAN_SECS),
+            ));
+        }
+        if self.mining.interval_secs == 0 {
+            return Err(ConfigError::Invalid(
+                "mining.interval_secs",
+                "must be positive".into(),
+            ));
+        }
+        logging::parse_filter(&self.log.level)
+            .map_err(|reason| ConfigError::Invalid("log.level", reason))?;
+        Ok(())
+    }
+
+    // Genesis of the configured network: the genesis file if set, else the built-in network,
+    // with the configured difficulty applied.
+    pub fn genesis(&self) -> Result<GenesisSpec, Box<dyn Error>> {
+        let mut genesis = match &self.chain.genesis_file {
+            Some(path) => GenesisSpec::from_file(path)?,
+            None => self.chain.network.genesis(),
+        };
+        if let Some(difficulty) = self.chain.difficulty {
+            genesis.consensus.difficulty = difficulty;
+        }
+        Ok(genesis)
+    }
+
+    // Settings the node application is created with.
+    pub fn node_options(&self) -> Result<NodeOptions, Box<dyn Error>> {
+        Ok(NodeOptions {
+            data_dir: self.data_dir.clone(),
+            node_address: self.node.address.clone(),
+            p2p_address: self.node.p2p_address.clone(),
+            genesis: self.genesis()?,
+            admin_token: self.node.admin_token.clone(),
+            ban_policy: self.ban_policy(),
+            ..NodeOptions::default()
+        })
+    }
+
+    // When misbehaving peers get banned, and for how long.
+    pub fn ban_policy(&self) -> BanPolicy {
+        BanPolicy {
+            threshold: self.peers.ban_threshold,
+            ban_duration: Duration::from_secs(self.peers.ban_secs),
+        }
+    }
+
+    // Settings of peer discovery for the node.
+    pub fn discovery(&self) -> DiscoveryConfig {
+        DiscoveryConfig {
+            own_address: format!("http://{}", self.node.address),
+            p2p_address: None,
+            seeds: self.peers.seeds.clone(),
+            target_outbound: self.peers.target_outbound,
+            interval: Duration::from_secs(self.peers.discovery_interval_secs),
+        }
+    }
+
+    // Time between background mining attempts, if the node mines on its own.
+    pub fn auto_mine_interval(&self) -> Option<Duration> {
+        if self.mining.auto_mine {
+            Some(Duration::from_secs(self.mining.interval_secs))
+        } else {
+            None
+        }
+    }
+}
+
+// Replace a setting with the value of an environment variable, if it is set.
+fn env_override<T: FromStr>(name: &'static str, setting: &mut T) -> Result<(), ConfigError> {
+    if let Ok(value) = env::var(name) {
+        *setting = value.parse().map_err(|_| ConfigError::Env(name, value))?;
+    }
+    Ok(())
+}
+
+// Split a comma-separated list, dropping empty entries.
+fn split_list(list: &str) -> Vec<String> {
+    list.split(',')
+        .map(str::trim)
+        .filter(|item| !item.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+// Check an address to listen on has the form host:port.
+fn check_address(setting: &'static str, address: &str) -> Result<(), ConfigError> {
+    match address.rsplit_once(':') {
+        Some((host, port)) if !host.is_empty() && port.parse::<u16>().is_ok() => Ok(()),
+        _ => Err(ConfigError::Invalid(
+            setting,
+            format!("{:?} is not a host:port address", address),
+        )),
+    }
+}
+
+// Check a URL points at an HTTP node API.
+fn check_url(setting: &'static str, url: &str) -> Result<(), ConfigError> {
+    match reqwest::Url::parse(url) {
+        Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => Ok(()),
+        _ => Err(ConfigError::Invalid(
+            setting,
+            format!("{:?} is not an http(s) URL", url),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ban_policy_reaches_the_node() {
+        let mut config: Config = toml::from_str(
+            "[peers]\n\
+             ban_threshold = 40\n\
+             ban_secs = 600\n",
+        )
+        .unwrap();
+        config.validate().unwrap();
+        let options = config.node_options().unwrap();
+        assert_eq!(options.ban_policy.threshold, 40);
+        assert_eq!(options.ban_policy.ban_duration, Duration::from_secs(600));
+
+        config.peers.ban_secs = 0;
+        assert!(config.validate().is_err());
+    }
+
+    // Setting named by the error validate returns once the config is changed by edit.
+    fn invalid_setting(edit: impl FnOnce(&mut Config)) -> &'static str {
+        let mut config = Config::default();
+        edit(&mut config);
+        match config.validate() {
+            Err(ConfigError::Invalid(setting, _)) => setting,
+            other => panic!("expected an invalid setting, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn defaults_are_valid() {
+        assert_eq!(Config::default().validate(), Ok(()));
+    }
+
+    #[test]
+    fn invalid_settings_are_named() {
+        assert_eq!(invalid_setting(|c| c.data_dir = PathBuf::new()), "data_dir");
+        assert_eq!(
+            invalid_setting(|c| c.node.address = "8000".into()),
+            "node.address"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.node.p2p_address = "0.0.0.0:99999".into()),
+            "node.p2p_address"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.node.allowlist = Some(vec!["abcd".into()])),
+            "node.allowlist"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.node.admin_token = Some(String::new())),
+            "node.admin_token"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.client.node_url = "ftp://node".into()),
+            "client.node_url"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.chain.difficulty = Some(MAX_DIFFICULTY + 1)),
+            "chain.difficulty"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.chain.genesis_file = Some("/nonexistent/genesis.json".into())),
+            "chain.genesis_file"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.peers.seeds = vec!["node-1:8000".into()]),
+            "peers.seeds"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.peers.discovery_interval_secs = 0),
+            "peers.discovery_interval_secs"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.peers.ban_threshold = 0),
+            "peers.ban_threshold"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.peers.ban_secs = MAX_BAN_SECS + 1),
+            "peers.ban_secs"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.mining.interval_secs = 0),
+            "mining.interval_secs"
+        );
+        assert_eq!(
+            invalid_setting(|c| c.log.level = "network=loud".into()),
+            "log.level"
+        );
+    }
+
+    #[test]
+    fn unknown_settings_are_refused() {
+        assert!(toml::from_str::<Config>("[peers]\nban_treshold = 40\n").is_err());
+    }
+}
@@ -0,0 +1,164 @@
+use actix_web::dev::ServiceRequest;
+use actix_web::http::header::{HeaderName, HeaderValue};
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+use std::io::{self, IsTerminal};
+use std::str::FromStr;
+use tracing_subscriber::EnvFilter;
+
+use super::clock::SharedRng;
+
+// Logs of the binary are written to standard error with tracing, leaving standard output to the
+// results of commands. Node operations run in spans (mining, block validation, consensus and
+// each HTTP request with its request ID) whose fields are attached to the events inside them.
+
+// Filter applied when none is configured.
+pub const DEFAULT_LOG_LEVEL: &str = "info";
+// Header carrying the ID of an HTTP request, taken from the client if it sends one.
+pub const REQUEST_ID_HEADER: HeaderName = HeaderName::from_static("x-request-id");
+// Longest request ID taken from a client; longer ones are replaced.
+const MAX_REQUEST_ID_LEN: usize = 64;
+
+// LogFormat is how log events are written.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum LogFormat {
+    // One line per event, for terminals.
+    #[default]
+    Text,
+    // Several lines per event with its fields and source, for reading closely.
+    Pretty,
+    // One JSON object per event with its spans, for log collectors.
+    Json,
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            LogFormat::Text => write!(f, "text"),
+            LogFormat::Pretty => write!(f, "pretty"),
+            LogFormat::Json => write!(f, "json"),
+        }
+    }
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(name: &str) -> Result<Self, Self::Err> {
+        match name {
+            "text" => Ok(LogFormat::Text),
+            "pretty" => Ok(LogFormat::Pretty),
+            "json" => Ok(LogFormat::Json),
+            _ => Err(format!("Unknown log format {}", name)),
+        }
+    }
+}
+
+// Parse a log filter: a default level and levels per module, e.g.
+// "info,versus::modules::network=debug".
+pub fn parse_filter(level: &str) -> Result<EnvFilter, String> {
+    if level.trim().is_empty() {
+        return Err("must not be empty".to_string());
+    }
+    EnvFilter::builder()
+        .parse(level)
+        .map_err(|e| format!("{:?} is not a log filter: {}", level, e))
+}
+
+// Install the logger of the process. Events of libraries logging through the log crate, such
+// as actix, are written too. Text is only coloured on terminals.
+pub fn init(format: LogFormat, level: &str) -> Result<(), Box<dyn Error>> {
+    let logger = tracing_subscriber::fmt()
+        .with_env_filter(parse_filter(level)?)
+        .with_writer(io::stderr)
+        .with_ansi(io::stderr().is_terminal());
+    let installed = match format {
+        LogFormat::Text => logger.try_init(),
+        LogFormat::Pretty => logger.pretty().try_init(),
+        LogFormat::Json => logger
+            .json()
+            .with_current_span(true)
+            .with_span_list(true)
+            .try_init(),
+    };
+    installed.map_err(|e| -> Box<dyn Error> { e })
+}
+
+// ID of an HTTP request: the one the client sent in REQUEST_ID_HEADER if it is short and
+// printable, else a new one drawn from the RNG of the node.
+pub fn request_id(req: &ServiceRequest, rng: &SharedRng) -> HeaderValue {
+    req.headers()
+        .get(&REQUEST_ID_HEADER)
+        .filter(|id| {
+            !id.is_empty()
+                && id.len() <= MAX_REQUEST_ID_LEN
+                && id.as_bytes().iter().all(u8::is_ascii_graphic)
+        })
+        .cloned()
+        .unwrap_or_else(|| {
+            HeaderValue::from_str(&format!("{:016x}", rng.next_u64()))
+                .expect("Hex digits are a valid header value")
+        })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use actix_web::{test as web_test, App};
+
+    use crate::modules::app::{Application, NodeOptions};
+    use crate::modules::network::auth::{NodeAuth, NodeIdentity};
+
+    #[test]
+    fn filters_set_levels_per_module() {
+        assert!(parse_filter("info,versus::modules::network=debug").is_ok());
+        assert!(parse_filter("warn").is_ok());
+        assert!(parse_filter("").is_err());
+        assert!(parse_filter("network=loud").is_err());
+    }
+
+    #[actix_web::test]
+    async fn responses_carry_the_request_id() {
+        let data_dir = std::env::temp_dir().join(format!("versus-logging-{}", std::process::id()));
+        std::fs::create_dir_all(&data_dir).unwrap();
+        let options = NodeOptions {
+            data_dir: data_dir.clone(),
+            rng: SharedRng::seeded(7),
+            ..NodeOptions::default()
+        };
+        let app =
+            Application::new(NodeAuth::new(NodeIdentity::generate(), None), &options).unwrap();
+        let service = web_test::init_service(App::new().configure(|cfg| app.config(cfg))).await;
+        let request_id = |request: web_test::TestRequest| {
+            let service = &service;
+            async move {
+                let response =
+                    web_test::call_service(service, request.uri("/v1/status").to_request()).await;
+                response.headers().get(&REQUEST_ID_HEADER).cloned().unwrap()
+            }
+        };
+
+        let given = request_id(
+            web_test::TestRequest::get().insert_header((REQUEST_ID_HEADER, "incident-42")),
+        )
+        .await;
+        assert_eq!(given, "incident-42");
+        let first = request_id(web_test::TestRequest::get()).await;
+        let second = request_id(web_test::TestRequest::get()).await;
+        assert_eq!(first.len(), 16);
+        assert_ne!(first, second);
+        // New IDs come from the RNG of the node
+        assert_eq!(
+            first,
+            format!("{:016x}", SharedRng::seeded(7).next_u64()).as_str()
+        );
+        let replaced = request_id(
+            web_test::TestRequest::get().insert_header((REQUEST_ID_HEADER, "not an id")),
+        )
+        .await;
+        assert_ne!(replaced, "not an id");
+        std::fs::remove_dir_all(data_dir).unwrap();
+    }
+}
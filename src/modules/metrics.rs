@@ -0,0 +1,340 @@
+use prometheus::{
+    Counter, Encoder, Gauge, HistogramOpts, HistogramVec, IntCounter, IntCounterVec, IntGauge,
+    IntGaugeVec, Opts, Registry, TextEncoder,
+};
+use std::fmt;
+use std::time::Duration;
+
+use super::blockchain::chain::Blockchain;
+
+// Metrics of a node, served in the Prometheus text format at GET /metrics.
+//
+// Each node has its own registry, as devnets run several nodes in one process. The state of the
+// chain (height, difficulty, mempool and peers) is read from the blockchain when metrics are
+// scraped; the other metrics count what the node did since it started.
+
+// Number of blocks the block interval is averaged over.
+pub const BLOCK_INTERVAL_WINDOW: usize = 10;
+
+// Outcomes of a consensus round.
+pub const CONSENSUS_ADOPTED: &str = "adopted";
+pub const CONSENSUS_KEPT: &str = "kept";
+pub const CONSENSUS_FAILED: &str = "failed";
+
+#[derive(Clone)]
+pub struct NodeMetrics {
+    registry: Registry,
+    chain_height: IntGauge,
+    difficulty: IntGauge,
+    block_interval: Gauge,
+    mempool_transactions: IntGauge,
+    mempool_bytes: IntGauge,
+    peers: IntGaugeVec,
+    banned_peers: IntGauge,
+    transport_peers: IntGauge,
+    blocks_mined: IntCounter,
+    hashes: IntCounter,
+    mining_seconds: Counter,
+    hashrate: Gauge,
+    http_requests: HistogramVec,
+    consensus_runs: IntCounterVec,
+    blocks_rejected: IntCounterVec,
+    block_announcements: IntCounterVec,
+}
+
+impl fmt::Debug for NodeMetrics {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("NodeMetrics").finish_non_exhaustive()
+    }
+}
+
+impl Default for NodeMetrics {
+    fn default() -> NodeMetrics {
+        NodeMetrics::new()
+    }
+}
+
+// Register a metric, whose name no other metric of the registry has.
+fn register<M: prometheus::core::Collector + Clone + 'static>(registry: &Registry, metric: M) -> M {
+    registry
+        .register(Box::new(metric.clone()))
+        .expect("Metric names are unique");
+    metric
+}
+
+fn opts(name: &str, help: &str) -> Opts {
+    Opts::new(name, help).namespace("versus")
+}
+
+impl NodeMetrics {
+    pub fn new() -> NodeMetrics {
+        let registry = Registry::new();
+        let r = &registry;
+        NodeMetrics {
+            chain_height: register(
+                r,
+                IntGauge::with_opts(opts("chain_height", "Height of the tip")).unwrap(),
+            ),
+            difficulty: register(
+                r,
+                IntGauge::with_opts(opts("difficulty", "Leading zeros block hashes need")).unwrap(),
+            ),
+            block_interval: register(
+                r,
+                Gauge::with_opts(opts(
+                    "block_interval_seconds",
+                    "Average time between the last blocks, 0 until two blocks follow genesis",
+                ))
+                .unwrap(),
+            ),
+            mempool_transactions: register(
+                r,
+                IntGauge::with_opts(opts("mempool_transactions", "Pending transactions")).unwrap(),
+            ),
+            mempool_bytes: register(
+                r,
+                IntGauge::with_opts(opts(
+                    "mempool_bytes",
+                    "Size of the pending transactions, as JSON",
+                ))
+                .unwrap(),
+            ),
+            peers: register(
+                r,
+                IntGaugeVec::new(
+                    opts("peers", "Peers, by who registered with whom"),
+                    &["direction"],
+                )
+                .unwrap(),
+            ),
+            banned_peers: register(
+                r,
+                IntGauge::with_opts(opts("banned_peers", "Peers banned for misbehaving")).unwrap(),
+            ),
+            transport_peers: register(
+                r,
+                IntGauge::with_opts(opts(
+                    "transport_peers",
+                    "Peers connected over the peer-to-peer transport",
+                ))
+                .unwrap(),
+            ),
+            blocks_mined: register(
+                r,
+                IntCounter::with_opts(opts("blocks_mined_total", "Blocks mined by this node"))
+                    .unwrap(),
+            ),
+            hashes: register(
+                r,
+                IntCounter::with_opts(opts(
+                    "mining_hashes_total",
+                    "Hashes computed looking for proofs of work",
+                ))
+                .unwrap(),
+            ),
+            mining_seconds: register(
+                r,
+                Counter::with_opts(opts(
+                    "mining_seconds_total",
+                    "Time spent looking for proofs of work",
+                ))
+                .unwrap(),
+            ),
+            hashrate: register(
+                r,
+                Gauge::with_opts(opts(
+                    "hashrate",
+                    "Hashes per second while mining the last block",
+                ))
+                .unwrap(),
+            ),
+            http_requests: register(
+                r,
+                HistogramVec::new(
+                    HistogramOpts::from(opts(
+                        "http_request_duration_seconds",
+                        "Time to answer HTTP requests, by route",
+                    )),
+                    &["method", "route", "status"],
+                )
+                .unwrap(),
+            ),
+            consensus_runs: register(
+                r,
+                IntCounterVec::new(
+                    opts(
+                        "consensus_runs_total",
+                        "Consensus rounds, by whether they adopted a chain of a peer",
+                    ),
+                    &["outcome"],
+                )
+                .unwrap(),
+            ),
+            blocks_rejected: register(
+                r,
+                IntCounterVec::new(
+                    opts("blocks_rejected_total", "Blocks refused by the chain"),
+                    &["reason"],
+                )
+                .unwrap(),
+            ),
+            block_announcements: register(
+                r,
+                IntCounterVec::new(
+                    opts(
+                        "block_announcements_total",
+                        "Blocks announced to peers over HTTP, by whether they took them",
+                    ),
+                    &["outcome"],
+                )
+                .unwrap(),
+            ),
+            registry,
+        }
+    }
+
+    // Read the state of the chain.
+    pub fn observe_chain(&self, blockchain: &Blockchain) {
+        let tip = blockchain.get_last_block();
+        self.chain_height.set(tip.index.into());
+        self.difficulty.set(blockchain.difficulty.into());
+        // The genesis block is created long before the chain starts, so it is left out
+        let mined = &blockchain.chain[1..];
+        let window = &mined[mined.len().saturating_sub(BLOCK_INTERVAL_WINDOW)..];
+        let interval = match window {
+            [first, .., last] => {
+                (last.timestamp - first.timestamp) as f64 / (window.len() - 1) as f64
+            }
+            _ => 0.0,
+        };
+        self.block_interval.set(interval);
+        let pending = &blockchain.unconfirmed_transactions;
+        self.mempool_transactions.set(pending.len() as i64);
+        self.mempool_bytes.set(
+            pending
+                .iter()
+                .map(|tx| serde_json::to_vec(tx).map_or(0, |bytes| bytes.len()))
+                .sum::<usize>() as i64,
+        );
+        let outbound = blockchain.peers.iter().filter(|peer| peer.outbound).count();
+        self.peers
+            .with_label_values(&["outbound"])
+            .set(outbound as i64);
+        self.peers
+            .with_label_values(&["inbound"])
+            .set((blockchain.peers.len() - outbound) as i64);
+        self.banned_peers
+            .set(blockchain.peer_scores.banned_count() as i64);
+    }
+
+    pub fn observe_transport_peers(&self, peers: usize) {
+        self.transport_peers.set(peers as i64);
+    }
+
+    // Record a block mined after computing a number of hashes for its proof of work.
+    pub fn block_mined(&self, hashes: u64, spent: Duration) {
+        self.blocks_mined.inc();
+        self.hashes.inc_by(hashes);
+        self.mining_seconds.inc_by(spent.as_secs_f64());
+        if !spent.is_zero() {
+            self.hashrate.set(hashes as f64 / spent.as_secs_f64());
+        }
+    }
+
+    pub fn http_request(&self, method: &str, route: &str, status: u16, spent: Duration) {
+        self.http_requests
+            .with_label_values(&[method, route, &status.to_string()])
+            .observe(spent.as_secs_f64());
+    }
+
+    pub fn consensus_run(&self, outcome: &str) {
+        self.consensus_runs.with_label_values(&[outcome]).inc();
+    }
+
+    pub fn block_rejected(&self, reason: &str) {
+        self.blocks_rejected.with_label_values(&[reason]).inc();
+    }
+
+    pub fn block_announced(&self, delivered: bool) {
+        let outcome = match delivered {
+            true => "success",
+            false => "failure",
+        };
+        self.block_announcements.with_label_values(&[outcome]).inc();
+    }
+
+    // Metrics in the Prometheus text format.
+    pub fn render(&self) -> String {
+        let mut text = Vec::new();
+        TextEncoder::new()
+            .encode(&self.registry.gather(), &mut text)
+            .expect("Metrics encode as text");
+        String::from_utf8(text).expect("Metrics text is UTF-8")
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::{ConsensusRound, Transaction};
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::clock::{Clock, ManualClock, SharedClock};
+
+    // Value of a sample of the rendered metrics, e.g. versus_chain_height.
+    fn sample(text: &str, name: &str) -> f64 {
+        text.lines()
+            .find_map(|line| line.strip_prefix(name)?.strip_prefix(' '))
+            .unwrap_or_else(|| panic!("{} missing from\n{}", name, text))
+            .parse()
+            .unwrap()
+    }
+
+    #[test]
+    fn node_activity_shows_in_metrics() {
+        let clock = ManualClock::new(1_700_000_000);
+        let mut blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        blockchain.set_clock(SharedClock::new(clock.clone()));
+        for i in 0..3 {
+            blockchain.add_new_transaction(Transaction {
+                author: "alice".to_string(),
+                content: format!("post {}", i),
+                timestamp: clock.now(),
+            });
+            blockchain.mine_block().unwrap();
+            clock.advance(30);
+        }
+        let pending = Transaction {
+            author: "bob".to_string(),
+            content: "draft".to_string(),
+            timestamp: clock.now(),
+        };
+        blockchain.add_new_transaction(pending.clone());
+        let forged = blockchain.get_last_block().with_modified_hash("forged");
+        assert!(blockchain.receive_block("peer", forged).is_err());
+        blockchain.apply_consensus(ConsensusRound::default());
+
+        let metrics = blockchain.metrics.clone();
+        metrics.observe_chain(&blockchain);
+        let text = metrics.render();
+        assert_eq!(sample(&text, "versus_chain_height"), 3.0);
+        assert_eq!(sample(&text, "versus_block_interval_seconds"), 30.0);
+        assert_eq!(sample(&text, "versus_blocks_mined_total"), 3.0);
+        assert_eq!(sample(&text, "versus_mempool_transactions"), 1.0);
+        assert_eq!(
+            sample(&text, "versus_mempool_bytes"),
+            serde_json::to_vec(&pending).unwrap().len() as f64
+        );
+        assert_eq!(
+            sample(
+                &text,
+                "versus_blocks_rejected_total{reason=\"invalid_proof\"}"
+            ),
+            1.0
+        );
+        assert_eq!(
+            sample(&text, "versus_consensus_runs_total{outcome=\"kept\"}"),
+            1.0
+        );
+        assert!(sample(&text, "versus_mining_hashes_total") >= 3.0);
+    }
+}
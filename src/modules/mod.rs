@@ -1,3 +1,11 @@
+pub mod api;
 pub mod app;
 pub mod blockchain;
+pub mod cli;
 pub mod client;
+pub mod clock;
+pub mod config;
+pub mod logging;
+pub mod metrics;
+pub mod network;
+pub mod webhooks;
@@ -0,0 +1,353 @@
+use ed25519_dalek::{Signature, Signer, SigningKey, Verifier, VerifyingKey};
+use rand::rngs::OsRng;
+use rand::{CryptoRng, RngCore};
+use std::collections::HashSet;
+use std::convert::TryInto;
+use std::error::Error;
+use std::fmt;
+use std::fs;
+use std::path::Path;
+
+use crate::modules::clock::SharedClock;
+
+// Name of the node identity key in the data directory.
+pub const KEY_FILE: &str = "node_key";
+// Headers carrying the signature of a node-to-node HTTP message.
+pub const NODE_KEY_HEADER: &str = "X-Node-Key";
+pub const NODE_TIMESTAMP_HEADER: &str = "X-Node-Timestamp";
+pub const NODE_SIGNATURE_HEADER: &str = "X-Node-Signature";
+// Largest accepted difference between a signature timestamp and our clock, in seconds.
+const MAX_CLOCK_SKEW_SECS: u64 = 300;
+
+// NodeIdentity is the key pair a node signs its peer messages with.
+pub struct NodeIdentity {
+    signing_key: SigningKey,
+}
+
+impl NodeIdentity {
+    // Generate a fresh random identity.
+    pub fn generate() -> NodeIdentity {
+        NodeIdentity::generate_with(&mut OsRng)
+    }
+
+    // Generate an identity from the given source of randomness, so tests and simulations can
+    // use a seeded RNG.
+    pub fn generate_with(rng: &mut (impl CryptoRng + RngCore)) -> NodeIdentity {
+        NodeIdentity {
+            signing_key: SigningKey::generate(rng),
+        }
+    }
+
+    // Load the identity stored at the given path, generating and storing a new one if missing.
+    pub fn load_or_generate(path: &Path) -> Result<NodeIdentity, Box<dyn Error>> {
+        if path.exists() {
+            let secret: [u8; 32] = hex::decode(fs::read_to_string(path)?.trim())?
+                .try_into()
+                .map_err(|_| "Invalid node key length")?;
+            return Ok(NodeIdentity {
+                signing_key: SigningKey::from_bytes(&secret),
+            });
+        }
+
+        let identity = NodeIdentity::generate();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        fs::write(path, hex::encode(identity.signing_key.to_bytes()))?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::PermissionsExt;
+            fs::set_permissions(path, fs::Permissions::from_mode(0o600))?;
+        }
+        Ok(identity)
+    }
+
+    // Hex-encoded public key identifying the node.
+    pub fn public_key(&self) -> String {
+        hex::encode(self.signing_key.verifying_key().to_bytes())
+    }
+
+    // Sign raw bytes with the node key.
+    pub fn sign(&self, bytes: &[u8]) -> Vec<u8> {
+        self.signing_key.sign(bytes).to_bytes().to_vec()
+    }
+}
+
+// Verify a signature over raw bytes against a hex-encoded public key.
+pub fn verify_signature(public_key: &str, bytes: &[u8], signature: &[u8]) -> bool {
+    let key_bytes: [u8; 32] = match hex::decode(public_key).ok().and_then(|b| b.try_into().ok()) {
+        Some(key_bytes) => key_bytes,
+        None => return false,
+    };
+    let (key, signature) = match (
+        VerifyingKey::from_bytes(&key_bytes),
+        Signature::from_slice(signature),
+    ) {
+        (Ok(key), Ok(signature)) => (key, signature),
+        _ => return false,
+    };
+    key.verify(bytes, &signature).is_ok()
+}
+
+// NodeSignature authenticates an HTTP message between nodes.
+#[derive(Debug, Clone)]
+pub struct NodeSignature {
+    pub node_key: String,
+    pub timestamp: i64,
+    pub signature: String,
+}
+
+impl NodeSignature {
+    // Read a signature from HTTP headers, returning None if the message is unsigned.
+    pub fn from_headers<'a>(header: impl Fn(&str) -> Option<&'a str>) -> Option<NodeSignature> {
+        Some(NodeSignature {
+            node_key: header(NODE_KEY_HEADER)?.to_string(),
+            timestamp: header(NODE_TIMESTAMP_HEADER)?.parse().ok()?,
+            signature: header(NODE_SIGNATURE_HEADER)?.to_string(),
+        })
+    }
+
+    // HTTP headers carrying the signature.
+    pub fn headers(&self) -> [(&'static str, String); 3] {
+        [
+            (NODE_KEY_HEADER, self.node_key.clone()),
+            (NODE_TIMESTAMP_HEADER, self.timestamp.to_string()),
+            (NODE_SIGNATURE_HEADER, self.signature.clone()),
+        ]
+    }
+}
+
+// AuthError represents the reasons a peer message is refused.
+#[derive(Debug, PartialEq, Eq)]
+pub enum AuthError {
+    MissingSignature,
+    InvalidSignature,
+    StaleTimestamp,
+    NotAllowed(String),
+}
+
+impl fmt::Display for AuthError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AuthError::MissingSignature => write!(f, "Peer message is not signed"),
+            AuthError::InvalidSignature => write!(f, "Peer message signature is invalid"),
+            AuthError::StaleTimestamp => write!(f, "Peer message timestamp is out of range"),
+            AuthError::NotAllowed(key) => write!(f, "Node key {} is not allowed", key),
+        }
+    }
+}
+
+impl Error for AuthError {}
+
+// NodeAuth signs outgoing peer messages and checks incoming ones against an optional allowlist.
+pub struct NodeAuth {
+    pub identity: NodeIdentity,
+    // Public keys of the nodes we accept peer messages from; None accepts any node.
+    allowlist: Option<HashSet<String>>,
+    // Clock signatures are timestamped and checked against.
+    clock: SharedClock,
+}
+
+impl NodeAuth {
+    // Create the authenticator for a node identity and optional allowlist of public keys.
+    pub fn new(identity: NodeIdentity, allowlist: Option<Vec<String>>) -> NodeAuth {
+        NodeAuth {
+            identity,
+            allowlist: allowlist.map(|keys| keys.into_iter().collect()),
+            clock: SharedClock::default(),
+        }
+    }
+
+    // Use the given clock instead of the system clock.
+    pub fn with_clock(mut self, clock: SharedClock) -> NodeAuth {
+        self.clock = clock;
+        self
+    }
+
+    // Hex-encoded public key of this node.
+    pub fn public_key(&self) -> String {
+        self.identity.public_key()
+    }
+
+    // Whether peer messages must be signed by an allowlisted node.
+    pub fn is_restricted(&self) -> bool {
+        self.allowlist.is_some()
+    }
+
+    // Check a node key against the allowlist.
+    pub fn check_allowed(&self, node_key: &str) -> Result<(), AuthError> {
+        match &self.allowlist {
+            Some(allowlist) if !allowlist.contains(node_key) => {
+                Err(AuthError::NotAllowed(node_key.to_string()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    // Sign an HTTP message body sent to or from the given path.
+    pub fn sign(&self, path: &str, body: &[u8]) -> NodeSignature {
+        let timestamp = self.clock.now();
+        let signature = self
+            .identity
+            .sign(&Self::signed_bytes(path, timestamp, body));
+        NodeSignature {
+            node_key: self.public_key(),
+            timestamp,
+            signature: hex::encode(signature),
+        }
+    }
+
+    // Verify an HTTP message body. Unsigned messages are only accepted when no allowlist is set.
+    // Returns the key of the signing node, if any.
+    pub fn verify(
+        &self,
+        path: &str,
+        body: &[u8],
+        signature: Option<NodeSignature>,
+    ) -> Result<Option<String>, AuthError> {
+        let signature = match signature {
+            Some(signature) => signature,
+            None if self.is_restricted() => return Err(AuthError::MissingSignature),
+            None => return Ok(None),
+        };
+        if self.clock.now().abs_diff(signature.timestamp) > MAX_CLOCK_SKEW_SECS {
+            return Err(AuthError::StaleTimestamp);
+        }
+        let signature_bytes =
+            hex::decode(&signature.signature).map_err(|_| AuthError::InvalidSignature)?;
+        if !verify_signature(
+            &signature.node_key,
+            &Self::signed_bytes(path, signature.timestamp, body),
+            &signature_bytes,
+        ) {
+            return Err(AuthError::InvalidSignature);
+        }
+        self.check_allowed(&signature.node_key)?;
+        Ok(Some(signature.node_key))
+    }
+
+    // Bytes covered by an HTTP message signature: the path, timestamp and body.
+    fn signed_bytes(path: &str, timestamp: i64, body: &[u8]) -> Vec<u8> {
+        let mut bytes = format!("{}\n{}\n", path, timestamp).into_bytes();
+        bytes.extend_from_slice(body);
+        bytes
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::clock::ManualClock;
+
+    const NOW: i64 = 1_700_000_000;
+
+    fn auth(allowlist: Option<Vec<String>>) -> NodeAuth {
+        NodeAuth::new(NodeIdentity::generate(), allowlist)
+            .with_clock(SharedClock::new(ManualClock::new(NOW)))
+    }
+
+    #[test]
+    fn signed_messages_verify_as_sent() {
+        let (ours, theirs) = (auth(None), auth(None));
+        let signature = theirs.sign("/add_block", b"block");
+
+        assert_eq!(
+            ours.verify("/add_block", b"block", Some(signature.clone())),
+            Ok(Some(theirs.public_key()))
+        );
+        // The signature survives the trip through HTTP headers
+        let headers = signature.headers();
+        let read = NodeSignature::from_headers(|name| {
+            headers
+                .iter()
+                .find(|(header, _)| *header == name)
+                .map(|(_, value)| value.as_str())
+        });
+        assert_eq!(
+            ours.verify("/add_block", b"block", read),
+            Ok(Some(theirs.public_key()))
+        );
+    }
+
+    #[test]
+    fn tampered_messages_are_refused() {
+        let (ours, theirs) = (auth(None), auth(None));
+        let signature = theirs.sign("/add_block", b"block");
+
+        assert_eq!(
+            ours.verify("/add_block", b"forged", Some(signature.clone())),
+            Err(AuthError::InvalidSignature)
+        );
+        assert_eq!(
+            ours.verify("/register_node", b"block", Some(signature.clone())),
+            Err(AuthError::InvalidSignature)
+        );
+        let mut replaced_key = signature.clone();
+        replaced_key.node_key = auth(None).public_key();
+        assert_eq!(
+            ours.verify("/add_block", b"block", Some(replaced_key)),
+            Err(AuthError::InvalidSignature)
+        );
+        let mut garbled = signature;
+        garbled.signature = "not hex".to_string();
+        assert_eq!(
+            ours.verify("/add_block", b"block", Some(garbled)),
+            Err(AuthError::InvalidSignature)
+        );
+    }
+
+    #[test]
+    fn unsigned_messages_are_only_accepted_without_an_allowlist() {
+        assert_eq!(auth(None).verify("/add_block", b"block", None), Ok(None));
+        let restricted = auth(Some(vec![auth(None).public_key()]));
+        assert_eq!(
+            restricted.verify("/add_block", b"block", None),
+            Err(AuthError::MissingSignature)
+        );
+    }
+
+    #[test]
+    fn only_allowlisted_nodes_are_accepted() {
+        let (allowed, other) = (auth(None), auth(None));
+        let ours = auth(Some(vec![allowed.public_key()]));
+
+        assert_eq!(
+            ours.verify(
+                "/add_block",
+                b"block",
+                Some(allowed.sign("/add_block", b"block"))
+            ),
+            Ok(Some(allowed.public_key()))
+        );
+        assert_eq!(
+            ours.verify(
+                "/add_block",
+                b"block",
+                Some(other.sign("/add_block", b"block"))
+            ),
+            Err(AuthError::NotAllowed(other.public_key()))
+        );
+    }
+
+    #[test]
+    fn signatures_must_be_timestamped_close_to_our_clock() {
+        let ours = auth(None);
+        let signed_at = |timestamp: i64| {
+            let theirs = auth(None).with_clock(SharedClock::new(ManualClock::new(timestamp)));
+            ours.verify(
+                "/add_block",
+                b"block",
+                Some(theirs.sign("/add_block", b"block")),
+            )
+        };
+
+        let skew = MAX_CLOCK_SKEW_SECS as i64;
+        assert!(signed_at(NOW - skew).is_ok());
+        assert!(signed_at(NOW + skew).is_ok());
+        assert_eq!(signed_at(NOW - skew - 1), Err(AuthError::StaleTimestamp));
+        assert_eq!(signed_at(NOW + skew + 1), Err(AuthError::StaleTimestamp));
+        // Timestamps far off either way are refused rather than overflowing
+        assert_eq!(signed_at(i64::MIN), Err(AuthError::StaleTimestamp));
+        assert_eq!(signed_at(i64::MAX), Err(AuthError::StaleTimestamp));
+    }
+}
@@ -0,0 +1,398 @@
+use actix_web::web;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::error::Error;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tracing::{debug, debug_span, info, warn, Instrument};
+
+use super::auth::NodeAuth;
+use super::handshake::{register_with, Handshake};
+use super::scoring::resolve_peer_key;
+use crate::modules::blockchain::chain::{Blockchain, NodePeer};
+
+// DiscoveryConfig controls how the node looks for and keeps outbound peers.
+#[derive(Debug, Clone)]
+pub struct DiscoveryConfig {
+    // Address other nodes can reach this node on.
+    pub own_address: String,
+    // Address of the node's peer-to-peer transport, advertised in handshakes.
+    pub p2p_address: Option<String>,
+    // Addresses tried first when the node knows too few peers.
+    pub seeds: Vec<String>,
+    // Number of outbound peers the node tries to maintain.
+    pub target_outbound: usize,
+    // Time between discovery rounds.
+    pub interval: Duration,
+}
+
+impl Default for DiscoveryConfig {
+    fn default() -> Self {
+        DiscoveryConfig {
+            own_address: "http://127.0.0.1:8080".to_string(),
+            p2p_address: None,
+            seeds: Vec::new(),
+            target_outbound: 8,
+            interval: Duration::from_secs(30),
+        }
+    }
+}
+
+// Number of discovery rounds in a row an outbound peer may fail to answer before it is dropped.
+pub const MAX_PEER_FAILURES: u32 = 3;
+// Largest number of addresses kept in the address book.
+pub const MAX_ADDRESS_BOOK: usize = 1024;
+// Largest number of addresses taken from the answer of one peer, and largest answer read.
+pub const MAX_PEERS_PER_ANSWER: usize = 256;
+const MAX_PEERS_ANSWER_BYTES: usize = 64 * 1024;
+
+// AddressBook holds the addresses learned from other nodes that we have not connected to yet,
+// oldest first. Once it holds MAX_ADDRESS_BOOK addresses, the oldest make room for new ones.
+#[derive(Debug, Default, Clone)]
+pub struct AddressBook {
+    order: VecDeque<String>,
+    addresses: HashSet<String>,
+}
+
+impl AddressBook {
+    // Add an address, unless it is already in the book.
+    pub fn insert(&mut self, address: String) {
+        if self.addresses.contains(&address) {
+            return;
+        }
+        if self.order.len() >= MAX_ADDRESS_BOOK {
+            if let Some(oldest) = self.order.pop_front() {
+                self.addresses.remove(&oldest);
+            }
+        }
+        self.addresses.insert(address.clone());
+        self.order.push_back(address);
+    }
+
+    // Keep only the addresses for which keep returns true.
+    pub fn retain(&mut self, keep: impl Fn(&str) -> bool) {
+        let addresses = &mut self.addresses;
+        self.order.retain(|address| {
+            let kept = keep(address);
+            if !kept {
+                addresses.remove(address);
+            }
+            kept
+        });
+    }
+
+    // The addresses in the book, oldest first.
+    pub fn addresses(&self) -> Vec<String> {
+        self.order.iter().cloned().collect()
+    }
+}
+
+// FailedPeers counts the discovery rounds in a row each outbound peer failed to answer, so a
+// peer is only dropped once it has been unreachable for a while rather than on one timeout.
+#[derive(Debug, Default)]
+pub struct FailedPeers(HashMap<String, u32>);
+
+impl FailedPeers {
+    // Record whether a peer answered. Returns true once it has failed MAX_PEER_FAILURES rounds in
+    // a row, and forgets it then so a peer registered again later starts afresh.
+    pub fn record(&mut self, node_address: &str, answered: bool) -> bool {
+        if answered {
+            self.0.remove(node_address);
+            return false;
+        }
+        let failures = self.0.entry(node_address.to_string()).or_insert(0);
+        *failures += 1;
+        if *failures < MAX_PEER_FAILURES {
+            return false;
+        }
+        self.0.remove(node_address);
+        true
+    }
+}
+
+// Run discovery rounds forever at the configured interval.
+pub async fn run_discovery(
+    blockchain: web::Data<Mutex<Blockchain>>,
+    auth: Arc<NodeAuth>,
+    config: DiscoveryConfig,
+) {
+    let mut interval = tokio::time::interval(config.interval);
+    let mut failed = FailedPeers::default();
+    loop {
+        interval.tick().await;
+        if let Err(e) = discover_once(&blockchain, &auth, &config, &mut failed)
+            .instrument(debug_span!("discovery"))
+            .await
+        {
+            warn!(error = %e, "Peer discovery failed");
+        }
+    }
+}
+
+// Perform one discovery round: ask known peers for their peers and register with new ones
+// until the target number of outbound peers is reached. Addresses stay in the address book until
+// they are tried, and outbound peers are dropped after failing MAX_PEER_FAILURES rounds in a row.
+// Returns the number of peers added.
+pub async fn discover_once(
+    blockchain: &web::Data<Mutex<Blockchain>>,
+    auth: &NodeAuth,
+    config: &DiscoveryConfig,
+    failed: &mut FailedPeers,
+) -> Result<usize, Box<dyn Error>> {
+    let client = reqwest::Client::new();
+    let (known, address_book, local) = {
+        let blockchain = blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        let known: Vec<NodePeer> = blockchain.peers.clone();
+        let address_book = blockchain.address_book.addresses();
+        let local = Handshake::local(&blockchain, &config.own_address)
+            .with_p2p_address(config.p2p_address.clone())
+            .with_node_key(auth.public_key());
+        (known, address_book, local)
+    };
+
+    // Ask outbound peers for their peers, dropping the ones that keep failing to answer
+    let mut candidates: Vec<String> = config.seeds.iter().cloned().chain(address_book).collect();
+    let mut unreachable = Vec::new();
+    for peer in known.iter().filter(|peer| peer.outbound) {
+        let answered = match fetch_peers(&client, &peer.node_address).await {
+            Ok(addresses) => {
+                candidates.extend(addresses);
+                true
+            }
+            Err(e) => {
+                debug!(peer = %peer.node_address, error = %e, "Failed to fetch peers");
+                false
+            }
+        };
+        if failed.record(&peer.node_address, answered) {
+            unreachable.push(peer.node_address.clone());
+        }
+    }
+
+    let mut outbound = known
+        .iter()
+        .filter(|peer| peer.outbound && !unreachable.contains(&peer.node_address))
+        .count();
+    let known_addresses: HashSet<&str> = known
+        .iter()
+        .map(|peer| peer.node_address.as_str())
+        .collect();
+    let mut added = 0;
+    let mut tried = HashSet::new();
+    let mut candidates = candidates.into_iter();
+    while outbound < config.target_outbound {
+        let candidate = match candidates.next() {
+            Some(candidate) => candidate,
+            None => break,
+        };
+        if candidate == config.own_address
+            || tried.contains(&candidate)
+            || known_addresses.contains(candidate.as_str())
+        {
+            continue;
+        }
+        tried.insert(candidate.clone());
+        let key = resolve_peer_key(&candidate).await;
+        if blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read")
+            .peer_scores
+            .is_banned(&key)
+        {
+            continue;
+        }
+
+        match register_with(&client, &candidate, &local, auth).await {
+            Ok(registration) => {
+                let key = registration.signer.clone().unwrap_or(key);
+                let mut blockchain = blockchain
+                    .lock()
+                    .expect("Unable to lock blockchain for update");
+                if blockchain.peer_scores.is_banned(&key) {
+                    continue;
+                }
+                info!(peer = %candidate, "Registered with peer");
+                blockchain.add_node_peer(NodePeer {
+                    node_address: candidate,
+                    handshake: Some(registration.handshake),
+                    outbound: true,
+                    key: Some(key),
+                });
+                blockchain.remember_addresses(
+                    registration.peers.into_iter().map(|peer| peer.node_address),
+                );
+                outbound += 1;
+                added += 1;
+            }
+            Err(e) => debug!(peer = %candidate, error = %e, "Failed to register with peer"),
+        }
+    }
+
+    let mut blockchain = blockchain
+        .lock()
+        .expect("Unable to lock blockchain for update");
+    // Only the addresses tried this round leave the address book; the candidates left over are
+    // kept for later rounds
+    blockchain
+        .address_book
+        .retain(|address| !tried.contains(address));
+    blockchain.remember_addresses(candidates.filter(|candidate| {
+        *candidate != config.own_address
+            && !config.seeds.contains(candidate)
+            && !tried.contains(candidate)
+    }));
+    if !unreachable.is_empty() {
+        info!(peers = ?unreachable, "Dropping unreachable peers");
+        blockchain
+            .peers
+            .retain(|peer| !unreachable.contains(&peer.node_address));
+    }
+    Ok(added)
+}
+
+// Ask a peer for the addresses of the peers it knows, taking at most MAX_PEERS_PER_ANSWER of
+// them. Answers larger than MAX_PEERS_ANSWER_BYTES are refused.
+pub async fn fetch_peers(
+    client: &reqwest::Client,
+    node_address: &str,
+) -> Result<Vec<String>, Box<dyn Error>> {
+    let mut response = client
+        .get(format!("{}/peers", node_address))
+        .send()
+        .await?
+        .error_for_status()?;
+    let mut body = Vec::new();
+    while let Some(chunk) = response.chunk().await? {
+        if body.len() + chunk.len() > MAX_PEERS_ANSWER_BYTES {
+            return Err("Peer list is too large".into());
+        }
+        body.extend_from_slice(&chunk);
+    }
+    let mut addresses: Vec<String> = serde_json::from_slice(&body)?;
+    addresses.truncate(MAX_PEERS_PER_ANSWER);
+    Ok(addresses)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::network::auth::NodeIdentity;
+
+    // Nothing listens on port 1, so connections to it are refused at once.
+    const UNREACHABLE: &str = "http://127.0.0.1:1";
+
+    fn node() -> (web::Data<Mutex<Blockchain>>, NodeAuth) {
+        let blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        (
+            web::Data::new(Mutex::new(blockchain)),
+            NodeAuth::new(NodeIdentity::generate(), None),
+        )
+    }
+
+    #[test]
+    fn peers_are_dropped_after_failing_rounds_in_a_row() {
+        let mut failed = FailedPeers::default();
+        for _ in 1..MAX_PEER_FAILURES {
+            assert!(!failed.record("http://peer", false));
+        }
+        // Answering once starts the count again
+        assert!(!failed.record("http://peer", true));
+        for _ in 1..MAX_PEER_FAILURES {
+            assert!(!failed.record("http://peer", false));
+        }
+        assert!(failed.record("http://peer", false));
+    }
+
+    #[test]
+    fn the_address_book_forgets_its_oldest_addresses_when_full() {
+        let mut book = AddressBook::default();
+        for i in 0..MAX_ADDRESS_BOOK + 10 {
+            book.insert(format!("http://node-{}", i));
+            book.insert(format!("http://node-{}", i));
+        }
+        assert_eq!(book.addresses().len(), MAX_ADDRESS_BOOK);
+        let addresses = book.addresses();
+        assert_eq!(addresses[0], "http://node-10");
+        assert_eq!(
+            addresses.last().unwrap(),
+            &format!("http://node-{}", MAX_ADDRESS_BOOK + 9)
+        );
+
+        // An address that was forgotten can be learned again
+        book.retain(|address| address != "http://node-10");
+        book.insert("http://node-0".to_string());
+        assert_eq!(book.addresses().len(), MAX_ADDRESS_BOOK);
+        assert_eq!(book.addresses().last().unwrap(), "http://node-0");
+    }
+
+    #[tokio::test]
+    async fn untried_addresses_stay_in_the_address_book() {
+        let (blockchain, auth) = node();
+        let addresses = vec![
+            format!("{}/a", UNREACHABLE),
+            format!("{}/b", UNREACHABLE),
+            format!("{}/c", UNREACHABLE),
+        ];
+        blockchain
+            .lock()
+            .unwrap()
+            .remember_addresses(addresses.clone());
+        blockchain.lock().unwrap().add_node_peer(NodePeer {
+            node_address: "http://127.0.0.1:2".to_string(),
+            handshake: None,
+            outbound: true,
+            key: None,
+        });
+        let config = DiscoveryConfig {
+            target_outbound: 1,
+            ..DiscoveryConfig::default()
+        };
+        let mut failed = FailedPeers::default();
+
+        // With enough outbound peers nothing is tried, and nothing is forgotten
+        let added = discover_once(&blockchain, &auth, &config, &mut failed)
+            .await
+            .unwrap();
+        assert_eq!(added, 0);
+        assert_eq!(
+            blockchain.lock().unwrap().address_book.addresses(),
+            addresses
+        );
+    }
+
+    #[tokio::test]
+    async fn unreachable_peers_are_kept_for_a_few_rounds() {
+        let (blockchain, auth) = node();
+        blockchain.lock().unwrap().add_node_peer(NodePeer {
+            node_address: UNREACHABLE.to_string(),
+            handshake: None,
+            outbound: true,
+            key: None,
+        });
+        blockchain
+            .lock()
+            .unwrap()
+            .remember_addresses(vec![format!("{}/a", UNREACHABLE)]);
+        let config = DiscoveryConfig {
+            target_outbound: 2,
+            ..DiscoveryConfig::default()
+        };
+        let mut failed = FailedPeers::default();
+
+        for _ in 1..MAX_PEER_FAILURES {
+            discover_once(&blockchain, &auth, &config, &mut failed)
+                .await
+                .unwrap();
+            assert_eq!(blockchain.lock().unwrap().peers.len(), 1);
+        }
+        discover_once(&blockchain, &auth, &config, &mut failed)
+            .await
+            .unwrap();
+        let blockchain = blockchain.lock().unwrap();
+        assert!(blockchain.peers.is_empty());
+        // The address that was tried and refused left the address book
+        assert!(blockchain.address_book.addresses().is_empty());
+    }
+}
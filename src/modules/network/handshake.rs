@@ -0,0 +1,233 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::error::Error;
+use std::fmt;
+
+use super::auth::{AuthError, NodeAuth, NodeSignature};
+use crate::modules::blockchain::chain::{Blockchain, NodePeer};
+
+// Version of the node-to-node protocol spoken by this build.
+pub const PROTOCOL_VERSION: u32 = 2;
+// Oldest protocol version of a peer we are still willing to talk to.
+pub const MIN_PROTOCOL_VERSION: u32 = 2;
+// Version of this node software.
+pub const SOFTWARE_VERSION: &str = env!("CARGO_PKG_VERSION");
+
+// Handshake is exchanged on registration so both sides can check they run the same network.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq, JsonSchema)]
+pub struct Handshake {
+    pub node_address: String,
+    pub protocol_version: u32,
+    pub software_version: String,
+    pub chain_id: String,
+    pub genesis_hash: String,
+    pub difficulty: i32,
+    pub tip_height: i32,
+    // Address of the node's peer-to-peer transport, if it runs one.
+    #[serde(default)]
+    pub p2p_address: Option<String>,
+    // Hex-encoded public key the node signs its peer messages with.
+    #[serde(default)]
+    pub node_key: Option<String>,
+}
+
+// Registration is the reply of a node that accepted our registration.
+#[derive(Debug, Deserialize, JsonSchema)]
+pub struct Registration {
+    pub handshake: Handshake,
+    pub chain: Vec<serde_json::Map<String, serde_json::Value>>,
+    #[serde(default)]
+    pub peers: Vec<NodePeer>,
+    // Node key the reply was signed with, if it was signed.
+    #[serde(skip)]
+    pub signer: Option<String>,
+}
+
+// HandshakeError represents the reasons a peer is refused as incompatible.
+#[derive(Debug, PartialEq, Eq)]
+pub enum HandshakeError {
+    UnsupportedProtocol(u32),
+    ChainIdMismatch(String),
+    GenesisMismatch(String),
+    DifficultyMismatch(i32),
+}
+
+impl fmt::Display for HandshakeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            HandshakeError::UnsupportedProtocol(version) => write!(
+                f,
+                "Unsupported protocol version {} (supported {}..={})",
+                version, MIN_PROTOCOL_VERSION, PROTOCOL_VERSION
+            ),
+            HandshakeError::ChainIdMismatch(chain_id) => {
+                write!(f, "Peer is on a different chain: {}", chain_id)
+            }
+            HandshakeError::GenesisMismatch(hash) => {
+                write!(f, "Peer has a different genesis block: {}", hash)
+            }
+            HandshakeError::DifficultyMismatch(difficulty) => {
+                write!(f, "Peer uses a different difficulty: {}", difficulty)
+            }
+        }
+    }
+}
+
+impl Error for HandshakeError {}
+
+impl Handshake {
+    // Build the handshake describing the local node.
+    pub fn local(blockchain: &Blockchain, node_address: &str) -> Handshake {
+        Handshake {
+            node_address: node_address.to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            software_version: SOFTWARE_VERSION.to_string(),
+            chain_id: blockchain.genesis.chain_id.clone(),
+            genesis_hash: blockchain.genesis_hash().to_string(),
+            difficulty: blockchain.difficulty,
+            tip_height: blockchain.get_last_block().index,
+            p2p_address: None,
+            node_key: None,
+        }
+    }
+
+    // Advertise the address of the node's peer-to-peer transport.
+    pub fn with_p2p_address(mut self, p2p_address: Option<String>) -> Handshake {
+        self.p2p_address = p2p_address;
+        self
+    }
+
+    // Advertise the public key the node signs its peer messages with.
+    pub fn with_node_key(mut self, node_key: String) -> Handshake {
+        self.node_key = Some(node_key);
+        self
+    }
+
+    // Check that a remote handshake is compatible with the local one.
+    pub fn check_compatible(&self, local: &Handshake) -> Result<(), HandshakeError> {
+        if self.protocol_version < MIN_PROTOCOL_VERSION || self.protocol_version > PROTOCOL_VERSION
+        {
+            return Err(HandshakeError::UnsupportedProtocol(self.protocol_version));
+        }
+        if self.chain_id != local.chain_id {
+            return Err(HandshakeError::ChainIdMismatch(self.chain_id.clone()));
+        }
+        if self.genesis_hash != local.genesis_hash {
+            return Err(HandshakeError::GenesisMismatch(self.genesis_hash.clone()));
+        }
+        if self.difficulty != local.difficulty {
+            return Err(HandshakeError::DifficultyMismatch(self.difficulty));
+        }
+        Ok(())
+    }
+}
+
+// Register with a remote node by sending it our signed handshake, and check its reply is
+// authentic and compatible.
+pub async fn register_with(
+    client: &reqwest::Client,
+    node_address: &str,
+    local: &Handshake,
+    auth: &NodeAuth,
+) -> Result<Registration, Box<dyn Error>> {
+    let url = reqwest::Url::parse(&format!("{}/register_node", node_address))?;
+    let payload = serde_json::to_string(local)?;
+    let mut request = client
+        .post(url.clone())
+        .header("Content-Type", "application/json");
+    for (name, value) in auth.sign(url.path(), payload.as_bytes()).headers() {
+        request = request.header(name, value);
+    }
+    let response = request.body(payload).send().await?;
+
+    let status = response.status();
+    let signature = NodeSignature::from_headers(|name| response.headers().get(name)?.to_str().ok());
+    let body = response.text().await?;
+    if !status.is_success() {
+        return Err(format!("Registration refused ({}): {}", status, body).into());
+    }
+    let signer = auth.verify(url.path(), body.as_bytes(), signature)?;
+    let mut registration: Registration = serde_json::from_str(&body)?;
+    if signer.is_some() && signer != registration.handshake.node_key {
+        return Err(AuthError::InvalidSignature.into());
+    }
+    registration.handshake.check_compatible(local)?;
+    registration.signer = signer;
+    Ok(registration)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn local() -> Handshake {
+        Handshake {
+            node_address: "http://127.0.0.1:8000".to_string(),
+            protocol_version: PROTOCOL_VERSION,
+            software_version: SOFTWARE_VERSION.to_string(),
+            chain_id: "versus-devnet".to_string(),
+            genesis_hash: "00ab".to_string(),
+            difficulty: 2,
+            tip_height: 0,
+            p2p_address: None,
+            node_key: None,
+        }
+    }
+
+    #[test]
+    fn compatible_peers_are_accepted() {
+        let remote = Handshake {
+            node_address: "http://127.0.0.1:8001".to_string(),
+            software_version: "0.0.1".to_string(),
+            tip_height: 12,
+            ..local()
+        };
+        assert_eq!(remote.check_compatible(&local()), Ok(()));
+    }
+
+    #[test]
+    fn unsupported_protocol_versions_are_refused() {
+        for version in [MIN_PROTOCOL_VERSION - 1, PROTOCOL_VERSION + 1] {
+            let remote = Handshake {
+                protocol_version: version,
+                ..local()
+            };
+            assert_eq!(
+                remote.check_compatible(&local()),
+                Err(HandshakeError::UnsupportedProtocol(version))
+            );
+        }
+    }
+
+    #[test]
+    fn other_networks_are_refused() {
+        let remote = Handshake {
+            chain_id: "versus-mainnet".to_string(),
+            ..local()
+        };
+        assert_eq!(
+            remote.check_compatible(&local()),
+            Err(HandshakeError::ChainIdMismatch(
+                "versus-mainnet".to_string()
+            ))
+        );
+
+        let remote = Handshake {
+            genesis_hash: "00cd".to_string(),
+            ..local()
+        };
+        assert_eq!(
+            remote.check_compatible(&local()),
+            Err(HandshakeError::GenesisMismatch("00cd".to_string()))
+        );
+
+        let remote = Handshake {
+            difficulty: 3,
+            ..local()
+        };
+        assert_eq!(
+            remote.check_compatible(&local()),
+            Err(HandshakeError::DifficultyMismatch(3))
+        );
+    }
+}
@@ -0,0 +1,9 @@
+pub mod auth;
+pub mod discovery;
+pub mod handshake;
+pub mod peer_client;
+pub mod scoring;
+#[cfg(test)]
+pub mod simulation;
+pub mod transport;
+pub mod wire;
@@ -0,0 +1,106 @@
+use std::error::Error;
+use std::future::Future;
+
+use crate::modules::api::listing::{ListQuery, MAX_PAGE_SIZE};
+use crate::modules::api::API_PREFIX;
+use crate::modules::blockchain::block::Block;
+use crate::modules::network::auth::NodeAuth;
+use crate::modules::network::wire::Message;
+
+// PeerClient is how a node talks to its peers during consensus, block announcement and gossip.
+// Nodes use their Transport, which reaches peers over HTTP and its connections; tests swap in a
+// simulated network.
+pub trait PeerClient: Send + Sync {
+    // Ask a peer for a page of its chain, from its genesis block or after the given cursor,
+    // answered as GET /v1/blocks does: a BlockList in an envelope.
+    fn fetch_blocks(
+        &self,
+        node_address: &str,
+        cursor: Option<&str>,
+    ) -> impl Future<Output = Result<Vec<u8>, Box<dyn Error>>> + Send;
+
+    // Send a block to a peer as POST /add_block does, signed with the node key.
+    fn send_block(
+        &self,
+        node_address: &str,
+        block: &Block,
+        auth: &NodeAuth,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+
+    // Send a message of the peer-to-peer transport to a peer, known by its node address. Fails
+    // if the peer is not connected over the transport, so that callers can use HTTP instead.
+    fn send_message(
+        &self,
+        node_address: &str,
+        message: Message,
+    ) -> impl Future<Output = Result<(), Box<dyn Error>>> + Send;
+}
+
+// Query for the largest page of a chain that starts after the cursor.
+pub fn chain_page(cursor: Option<&str>) -> ListQuery {
+    ListQuery {
+        limit: Some(MAX_PAGE_SIZE),
+        cursor: cursor.map(str::to_string),
+        ..ListQuery::default()
+    }
+}
+
+// HttpPeerClient talks to peers over their HTTP API only: no peer is connected over the
+// transport.
+#[derive(Debug, Default, Clone)]
+pub struct HttpPeerClient {
+    client: reqwest::Client,
+}
+
+impl HttpPeerClient {
+    pub fn new() -> HttpPeerClient {
+        HttpPeerClient::default()
+    }
+}
+
+impl PeerClient for HttpPeerClient {
+    async fn fetch_blocks(
+        &self,
+        node_address: &str,
+        cursor: Option<&str>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        let response = self
+            .client
+            .get(format!("{}{}/blocks", node_address, API_PREFIX))
+            .query(&chain_page(cursor))
+            .send()
+            .await?;
+        Ok(response.bytes().await?.to_vec())
+    }
+
+    async fn send_block(
+        &self,
+        node_address: &str,
+        block: &Block,
+        auth: &NodeAuth,
+    ) -> Result<(), Box<dyn Error>> {
+        let json_str = serde_json::to_string(block)?;
+        let url = reqwest::Url::parse(&format!("{}/add_block", node_address))?;
+        let mut request = self
+            .client
+            .post(url.clone())
+            .header("Content-Type", "application/json");
+        for (name, value) in auth.sign(url.path(), json_str.as_bytes()).headers() {
+            request = request.header(name, value);
+        }
+
+        let response = request.body(json_str).send().await?;
+        if !response.status().is_success() {
+            return Err(response.status().to_string().into());
+        }
+        Ok(())
+    }
+
+    async fn send_message(
+        &self,
+        node_address: &str,
+        _message: Message,
+    ) -> Result<(), Box<dyn Error>> {
+        Err(format!("{} is not connected over the transport", node_address).into())
+    }
+}
@@ -0,0 +1,321 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::convert::TryFrom;
+use std::error::Error;
+use std::fs;
+use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::path::{Path, PathBuf};
+use std::time::Duration;
+use tracing::{debug, error, warn};
+
+use crate::modules::blockchain::chain::{write_atomically, BlockError};
+use crate::modules::clock::SharedClock;
+
+// Name of the persisted ban list in the data directory.
+pub const BAN_FILE: &str = "banned_peers.json";
+// Longest ban a policy may set, in seconds: ten years.
+pub const MAX_BAN_SECS: u64 = 10 * 365 * 24 * 60 * 60;
+
+// Offence represents a kind of misbehaviour a peer can be penalised for.
+#[derive(Debug, Serialize, Deserialize, Clone, Copy, PartialEq, Eq, JsonSchema)]
+pub enum Offence {
+    InvalidProofOfWork,
+    InvalidPreviousHash,
+    InvalidChain,
+    MalformedJson,
+    MalformedMessage,
+    OversizedPayload,
+    InvalidTransaction,
+}
+
+impl Offence {
+    // Number of points added to a peer's score for this offence.
+    pub fn penalty(&self) -> u32 {
+        match self {
+            Offence::InvalidProofOfWork => 50,
+            Offence::InvalidPreviousHash => 10,
+            Offence::InvalidChain => 50,
+            Offence::MalformedJson => 20,
+            Offence::MalformedMessage => 20,
+            Offence::OversizedPayload => 25,
+            Offence::InvalidTransaction => 20,
+        }
+    }
+}
+
+impl From<&BlockError> for Offence {
+    fn from(err: &BlockError) -> Self {
+        match err {
+            BlockError::PreviousHashMismatch => Offence::InvalidPreviousHash,
+            BlockError::InvalidProof => Offence::InvalidProofOfWork,
+        }
+    }
+}
+
+// BanPolicy controls when a misbehaving peer gets banned and for how long.
+#[derive(Debug, Clone)]
+pub struct BanPolicy {
+    pub threshold: u32,
+    pub ban_duration: Duration,
+}
+
+impl Default for BanPolicy {
+    fn default() -> Self {
+        BanPolicy {
+            threshold: 100,
+            ban_duration: Duration::from_secs(24 * 60 * 60),
+        }
+    }
+}
+
+// BanEntry records why a peer was banned and until when (unix seconds).
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub struct BanEntry {
+    pub until: i64,
+    pub reason: Offence,
+}
+
+// PeerScores tracks misbehaviour scores per peer and the resulting bans.
+#[derive(Debug, Default)]
+pub struct PeerScores {
+    pub policy: BanPolicy,
+    scores: HashMap<String, u32>,
+    bans: HashMap<String, BanEntry>,
+    ban_file: Option<PathBuf>,
+    // Clock bans are timed against.
+    pub clock: SharedClock,
+}
+
+impl PeerScores {
+    // Create an in-memory score table with the given policy.
+    pub fn new(policy: BanPolicy) -> PeerScores {
+        PeerScores {
+            policy,
+            ..Default::default()
+        }
+    }
+
+    // Create a score table whose ban list is loaded from and persisted to a file.
+    pub fn load(path: &Path, policy: BanPolicy) -> Result<PeerScores, Box<dyn Error>> {
+        let mut scores = PeerScores::new(policy);
+        if path.exists() {
+            let content = fs::read_to_string(path)?;
+            scores.bans = serde_json::from_str(&content)?;
+        }
+        scores.ban_file = Some(path.to_path_buf());
+        scores.prune_expired();
+        Ok(scores)
+    }
+
+    // Write the ban list to its file, if one was configured.
+    pub fn save(&self) -> Result<(), Box<dyn Error>> {
+        let path = match &self.ban_file {
+            Some(path) => path,
+            None => return Ok(()),
+        };
+        write_atomically(path, &serde_json::to_string_pretty(&self.bans)?)
+    }
+
+    // Penalise a peer for an offence. Returns true if the peer got banned as a result.
+    pub fn penalise(&mut self, peer: &str, offence: Offence) -> bool {
+        let score = self.scores.entry(peer.to_string()).or_insert(0);
+        *score += offence.penalty();
+        if *score < self.policy.threshold {
+            debug!(peer, ?offence, score = *score, "Penalised peer");
+            return false;
+        }
+
+        self.scores.remove(peer);
+        let ban_secs = i64::try_from(self.policy.ban_duration.as_secs()).unwrap_or(i64::MAX);
+        self.bans.insert(
+            peer.to_string(),
+            BanEntry {
+                until: self.clock.now().saturating_add(ban_secs),
+                reason: offence,
+            },
+        );
+        warn!(peer, ?offence, "Banned peer");
+        if let Err(e) = self.save() {
+            error!(error = %e, "Failed to persist ban list");
+        }
+        true
+    }
+
+    // Check whether a peer is currently banned.
+    pub fn is_banned(&self, peer: &str) -> bool {
+        self.bans
+            .get(peer)
+            .is_some_and(|ban| ban.until > self.clock.now())
+    }
+
+    // Current misbehaviour score of a peer.
+    pub fn score(&self, peer: &str) -> u32 {
+        self.scores.get(peer).copied().unwrap_or(0)
+    }
+
+    // All bans keyed by peer, including ones that ran out since the list was last pruned.
+    pub fn bans(&self) -> &HashMap<String, BanEntry> {
+        &self.bans
+    }
+
+    // Number of peers currently banned.
+    pub fn banned_count(&self) -> usize {
+        let now = self.clock.now();
+        self.bans.values().filter(|ban| ban.until > now).count()
+    }
+
+    // Drop bans that have run out.
+    fn prune_expired(&mut self) {
+        let now = self.clock.now();
+        self.bans.retain(|_, ban| ban.until > now);
+    }
+}
+
+// Peers that proved they hold a node key are scored under it, so that nodes sharing a host or
+// NAT are told apart. Other peers are scored under the keys below.
+
+// Key a peer is scored under by the socket it connects from: its IP address and port, with IPv4
+// addresses mapped into IPv6 written as IPv4 so that dual-stack listeners score a peer under the
+// same key as IPv4 ones.
+pub fn socket_key(addr: SocketAddr) -> String {
+    SocketAddr::new(addr.ip().to_canonical(), addr.port()).to_string()
+}
+
+// Host, in lower case and without brackets, and port of a node address.
+fn host_and_port(node_address: &str) -> Option<(String, u16)> {
+    let url = reqwest::Url::parse(node_address).ok()?;
+    let host = url.host_str()?.to_ascii_lowercase();
+    let host = host
+        .trim_start_matches('[')
+        .trim_end_matches(']')
+        .to_string();
+    Some((host, url.port_or_known_default()?))
+}
+
+// Derive the key a peer is scored under from its node address without a DNS lookup: the IP
+// address and port of the URL, the loopback address for localhost, and other host names as
+// they are.
+pub fn peer_key(node_address: &str) -> String {
+    let (host, port) = match host_and_port(node_address) {
+        Some(host_and_port) => host_and_port,
+        None => return node_address.to_string(),
+    };
+    if host == "localhost" {
+        return socket_key(SocketAddr::new(Ipv4Addr::LOCALHOST.into(), port));
+    }
+    match host.parse() {
+        Ok(ip) => socket_key(SocketAddr::new(ip, port)),
+        Err(_) => format!("{}:{}", host, port),
+    }
+}
+
+// Resolve the key a peer is scored under from its node address, looking host names up so that
+// a peer named in a URL is scored under the IP address it listens on. IPv4 addresses are
+// preferred, as with the loopback address. Falls back to peer_key if the lookup fails.
+pub async fn resolve_peer_key(node_address: &str) -> String {
+    let key = peer_key(node_address);
+    if key.parse::<SocketAddr>().is_ok() {
+        return key;
+    }
+    let (host, port) = match host_and_port(node_address) {
+        Some(host_and_port) => host_and_port,
+        None => return key,
+    };
+    let addresses: Vec<IpAddr> = match tokio::net::lookup_host((host, port)).await {
+        Ok(addresses) => addresses.map(|address| address.ip()).collect(),
+        Err(_) => return key,
+    };
+    addresses
+        .iter()
+        .find(|ip| ip.is_ipv4())
+        .or_else(|| addresses.first())
+        .map_or(key, |ip| socket_key(SocketAddr::new(*ip, port)))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::clock::ManualClock;
+
+    #[test]
+    fn bans_expire_with_the_clock() {
+        let clock = ManualClock::new(1_000);
+        let mut scores = PeerScores::new(BanPolicy {
+            threshold: 50,
+            ban_duration: Duration::from_secs(60),
+        });
+        scores.clock = SharedClock::new(clock.clone());
+
+        assert!(scores.penalise("peer", Offence::InvalidChain));
+        assert!(scores.is_banned("peer"));
+        clock.advance(59);
+        assert!(scores.is_banned("peer"));
+        clock.advance(1);
+        assert!(!scores.is_banned("peer"));
+    }
+
+    #[test]
+    fn bans_longer_than_time_itself_do_not_overflow() {
+        let mut scores = PeerScores::new(BanPolicy {
+            threshold: 1,
+            ban_duration: Duration::from_secs(u64::MAX),
+        });
+        scores.clock = SharedClock::new(ManualClock::new(1_000));
+
+        assert!(scores.penalise("peer", Offence::MalformedJson));
+        assert_eq!(scores.bans()["peer"].until, i64::MAX);
+        assert!(scores.is_banned("peer"));
+    }
+
+    #[test]
+    fn scores_add_up_until_the_peer_is_banned() {
+        let mut scores = PeerScores::new(BanPolicy::default());
+
+        assert!(!scores.penalise("peer", Offence::InvalidPreviousHash));
+        assert!(!scores.penalise("peer", Offence::MalformedJson));
+        assert_eq!(scores.score("peer"), 30);
+        assert_eq!(scores.score("other"), 0);
+        assert!(scores.bans().is_empty());
+        assert!(!scores.penalise("peer", Offence::InvalidChain));
+        assert!(scores.penalise("peer", Offence::InvalidChain));
+        // The score starts over once the peer is banned
+        assert_eq!(scores.score("peer"), 0);
+        assert_eq!(scores.bans()["peer"].reason, Offence::InvalidChain);
+    }
+
+    #[test]
+    fn peers_are_scored_under_their_address_and_port() {
+        assert_eq!(peer_key("http://127.0.0.1:8000"), "127.0.0.1:8000");
+        assert_eq!(peer_key("http://localhost:8000"), "127.0.0.1:8000");
+        assert_eq!(peer_key("http://LocalHost:8000"), "127.0.0.1:8000");
+        assert_eq!(peer_key("http://[::1]:8000"), "[::1]:8000");
+        assert_eq!(peer_key("http://[::ffff:10.0.0.1]:8000"), "10.0.0.1:8000");
+        assert_eq!(
+            socket_key("[::ffff:127.0.0.1]:8000".parse().unwrap()),
+            peer_key("http://localhost:8000")
+        );
+        assert_eq!(peer_key("http://node-1"), "node-1:80");
+        // Nodes on the same host are different peers
+        assert_ne!(
+            peer_key("http://127.0.0.1:8000"),
+            peer_key("http://127.0.0.1:8001")
+        );
+    }
+
+    #[tokio::test]
+    async fn host_names_resolve_to_their_ip_address() {
+        assert_eq!(
+            resolve_peer_key("http://localhost:8000").await,
+            "127.0.0.1:8000"
+        );
+        assert_eq!(
+            resolve_peer_key("http://10.0.0.1:8000").await,
+            "10.0.0.1:8000"
+        );
+        assert_eq!(
+            resolve_peer_key("http://unknown.invalid:8000").await,
+            "unknown.invalid:8000"
+        );
+    }
+}
@@ -0,0 +1,930 @@
+use actix_web::web;
+use std::collections::HashMap;
+use std::error::Error;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::io::{AsyncRead, AsyncWrite};
+use tokio::net::{TcpListener, TcpStream};
+use tokio::sync::{mpsc, Notify};
+use tracing::{debug, info, info_span, warn, Instrument};
+
+use super::auth::{verify_signature, NodeAuth};
+use super::handshake::Handshake;
+use super::peer_client::{HttpPeerClient, PeerClient};
+use super::scoring::{socket_key, Offence};
+use super::wire::{read_frame, write_frame, Frame, FrameTooLarge, Inventory, Message, MAX_HEADERS};
+use crate::modules::blockchain::block::Block;
+use crate::modules::blockchain::chain::{Blockchain, Received, Transaction, TransactionError};
+use crate::modules::clock::SharedRng;
+
+// Default address the peer-to-peer transport listens on.
+pub const DEFAULT_P2P_ADDRESS: &str = "127.0.0.1:9080";
+// Time a peer has to send its handshake after connecting.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+// Time between attempts to connect to registered peers and pings on open connections.
+const MAINTENANCE_INTERVAL: Duration = Duration::from_secs(15);
+// Number of random bytes in the challenge of a handshake.
+const CHALLENGE_BYTES: usize = 32;
+// Messages queued for a peer before further broadcasts to it are dropped.
+const PEER_QUEUE_LEN: usize = 256;
+
+// Connection is an open connection to a peer, as seen by the other connections.
+struct Connection {
+    // Tells this connection apart from a later one to the same peer.
+    id: u64,
+    // Node key of the node that opened the connection.
+    opened_by: String,
+    // Address of the HTTP API of the peer, which the rest of the node knows it by.
+    node_address: String,
+    sender: mpsc::Sender<Message>,
+    // Notified to close the connection when a connection to the same peer replaces it.
+    close: Arc<Notify>,
+}
+
+// Transport keeps long-lived TCP connections to peers and pushes blocks and transactions over them.
+// As a PeerClient, it sends messages to the peers it is connected to and reaches the HTTP API of
+// peers for their chain.
+pub struct Transport {
+    blockchain: web::Data<Mutex<Blockchain>>,
+    auth: Arc<NodeAuth>,
+    // Address this transport listens on, advertised to peers in handshakes.
+    pub listen_address: String,
+    // Address of the node's HTTP API, advertised to peers in handshakes.
+    http_address: String,
+    connections: Mutex<HashMap<String, Connection>>,
+    next_connection_id: AtomicU64,
+    // Source of handshake challenges.
+    rng: SharedRng,
+    http: HttpPeerClient,
+}
+
+impl Transport {
+    // Create a transport for the given blockchain.
+    pub fn new(
+        blockchain: web::Data<Mutex<Blockchain>>,
+        auth: Arc<NodeAuth>,
+        listen_address: &str,
+        http_address: &str,
+    ) -> Transport {
+        Transport {
+            blockchain,
+            auth,
+            listen_address: listen_address.to_string(),
+            http_address: http_address.to_string(),
+            connections: Mutex::new(HashMap::new()),
+            next_connection_id: AtomicU64::new(0),
+            rng: SharedRng::default(),
+            http: HttpPeerClient::new(),
+        }
+    }
+
+    // Draw handshake challenges from the given source instead of a generator of its own.
+    pub fn with_rng(mut self, rng: SharedRng) -> Transport {
+        self.rng = rng;
+        self
+    }
+
+    // Address of the node's HTTP API, advertised to peers in handshakes.
+    pub fn http_address(&self) -> &str {
+        &self.http_address
+    }
+
+    // Accept peer connections and keep connecting to registered peers, forever.
+    pub async fn serve(self: Arc<Self>, listener: TcpListener) {
+        tokio::spawn(self.clone().maintain().in_current_span());
+        loop {
+            match listener.accept().await {
+                Ok((stream, remote)) => {
+                    tokio::spawn(
+                        self.clone()
+                            .handle_connection(stream, remote, false)
+                            .instrument(info_span!("peer_connection", %remote)),
+                    );
+                }
+                Err(e) => warn!(error = %e, "Failed to accept peer connection"),
+            }
+        }
+    }
+
+    // Connect to a peer transport at the given address.
+    pub async fn connect(self: Arc<Self>, address: String) -> io::Result<()> {
+        let stream = TcpStream::connect(&address).await?;
+        let remote = stream.peer_addr()?;
+        tokio::spawn(
+            self.handle_connection(stream, remote, true)
+                .instrument(info_span!("peer_connection", %remote)),
+        );
+        Ok(())
+    }
+
+    // Node keys of the peers we currently hold a connection to.
+    pub fn connected_peers(&self) -> Vec<String> {
+        self.connections
+            .lock()
+            .expect("Unable to lock connections")
+            .keys()
+            .cloned()
+            .collect()
+    }
+
+    // Send a message to every connected peer, except the one it came from. Peers too slow to
+    // take the message miss it; they catch up from the next announcement or their headers.
+    pub fn broadcast(&self, message: Message, except: Option<&str>) {
+        let connections = self.connections.lock().expect("Unable to lock connections");
+        for (peer, connection) in connections.iter() {
+            if Some(peer.as_str()) == except {
+                continue;
+            }
+            if let Err(mpsc::error::TrySendError::Full(_)) =
+                connection.sender.try_send(message.clone())
+            {
+                debug!(%peer, "Peer queue full, dropping message");
+            }
+        }
+    }
+
+    // Announce a newly accepted block to connected peers.
+    pub fn announce_block(&self, block: &Block, except: Option<&str>) {
+        self.broadcast(
+            Message::Inv(vec![Inventory::Block(block.hash.clone())]),
+            except,
+        );
+    }
+
+    // Announce a newly accepted transaction to connected peers.
+    pub fn announce_transaction(&self, transaction: &Transaction, except: Option<&str>) {
+        if let Ok(hash) = transaction.compute_hash() {
+            self.broadcast(Message::Inv(vec![Inventory::Tx(hash)]), except);
+        }
+    }
+
+    // Handshake describing this node on the transport.
+    fn local_handshake(&self) -> Handshake {
+        let blockchain = self
+            .blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read");
+        Handshake::local(&blockchain, &self.http_address)
+            .with_p2p_address(Some(self.listen_address.clone()))
+            .with_node_key(self.auth.public_key())
+    }
+
+    // Periodically connect to registered peers that advertise a transport and ping open connections.
+    async fn maintain(self: Arc<Self>) {
+        let mut interval = tokio::time::interval(MAINTENANCE_INTERVAL);
+        let mut nonce = 0;
+        loop {
+            interval.tick().await;
+            nonce += 1;
+            self.broadcast(Message::Ping(nonce), None);
+
+            let connected = self.connected_peers();
+            let addresses: Vec<String> = self
+                .blockchain
+                .lock()
+                .expect("Unable to lock blockchain for read")
+                .peers
+                .iter()
+                .filter_map(|peer| peer.handshake.as_ref())
+                .filter(|handshake| {
+                    !handshake
+                        .node_key
+                        .as_ref()
+                        .is_some_and(|node_key| connected.contains(node_key))
+                })
+                .filter_map(|handshake| handshake.p2p_address.clone())
+                .filter(|address| *address != self.listen_address)
+                .collect();
+            for address in addresses {
+                if let Err(e) = self.clone().connect(address.clone()).await {
+                    debug!(%address, error = %e, "Failed to connect to peer transport");
+                }
+            }
+        }
+    }
+
+    // Exchange handshakes with a newly connected peer, then serve it until the connection drops.
+    // outbound is whether we opened the connection.
+    async fn handle_connection(
+        self: Arc<Self>,
+        stream: TcpStream,
+        remote: SocketAddr,
+        outbound: bool,
+    ) {
+        // Until the peer proves it holds a node key, it is scored under its socket address
+        let scored_peer = socket_key(remote);
+        if self
+            .blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read")
+            .peer_scores
+            .is_banned(&scored_peer)
+        {
+            return;
+        }
+        let (mut reader, mut writer) = stream.into_split();
+
+        let local = self.local_handshake();
+        let challenge = self.rng.bytes(CHALLENGE_BYTES);
+        let (remote_handshake, node_key) = match exchange_handshakes(
+            &mut reader,
+            &mut writer,
+            &self.auth,
+            &local,
+            challenge,
+        )
+        .await
+        {
+            Ok(exchanged) => exchanged,
+            Err(Some(offence)) => {
+                self.penalise(&scored_peer, offence);
+                return;
+            }
+            Err(None) => return,
+        };
+
+        // Key the connection, and score the peer, by the node key it proved it holds. The
+        // transport address it advertises is its own claim, so another node could advertise it
+        // too.
+        let peer = node_key.clone();
+        let scored_peer = node_key.clone();
+        if self
+            .blockchain
+            .lock()
+            .expect("Unable to lock blockchain for read")
+            .peer_scores
+            .is_banned(&scored_peer)
+        {
+            return;
+        }
+        let (sender, mut receiver) = mpsc::channel(PEER_QUEUE_LEN);
+        let close = Arc::new(Notify::new());
+        let connection = Connection {
+            id: self.next_connection_id.fetch_add(1, Ordering::Relaxed),
+            opened_by: match outbound {
+                true => self.auth.public_key(),
+                false => node_key.clone(),
+            },
+            node_address: remote_handshake.node_address.clone(),
+            sender: sender.clone(),
+            close: close.clone(),
+        };
+        let id = connection.id;
+        if !self.register_connection(&node_key, connection) {
+            debug!(%peer, "Already connected to peer");
+            return;
+        }
+        info!(
+            %peer,
+            node_address = %remote_handshake.node_address,
+            tip_height = remote_handshake.tip_height,
+            "Peer transport connected"
+        );
+
+        // Ask for the blocks we are missing
+        if remote_handshake.tip_height > local.tip_height {
+            let _ = sender.try_send(Message::GetHeaders {
+                from_height: local.tip_height + 1,
+            });
+        }
+
+        let auth = self.auth.clone();
+        let writer_task = tokio::spawn(async move {
+            while let Some(message) = receiver.recv().await {
+                if write_frame(&mut writer, &message, &auth.identity)
+                    .await
+                    .is_err()
+                {
+                    break;
+                }
+            }
+        });
+
+        loop {
+            let frame = tokio::select! {
+                frame = read_frame(&mut reader) => frame,
+                _ = close.notified() => break,
+            };
+            let frame = match frame {
+                Ok(frame) => frame,
+                Err(e) => {
+                    self.penalise_for_frame_error(&scored_peer, &e);
+                    break;
+                }
+            };
+            // Every frame must be signed by the key the peer presented in its handshake
+            if !frame.is_signed_by(&node_key) {
+                self.penalise(&scored_peer, Offence::MalformedMessage);
+                break;
+            }
+            for reply in self.handle_message(&peer, &scored_peer, frame.message) {
+                if sender.send(reply).await.is_err() {
+                    break;
+                }
+            }
+        }
+
+        {
+            let mut connections = self.connections.lock().expect("Unable to lock connections");
+            if connections
+                .get(&peer)
+                .is_some_and(|current| current.id == id)
+            {
+                connections.remove(&peer);
+            }
+        }
+        writer_task.abort();
+        info!(%peer, "Peer transport disconnected");
+    }
+
+    // Register a connection to a peer, by its node key. When two nodes connect to each other at
+    // the same time, both keep the connection opened by the node with the lower node key, so they
+    // agree on which one to drop. Returns false if the connection is not kept.
+    fn register_connection(&self, node_key: &str, connection: Connection) -> bool {
+        let mut connections = self.connections.lock().expect("Unable to lock connections");
+        if let Some(current) = connections.get(node_key) {
+            let preferred = self.auth.public_key().min(node_key.to_string());
+            if current.opened_by == connection.opened_by || connection.opened_by != preferred {
+                return false;
+            }
+            current.close.notify_one();
+        }
+        connections.insert(node_key.to_string(), connection);
+        true
+    }
+
+    // Penalise a peer for a frame we could not decode.
+    fn penalise_for_frame_error(&self, scored_peer: &str, err: &io::Error) {
+        if let Some(offence) = frame_offence(err) {
+            self.penalise(scored_peer, offence);
+        }
+    }
+
+    // Penalise a peer for an offence committed on the transport.
+    fn penalise(&self, scored_peer: &str, offence: Offence) {
+        self.blockchain
+            .lock()
+            .expect("Unable to lock blockchain for update")
+            .peer_scores
+            .penalise(scored_peer, offence);
+    }
+
+    // Handle a message from a peer, relaying what it brought to our other peers, and return the
+    // replies to send back.
+    fn handle_message(&self, peer: &str, scored_peer: &str, message: Message) -> Vec<Message> {
+        let gossip = handle_gossip(
+            &mut self
+                .blockchain
+                .lock()
+                .expect("Unable to lock blockchain for update"),
+            scored_peer,
+            message,
+        );
+        if let Some(relay) = gossip.relay {
+            self.broadcast(relay, Some(peer));
+        }
+        gossip.replies
+    }
+}
+
+impl PeerClient for Transport {
+    async fn fetch_blocks(
+        &self,
+        node_address: &str,
+        cursor: Option<&str>,
+    ) -> Result<Vec<u8>, Box<dyn Error>> {
+        self.http.fetch_blocks(node_address, cursor).await
+    }
+
+    async fn send_block(
+        &self,
+        node_address: &str,
+        block: &Block,
+        auth: &NodeAuth,
+    ) -> Result<(), Box<dyn Error>> {
+        self.http.send_block(node_address, block, auth).await
+    }
+
+    async fn send_message(
+        &self,
+        node_address: &str,
+        message: Message,
+    ) -> Result<(), Box<dyn Error>> {
+        let connections = self.connections.lock().expect("Unable to lock connections");
+        let connection = connections
+            .values()
+            .find(|connection| connection.node_address == node_address)
+            .ok_or_else(|| format!("{} is not connected over the transport", node_address))?;
+        connection
+            .sender
+            .try_send(message)
+            .map_err(|_| format!("Queue of {} is full", node_address))?;
+        Ok(())
+    }
+}
+
+// Gossip is what handling a message from a peer leads to: replies to the peer, and a message
+// to relay to our other peers.
+pub struct Gossip {
+    pub replies: Vec<Message>,
+    pub relay: Option<Message>,
+}
+
+impl Gossip {
+    fn reply(replies: Vec<Message>) -> Gossip {
+        Gossip {
+            replies,
+            relay: None,
+        }
+    }
+
+    fn relay(relay: Message) -> Gossip {
+        Gossip {
+            replies: vec![],
+            relay: Some(relay),
+        }
+    }
+}
+
+// Handle a message from a peer, whose offences are scored under scored_peer. Blocks and
+// transactions it sends are taken in and announced on to our other peers; blocks we are
+// missing are asked for by hash, or by height when we are behind. Nodes handle messages of the
+// transport with it, and simulated networks with their own message passing.
+pub fn handle_gossip(blockchain: &mut Blockchain, scored_peer: &str, message: Message) -> Gossip {
+    match message {
+        Message::Ping(nonce) => Gossip::reply(vec![Message::Pong(nonce)]),
+        Message::Inv(items) => {
+            let wanted: Vec<Inventory> = items
+                .into_iter()
+                .filter(|item| match item {
+                    Inventory::Block(hash) => !blockchain.has_block(hash),
+                    Inventory::Tx(hash) => !blockchain.has_transaction(hash),
+                })
+                .collect();
+            if wanted.is_empty() {
+                Gossip::reply(vec![])
+            } else {
+                Gossip::reply(vec![Message::GetData(wanted)])
+            }
+        }
+        Message::GetData(items) => Gossip::reply(
+            items
+                .into_iter()
+                .filter_map(|item| match item {
+                    Inventory::Block(hash) => blockchain
+                        .chain
+                        .iter()
+                        .find(|block| block.hash == hash)
+                        .cloned()
+                        .map(Message::Block),
+                    Inventory::Tx(hash) => blockchain
+                        .unconfirmed_transactions
+                        .iter()
+                        .find(|tx| tx.compute_hash().ok().as_deref() == Some(hash.as_str()))
+                        .cloned()
+                        .map(Message::Tx),
+                })
+                .collect(),
+        ),
+        Message::Block(block) => {
+            let tip_height = blockchain.get_last_block().index;
+            let index = block.index;
+            match blockchain.receive_block(scored_peer, block) {
+                Ok(Received::Added) => {
+                    let tip = blockchain.get_last_block().hash.clone();
+                    Gossip::relay(Message::Inv(vec![Inventory::Block(tip)]))
+                }
+                // We are behind the peer: fetch the blocks in between
+                Ok(Received::Orphan(_)) if index > tip_height + 1 => {
+                    Gossip::reply(vec![Message::GetHeaders {
+                        from_height: tip_height + 1,
+                    }])
+                }
+                // The block is on another branch: walk it back to where it leaves our chain
+                Ok(Received::Orphan(parent)) => {
+                    Gossip::reply(vec![Message::GetData(vec![Inventory::Block(parent)])])
+                }
+                Ok(Received::Known) | Ok(Received::Fork) | Err(_) => Gossip::reply(vec![]),
+            }
+        }
+        Message::Tx(transaction) => {
+            let hash = match transaction.compute_hash() {
+                Ok(hash) => hash,
+                Err(_) => return Gossip::reply(vec![]),
+            };
+            if blockchain.has_transaction(&hash) {
+                return Gossip::reply(vec![]);
+            }
+            match blockchain.accept_transaction(transaction) {
+                Ok(()) => Gossip::relay(Message::Inv(vec![Inventory::Tx(hash)])),
+                Err(TransactionError::InvalidData) => {
+                    blockchain
+                        .peer_scores
+                        .penalise(scored_peer, Offence::InvalidTransaction);
+                    Gossip::reply(vec![])
+                }
+                // Not the peer's fault: the transaction is dropped until blocks make room
+                Err(TransactionError::MempoolFull) => Gossip::reply(vec![]),
+            }
+        }
+        Message::GetHeaders { from_height } => {
+            let headers = blockchain
+                .chain
+                .iter()
+                .skip(from_height.max(0) as usize)
+                .take(MAX_HEADERS)
+                .map(|block| block.header())
+                .collect();
+            Gossip::reply(vec![Message::Headers(headers)])
+        }
+        Message::Headers(headers) => {
+            let tip_height = blockchain.get_last_block().index;
+            let wanted: Vec<Inventory> = headers
+                .into_iter()
+                .filter(|header| header.index > tip_height && !blockchain.has_block(&header.hash))
+                .map(|header| Inventory::Block(header.hash))
+                .collect();
+            if wanted.is_empty() {
+                Gossip::reply(vec![])
+            } else {
+                Gossip::reply(vec![Message::GetData(wanted)])
+            }
+        }
+        Message::Pong(_) | Message::Handshake { .. } | Message::Verack(_) => Gossip::reply(vec![]),
+    }
+}
+
+// Offence committed by sending a frame we could not decode. Closed connections are not one.
+fn frame_offence(err: &io::Error) -> Option<Offence> {
+    if err.kind() != io::ErrorKind::InvalidData {
+        return None;
+    }
+    if err
+        .get_ref()
+        .is_some_and(|inner| inner.is::<FrameTooLarge>())
+    {
+        Some(Offence::OversizedPayload)
+    } else {
+        Some(Offence::MalformedMessage)
+    }
+}
+
+// Exchange handshakes over a new connection and prove both sides hold the key they advertise:
+// each side sends its handshake with a random challenge, signed with its node key, and answers
+// the challenge of the other with a Verack signed the same way. A recorded handshake is of no
+// use without the key, as the challenge is new on every connection. Returns the handshake and
+// key of the peer, or the offence it committed if it is refused for misbehaving.
+pub(crate) async fn exchange_handshakes<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    auth: &NodeAuth,
+    local: &Handshake,
+    challenge: Vec<u8>,
+) -> Result<(Handshake, String), Option<Offence>>
+where
+    R: AsyncRead + Unpin,
+    W: AsyncWrite + Unpin,
+{
+    let hello = Message::Handshake {
+        handshake: local.clone(),
+        challenge: challenge.clone(),
+    };
+    write_frame(writer, &hello, &auth.identity)
+        .await
+        .map_err(|_| None)?;
+    let (handshake, their_challenge, node_key) = match read_handshake_frame(reader).await? {
+        Frame {
+            message:
+                Message::Handshake {
+                    handshake,
+                    challenge,
+                },
+            payload,
+            signature,
+        } => match handshake.node_key.clone() {
+            // The handshake must be signed by the key it advertises
+            Some(node_key) if verify_signature(&node_key, &payload, &signature) => {
+                (handshake, challenge, node_key)
+            }
+            _ => return Err(Some(Offence::MalformedMessage)),
+        },
+        _ => return Err(Some(Offence::MalformedMessage)),
+    };
+    if let Err(e) = auth.check_allowed(&node_key) {
+        warn!(error = %e, "Refusing peer transport");
+        return Err(None);
+    }
+    if let Err(e) = handshake.check_compatible(local) {
+        warn!(error = %e, "Refusing peer transport");
+        return Err(None);
+    }
+
+    write_frame(writer, &Message::Verack(their_challenge), &auth.identity)
+        .await
+        .map_err(|_| None)?;
+    let frame = read_handshake_frame(reader).await?;
+    let answered = matches!(&frame.message, Message::Verack(echo) if *echo == challenge);
+    if !answered || !frame.is_signed_by(&node_key) {
+        warn!(%node_key, "Peer failed to answer our challenge");
+        return Err(Some(Offence::MalformedMessage));
+    }
+    Ok((handshake, node_key))
+}
+
+// Read a frame of the handshake, which the peer has HANDSHAKE_TIMEOUT to send.
+async fn read_handshake_frame<R: AsyncRead + Unpin>(
+    reader: &mut R,
+) -> Result<Frame, Option<Offence>> {
+    match tokio::time::timeout(HANDSHAKE_TIMEOUT, read_frame(reader)).await {
+        Ok(Ok(frame)) => Ok(frame),
+        Ok(Err(e)) => Err(frame_offence(&e)),
+        Err(_) => Err(None),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::chain::MAX_PENDING_TRANSACTIONS;
+    use crate::modules::blockchain::genesis::Network;
+    use crate::modules::network::auth::NodeIdentity;
+    use crate::modules::network::wire::write_frame;
+
+    fn transport() -> Transport {
+        let blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        Transport::new(
+            web::Data::new(Mutex::new(blockchain)),
+            Arc::new(NodeAuth::new(NodeIdentity::generate(), None)),
+            "127.0.0.1:9080",
+            "http://127.0.0.1:8080",
+        )
+    }
+
+    fn connection(transport: &Transport, opened_by: &str) -> (Connection, mpsc::Receiver<Message>) {
+        let (sender, receiver) = mpsc::channel(PEER_QUEUE_LEN);
+        let connection = Connection {
+            id: transport.next_connection_id.fetch_add(1, Ordering::Relaxed),
+            opened_by: opened_by.to_string(),
+            node_address: format!("http://{}", opened_by),
+            sender,
+            close: Arc::new(Notify::new()),
+        };
+        (connection, receiver)
+    }
+
+    // Key of the node that opened the connection a transport keeps, when connections opened by
+    // first and second arrive in that order.
+    fn kept(transport: &Transport, node_key: &str, first: &str, second: &str) -> String {
+        transport.connections.lock().unwrap().clear();
+        let (first, _) = connection(transport, first);
+        let (second, _) = connection(transport, second);
+        assert!(transport.register_connection(node_key, first));
+        transport.register_connection(node_key, second);
+        transport.connections.lock().unwrap()[node_key]
+            .opened_by
+            .clone()
+    }
+
+    #[test]
+    fn both_sides_keep_the_connection_opened_by_the_lower_key() {
+        let ours = transport();
+        let theirs = transport();
+        let (our_key, their_key) = (ours.auth.public_key(), theirs.auth.public_key());
+        let lower = our_key.clone().min(their_key.clone());
+
+        // Whichever connection each side registers first, they end up with the same one
+        for (first, second) in [(&our_key, &their_key), (&their_key, &our_key)] {
+            assert_eq!(kept(&ours, &their_key, first, second), lower);
+            assert_eq!(kept(&theirs, &our_key, second, first), lower);
+        }
+        // A second connection opened by the same node is refused
+        assert_eq!(kept(&ours, &their_key, &their_key, &their_key), their_key);
+    }
+
+    fn handshake_of(auth: &NodeAuth) -> Handshake {
+        let blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        Handshake::local(&blockchain, "http://127.0.0.1:8080").with_node_key(auth.public_key())
+    }
+
+    // Run the handshake of a node over one end of a connection, closing it when done.
+    async fn handshake_over(
+        stream: tokio::io::DuplexStream,
+        auth: &NodeAuth,
+        challenge: &[u8],
+    ) -> Result<(Handshake, String), Option<Offence>> {
+        let (mut reader, mut writer) = tokio::io::split(stream);
+        let local = handshake_of(auth);
+        exchange_handshakes(&mut reader, &mut writer, auth, &local, challenge.to_vec()).await
+    }
+
+    #[tokio::test]
+    async fn handshakes_prove_both_nodes_hold_their_key() {
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let (our_auth, their_auth) = (
+            NodeAuth::new(NodeIdentity::generate(), None),
+            NodeAuth::new(NodeIdentity::generate(), None),
+        );
+        let (we_got, they_got) = tokio::join!(
+            handshake_over(ours, &our_auth, b"ours"),
+            handshake_over(theirs, &their_auth, b"theirs"),
+        );
+        assert_eq!(we_got.unwrap().1, their_auth.public_key());
+        assert_eq!(they_got.unwrap().1, our_auth.public_key());
+    }
+
+    #[tokio::test]
+    async fn handshakes_signed_with_another_key_are_refused() {
+        let (ours, mut theirs) = tokio::io::duplex(1 << 20);
+        let impersonated = NodeAuth::new(NodeIdentity::generate(), None);
+        let hello = Message::Handshake {
+            handshake: handshake_of(&impersonated),
+            challenge: b"theirs".to_vec(),
+        };
+        write_frame(&mut theirs, &hello, &NodeIdentity::generate())
+            .await
+            .unwrap();
+
+        let auth = NodeAuth::new(NodeIdentity::generate(), None);
+        assert_eq!(
+            handshake_over(ours, &auth, b"ours").await.err(),
+            Some(Some(Offence::MalformedMessage))
+        );
+    }
+
+    #[tokio::test]
+    async fn nodes_outside_the_allowlist_are_refused() {
+        let (ours, theirs) = tokio::io::duplex(1 << 20);
+        let their_auth = NodeAuth::new(NodeIdentity::generate(), None);
+        let our_auth = NodeAuth::new(
+            NodeIdentity::generate(),
+            Some(vec![NodeIdentity::generate().public_key()]),
+        );
+        let (we_got, they_got) = tokio::join!(
+            handshake_over(ours, &our_auth, b"ours"),
+            handshake_over(theirs, &their_auth, b"theirs"),
+        );
+        // Being refused is not an offence, and the peer never gets our answer
+        assert_eq!(we_got.err(), Some(None));
+        assert!(they_got.is_err());
+    }
+
+    #[tokio::test]
+    async fn recorded_handshakes_cannot_be_replayed() {
+        // Frames a node signed on an earlier connection, answering the challenge of that one
+        let recorded = NodeAuth::new(NodeIdentity::generate(), None);
+        let hello = Message::Handshake {
+            handshake: handshake_of(&recorded),
+            challenge: b"theirs".to_vec(),
+        };
+        let verack = Message::Verack(b"earlier".to_vec());
+        let (ours, mut theirs) = tokio::io::duplex(1 << 20);
+        write_frame(&mut theirs, &hello, &recorded.identity)
+            .await
+            .unwrap();
+        write_frame(&mut theirs, &verack, &recorded.identity)
+            .await
+            .unwrap();
+
+        let auth = NodeAuth::new(NodeIdentity::generate(), None);
+        assert_eq!(
+            handshake_over(ours, &auth, b"ours").await.err(),
+            Some(Some(Offence::MalformedMessage))
+        );
+    }
+
+    // Connect to a transport as a node advertising the given transport address, returning the
+    // connection once the handshake is done.
+    async fn connect_as(
+        address: SocketAddr,
+        auth: &NodeAuth,
+        p2p_address: &str,
+    ) -> (
+        tokio::net::tcp::OwnedReadHalf,
+        tokio::net::tcp::OwnedWriteHalf,
+    ) {
+        let (mut reader, mut writer) = TcpStream::connect(address).await.unwrap().into_split();
+        let local = handshake_of(auth).with_p2p_address(Some(p2p_address.to_string()));
+        exchange_handshakes(&mut reader, &mut writer, auth, &local, b"ours".to_vec())
+            .await
+            .unwrap();
+        (reader, writer)
+    }
+
+    #[tokio::test]
+    async fn nodes_cannot_take_over_the_connection_of_another() {
+        let transport = Arc::new(transport());
+        let listener = TcpListener::bind("127.0.0.1:0").await.unwrap();
+        let address = listener.local_addr().unwrap();
+        tokio::spawn(transport.clone().serve(listener));
+
+        let real = NodeAuth::new(NodeIdentity::generate(), None);
+        let _real_connection = connect_as(address, &real, "10.0.0.1:9080").await;
+        // A node with the lowest key claims the transport address of the real one, which would
+        // win if connections were told apart by the address peers advertise
+        let lowest = real.public_key().min(transport.auth.public_key());
+        let impostor = loop {
+            let auth = NodeAuth::new(NodeIdentity::generate(), None);
+            if auth.public_key() < lowest {
+                break auth;
+            }
+        };
+        let _impostor_connection = connect_as(address, &impostor, "10.0.0.1:9080").await;
+
+        let mut connected = vec![];
+        for _ in 0..100 {
+            connected = transport.connected_peers();
+            if connected.len() == 2 {
+                break;
+            }
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        connected.sort();
+        let mut expected = vec![real.public_key(), impostor.public_key()];
+        expected.sort();
+        assert_eq!(connected, expected);
+    }
+
+    #[test]
+    fn transactions_leave_the_mempool_once_a_peer_confirms_them() {
+        let genesis = Network::Devnet.genesis();
+        let mut ours = Blockchain::from_genesis(&genesis).unwrap();
+        let mut theirs = Blockchain::from_genesis(&genesis).unwrap();
+        let transaction = Transaction {
+            author: "alice".to_string(),
+            content: "hello".to_string(),
+            timestamp: 1_700_000_000,
+        };
+        let hash = transaction.compute_hash().unwrap();
+
+        let gossip = handle_gossip(&mut ours, "peer", Message::Tx(transaction.clone()));
+        assert!(gossip.relay.is_some());
+        assert_eq!(ours.unconfirmed_transactions.len(), 1);
+
+        theirs.add_new_transaction(transaction.clone());
+        theirs.mine_block().unwrap();
+        handle_gossip(&mut ours, "peer", Message::Block(theirs.chain[1].clone()));
+        assert_eq!(ours.chain.len(), 2);
+        assert!(ours.unconfirmed_transactions.is_empty());
+
+        // Late announcements of the transaction do not bring it back
+        let gossip = handle_gossip(&mut ours, "peer", Message::Inv(vec![Inventory::Tx(hash)]));
+        assert!(gossip.replies.is_empty());
+        let gossip = handle_gossip(&mut ours, "peer", Message::Tx(transaction));
+        assert!(gossip.relay.is_none());
+        assert!(ours.unconfirmed_transactions.is_empty());
+    }
+
+    #[test]
+    fn invalid_or_excess_transactions_are_not_taken_in() {
+        let mut blockchain = Blockchain::from_genesis(&Network::Devnet.genesis()).unwrap();
+        let post = |author: &str, content: &str| {
+            Message::Tx(Transaction {
+                author: author.to_string(),
+                content: content.to_string(),
+                timestamp: 1_700_000_000,
+            })
+        };
+
+        let gossip = handle_gossip(&mut blockchain, "peer", post("alice", ""));
+        assert!(gossip.relay.is_none());
+        assert!(blockchain.unconfirmed_transactions.is_empty());
+        assert_eq!(
+            blockchain.peer_scores.score("peer"),
+            Offence::InvalidTransaction.penalty()
+        );
+
+        for i in 0..MAX_PENDING_TRANSACTIONS {
+            blockchain.add_new_transaction(Transaction {
+                author: "bob".to_string(),
+                content: format!("post {}", i),
+                timestamp: 1_700_000_000,
+            });
+        }
+        let gossip = handle_gossip(&mut blockchain, "other", post("alice", "hello"));
+        assert!(gossip.relay.is_none());
+        assert_eq!(
+            blockchain.unconfirmed_transactions.len(),
+            MAX_PENDING_TRANSACTIONS
+        );
+        // A full mempool is not the peer's fault
+        assert_eq!(blockchain.peer_scores.score("other"), 0);
+    }
+
+    #[test]
+    fn broadcasts_to_a_slow_peer_are_dropped() {
+        let transport = transport();
+        let (connection, mut receiver) = connection(&transport, "peer");
+        assert!(transport.register_connection("peer", connection));
+        for nonce in 0..PEER_QUEUE_LEN as u64 + 10 {
+            transport.broadcast(Message::Ping(nonce), None);
+        }
+        let mut queued = 0;
+        while receiver.try_recv().is_ok() {
+            queued += 1;
+        }
+        assert_eq!(queued, PEER_QUEUE_LEN);
+    }
+}
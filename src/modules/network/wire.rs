@@ -0,0 +1,185 @@
+use serde::{Deserialize, Serialize};
+use std::io;
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+use super::auth::{verify_signature, NodeIdentity};
+use super::handshake::Handshake;
+use crate::modules::blockchain::block::{Block, BlockHeader};
+use crate::modules::blockchain::chain::Transaction;
+
+// Largest frame accepted from a peer.
+pub const MAX_FRAME_BYTES: usize = 8 * 1024 * 1024;
+// Size of the ed25519 signature in front of every frame.
+pub const SIGNATURE_BYTES: usize = 64;
+// Largest number of headers sent in one Headers message.
+pub const MAX_HEADERS: usize = 2000;
+
+// Inventory identifies an object a peer has, by hash.
+#[derive(Debug, Serialize, Deserialize, Clone, PartialEq, Eq)]
+pub enum Inventory {
+    Block(String),
+    Tx(String),
+}
+
+// Message is a single frame exchanged between nodes over the peer-to-peer transport.
+#[derive(Debug, Serialize, Deserialize, Clone)]
+pub enum Message {
+    // First message on a connection: the sender's handshake and a random challenge the other
+    // side must answer with a Verack, so a recorded handshake cannot be replayed.
+    Handshake {
+        handshake: Handshake,
+        challenge: Vec<u8>,
+    },
+    // Answer to the challenge of a Handshake, echoing it in a frame signed with the node key.
+    Verack(Vec<u8>),
+    Ping(u64),
+    Pong(u64),
+    Inv(Vec<Inventory>),
+    GetData(Vec<Inventory>),
+    Block(Block),
+    Tx(Transaction),
+    GetHeaders {
+        from_height: i32,
+    },
+    Headers(Vec<BlockHeader>),
+}
+
+// Encode a message into our binary encoding.
+pub fn encode(message: &Message) -> io::Result<Vec<u8>> {
+    bincode::serialize(message).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Decode a message from our binary encoding.
+pub fn decode(bytes: &[u8]) -> io::Result<Message> {
+    bincode::deserialize(bytes).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+// Frame is a message received from a peer together with the signature over its encoding.
+pub struct Frame {
+    pub message: Message,
+    pub payload: Vec<u8>,
+    pub signature: Vec<u8>,
+}
+
+impl Frame {
+    // Check the frame was signed by the node with the given public key.
+    pub fn is_signed_by(&self, public_key: &str) -> bool {
+        verify_signature(public_key, &self.payload, &self.signature)
+    }
+}
+
+// Write a message as a frame: a 4-byte big-endian length, then the node's signature over the
+// encoded message, then the encoded message.
+pub async fn write_frame<W: AsyncWrite + Unpin>(
+    writer: &mut W,
+    message: &Message,
+    identity: &NodeIdentity,
+) -> io::Result<()> {
+    let bytes = encode(message)?;
+    let signature = identity.sign(&bytes);
+    writer
+        .write_u32((SIGNATURE_BYTES + bytes.len()) as u32)
+        .await?;
+    writer.write_all(&signature).await?;
+    writer.write_all(&bytes).await?;
+    writer.flush().await
+}
+
+// Read a single frame and decode the message it carries. The signature is checked by the caller.
+pub async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> io::Result<Frame> {
+    let len = reader.read_u32().await? as usize;
+    if len > MAX_FRAME_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            FrameTooLarge(len),
+        ));
+    }
+    if len < SIGNATURE_BYTES {
+        return Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            "Frame too short for signature",
+        ));
+    }
+    let mut bytes = vec![0; len];
+    reader.read_exact(&mut bytes).await?;
+    let payload = bytes.split_off(SIGNATURE_BYTES);
+    Ok(Frame {
+        message: decode(&payload)?,
+        payload,
+        signature: bytes,
+    })
+}
+
+// FrameTooLarge is the error returned when a peer announces a frame above MAX_FRAME_BYTES.
+#[derive(Debug)]
+pub struct FrameTooLarge(pub usize);
+
+impl std::fmt::Display for FrameTooLarge {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "Frame of {} bytes exceeds the limit", self.0)
+    }
+}
+
+impl std::error::Error for FrameTooLarge {}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    async fn frame_bytes(message: &Message, identity: &NodeIdentity) -> Vec<u8> {
+        let mut bytes = Vec::new();
+        write_frame(&mut bytes, message, identity).await.unwrap();
+        bytes
+    }
+
+    // Bytes of a frame announcing len bytes, followed by the given ones.
+    fn raw_frame(len: u32, rest: &[u8]) -> Vec<u8> {
+        let mut bytes = len.to_be_bytes().to_vec();
+        bytes.extend_from_slice(rest);
+        bytes
+    }
+
+    #[tokio::test]
+    async fn frames_carry_signed_messages() {
+        let identity = NodeIdentity::generate();
+        let bytes = frame_bytes(&Message::Ping(7), &identity).await;
+        let frame = read_frame(&mut bytes.as_slice()).await.unwrap();
+        assert!(matches!(frame.message, Message::Ping(7)));
+        assert!(frame.is_signed_by(&identity.public_key()));
+        assert!(!frame.is_signed_by(&NodeIdentity::generate().public_key()));
+    }
+
+    #[tokio::test]
+    async fn oversized_frames_are_refused_before_reading_them() {
+        let bytes = raw_frame(MAX_FRAME_BYTES as u32 + 1, &[]);
+        let err = read_frame(&mut bytes.as_slice()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(err.get_ref().unwrap().is::<FrameTooLarge>());
+    }
+
+    #[tokio::test]
+    async fn truncated_frames_are_refused() {
+        let identity = NodeIdentity::generate();
+        let bytes = frame_bytes(&Message::Ping(7), &identity).await;
+        let err = read_frame(&mut &bytes[..bytes.len() - 1])
+            .await
+            .err()
+            .unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+
+        // Frames too short to hold a signature
+        let bytes = raw_frame(SIGNATURE_BYTES as u32 - 1, &[0; SIGNATURE_BYTES - 1]);
+        let err = read_frame(&mut bytes.as_slice()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+    }
+
+    #[tokio::test]
+    async fn undecodable_messages_are_refused() {
+        let mut rest = vec![0; SIGNATURE_BYTES];
+        rest.extend_from_slice(&[0xff; 8]);
+        let bytes = raw_frame(rest.len() as u32, &rest);
+        let err = read_frame(&mut bytes.as_slice()).await.err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert!(!err.get_ref().unwrap().is::<FrameTooLarge>());
+    }
+}
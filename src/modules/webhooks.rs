@@ -0,0 +1,661 @@
+use schemars::JsonSchema;
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+use std::collections::HashMap;
+use std::error::Error;
+use std::fs::{self, OpenOptions};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+use tokio::sync::broadcast::{self, error::RecvError};
+use tracing::{debug, error, info_span, warn, Instrument};
+
+use super::api::TransactionRecord;
+use super::blockchain::chain::write_atomically;
+use super::blockchain::events::NodeEvent;
+use super::clock::{SharedClock, SharedRng};
+use super::network::auth::NodeAuth;
+
+// Webhooks deliver chain events to URLs registered by operators, for services that cannot keep
+// a connection open to /v1/events.
+//
+// A delivery is a POST of a WebhookDelivery as JSON, signed with the node key as messages
+// between nodes are: the X-Node-Key, X-Node-Timestamp and X-Node-Signature headers carry an
+// ed25519 signature of "<path>\n<timestamp>\n<body>", which receivers check against the public
+// key of the node. Deliveries that fail or are answered with anything but a 2xx are retried
+// with backoff, and written to the dead-letter log once every attempt failed, or when the node
+// shuts down before they succeeded. Deliveries run independently of each other, so they may
+// arrive out of order. Events the node drops because deliveries fell behind the chain are
+// recorded in the dead-letter log too, as an events_missed entry for each webhook.
+
+// Files of the data directory holding the webhooks and the deliveries that failed.
+pub const WEBHOOKS_FILE: &str = "webhooks.json";
+pub const DEAD_LETTER_FILE: &str = "webhooks_dead_letter.jsonl";
+// Attempts at a delivery before it goes to the dead-letter log.
+pub const MAX_ATTEMPTS: u32 = 5;
+// Wait before the first retry of a delivery, doubled after each one.
+const FIRST_RETRY_DELAY: Duration = Duration::from_secs(1);
+const DELIVERY_TIMEOUT: Duration = Duration::from_secs(10);
+
+// WebhookEvent is a kind of event webhooks are registered for.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize, JsonSchema)]
+#[serde(rename_all = "snake_case")]
+pub enum WebhookEvent {
+    // A block was added to the chain, mined by this node or another one. Its data is the block.
+    BlockMined,
+    // A transaction was included in a block added to the chain. Its data is the
+    // TransactionRecord of the confirmed transaction.
+    TransactionConfirmed,
+    // Consensus replaced blocks of the chain. Its data is the Reorg.
+    Reorg,
+    // Events were dropped before deliveries were made for them. It is only recorded in the
+    // dead-letter log, not delivered or registered for. Its data holds the number of events.
+    EventsMissed,
+}
+
+// Webhook is a URL events of some kinds are delivered to.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct Webhook {
+    pub id: String,
+    pub url: String,
+    pub events: Vec<WebhookEvent>,
+    pub created_at: i64,
+}
+
+// WebhookDelivery is the body POSTed to a webhook.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct WebhookDelivery {
+    // Stays the same across attempts, so that receivers can drop duplicates.
+    pub id: String,
+    pub webhook_id: String,
+    pub event: WebhookEvent,
+    pub created_at: i64,
+    pub data: Value,
+}
+
+// DeadLetter is a delivery that failed every attempt.
+#[derive(Debug, Clone, Serialize, Deserialize, JsonSchema)]
+pub struct DeadLetter {
+    pub url: String,
+    pub attempts: u32,
+    pub error: String,
+    pub failed_at: i64,
+    pub delivery: WebhookDelivery,
+}
+
+// Webhooks holds the webhooks of a node, kept in its data directory, and delivers events to
+// them.
+pub struct Webhooks {
+    hooks: Mutex<Vec<Webhook>>,
+    path: PathBuf,
+    // Held while the dead-letter log is written, so that entries do not interleave.
+    dead_letter_log: Mutex<PathBuf>,
+    // Deliveries being attempted by id, with their URL, attempts so far and last error, so that
+    // the ones still retrying at shutdown can be written to the dead-letter log.
+    in_flight: Mutex<HashMap<String, InFlight>>,
+    clock: SharedClock,
+    rng: SharedRng,
+    client: reqwest::Client,
+    retry_delay: Duration,
+}
+
+struct InFlight {
+    url: String,
+    delivery: WebhookDelivery,
+    attempts: u32,
+    error: Option<String>,
+}
+
+impl Webhooks {
+    // Load the webhooks kept in a data directory, if any. Webhook and delivery IDs are drawn
+    // from the given RNG.
+    pub fn load(
+        data_dir: &Path,
+        clock: SharedClock,
+        rng: SharedRng,
+    ) -> Result<Webhooks, Box<dyn Error>> {
+        let path = data_dir.join(WEBHOOKS_FILE);
+        let hooks = match path.exists() {
+            true => serde_json::from_str(&fs::read_to_string(&path)?)?,
+            false => Vec::new(),
+        };
+        Ok(Webhooks {
+            hooks: Mutex::new(hooks),
+            path,
+            dead_letter_log: Mutex::new(data_dir.join(DEAD_LETTER_FILE)),
+            in_flight: Mutex::new(HashMap::new()),
+            clock,
+            rng,
+            client: reqwest::Client::builder()
+                .timeout(DELIVERY_TIMEOUT)
+                .build()?,
+            retry_delay: FIRST_RETRY_DELAY,
+        })
+    }
+
+    fn random_id(&self, bytes: usize) -> String {
+        hex::encode(self.rng.bytes(bytes))
+    }
+
+    pub fn list(&self) -> Vec<Webhook> {
+        self.hooks.lock().unwrap().clone()
+    }
+
+    // Register a webhook for some kinds of events, returning it or why it was refused.
+    pub fn add(&self, url: &str, events: Vec<WebhookEvent>) -> Result<Webhook, String> {
+        match reqwest::Url::parse(url) {
+            Ok(parsed) if matches!(parsed.scheme(), "http" | "https") && parsed.has_host() => {}
+            _ => return Err(format!("{:?} is not an http(s) URL", url)),
+        }
+        if events.is_empty() {
+            return Err("A webhook needs at least one event".to_string());
+        }
+        if events.contains(&WebhookEvent::EventsMissed) {
+            return Err("Missed events are only recorded in the dead-letter log".to_string());
+        }
+        let webhook = Webhook {
+            id: self.random_id(8),
+            url: url.to_string(),
+            events,
+            created_at: self.clock.now(),
+        };
+        let mut hooks = self.hooks.lock().unwrap();
+        hooks.push(webhook.clone());
+        self.save(&hooks).map_err(|e| e.to_string())?;
+        Ok(webhook)
+    }
+
+    // Remove a webhook, returning it if there was one with the id.
+    pub fn remove(&self, id: &str) -> Result<Option<Webhook>, Box<dyn Error>> {
+        let mut hooks = self.hooks.lock().unwrap();
+        let Some(position) = hooks.iter().position(|hook| hook.id == id) else {
+            return Ok(None);
+        };
+        let webhook = hooks.remove(position);
+        self.save(&hooks)?;
+        Ok(Some(webhook))
+    }
+
+    fn save(&self, hooks: &[Webhook]) -> Result<(), Box<dyn Error>> {
+        write_atomically(&self.path, &serde_json::to_string_pretty(hooks)?)
+    }
+
+    // The last deliveries that failed, oldest first.
+    pub fn dead_letters(&self, limit: usize) -> Result<Vec<DeadLetter>, Box<dyn Error>> {
+        let path = self.dead_letter_log.lock().unwrap();
+        if !path.exists() {
+            return Ok(Vec::new());
+        }
+        let log = fs::read_to_string(&*path)?;
+        let lines: Vec<&str> = log.lines().filter(|line| !line.is_empty()).collect();
+        lines[lines.len().saturating_sub(limit)..]
+            .iter()
+            .map(|line| Ok(serde_json::from_str(line)?))
+            .collect()
+    }
+
+    fn write_dead_letter(&self, dead_letter: &DeadLetter) -> Result<(), Box<dyn Error>> {
+        let path = self.dead_letter_log.lock().unwrap();
+        if let Some(dir) = path.parent() {
+            fs::create_dir_all(dir)?;
+        }
+        let mut log = OpenOptions::new().create(true).append(true).open(&*path)?;
+        writeln!(log, "{}", serde_json::to_string(dead_letter)?)?;
+        Ok(())
+    }
+
+    // Deliveries an event of the chain makes, to the webhooks registered for them.
+    pub fn deliveries(&self, event: &NodeEvent) -> Vec<(String, WebhookDelivery)> {
+        let payloads = match event {
+            NodeEvent::Block(block) => {
+                let mut payloads = vec![(WebhookEvent::BlockMined, json!(block))];
+                payloads.extend(block.transactions.iter().map(|transaction| {
+                    let record = TransactionRecord::confirmed(transaction, block);
+                    (WebhookEvent::TransactionConfirmed, json!(record))
+                }));
+                payloads
+            }
+            NodeEvent::Reorg(reorg) => vec![(WebhookEvent::Reorg, json!(reorg))],
+            NodeEvent::Transaction(_) | NodeEvent::Peer(_) => return Vec::new(),
+        };
+        let hooks = self.hooks.lock().unwrap();
+        let mut deliveries = Vec::new();
+        for (event, data) in payloads {
+            for hook in hooks.iter().filter(|hook| hook.events.contains(&event)) {
+                let delivery = WebhookDelivery {
+                    id: self.random_id(16),
+                    webhook_id: hook.id.clone(),
+                    event,
+                    created_at: self.clock.now(),
+                    data: data.clone(),
+                };
+                deliveries.push((hook.url.clone(), delivery));
+            }
+        }
+        deliveries
+    }
+
+    // Deliver the events of the chain to the webhooks, until the node shuts down.
+    pub async fn run(
+        self: Arc<Self>,
+        mut events: broadcast::Receiver<NodeEvent>,
+        auth: Arc<NodeAuth>,
+    ) {
+        loop {
+            let event = match events.recv().await {
+                Ok(event) => event,
+                Err(RecvError::Lagged(missed)) => {
+                    warn!(missed, "Webhooks fell behind and missed events");
+                    self.record_missed(missed);
+                    continue;
+                }
+                Err(RecvError::Closed) => return,
+            };
+            for (url, delivery) in self.deliveries(&event) {
+                let span = info_span!("webhook_delivery", delivery_id = %delivery.id, %url);
+                tokio::spawn(
+                    self.clone()
+                        .deliver(url, delivery, auth.clone())
+                        .instrument(span),
+                );
+            }
+        }
+    }
+
+    // Record in the dead-letter log that events were dropped before deliveries were made for
+    // them, once for each webhook since we cannot tell which ones they were for.
+    fn record_missed(&self, missed: u64) {
+        let error = format!(
+            "Webhooks fell behind the chain and missed {} events",
+            missed
+        );
+        for hook in self.list() {
+            let dead_letter = DeadLetter {
+                url: hook.url,
+                attempts: 0,
+                error: error.clone(),
+                failed_at: self.clock.now(),
+                delivery: WebhookDelivery {
+                    id: self.random_id(16),
+                    webhook_id: hook.id,
+                    event: WebhookEvent::EventsMissed,
+                    created_at: self.clock.now(),
+                    data: json!({ "missed": missed }),
+                },
+            };
+            if let Err(e) = self.write_dead_letter(&dead_letter) {
+                error!(error = %e, "Failed to write webhook dead letter");
+            }
+        }
+    }
+
+    // Deliver to a webhook, retrying with backoff and writing it to the dead-letter log if
+    // every attempt fails.
+    pub async fn deliver(
+        self: Arc<Self>,
+        url: String,
+        delivery: WebhookDelivery,
+        auth: Arc<NodeAuth>,
+    ) {
+        let id = delivery.id.clone();
+        self.in_flight.lock().unwrap().insert(
+            id.clone(),
+            InFlight {
+                url: url.clone(),
+                delivery: delivery.clone(),
+                attempts: 0,
+                error: None,
+            },
+        );
+        let mut delay = self.retry_delay;
+        let mut attempts = 0;
+        let error = loop {
+            attempts += 1;
+            let error = match self.send(&url, &delivery, &auth).await {
+                Ok(()) => {
+                    debug!(attempts, "Delivered webhook");
+                    self.in_flight.lock().unwrap().remove(&id);
+                    return;
+                }
+                Err(e) => e,
+            };
+            debug!(attempts, %error, "Webhook delivery attempt failed");
+            if attempts == MAX_ATTEMPTS {
+                break error;
+            }
+            if let Some(in_flight) = self.in_flight.lock().unwrap().get_mut(&id) {
+                in_flight.attempts = attempts;
+                in_flight.error = Some(error);
+            }
+            tokio::time::sleep(delay).await;
+            delay *= 2;
+        };
+        // Deliveries still retrying at shutdown were written to the log already
+        if self.in_flight.lock().unwrap().remove(&id).is_none() {
+            return;
+        }
+        let dead_letter = DeadLetter {
+            url,
+            attempts,
+            error,
+            failed_at: self.clock.now(),
+            delivery,
+        };
+        warn!(attempts, error = %dead_letter.error, "Webhook delivery failed");
+        if let Err(e) = self.write_dead_letter(&dead_letter) {
+            error!(error = %e, "Failed to write webhook dead letter");
+        }
+    }
+
+    // Write the deliveries that have not succeeded yet to the dead-letter log, before the node
+    // shuts down and their retries stop.
+    pub fn flush(&self) -> Result<(), Box<dyn Error>> {
+        let in_flight: Vec<InFlight> = self
+            .in_flight
+            .lock()
+            .unwrap()
+            .drain()
+            .map(|(_, pending)| pending)
+            .collect();
+        if !in_flight.is_empty() {
+            warn!(
+                deliveries = in_flight.len(),
+                "Shutting down with webhook deliveries pending"
+            );
+        }
+        for pending in in_flight {
+            self.write_dead_letter(&DeadLetter {
+                url: pending.url,
+                attempts: pending.attempts,
+                error: match pending.error {
+                    Some(error) => format!("Node shut down while retrying: {}", error),
+                    None => "Node shut down before the delivery succeeded".to_string(),
+                },
+                failed_at: self.clock.now(),
+                delivery: pending.delivery,
+            })?;
+        }
+        Ok(())
+    }
+
+    async fn send(
+        &self,
+        url: &str,
+        delivery: &WebhookDelivery,
+        auth: &NodeAuth,
+    ) -> Result<(), String> {
+        let parsed = reqwest::Url::parse(url).map_err(|e| e.to_string())?;
+        let body = serde_json::to_string(delivery).map_err(|e| e.to_string())?;
+        let mut request = self
+            .client
+            .post(parsed.clone())
+            .header("Content-Type", "application/json");
+        for (name, value) in auth.sign(parsed.path(), body.as_bytes()).headers() {
+            request = request.header(name, value);
+        }
+        let response = request.body(body).send().await.map_err(|e| e.to_string())?;
+        match response.status() {
+            status if status.is_success() => Ok(()),
+            status => Err(format!("Webhook answered {}", status)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::modules::blockchain::block::Block;
+    use crate::modules::blockchain::chain::Transaction;
+    use crate::modules::network::auth::{NodeIdentity, NodeSignature};
+    use actix_web::{web, App, HttpRequest, HttpResponse, HttpServer};
+
+    fn data_dir(name: &str) -> PathBuf {
+        let dir = std::env::temp_dir().join(format!("versus-{}-{}", name, std::process::id()));
+        let _ = fs::remove_dir_all(&dir);
+        fs::create_dir_all(&dir).unwrap();
+        dir
+    }
+
+    fn block() -> Block {
+        let transaction = |author: &str| Transaction {
+            author: author.to_string(),
+            content: "post".to_string(),
+            timestamp: 1_000,
+        };
+        Block {
+            index: 1,
+            transactions: vec![transaction("alice"), transaction("bob")],
+            timestamp: 1_000,
+            previous_hash: String::new(),
+            nonce: 0,
+            hash: "hash".to_string(),
+        }
+    }
+
+    #[test]
+    fn deliveries_follow_registrations() {
+        let dir = data_dir("webhooks");
+        let webhooks = Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap();
+        assert!(webhooks
+            .add("ftp://host", vec![WebhookEvent::Reorg])
+            .is_err());
+        assert!(webhooks.add("http://host", vec![]).is_err());
+        let blocks = webhooks
+            .add("http://blocks", vec![WebhookEvent::BlockMined])
+            .unwrap();
+        webhooks
+            .add(
+                "http://transactions",
+                vec![WebhookEvent::TransactionConfirmed, WebhookEvent::Reorg],
+            )
+            .unwrap();
+
+        let deliveries = webhooks.deliveries(&NodeEvent::Block(block()));
+        let sent: Vec<(&str, WebhookEvent)> = deliveries
+            .iter()
+            .map(|(url, delivery)| (url.as_str(), delivery.event))
+            .collect();
+        assert_eq!(
+            sent,
+            vec![
+                ("http://blocks", WebhookEvent::BlockMined),
+                ("http://transactions", WebhookEvent::TransactionConfirmed),
+                ("http://transactions", WebhookEvent::TransactionConfirmed),
+            ]
+        );
+        assert_eq!(deliveries[2].1.data["author"], "bob");
+        assert_eq!(deliveries[2].1.data["block_height"], 1);
+
+        // Webhooks are kept across restarts
+        let webhooks = Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap();
+        assert_eq!(webhooks.list().len(), 2);
+        assert!(webhooks.remove(&blocks.id).unwrap().is_some());
+        assert!(webhooks.remove(&blocks.id).unwrap().is_none());
+        let webhooks = Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap();
+        assert_eq!(webhooks.list().len(), 1);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    // Receiver answering with errors to the first requests of each path and to every request to
+    // /down, and with success otherwise, recording who signed each request it got.
+    #[derive(Default)]
+    struct Receiver {
+        failures: usize,
+        requests: Mutex<Vec<(String, Option<String>)>>,
+    }
+
+    async fn receive(
+        receiver: web::Data<Receiver>,
+        req: HttpRequest,
+        body: web::Bytes,
+    ) -> HttpResponse {
+        let signature = NodeSignature::from_headers(|name| req.headers().get(name)?.to_str().ok());
+        let signer = NodeAuth::new(NodeIdentity::generate(), None)
+            .verify(req.path(), &body, signature)
+            .ok()
+            .flatten();
+        let mut requests = receiver.requests.lock().unwrap();
+        requests.push((req.path().to_string(), signer));
+        let attempts = requests
+            .iter()
+            .filter(|(path, _)| path == req.path())
+            .count();
+        match req.path() == "/down" || attempts <= receiver.failures {
+            true => HttpResponse::InternalServerError().finish(),
+            false => HttpResponse::Ok().finish(),
+        }
+    }
+
+    #[actix_web::test]
+    async fn failed_deliveries_are_retried_then_dead_lettered() {
+        let receiver = web::Data::new(Receiver {
+            failures: 2,
+            ..Receiver::default()
+        });
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = {
+            let receiver = receiver.clone();
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(receiver.clone())
+                    .default_service(web::to(receive))
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run()
+        };
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = data_dir("webhook-deliveries");
+        let mut webhooks =
+            Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap();
+        webhooks.retry_delay = Duration::from_millis(1);
+        let webhooks = Arc::new(webhooks);
+        let auth = Arc::new(NodeAuth::new(NodeIdentity::generate(), None));
+        let delivery = |url: &str| WebhookDelivery {
+            id: format!("delivery-{}", url),
+            webhook_id: url.to_string(),
+            event: WebhookEvent::BlockMined,
+            created_at: 0,
+            data: json!(block()),
+        };
+
+        // Two failures and a success
+        let url = format!("http://{}/flaky", address);
+        webhooks
+            .clone()
+            .deliver(url.clone(), delivery(&url), auth.clone())
+            .await;
+        assert!(webhooks.dead_letters(10).unwrap().is_empty());
+
+        // Down for longer than the retries last
+        let url = format!("http://{}/down", address);
+        let down = delivery(&url);
+        webhooks
+            .clone()
+            .deliver(url.clone(), down.clone(), auth.clone())
+            .await;
+        let dead_letters = webhooks.dead_letters(10).unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, url);
+        assert_eq!(dead_letters[0].attempts, MAX_ATTEMPTS);
+        assert_eq!(dead_letters[0].delivery.id, down.id);
+
+        let requests = receiver.requests.lock().unwrap().clone();
+        let count = |path: &str| requests.iter().filter(|(p, _)| p == path).count();
+        assert_eq!(count("/flaky"), 3);
+        assert_eq!(count("/down"), MAX_ATTEMPTS as usize);
+        assert!(requests
+            .iter()
+            .all(|(_, signer)| signer.as_deref() == Some(auth.public_key().as_str())));
+        handle.stop(false).await;
+        fs::remove_dir_all(dir).unwrap();
+    }
+    #[tokio::test]
+    async fn missed_events_are_dead_lettered() {
+        let dir = data_dir("webhook-missed");
+        let webhooks =
+            Arc::new(Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap());
+        let hook = webhooks
+            .add("http://blocks", vec![WebhookEvent::BlockMined])
+            .unwrap();
+        assert!(webhooks
+            .add("http://gaps", vec![WebhookEvent::EventsMissed])
+            .is_err());
+
+        // Events published faster than they are taken make the receiver skip the oldest ones
+        let (sender, events) = broadcast::channel(1);
+        for _ in 0..3 {
+            sender.send(NodeEvent::Block(block())).unwrap();
+        }
+        drop(sender);
+        let auth = Arc::new(NodeAuth::new(NodeIdentity::generate(), None));
+        webhooks.clone().run(events, auth).await;
+
+        let dead_letters = webhooks.dead_letters(10).unwrap();
+        let missed = dead_letters
+            .iter()
+            .find(|dead| dead.delivery.event == WebhookEvent::EventsMissed)
+            .unwrap();
+        assert_eq!(missed.url, hook.url);
+        assert_eq!(missed.delivery.webhook_id, hook.id);
+        assert_eq!(missed.delivery.data["missed"], 2);
+        fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[actix_web::test]
+    async fn deliveries_pending_at_shutdown_are_dead_lettered() {
+        let receiver = web::Data::new(Receiver::default());
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let address = listener.local_addr().unwrap();
+        let server = {
+            let receiver = receiver.clone();
+            HttpServer::new(move || {
+                App::new()
+                    .app_data(receiver.clone())
+                    .default_service(web::to(receive))
+            })
+            .workers(1)
+            .listen(listener)
+            .unwrap()
+            .run()
+        };
+        let handle = server.handle();
+        actix_web::rt::spawn(server);
+
+        let dir = data_dir("webhook-shutdown");
+        let mut webhooks =
+            Webhooks::load(&dir, SharedClock::default(), SharedRng::default()).unwrap();
+        webhooks.retry_delay = Duration::from_secs(60);
+        let webhooks = Arc::new(webhooks);
+        let auth = Arc::new(NodeAuth::new(NodeIdentity::generate(), None));
+        let url = format!("http://{}/down", address);
+        let delivery = WebhookDelivery {
+            id: "pending".to_string(),
+            webhook_id: "hook".to_string(),
+            event: WebhookEvent::BlockMined,
+            created_at: 0,
+            data: json!(block()),
+        };
+        let retrying = actix_web::rt::spawn(webhooks.clone().deliver(url.clone(), delivery, auth));
+        while receiver.requests.lock().unwrap().is_empty() {
+            tokio::time::sleep(Duration::from_millis(10)).await;
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+
+        webhooks.flush().unwrap();
+        let dead_letters = webhooks.dead_letters(10).unwrap();
+        assert_eq!(dead_letters.len(), 1);
+        assert_eq!(dead_letters[0].url, url);
+        assert_eq!(dead_letters[0].attempts, 1);
+        assert_eq!(dead_letters[0].delivery.id, "pending");
+        assert!(dead_letters[0].error.contains("shut down"));
+        retrying.abort();
+        handle.stop(false).await;
+        fs::remove_dir_all(dir).unwrap();
+    }
+}